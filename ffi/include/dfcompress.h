@@ -0,0 +1,63 @@
+/* Copyright 2018, Mathias Rav <m@git.strova.dk>
+ * SPDX-License-Identifier: LGPL-2.1+
+ *
+ * C interface of dfcompress, for converting between compressed and
+ * uncompressed Dwarf Fortress data files. Link with -ldfcompress_ffi.
+ */
+#ifndef DFCOMPRESS_H
+#define DFCOMPRESS_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Status codes returned by the functions below. */
+#define DF_OK 0
+#define DF_ERROR_IO 1
+#define DF_ERROR_UNEXPECTED_EOF 2
+#define DF_ERROR_VERSION_IS_ZERO 3
+#define DF_ERROR_COMPRESSION_UNKNOWN 4
+#define DF_ERROR_CHUNK_TOO_LARGE 5
+#define DF_ERROR_NOT_COMPRESSED 6
+#define DF_ERROR_LIMIT_EXCEEDED 7
+#define DF_ERROR_CANCELLED 8
+#define DF_ERROR_INVALID_ARGUMENT 9
+#define DF_ERROR_NOT_A_DELTA 10
+#define DF_ERROR_DELTA_MISMATCH 11
+#define DF_ERROR_DELTA_CORRUPT 12
+/* An error of a kind that this version of the header does not know. */
+#define DF_ERROR_OTHER 255
+
+/* Returns a static description of a status code. */
+const char *df_strerror(int code);
+
+/* Compresses or decompresses a whole data file in memory.
+ * On success, *output receives a buffer of *output_len bytes,
+ * to be freed with df_free_buf. */
+int df_compress_buf(const uint8_t *input, size_t input_len,
+                    uint8_t **output, size_t *output_len);
+int df_uncompress_buf(const uint8_t *input, size_t input_len,
+                      uint8_t **output, size_t *output_len);
+void df_free_buf(uint8_t *buf, size_t len);
+
+/* Streaming interface. Feed the input file with df_stream_write, call
+ * df_stream_finish at its end, and take the output with df_stream_read
+ * as it becomes available, until it returns 0 after df_stream_finish.
+ * Once a call fails, every later call fails with the same status. */
+typedef struct DfStream DfStream;
+
+DfStream *df_compress_new(void);
+DfStream *df_uncompress_new(void);
+int df_stream_write(DfStream *stream, const uint8_t *data, size_t len);
+int df_stream_finish(DfStream *stream);
+size_t df_stream_read(DfStream *stream, uint8_t *out, size_t cap);
+void df_stream_free(DfStream *stream);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
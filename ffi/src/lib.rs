@@ -0,0 +1,360 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+//! C bindings of dfcompress. See `include/dfcompress.h` for the API.
+use std::os::raw::{c_char, c_int};
+use std::{ptr, slice};
+
+use dfcompress::{Error, ErrorKind, Recoder, Result, Target};
+
+pub const DF_OK: c_int = 0;
+pub const DF_ERROR_IO: c_int = 1;
+pub const DF_ERROR_UNEXPECTED_EOF: c_int = 2;
+pub const DF_ERROR_VERSION_IS_ZERO: c_int = 3;
+pub const DF_ERROR_COMPRESSION_UNKNOWN: c_int = 4;
+pub const DF_ERROR_CHUNK_TOO_LARGE: c_int = 5;
+pub const DF_ERROR_NOT_COMPRESSED: c_int = 6;
+pub const DF_ERROR_LIMIT_EXCEEDED: c_int = 7;
+pub const DF_ERROR_CANCELLED: c_int = 8;
+pub const DF_ERROR_INVALID_ARGUMENT: c_int = 9;
+pub const DF_ERROR_NOT_A_DELTA: c_int = 10;
+pub const DF_ERROR_DELTA_MISMATCH: c_int = 11;
+pub const DF_ERROR_DELTA_CORRUPT: c_int = 12;
+/// An error of a kind that these bindings do not know yet.
+pub const DF_ERROR_OTHER: c_int = 255;
+
+fn code(e: &Error) -> c_int {
+    match e.kind() {
+        ErrorKind::Cancelled => DF_ERROR_CANCELLED,
+        ErrorKind::ChunkTooLarge(_) => DF_ERROR_CHUNK_TOO_LARGE,
+        ErrorKind::CompressionUnknown(_) => DF_ERROR_COMPRESSION_UNKNOWN,
+        ErrorKind::DeltaCorrupt => DF_ERROR_DELTA_CORRUPT,
+        ErrorKind::DeltaMismatch => DF_ERROR_DELTA_MISMATCH,
+        ErrorKind::Io(_) => DF_ERROR_IO,
+        ErrorKind::LimitExceeded => DF_ERROR_LIMIT_EXCEEDED,
+        ErrorKind::NotADelta => DF_ERROR_NOT_A_DELTA,
+        ErrorKind::NotCompressed => DF_ERROR_NOT_COMPRESSED,
+        ErrorKind::UnexpectedEof => DF_ERROR_UNEXPECTED_EOF,
+        ErrorKind::VersionIsZero => DF_ERROR_VERSION_IS_ZERO,
+        _ => DF_ERROR_OTHER,
+    }
+}
+
+fn status(result: Result<()>) -> c_int {
+    match result {
+        Ok(()) => DF_OK,
+        Err(ref e) => code(e),
+    }
+}
+
+/// Returns a static description of a status code.
+#[no_mangle]
+pub extern "C" fn df_strerror(code: c_int) -> *const c_char {
+    let message: &'static [u8] = match code {
+        DF_OK => b"Success\0",
+        DF_ERROR_IO => b"I/O error\0",
+        DF_ERROR_UNEXPECTED_EOF => b"Unexpected end of input\0",
+        DF_ERROR_VERSION_IS_ZERO => b"Version is zero\0",
+        DF_ERROR_COMPRESSION_UNKNOWN => b"Unknown compression\0",
+        DF_ERROR_CHUNK_TOO_LARGE => b"Chunk too large\0",
+        DF_ERROR_NOT_COMPRESSED => b"Not compressed\0",
+        DF_ERROR_LIMIT_EXCEEDED => b"Decompressed size exceeds the limit\0",
+        DF_ERROR_CANCELLED => b"Cancelled\0",
+        DF_ERROR_INVALID_ARGUMENT => b"Invalid argument\0",
+        DF_ERROR_NOT_A_DELTA => b"Not a delta\0",
+        DF_ERROR_DELTA_MISMATCH => b"Delta does not apply to this base\0",
+        DF_ERROR_DELTA_CORRUPT => b"Delta is damaged\0",
+        DF_ERROR_OTHER => b"Other error\0",
+        _ => b"Unknown error\0",
+    };
+    message.as_ptr() as *const c_char
+}
+
+/// Hands `data` to C, storing it in `*output` and `*output_len`.
+unsafe fn give(data: Vec<u8>, output: *mut *mut u8, output_len: *mut usize) {
+    *output_len = data.len();
+    *output = Box::into_raw(data.into_boxed_slice()) as *mut u8;
+}
+
+unsafe fn convert(
+    input: *const u8,
+    input_len: usize,
+    output: *mut *mut u8,
+    output_len: *mut usize,
+    f: fn(&[u8]) -> Result<Vec<u8>>,
+) -> c_int {
+    if (input.is_null() && input_len > 0) || output.is_null() || output_len.is_null() {
+        return DF_ERROR_INVALID_ARGUMENT;
+    }
+    let input = match input_len {
+        0 => &[][..],
+        _ => slice::from_raw_parts(input, input_len),
+    };
+    match f(input) {
+        Ok(data) => {
+            give(data, output, output_len);
+            DF_OK
+        }
+        Err(ref e) => code(e),
+    }
+}
+
+/// Compresses the data file in `input`, like the `dfcompress` program.
+///
+/// # Safety
+///
+/// `input` must point to `input_len` readable bytes, and `output` and
+/// `output_len` must be writable. Free the output with `df_free_buf`.
+#[no_mangle]
+pub unsafe extern "C" fn df_compress_buf(
+    input: *const u8,
+    input_len: usize,
+    output: *mut *mut u8,
+    output_len: *mut usize,
+) -> c_int {
+    convert(
+        input,
+        input_len,
+        output,
+        output_len,
+        dfcompress::compress_bytes,
+    )
+}
+
+/// Decompresses the data file in `input`, like the `dfuncompress` program.
+///
+/// # Safety
+///
+/// As for `df_compress_buf`.
+#[no_mangle]
+pub unsafe extern "C" fn df_uncompress_buf(
+    input: *const u8,
+    input_len: usize,
+    output: *mut *mut u8,
+    output_len: *mut usize,
+) -> c_int {
+    convert(
+        input,
+        input_len,
+        output,
+        output_len,
+        dfcompress::decompress_bytes,
+    )
+}
+
+/// Frees a buffer returned by `df_compress_buf` or `df_uncompress_buf`.
+///
+/// # Safety
+///
+/// `buf` and `len` must come from one of those functions, or `buf` is null.
+#[no_mangle]
+pub unsafe extern "C" fn df_free_buf(buf: *mut u8, len: usize) {
+    if !buf.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(buf, len)));
+    }
+}
+
+/// A streaming compressor or decompressor, fed with `df_stream_write`.
+pub struct DfStream {
+    recoder: Recoder,
+    /// The status of the first call that failed, or `DF_OK`.
+    status: c_int,
+    finished: bool,
+    /// The number of bytes of the output already taken by `df_stream_read`.
+    taken: usize,
+}
+
+impl DfStream {
+    fn new(target: Target) -> *mut DfStream {
+        let stream = DfStream {
+            recoder: Recoder::new(target),
+            status: DF_OK,
+            finished: false,
+            taken: 0,
+        };
+        Box::into_raw(Box::new(stream))
+    }
+
+    /// Runs `f` unless an earlier call failed, remembering its status.
+    fn run<F: FnOnce(&mut Recoder) -> Result<()>>(&mut self, f: F) -> c_int {
+        if self.status == DF_OK {
+            self.status = status(f(&mut self.recoder));
+        }
+        self.status
+    }
+
+    /// Discards the output already read, once there is enough of it.
+    fn compact(&mut self) {
+        let output = self.recoder.output();
+        if self.taken == output.len() {
+            output.clear();
+            self.taken = 0;
+        } else if self.taken > output.len() / 2 {
+            output.drain(..self.taken);
+            self.taken = 0;
+        }
+    }
+}
+
+/// Creates a stream that compresses a data file.
+#[no_mangle]
+pub extern "C" fn df_compress_new() -> *mut DfStream {
+    DfStream::new(Target::Compressed)
+}
+
+/// Creates a stream that decompresses a data file.
+#[no_mangle]
+pub extern "C" fn df_uncompress_new() -> *mut DfStream {
+    DfStream::new(Target::Uncompressed)
+}
+
+/// Feeds the next `len` bytes of the input file to `stream`.
+/// After an error, the stream fails every call with the same status.
+///
+/// # Safety
+///
+/// `stream` must come from `df_compress_new` or `df_uncompress_new`, and
+/// `data` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn df_stream_write(
+    stream: *mut DfStream,
+    data: *const u8,
+    len: usize,
+) -> c_int {
+    let stream = match stream.as_mut() {
+        Some(stream) if !stream.finished && (len == 0 || !data.is_null()) => stream,
+        _ => return DF_ERROR_INVALID_ARGUMENT,
+    };
+    let data = match len {
+        0 => &[][..],
+        _ => slice::from_raw_parts(data, len),
+    };
+    stream.compact();
+    stream.run(|recoder| recoder.push(data))
+}
+
+/// Signals the end of the input, making the rest of the output available.
+///
+/// # Safety
+///
+/// As for `df_stream_write`.
+#[no_mangle]
+pub unsafe extern "C" fn df_stream_finish(stream: *mut DfStream) -> c_int {
+    let stream = match stream.as_mut() {
+        Some(stream) => stream,
+        None => return DF_ERROR_INVALID_ARGUMENT,
+    };
+    if stream.finished {
+        return stream.status;
+    }
+    stream.finished = true;
+    stream.run(Recoder::finish)
+}
+
+/// Copies up to `cap` bytes of the available output to `out`, returning
+/// the number of bytes copied. Returns 0 once the output is exhausted.
+///
+/// # Safety
+///
+/// `stream` must be as for `df_stream_write`, and `out` must point to `cap`
+/// writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn df_stream_read(stream: *mut DfStream, out: *mut u8, cap: usize) -> usize {
+    let stream = match stream.as_mut() {
+        Some(stream) if !out.is_null() => stream,
+        _ => return 0,
+    };
+    let taken = stream.taken;
+    let available = &stream.recoder.output()[taken..];
+    let n = available.len().min(cap);
+    ptr::copy_nonoverlapping(available.as_ptr(), out, n);
+    stream.taken += n;
+    n
+}
+
+/// Frees a stream.
+///
+/// # Safety
+///
+/// `stream` must come from `df_compress_new` or `df_uncompress_new`, or be
+/// null. It must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn df_stream_free(stream: *mut DfStream) {
+    if !stream.is_null() {
+        drop(Box::from_raw(stream));
+    }
+}
+
+#[cfg(test)]
+fn sample() -> Vec<u8> {
+    let header = dfcompress::Header::new(1625, dfcompress::Compression::Uncompressed);
+    let mut data = header.to_bytes().to_vec();
+    data.extend((0..100000usize).map(|i| (i * i % 251) as u8));
+    data
+}
+
+#[test]
+fn buf_test() {
+    let data = sample();
+    let (mut compressed, mut compressed_len) = (ptr::null_mut(), 0);
+    unsafe {
+        let code = df_compress_buf(
+            data.as_ptr(),
+            data.len(),
+            &mut compressed,
+            &mut compressed_len,
+        );
+        assert_eq!(code, DF_OK);
+        let expected = dfcompress::compress_bytes(&data).unwrap();
+        assert_eq!(
+            slice::from_raw_parts(compressed, compressed_len),
+            &expected[..]
+        );
+        let (mut output, mut output_len) = (ptr::null_mut(), 0);
+        let code = df_uncompress_buf(compressed, compressed_len, &mut output, &mut output_len);
+        assert_eq!(code, DF_OK);
+        assert_eq!(slice::from_raw_parts(output, output_len), &data[..]);
+        df_free_buf(output, output_len);
+        let code = df_uncompress_buf(compressed, compressed_len - 1, &mut output, &mut output_len);
+        assert_eq!(code, DF_ERROR_UNEXPECTED_EOF);
+        df_free_buf(compressed, compressed_len);
+    }
+}
+
+#[test]
+fn stream_test() {
+    unsafe fn run(stream: *mut DfStream, input: &[u8]) -> (c_int, Vec<u8>) {
+        let mut output = Vec::new();
+        let mut block = [0; 1000];
+        for piece in input.chunks(777) {
+            let code = df_stream_write(stream, piece.as_ptr(), piece.len());
+            if code != DF_OK {
+                return (code, output);
+            }
+            let n = df_stream_read(stream, block.as_mut_ptr(), block.len());
+            output.extend_from_slice(&block[..n]);
+        }
+        let code = df_stream_finish(stream);
+        loop {
+            let n = df_stream_read(stream, block.as_mut_ptr(), block.len());
+            if n == 0 {
+                break;
+            }
+            output.extend_from_slice(&block[..n]);
+        }
+        df_stream_free(stream);
+        (code, output)
+    }
+
+    let data = sample();
+    let compressed = dfcompress::compress_bytes(&data).unwrap();
+    unsafe {
+        assert_eq!(run(df_compress_new(), &data), (DF_OK, compressed.clone()));
+        assert_eq!(run(df_uncompress_new(), &compressed), (DF_OK, data.clone()));
+        assert_eq!(run(df_uncompress_new(), &data), (DF_OK, data.clone()));
+        let truncated = &compressed[..compressed.len() - 1];
+        assert_eq!(
+            run(df_uncompress_new(), truncated).0,
+            DF_ERROR_UNEXPECTED_EOF
+        );
+    }
+}
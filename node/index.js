@@ -0,0 +1,37 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+'use strict';
+const { Transform } = require('stream');
+const native = require('./dfcompress.node');
+
+/** A Transform stream that converts a data file with a native Converter. */
+class ConvertStream extends Transform {
+  constructor(compress, options) {
+    super(options);
+    this.converter = new native.Converter(compress);
+  }
+
+  _transform(chunk, encoding, callback) {
+    try {
+      callback(null, this.converter.write(chunk));
+    } catch (e) {
+      callback(e);
+    }
+  }
+
+  _flush(callback) {
+    try {
+      callback(null, this.converter.finish());
+    } catch (e) {
+      callback(e);
+    }
+  }
+}
+
+module.exports = {
+  compress: native.compress,
+  decompress: native.decompress,
+  Converter: native.Converter,
+  createCompressStream: (options) => new ConvertStream(true, options),
+  createDecompressStream: (options) => new ConvertStream(false, options),
+};
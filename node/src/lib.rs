@@ -0,0 +1,57 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+//! Node.js bindings of dfcompress. `index.js` wraps them in Node streams.
+use dfcompress::{Error, Recoder, Target};
+use napi::bindgen_prelude::Buffer;
+use napi_derive::napi;
+
+fn napi_err(e: Error) -> napi::Error {
+    napi::Error::from_reason(e.to_string())
+}
+
+/// Compresses a data file.
+#[napi]
+pub fn compress(data: Buffer) -> napi::Result<Buffer> {
+    dfcompress::compress_bytes(&data)
+        .map(Buffer::from)
+        .map_err(napi_err)
+}
+
+/// Decompresses a data file.
+#[napi]
+pub fn decompress(data: Buffer) -> napi::Result<Buffer> {
+    dfcompress::decompress_bytes(&data)
+        .map(Buffer::from)
+        .map_err(napi_err)
+}
+
+/// Converts a data file fed piece by piece: `write` returns the output so
+/// far, `finish` the rest.
+#[napi]
+pub struct Converter(Recoder);
+
+#[napi]
+impl Converter {
+    /// A converter that compresses, or decompresses if `compress` is false.
+    #[napi(constructor)]
+    pub fn new(compress: bool) -> Converter {
+        let target = if compress {
+            Target::Compressed
+        } else {
+            Target::Uncompressed
+        };
+        Converter(Recoder::new(target))
+    }
+
+    #[napi]
+    pub fn write(&mut self, data: Buffer) -> napi::Result<Buffer> {
+        self.0.push(&data).map_err(napi_err)?;
+        Ok(std::mem::take(self.0.output()).into())
+    }
+
+    #[napi]
+    pub fn finish(&mut self) -> napi::Result<Buffer> {
+        self.0.finish().map_err(napi_err)?;
+        Ok(std::mem::take(self.0.output()).into())
+    }
+}
@@ -0,0 +1,24 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+'use strict';
+const assert = require('assert');
+const { Readable } = require('stream');
+const dfcompress = require('.');
+
+const data = Buffer.alloc(8 + 100000);
+data.writeUInt32LE(1625, 0);
+for (let i = 0; i < 100000; i++) data[8 + i] = (i * i) % 251;
+
+const compressed = dfcompress.compress(data);
+assert.strictEqual(compressed.readUInt32LE(4), 1);
+assert.deepStrictEqual(dfcompress.decompress(compressed), data);
+assert.throws(() => dfcompress.decompress(compressed.subarray(0, -1)));
+
+const pieces = [];
+Readable.from([compressed.subarray(0, 1000), compressed.subarray(1000)])
+  .pipe(dfcompress.createDecompressStream())
+  .on('data', (piece) => pieces.push(piece))
+  .on('end', () => {
+    assert.deepStrictEqual(Buffer.concat(pieces), data);
+    console.log('ok');
+  });
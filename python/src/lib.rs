@@ -0,0 +1,199 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+//! Python bindings of dfcompress, built with maturin.
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use dfcompress::{Error, ErrorKind, Recoder, Summary as RustSummary, Target};
+use pyo3::exceptions::{PyOSError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+/// I/O errors become `OSError`, invalid data files `ValueError`.
+fn py_err(e: Error) -> PyErr {
+    match e.kind() {
+        ErrorKind::Io(_) => PyOSError::new_err(e.to_string()),
+        _ => PyValueError::new_err(e.to_string()),
+    }
+}
+
+/// Statistics about a converted file.
+#[pyclass(get_all, frozen)]
+struct Summary {
+    version: u32,
+    chunks: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+impl From<RustSummary> for Summary {
+    fn from(summary: RustSummary) -> Summary {
+        Summary {
+            version: summary.version,
+            chunks: summary.chunks,
+            bytes_in: summary.bytes_in,
+            bytes_out: summary.bytes_out,
+        }
+    }
+}
+
+#[pymethods]
+impl Summary {
+    fn __repr__(&self) -> String {
+        format!(
+            "Summary(version={}, chunks={}, bytes_in={}, bytes_out={})",
+            self.version, self.chunks, self.bytes_in, self.bytes_out
+        )
+    }
+}
+
+/// Compresses a data file held in memory.
+#[pyfunction]
+fn compress<'py>(py: Python<'py>, data: &[u8]) -> PyResult<Bound<'py, PyBytes>> {
+    let output = py.allow_threads(|| dfcompress::compress_bytes(data));
+    Ok(PyBytes::new(py, &output.map_err(py_err)?))
+}
+
+/// Decompresses a data file held in memory.
+#[pyfunction]
+fn decompress<'py>(py: Python<'py>, data: &[u8]) -> PyResult<Bound<'py, PyBytes>> {
+    let output = py.allow_threads(|| dfcompress::decompress_bytes(data));
+    Ok(PyBytes::new(py, &output.map_err(py_err)?))
+}
+
+fn convert_file(py: Python, src: PathBuf, dst: PathBuf, target: Target) -> PyResult<Summary> {
+    let summary = py.allow_threads(|| {
+        let input = BufReader::new(File::open(src)?);
+        let output = BufWriter::new(File::create(dst)?);
+        dfcompress::recode(input, output, target)
+    });
+    Ok(summary.map_err(py_err)?.into())
+}
+
+/// Compresses the data file `src` into `dst`.
+#[pyfunction]
+fn compress_file(py: Python, src: PathBuf, dst: PathBuf) -> PyResult<Summary> {
+    convert_file(py, src, dst, Target::Compressed)
+}
+
+/// Decompresses the data file `src` into `dst`.
+#[pyfunction]
+fn decompress_file(py: Python, src: PathBuf, dst: PathBuf) -> PyResult<Summary> {
+    convert_file(py, src, dst, Target::Uncompressed)
+}
+
+/// Feeds `data` to `recoder` and takes the output.
+fn write<'py>(
+    py: Python<'py>,
+    recoder: &Mutex<Recoder>,
+    data: &[u8],
+) -> PyResult<Bound<'py, PyBytes>> {
+    let mut recoder = recoder.lock().unwrap();
+    recoder.push(data).map_err(py_err)?;
+    let output = PyBytes::new(py, recoder.output());
+    recoder.output().clear();
+    Ok(output)
+}
+
+fn finish<'py>(py: Python<'py>, recoder: &Mutex<Recoder>) -> PyResult<Bound<'py, PyBytes>> {
+    let mut recoder = recoder.lock().unwrap();
+    recoder.finish().map_err(py_err)?;
+    let output = PyBytes::new(py, recoder.output());
+    recoder.output().clear();
+    Ok(output)
+}
+
+/// Compresses a data file fed piece by piece: `write` returns the output
+/// available so far, and `finish` the rest.
+#[pyclass]
+struct Compressor(Mutex<Recoder>);
+
+#[pymethods]
+impl Compressor {
+    #[new]
+    fn new() -> Compressor {
+        Compressor(Mutex::new(Recoder::new(Target::Compressed)))
+    }
+
+    fn write<'py>(&self, py: Python<'py>, data: &[u8]) -> PyResult<Bound<'py, PyBytes>> {
+        write(py, &self.0, data)
+    }
+
+    fn finish<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyBytes>> {
+        finish(py, &self.0)
+    }
+}
+
+/// Decompresses a data file fed piece by piece, like `Compressor`.
+#[pyclass]
+struct Decompressor(Mutex<Recoder>);
+
+#[pymethods]
+impl Decompressor {
+    #[new]
+    fn new() -> Decompressor {
+        Decompressor(Mutex::new(Recoder::new(Target::Uncompressed)))
+    }
+
+    fn write<'py>(&self, py: Python<'py>, data: &[u8]) -> PyResult<Bound<'py, PyBytes>> {
+        write(py, &self.0, data)
+    }
+
+    fn finish<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyBytes>> {
+        finish(py, &self.0)
+    }
+}
+
+#[pymodule]
+#[pyo3(name = "dfcompress")]
+fn dfcompress_module(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(compress, m)?)?;
+    m.add_function(wrap_pyfunction!(decompress, m)?)?;
+    m.add_function(wrap_pyfunction!(compress_file, m)?)?;
+    m.add_function(wrap_pyfunction!(decompress_file, m)?)?;
+    m.add_class::<Compressor>()?;
+    m.add_class::<Decompressor>()?;
+    m.add_class::<Summary>()?;
+    Ok(())
+}
+
+#[test]
+fn python_test() {
+    use pyo3::ffi::c_str;
+    Python::with_gil(|py| {
+        let module = PyModule::new(py, "dfcompress").unwrap();
+        dfcompress_module(&module).unwrap();
+        let locals = pyo3::types::PyDict::new(py);
+        locals.set_item("dfcompress", module).unwrap();
+        let header = dfcompress::Header::new(1625, dfcompress::Compression::Uncompressed);
+        let mut data = header.to_bytes().to_vec();
+        data.extend((0..100000usize).map(|i| (i * i % 251) as u8));
+        let expected = dfcompress::compress_bytes(&data).unwrap();
+        locals.set_item("data", PyBytes::new(py, &data)).unwrap();
+        locals
+            .set_item("expected", PyBytes::new(py, &expected))
+            .unwrap();
+        let script = c_str!(
+            r#"
+compressed = dfcompress.compress(data)
+assert compressed == expected
+assert dfcompress.decompress(compressed) == data
+
+stream = dfcompress.Decompressor()
+output = b"".join(stream.write(compressed[i:i + 777]) for i in range(0, len(compressed), 777))
+assert output + stream.finish() == data
+stream = dfcompress.Compressor()
+assert stream.write(data) + stream.finish() == compressed
+
+try:
+    dfcompress.decompress(compressed[:-1])
+    assert False
+except ValueError:
+    pass
+"#
+        );
+        py.run(script, Some(&locals), None).unwrap();
+    });
+}
@@ -0,0 +1,342 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+use std::collections::VecDeque;
+use std::io;
+use std::io::prelude::*;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::{frame_len, push_frame, read_chunk, ChunkCodec, CompressWriter, Compression};
+use super::{DecompressReader, Error, ErrorKind, Header, Options, Result, Summary, Target};
+
+/// The size of the blocks read from the input.
+const BLOCK_SIZE: usize = 1 << 16;
+
+/// Like `recode_with`, but over tokio's `AsyncRead` and `AsyncWrite`.
+///
+/// (De)compression runs on the calling task, one chunk at a time; only
+/// waiting for I/O yields to the runtime.
+pub async fn recode_async<R, W>(
+    stdin: R,
+    stdout: W,
+    target: Target,
+    options: &Options,
+) -> Result<Summary>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut input = Prefetch::new(stdin);
+    let mut output = Output {
+        inner: stdout,
+        count: 0,
+    };
+    let header = input.read_header().await?;
+    let to = match target {
+        Target::Compressed => options.codec.compression(),
+        Target::Uncompressed => Compression::Uncompressed,
+    };
+    let mut skipped = 0;
+    let chunks = if options.copies(header.compression, to) {
+        copy_payload(&mut input, &mut output, header, options).await?
+    } else {
+        let codec = options.build_decoder(header.compression.codec().unwrap_or_default());
+        let mut source = DecompressReader::from_parts(&mut input, header, codec);
+        source.configure(options);
+        let mut block = vec![0; BLOCK_SIZE];
+        let chunks = if !to.is_compressed() {
+            let header = Header::new(header.version, Compression::Uncompressed);
+            output.write(&header.to_bytes()).await?;
+            while let Some(n) = read_block(&mut source, &mut block).await? {
+                output.write(&block[..n]).await?;
+            }
+            source.chunks()
+        } else {
+            // Progress is reported by the reader, which knows the input offset.
+            let mut options = options.clone();
+            if header.compression.is_compressed() {
+                options.hooks = options.hooks.silent();
+            }
+            let mut writer = CompressWriter::with_options(Vec::new(), header.version, &options)?;
+            while let Some(n) = read_block(&mut source, &mut block).await? {
+                writer.write_all(&block[..n])?;
+                output.drain(writer.get_mut()).await?;
+            }
+            writer.try_finish()?;
+            output.drain(writer.get_mut()).await?;
+            writer.chunks()
+        };
+        skipped = source.errors().len() as u64;
+        chunks
+    };
+    output.inner.flush().await?;
+    Ok(Summary {
+        version: header.version,
+        chunks,
+        bytes_in: input.count,
+        bytes_out: output.count,
+        skipped,
+    })
+}
+
+pub async fn dfcompress_async<R, W>(stdin: R, stdout: W) -> Result<Summary>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    recode_async(stdin, stdout, Target::Compressed, &Options::default()).await
+}
+
+pub async fn dfuncompress_async<R, W>(stdin: R, stdout: W) -> Result<Summary>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    recode_async(stdin, stdout, Target::Uncompressed, &Options::default()).await
+}
+
+/// Writes `header` followed by the rest of `input`, like the `copy_payload`
+/// of `recode_with`.
+async fn copy_payload<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    input: &mut Prefetch<R>,
+    output: &mut Output<W>,
+    header: Header,
+    options: &Options,
+) -> Result<u64> {
+    output.write(&header.to_bytes()).await?;
+    if !header.compression.is_compressed() {
+        let mut block = vec![0; BLOCK_SIZE];
+        while let Some(n) = read_block(input, &mut block).await? {
+            output.write(&block[..n]).await?;
+        }
+        return Ok(0);
+    }
+    let mut payload = Vec::new();
+    let mut frame = Vec::new();
+    let mut chunks = 0;
+    loop {
+        options.hooks.check()?;
+        match read_chunk(input, &mut payload) {
+            Ok(true) => (),
+            Ok(false) => break,
+            Err(ref e) if would_block(e) => {
+                input.fill().await?;
+                continue;
+            }
+            Err(e) => return Err(e),
+        }
+        frame.clear();
+        push_frame(&mut frame, &payload)?;
+        output.write(&frame).await?;
+        options.hooks.progress(output.count, output.count, chunks);
+        chunks += 1;
+    }
+    Ok(chunks)
+}
+
+/// Reads from `source`, fetching more input whenever it would block.
+/// Returns `None` at the end of the input.
+async fn read_block<S: Source>(source: &mut S, block: &mut [u8]) -> Result<Option<usize>> {
+    loop {
+        match source.read(block) {
+            Ok(0) => return Ok(None),
+            Ok(n) => return Ok(Some(n)),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => source.prefetch().fill().await?,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+pub(crate) fn would_block(e: &Error) -> bool {
+    match e.kind() {
+        ErrorKind::Io(e) => e.kind() == io::ErrorKind::WouldBlock,
+        _ => false,
+    }
+}
+
+/// Buffers the input of `recode_async` so that the synchronous readers can
+/// parse it. Reading fails with `WouldBlock` instead of waiting for input;
+/// call `fill` and try again.
+///
+/// In `framed` mode, a chunk is only handed out once it is buffered whole, so
+/// that `WouldBlock` never interrupts a chunk halfway.
+pub(crate) struct Prefetch<R> {
+    inner: R,
+    buf: VecDeque<u8>,
+    framed: bool,
+    eof: bool,
+    /// The number of bytes left of the chunk being handed out.
+    left: usize,
+    /// The number of bytes read from `inner`.
+    count: u64,
+}
+
+impl<R: AsyncRead + Unpin> Prefetch<R> {
+    pub(crate) fn new(inner: R) -> Prefetch<R> {
+        Prefetch {
+            inner,
+            buf: VecDeque::new(),
+            framed: false,
+            eof: false,
+            left: 0,
+            count: 0,
+        }
+    }
+
+    /// Reads the header, switching to `framed` mode for compressed input.
+    pub(crate) async fn read_header(&mut self) -> Result<Header> {
+        while self.buf.len() < 8 && !self.eof {
+            self.fill().await?;
+        }
+        let header = Header::read(&mut *self)?;
+        self.framed = header.compression.is_compressed();
+        Ok(header)
+    }
+
+    /// Reads the next block of the input.
+    pub(crate) async fn fill(&mut self) -> io::Result<()> {
+        let mut block = [0; 8192];
+        let n = self.inner.read(&mut block).await?;
+        self.eof = n == 0;
+        self.buf.extend(&block[..n]);
+        self.count += n as u64;
+        Ok(())
+    }
+}
+
+impl<R: AsyncRead + Unpin> Read for Prefetch<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let mut n = out.len();
+        if self.framed {
+            if self.left == 0 {
+                self.left = match frame_len(self.buf.make_contiguous()) {
+                    Some(len) if len <= self.buf.len() => len,
+                    // Let the reader see how the input was truncated.
+                    _ if self.eof => self.buf.len(),
+                    _ => return Err(io::ErrorKind::WouldBlock.into()),
+                };
+            }
+            n = n.min(self.left);
+        } else if self.buf.is_empty() && !self.eof {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let n = self.buf.read(&mut out[..n])?;
+        if self.framed {
+            self.left -= n;
+        }
+        Ok(n)
+    }
+}
+
+/// A reader of the buffered input of `recode_async`.
+trait Source: Read {
+    type Input: AsyncRead + Unpin;
+
+    fn prefetch(&mut self) -> &mut Prefetch<Self::Input>;
+}
+
+impl<R: AsyncRead + Unpin> Source for Prefetch<R> {
+    type Input = R;
+
+    fn prefetch(&mut self) -> &mut Prefetch<R> {
+        self
+    }
+}
+
+impl<R: AsyncRead + Unpin, C: ChunkCodec> Source for DecompressReader<&mut Prefetch<R>, C> {
+    type Input = R;
+
+    fn prefetch(&mut self) -> &mut Prefetch<R> {
+        self.get_mut()
+    }
+}
+
+/// Counts the bytes written to `inner`.
+struct Output<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: AsyncWrite + Unpin> Output<W> {
+    async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.inner.write_all(data).await?;
+        self.count += data.len() as u64;
+        Ok(())
+    }
+
+    /// Writes and clears `buf`.
+    async fn drain(&mut self, buf: &mut Vec<u8>) -> io::Result<()> {
+        self.write(buf).await?;
+        buf.clear();
+        Ok(())
+    }
+}
+
+#[test]
+fn recode_async_test() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let data = crate::fixture::sample_file(500000);
+    let compressed = crate::compress_bytes(&data).unwrap();
+    let mut output = Vec::new();
+    let future = dfcompress_async(&data[..], &mut output);
+    fn assert_send<T: Send>(_: &T) {}
+    assert_send(&future);
+    let summary = runtime.block_on(future).unwrap();
+    assert_eq!(output, compressed);
+    assert_eq!(summary.chunks, 25);
+    assert_eq!(summary.bytes_in, data.len() as u64);
+    assert_eq!(summary.bytes_out, compressed.len() as u64);
+
+    for (input, expected) in [
+        (&compressed, &data),
+        (&data, &data),
+        (&compressed, &compressed),
+    ] {
+        let target = match expected[4] {
+            0 => Target::Uncompressed,
+            _ => Target::Compressed,
+        };
+        let mut output = Vec::new();
+        let options = Options::default();
+        let future = recode_async(&input[..], &mut output, target, &options);
+        runtime.block_on(future).unwrap();
+        assert_eq!(&output, expected);
+    }
+
+    // Chunks are copied or compressed again as `recode_with` would.
+    let fast = Options::new().fast();
+    let mut fast_compressed = Vec::new();
+    crate::recode_with(&data[..], &mut fast_compressed, Target::Compressed, &fast).unwrap();
+    for options in [
+        Options::new(),
+        Options::new().recompress(true),
+        Options::new().level(9),
+    ] {
+        let mut expected = Vec::new();
+        let input = &fast_compressed[..];
+        crate::recode_with(input, &mut expected, Target::Compressed, &options).unwrap();
+        let mut output = Vec::new();
+        let future = recode_async(input, &mut output, Target::Compressed, &options);
+        runtime.block_on(future).unwrap();
+        assert_eq!(output, expected, "{:?}", options);
+    }
+
+    let truncated = &compressed[..compressed.len() - 1];
+    let future = dfuncompress_async(truncated, tokio::io::sink());
+    assert!(runtime.block_on(future).is_err());
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn recode_async_dictionary_test() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let data = crate::fixture::sample_file(50000);
+    let (options, compressed) = crate::fixture::dictionary_file(50000);
+    let mut output = Vec::new();
+    let future = recode_async(&compressed[..], &mut output, Target::Uncompressed, &options);
+    runtime.block_on(future).unwrap();
+    assert_eq!(output, data);
+}
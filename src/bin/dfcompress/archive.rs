@@ -0,0 +1,266 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+//! The `.dfpack` archives of `pack` and `unpack`: a region folder in one stream,
+//! compressed as a whole rather than in chunks.
+//!
+//! An archive is `MAGIC`, a byte naming the codec of the stream, and the
+//! stream. That holds the length of the manifest in four little-endian
+//! bytes, the manifest in JSON, and the contents of its members one after
+//! another. Data files are stored uncompressed, header and all.
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+use clap::ValueEnum;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use serde::{Deserialize, Serialize};
+
+use super::exit::{Failure, Status};
+
+/// The start of every archive, ending in the version of the format.
+pub(crate) const MAGIC: &[u8; 8] = b"dfpack\0\x01";
+
+/// How the stream of an archive is compressed.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum ArchiveCodec {
+    /// Zlib at the best level
+    Zlib = 1,
+    /// Zstandard at level 19, which compresses better (the default)
+    #[cfg(feature = "zstd")]
+    Zstd = 2,
+}
+
+impl ArchiveCodec {
+    pub(crate) fn from_byte(byte: u8) -> Option<ArchiveCodec> {
+        match byte {
+            1 => Some(ArchiveCodec::Zlib),
+            #[cfg(feature = "zstd")]
+            2 => Some(ArchiveCodec::Zstd),
+            _ => None,
+        }
+    }
+}
+
+impl Default for ArchiveCodec {
+    fn default() -> ArchiveCodec {
+        #[cfg(feature = "zstd")]
+        return ArchiveCodec::Zstd;
+        #[cfg(not(feature = "zstd"))]
+        return ArchiveCodec::Zlib;
+    }
+}
+
+/// The members of an archive, in the order of their contents.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Manifest {
+    pub members: Vec<Member>,
+}
+
+/// A file of the folder.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Member {
+    /// The path of the file in the folder, with `/` between components.
+    pub path: String,
+    /// The bytes of its contents in the stream.
+    pub size: u64,
+    pub kind: Kind,
+    /// The save version of a data file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u32>,
+    /// When the file was last modified, in seconds and nanoseconds since
+    /// the Unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified: Option<(u64, u32)>,
+}
+
+impl Member {
+    /// Where the member goes in the folder `dir`, if its path stays inside.
+    pub(crate) fn path_in(&self, dir: &Path) -> Option<PathBuf> {
+        let mut path = dir.to_path_buf();
+        for name in self.path.split('/') {
+            let mut components = Path::new(name).components();
+            match (components.next(), components.next()) {
+                (Some(Component::Normal(name)), None) if !name.is_empty() => path.push(name),
+                _ => return None,
+            }
+        }
+        Some(path)
+    }
+}
+
+/// What a member was in the folder.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Kind {
+    /// A compressed data file, stored decompressed.
+    Compressed,
+    /// An uncompressed data file.
+    Uncompressed,
+    /// Any other file, stored as it is.
+    Other,
+}
+
+/// The compressor of the stream.
+pub(crate) enum Encoder<W: Write> {
+    Zlib(ZlibEncoder<W>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::Encoder<'static, W>),
+}
+
+impl<W: Write> Encoder<W> {
+    pub(crate) fn new(codec: ArchiveCodec, writer: W) -> io::Result<Encoder<W>> {
+        Ok(match codec {
+            ArchiveCodec::Zlib => {
+                Encoder::Zlib(ZlibEncoder::new(writer, flate2::Compression::best()))
+            }
+            #[cfg(feature = "zstd")]
+            ArchiveCodec::Zstd => {
+                let mut encoder = zstd::Encoder::new(writer, 19)?;
+                encoder.include_checksum(true)?;
+                encoder.long_distance_matching(true)?;
+                Encoder::Zstd(encoder)
+            }
+        })
+    }
+
+    /// Ends the stream, returning the writer.
+    pub(crate) fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::Zlib(encoder) => encoder.finish(),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Zlib(encoder) => encoder.write(buf),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Zlib(encoder) => encoder.flush(),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// The decompressor of the stream.
+pub(crate) enum Decoder<R: Read> {
+    Zlib(ZlibDecoder<R>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::Decoder<'static, io::BufReader<R>>),
+}
+
+impl<R: Read> Decoder<R> {
+    pub(crate) fn new(codec: ArchiveCodec, reader: R) -> io::Result<Decoder<R>> {
+        Ok(match codec {
+            ArchiveCodec::Zlib => Decoder::Zlib(ZlibDecoder::new(reader)),
+            #[cfg(feature = "zstd")]
+            ArchiveCodec::Zstd => Decoder::Zstd(zstd::Decoder::new(reader)?),
+        })
+    }
+}
+
+impl<R: Read> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Decoder::Zlib(decoder) => decoder.read(buf),
+            #[cfg(feature = "zstd")]
+            Decoder::Zstd(decoder) => decoder.read(buf),
+        }
+    }
+}
+
+/// Reads the start of an archive from `reader`, up to the contents of its
+/// members, which the stream it returns goes on with.
+pub(crate) fn open_archive<R: Read>(mut reader: R) -> Result<(Manifest, Decoder<R>), Failure> {
+    let not_archive = || Failure::new(Status::NotDataFile, "Not a dfpack archive");
+    let mut start = [0; 9];
+    reader.read_exact(&mut start).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => not_archive(),
+        _ => Failure::new(Status::Io, e),
+    })?;
+    if start[..8] != MAGIC[..] {
+        return Err(not_archive());
+    }
+    let mut stream = match ArchiveCodec::from_byte(start[8]) {
+        Some(codec) => Decoder::new(codec, reader).map_err(|e| Failure::new(Status::Io, e))?,
+        None => {
+            let message = format!(
+                "The archive's codec {} is unknown, or not built in",
+                start[8]
+            );
+            return Err(Failure::new(Status::NotDataFile, message));
+        }
+    };
+    let corrupt = |e: &dyn std::fmt::Display| {
+        Failure::new(Status::Corrupt, format!("The manifest is damaged: {}", e))
+    };
+    let mut len = [0; 4];
+    stream.read_exact(&mut len).map_err(|e| corrupt(&e))?;
+    let len = u32::from_le_bytes(len) as u64;
+    let mut json = Vec::new();
+    stream
+        .by_ref()
+        .take(len)
+        .read_to_end(&mut json)
+        .map_err(|e| corrupt(&e))?;
+    if json.len() as u64 != len {
+        return Err(corrupt(&"it is truncated"));
+    }
+    let manifest = serde_json::from_slice(&json).map_err(|e| corrupt(&e))?;
+    Ok((manifest, stream))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(path: &str) -> Member {
+        Member {
+            path: path.to_string(),
+            size: 0,
+            kind: Kind::Other,
+            version: None,
+            modified: None,
+        }
+    }
+
+    #[test]
+    fn path_in_test() {
+        let dir = Path::new("out");
+        assert_eq!(
+            member("world.sav").path_in(dir),
+            Some(dir.join("world.sav"))
+        );
+        assert_eq!(
+            member("art/image-1.dat").path_in(dir),
+            Some(dir.join("art").join("image-1.dat"))
+        );
+        for path in [
+            "",
+            ".",
+            "..",
+            "../world.sav",
+            "art/../../world.sav",
+            "art/./world.sav",
+            "art//world.sav",
+            "art/",
+            "/etc/passwd",
+            "art/..",
+        ] {
+            assert_eq!(member(path).path_in(dir), None, "{:?}", path);
+        }
+        #[cfg(windows)]
+        for path in ["C:/world.sav", "C:world.sav", "art\\..\\..\\world.sav"] {
+            assert_eq!(member(path).path_in(dir), None, "{:?}", path);
+        }
+    }
+}
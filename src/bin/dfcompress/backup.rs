@@ -0,0 +1,632 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+//! The `backup` subcommand, which takes snapshots of region folders into a
+//! store where each chunk is kept once, so that snapshots of the same world
+//! take little more than what changed between them.
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io;
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::sync::Mutex;
+
+use dfcompress::{Options, Target};
+use log::info;
+use serde_json::{json, Value};
+
+use super::archive::Kind;
+use super::batch::{all_files, default_threads, Batch, Done};
+use super::convert::failed;
+use super::exit::{Failure, Status};
+use super::pack::{describe, modified};
+use super::progress::with_bar;
+use super::store::{self, valid_name, Chunker, Entry, Snapshot, Store};
+use super::unpack::unpack_file;
+use super::{
+    at, dry_run_json, open, Backup, BackupCommand, BackupCreate, BackupGc, BackupList, BackupPrune,
+    BackupRestore, Verbosity,
+};
+
+impl Backup {
+    pub(crate) fn run(self, verbosity: Verbosity) -> Status {
+        let result = match self.command {
+            BackupCommand::Create(create) => create.create(verbosity),
+            BackupCommand::List(list) => list.list(verbosity),
+            BackupCommand::Restore(restore) => restore.restore(verbosity),
+            BackupCommand::Prune(prune) => prune.prune(verbosity),
+            BackupCommand::Gc(gc) => gc.gc(verbosity),
+        };
+        match result {
+            Ok(status) => status,
+            Err(e) => {
+                eprintln!("{}", e);
+                e.status
+            }
+        }
+    }
+}
+
+/// What was stored of a file.
+#[derive(Default)]
+struct Stored {
+    chunks: usize,
+    new: usize,
+    bytes: u64,
+}
+
+impl BackupCreate {
+    fn create(&self, verbosity: Verbosity) -> Result<Status, Failure> {
+        // Snapshots of a folder name it the same however it was given.
+        let source = self.dir.canonicalize().map_err(|e| at(&self.dir, e))?;
+        let name = match self.name {
+            Some(ref name) => name.clone(),
+            None => {
+                let folder = source
+                    .file_name()
+                    .map_or("backup".into(), |name| name.to_string_lossy().into_owned());
+                // The date, without the colons that Windows forbids.
+                let date = store::date(store::now())
+                    .replace(' ', "T")
+                    .replace(':', "-");
+                format!("{}-{}", folder, date)
+            }
+        };
+        if !valid_name(&name) {
+            let message = format!("{} cannot name a snapshot", name);
+            return Err(Failure::new(Status::Usage, message));
+        }
+        let store = Store::open(&self.store, true)?;
+        let _lock = store.lock()?;
+        if store.has_snapshot(&name) {
+            let message = format!("There is a snapshot {} already; give another --name", name);
+            return Err(Failure::new(Status::Usage, message));
+        }
+        let created = store::now();
+        let mut files = Vec::new();
+        all_files(&self.dir, &self.exclude, &mut files)?;
+        let batch = Batch {
+            files,
+            dirs: vec![self.dir.clone()],
+            // The total is that of the snapshot, printed below.
+            report: false,
+            json: self.json,
+            stdout_taken: false,
+            verdicts: true,
+            verbosity,
+        };
+        let threads = self.threads.map_or_else(default_threads, NonZeroUsize::get);
+        let codec = self.codec.unwrap_or_default();
+        let entries = Mutex::new(BTreeMap::new());
+        let status = batch.run(threads, |path, bar| {
+            // The size of a compressed file's data is only known once it is
+            // decompressed into the chunker.
+            let mut member = describe(&self.dir, path)?;
+            bar.set_length(member.size);
+            let mut chunker = Chunker::new(&store, codec);
+            let size = match member.kind {
+                Kind::Compressed => {
+                    let options = with_bar(&Options::new(), bar, None);
+                    let reader = open(path)?;
+                    dfcompress::recode_with(reader, &mut chunker, Target::Uncompressed, &options)
+                        .map_err(|e| failed(path, e))?
+                        .bytes_out
+                }
+                Kind::Uncompressed | Kind::Other => {
+                    let mut file = File::open(path).map_err(|e| at(path, e))?;
+                    io::copy(&mut file, &mut chunker).map_err(|e| at(path, e))?
+                }
+            };
+            let chunker = chunker.finish().map_err(|e| at(path, e))?;
+            let metadata = fs::metadata(path).map_err(|e| at(path, e))?;
+            let changed = metadata.len() != member.size
+                || modified(&metadata) != member.modified
+                || (member.kind != Kind::Compressed && size != member.size);
+            if changed {
+                return Err(at(path, "Changed while it was backed up"));
+            }
+            member.size = size;
+            let stored = Stored {
+                chunks: chunker.chunks.len(),
+                new: chunker.new,
+                bytes: chunker.stored,
+            };
+            let line = format!(
+                "{} bytes, {} chunks, {} new",
+                member.size, stored.chunks, stored.new
+            );
+            let mut json = serde_json::Map::new();
+            json.insert("size".into(), json!(member.size));
+            json.insert("chunks".into(), json!(stored.chunks));
+            json.insert("new_chunks".into(), json!(stored.new));
+            json.insert("stored".into(), json!(stored.bytes));
+            let entry = Entry {
+                member,
+                chunks: chunker.chunks,
+            };
+            entries
+                .lock()
+                .unwrap()
+                .insert(path.to_path_buf(), (entry, stored));
+            Ok(Done {
+                line: Some(line),
+                json,
+                ..Done::default()
+            })
+        });
+        if status != Status::Success {
+            // The chunks stored are kept, for the next snapshot to share.
+            eprintln!("{}: not saved, as some files were not backed up", name);
+            return Ok(status);
+        }
+        let mut total = Stored::default();
+        let files = entries
+            .into_inner()
+            .unwrap()
+            .into_values()
+            .map(|(entry, stored)| {
+                total.chunks += stored.chunks;
+                total.new += stored.new;
+                total.bytes += stored.bytes;
+                entry
+            })
+            .collect();
+        let snapshot = Snapshot {
+            name,
+            source,
+            created,
+            files,
+        };
+        store.save(&snapshot)?;
+        if !verbosity.quiet || self.json {
+            println!(
+                "{}",
+                match self.json {
+                    true => json!({
+                        "snapshot": snapshot.name,
+                        "dir": self.dir,
+                        "files": snapshot.files.len(),
+                        "size": snapshot.size(),
+                        "chunks": total.chunks,
+                        "new_chunks": total.new,
+                        "stored": total.bytes,
+                    })
+                    .to_string(),
+                    false => format!(
+                        "{}: {} files, {} bytes in {} chunks, {} new; {} bytes stored",
+                        snapshot.name,
+                        snapshot.files.len(),
+                        snapshot.size(),
+                        total.chunks,
+                        total.new,
+                        total.bytes
+                    ),
+                }
+            );
+        }
+        Ok(Status::Success)
+    }
+}
+
+impl BackupList {
+    fn list(&self, verbosity: Verbosity) -> Result<Status, Failure> {
+        let store = Store::open(&self.store, false)?;
+        let snapshots = store.snapshots()?;
+        let blobs = store.blobs()?;
+        let stored: u64 = blobs.iter().map(|(_, size)| size).sum();
+        let mut seen = HashSet::new();
+        let mut size = 0;
+        for snapshot in &snapshots {
+            size += snapshot.size();
+            seen.extend(snapshot.files.iter().flat_map(|entry| &entry.chunks));
+            let line = match self.json {
+                true => json!({
+                    "snapshot": snapshot.name,
+                    "source": snapshot.source,
+                    "created": snapshot.created,
+                    "files": snapshot.files.len(),
+                    "size": snapshot.size(),
+                    "chunks": snapshot.chunks(),
+                })
+                .to_string(),
+                false => format!(
+                    "{}  {}  {}: {} files, {} bytes in {} chunks",
+                    snapshot.name,
+                    store::date(snapshot.created),
+                    snapshot.source.display(),
+                    snapshot.files.len(),
+                    snapshot.size(),
+                    snapshot.chunks()
+                ),
+            };
+            println!("{}", line);
+        }
+        let missing = seen
+            .iter()
+            .filter(|hash| blobs.binary_search_by(|(blob, _)| blob.cmp(hash)).is_err())
+            .count();
+        if !verbosity.quiet || self.json {
+            let line = match self.json {
+                true => json!({
+                    "store": self.store,
+                    "snapshots": snapshots.len(),
+                    "size": size,
+                    "chunks": blobs.len(),
+                    "stored": stored,
+                    "missing": missing,
+                })
+                .to_string(),
+                false => format!(
+                    "{}: {} snapshots of {} bytes, {} chunks in {} bytes",
+                    self.store.display(),
+                    snapshots.len(),
+                    size,
+                    blobs.len(),
+                    stored
+                ),
+            };
+            println!("{}", line);
+        }
+        if missing > 0 {
+            eprintln!(
+                "{}: {} chunks of the snapshots are missing",
+                self.store.display(),
+                missing
+            );
+            return Ok(Status::Corrupt);
+        }
+        Ok(Status::Success)
+    }
+}
+
+impl BackupRestore {
+    /// Writes the files of the snapshot into the folder, checking the hash
+    /// of each chunk as it is read.
+    fn restore(&self, verbosity: Verbosity) -> Result<Status, Failure> {
+        let store = Store::open(&self.store, false)?;
+        let snapshot = store.snapshot(&self.snapshot)?;
+        let dir = &self.output;
+        fs::create_dir_all(dir).map_err(|e| at(dir, e))?;
+        for entry in &snapshot.files {
+            let member = &entry.member;
+            let path = member.path_in(dir).ok_or_else(|| {
+                let message = format!("{}: Not in the folder", member.path);
+                Failure::new(Status::Corrupt, format!("{}: {}", snapshot.name, message))
+            })?;
+            info!("{}: restoring {} bytes", path.display(), member.size);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).map_err(|e| at(parent, e))?;
+            }
+            let mut contents = store.contents(&entry.chunks);
+            if let Err(e) = unpack_file(&mut contents, member, &path, self.force) {
+                // A chunk that could not be read says why better.
+                return Err(match contents.error.take() {
+                    Some(e) => at(&path, e),
+                    None => e,
+                });
+            }
+        }
+        if !verbosity.quiet || self.json {
+            println!("{}", self.report(&snapshot.name, snapshot.files.len(), dir));
+        }
+        Ok(Status::Success)
+    }
+
+    fn report(&self, name: &str, files: usize, dir: &Path) -> String {
+        match self.json {
+            true => json!({
+                "snapshot": name,
+                "dir": dir,
+                "files": files,
+            })
+            .to_string(),
+            false => format!("{}: {} files restored into {}", name, files, dir.display()),
+        }
+    }
+}
+
+/// The period that a time falls in, numbered from the Unix epoch.
+type Period = fn(u64) -> i64;
+
+/// The periods that `prune` keeps the newest snapshot of, named as in its
+/// report.
+const PERIODS: [(&str, Period); 3] = [
+    ("daily", |created| (created / 86400) as i64),
+    // The Unix epoch was a Thursday, and weeks start on Mondays.
+    ("weekly", |created| {
+        ((created / 86400) as i64 + 3).div_euclid(7)
+    }),
+    ("monthly", |created| {
+        let (year, month, _) = store::civil((created / 86400) as i64);
+        year * 12 + month as i64
+    }),
+];
+
+impl BackupPrune {
+    /// Why each of `snapshots`, of one folder from the newest, is kept: the
+    /// policies keeping it, or none if it is to be removed.
+    fn reasons(&self, snapshots: &[&Snapshot]) -> Vec<Vec<&'static str>> {
+        let mut reasons = vec![Vec::new(); snapshots.len()];
+        let last = self.keep_last.map_or(0, NonZeroUsize::get);
+        for reasons in reasons.iter_mut().take(last) {
+            reasons.push("last");
+        }
+        let keep = [self.keep_daily, self.keep_weekly, self.keep_monthly];
+        for ((name, period), keep) in PERIODS.into_iter().zip(keep) {
+            let Some(keep) = keep else { continue };
+            let mut kept: Vec<i64> = Vec::new();
+            for (i, snapshot) in snapshots.iter().enumerate() {
+                let period = period(snapshot.created);
+                if kept.len() == keep.get() {
+                    break;
+                }
+                // The first of each period is its newest.
+                if kept.last() != Some(&period) {
+                    kept.push(period);
+                    reasons[i].push(name);
+                }
+            }
+        }
+        reasons
+    }
+
+    /// Removes the snapshots that no policy keeps.
+    fn prune(&self, verbosity: Verbosity) -> Result<Status, Failure> {
+        if [
+            self.keep_last,
+            self.keep_daily,
+            self.keep_weekly,
+            self.keep_monthly,
+        ]
+        .iter()
+        .all(Option::is_none)
+        {
+            let message =
+                "prune needs a policy: --keep-last, --keep-daily, --keep-weekly or --keep-monthly";
+            return Err(Failure::new(Status::Usage, message));
+        }
+        let store = Store::open(&self.store, false)?;
+        let _lock = store.lock()?;
+        let snapshots = store.snapshots()?;
+        // Each folder is kept by the policies on its own, from its newest. A
+        // source may be one given as it was, as by older versions, or the
+        // folder may be gone.
+        let mut folders: BTreeMap<_, Vec<&Snapshot>> = BTreeMap::new();
+        for snapshot in snapshots.iter().rev() {
+            let source = snapshot
+                .source
+                .canonicalize()
+                .unwrap_or_else(|_| snapshot.source.clone());
+            folders.entry(source).or_default().push(snapshot);
+        }
+        let (mut kept, mut removed) = (0, 0);
+        for snapshots in folders.values() {
+            for (snapshot, reasons) in snapshots.iter().zip(self.reasons(snapshots)) {
+                let keep = !reasons.is_empty();
+                if !keep && !self.dry_run {
+                    store.remove_snapshot(&snapshot.name)?;
+                }
+                match keep {
+                    true => kept += 1,
+                    false => removed += 1,
+                }
+                let line = match self.json {
+                    true => {
+                        let mut object = dry_run_json(self.dry_run);
+                        object.insert("snapshot".into(), json!(snapshot.name));
+                        object.insert("source".into(), json!(snapshot.source));
+                        object.insert("created".into(), json!(snapshot.created));
+                        object.insert("kept".into(), json!(keep));
+                        object.insert("policies".into(), json!(reasons));
+                        Value::Object(object).to_string()
+                    }
+                    false => {
+                        let mut line =
+                            format!("{}  {}: ", snapshot.name, store::date(snapshot.created));
+                        match (keep, self.dry_run) {
+                            (true, _) => write!(line, "kept ({})", reasons.join(", ")),
+                            (false, true) => write!(line, "would be removed"),
+                            (false, false) => write!(line, "removed"),
+                        }
+                        .expect("a string");
+                        line
+                    }
+                };
+                if !verbosity.quiet || self.json {
+                    println!("{}", line);
+                }
+            }
+        }
+        if !verbosity.quiet || self.json {
+            let line = match self.json {
+                true => {
+                    let mut object = dry_run_json(self.dry_run);
+                    object.insert("store".into(), json!(self.store));
+                    object.insert("kept".into(), json!(kept));
+                    object.insert("removed".into(), json!(removed));
+                    Value::Object(object).to_string()
+                }
+                false => format!(
+                    "{}: {} snapshots kept, {} {}; backup gc frees their chunks",
+                    self.store.display(),
+                    kept,
+                    removed,
+                    match self.dry_run {
+                        true => "would be removed",
+                        false => "removed",
+                    }
+                ),
+            };
+            println!("{}", line);
+        }
+        Ok(Status::Success)
+    }
+}
+
+impl BackupGc {
+    /// Removes the chunks that no snapshot has.
+    fn gc(&self, verbosity: Verbosity) -> Result<Status, Failure> {
+        let store = Store::open(&self.store, false)?;
+        let _lock = store.lock()?;
+        let snapshots = store.snapshots()?;
+        let referenced: HashSet<&String> = snapshots
+            .iter()
+            .flat_map(|snapshot| &snapshot.files)
+            .flat_map(|entry| &entry.chunks)
+            .collect();
+        let (mut chunks, mut freed, mut kept) = (0, 0, 0);
+        for (hash, size) in store.blobs()? {
+            if referenced.contains(&hash) {
+                kept += 1;
+                continue;
+            }
+            if !self.dry_run {
+                store.remove_blob(&hash)?;
+            }
+            chunks += 1;
+            freed += size;
+        }
+        if !verbosity.quiet || self.json {
+            let line = match self.json {
+                true => {
+                    let mut object = dry_run_json(self.dry_run);
+                    object.insert("store".into(), json!(self.store));
+                    object.insert("snapshots".into(), json!(snapshots.len()));
+                    object.insert("kept".into(), json!(kept));
+                    object.insert("removed".into(), json!(chunks));
+                    object.insert("freed".into(), json!(freed));
+                    Value::Object(object).to_string()
+                }
+                false => format!(
+                    "{}: {} chunks kept for {} snapshots, {} {}, {} {} bytes",
+                    self.store.display(),
+                    kept,
+                    snapshots.len(),
+                    chunks,
+                    match self.dry_run {
+                        true => "would be removed",
+                        false => "removed",
+                    },
+                    match self.dry_run {
+                        true => "freeing",
+                        false => "freed",
+                    },
+                    freed
+                ),
+            };
+            println!("{}", line);
+        }
+        Ok(Status::Success)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2024-01-01, a Monday, in days since the Unix epoch.
+    const MONDAY: u64 = 19723;
+
+    fn policy(last: usize, daily: usize, weekly: usize, monthly: usize) -> BackupPrune {
+        BackupPrune {
+            store: "store".into(),
+            keep_last: NonZeroUsize::new(last),
+            keep_daily: NonZeroUsize::new(daily),
+            keep_weekly: NonZeroUsize::new(weekly),
+            keep_monthly: NonZeroUsize::new(monthly),
+            dry_run: true,
+            json: false,
+        }
+    }
+
+    /// The reasons of `policy` for snapshots taken at `created`, from the
+    /// newest.
+    fn reasons(policy: &BackupPrune, created: &[u64]) -> Vec<Vec<&'static str>> {
+        let snapshots: Vec<Snapshot> = (created.iter())
+            .enumerate()
+            .map(|(i, &created)| Snapshot {
+                name: format!("s{}", i),
+                source: "region1".into(),
+                created,
+                files: Vec::new(),
+            })
+            .collect();
+        policy.reasons(&snapshots.iter().collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn civil_test() {
+        assert_eq!(store::civil(0), (1970, 1, 1));
+        assert_eq!(store::civil(-1), (1969, 12, 31));
+        assert_eq!(store::civil(MONDAY as i64 - 1), (2023, 12, 31));
+        assert_eq!(store::civil(MONDAY as i64), (2024, 1, 1));
+        assert_eq!(store::civil(MONDAY as i64 + 59), (2024, 2, 29));
+        assert_eq!(store::civil(MONDAY as i64 + 60), (2024, 3, 1));
+        assert_eq!(store::civil(MONDAY as i64 + 365), (2024, 12, 31));
+        assert_eq!(store::civil(MONDAY as i64 + 366), (2025, 1, 1));
+    }
+
+    #[test]
+    fn keep_last_test() {
+        let created = [300, 200, 100];
+        let kept = vec![vec!["last"], vec!["last"], vec![]];
+        assert_eq!(reasons(&policy(2, 0, 0, 0), &created), kept);
+        let kept = vec![vec!["last"]; 3];
+        assert_eq!(reasons(&policy(5, 0, 0, 0), &created), kept);
+    }
+
+    #[test]
+    fn keep_daily_test() {
+        let day = MONDAY * 86400;
+        // The newest of each day is kept, a day ending at midnight in UTC.
+        let created = [day + 86399, day, day - 1, day - 43200, day - 86401];
+        let kept = vec![vec!["daily"], vec![], vec!["daily"], vec![], vec![]];
+        assert_eq!(reasons(&policy(0, 2, 0, 0), &created), kept);
+        let kept = vec![
+            vec!["last", "daily"],
+            vec!["last"],
+            vec!["daily"],
+            vec![],
+            vec!["daily"],
+        ];
+        assert_eq!(reasons(&policy(2, 3, 0, 0), &created), kept);
+    }
+
+    #[test]
+    fn keep_weekly_test() {
+        let day = MONDAY * 86400;
+        // Weeks start on Mondays: the Sunday before is of the week before,
+        // as is the Monday before that.
+        let created = [
+            day,
+            day - 1,
+            day - 86400,
+            day - 7 * 86400,
+            day - 7 * 86400 - 1,
+        ];
+        let kept = vec![
+            vec!["weekly"],
+            vec!["weekly"],
+            vec![],
+            vec![],
+            vec!["weekly"],
+        ];
+        assert_eq!(reasons(&policy(0, 0, 3, 0), &created), kept);
+        let kept = vec![vec!["weekly"], vec!["weekly"], vec![], vec![], vec![]];
+        assert_eq!(reasons(&policy(0, 0, 2, 0), &created), kept);
+    }
+
+    #[test]
+    fn keep_monthly_test() {
+        let day = MONDAY * 86400;
+        // March 1, February 29 and February 1, 2024, and the day before.
+        let created = [
+            (day + 60 * 86400),
+            day + 59 * 86400,
+            day + 31 * 86400,
+            day + 31 * 86400 - 1,
+        ];
+        let kept = vec![vec!["monthly"], vec!["monthly"], vec![], vec!["monthly"]];
+        assert_eq!(reasons(&policy(0, 0, 0, 3), &created), kept);
+    }
+}
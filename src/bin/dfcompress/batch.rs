@@ -0,0 +1,416 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+//! Running a command on many files.
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+use dfcompress::{Header, Summary};
+use glob::Pattern;
+use indicatif::ProgressBar;
+use log::info;
+use serde_json::{json, Map, Value};
+
+use super::exit::{Failure, Status};
+use super::progress::Progress;
+use super::solid;
+use super::stats::Report;
+use super::{at, Verbosity};
+
+/// The files a command runs on.
+pub(crate) struct Batch {
+    pub files: Vec<PathBuf>,
+    /// The directories among the inputs, which the files in them are
+    /// relative to.
+    pub dirs: Vec<PathBuf>,
+    /// Whether to report on each file and print a total.
+    pub report: bool,
+    /// Whether to report in JSON, one object per line.
+    pub json: bool,
+    /// Whether standard output is taken by a converted file, so that reports
+    /// go to standard error.
+    pub stdout_taken: bool,
+    /// Whether to print `FAILED` for each file that failed, next to the
+    /// reports of the others, with the error on standard error as usual.
+    pub verdicts: bool,
+    pub verbosity: Verbosity,
+}
+
+/// What processing one file resulted in.
+#[derive(Default)]
+pub(crate) struct Done {
+    /// What to print about the file.
+    pub line: Option<String>,
+    /// What a conversion did.
+    pub summary: Option<Summary>,
+    /// The report of `--stats`.
+    pub stats: Option<Report>,
+    /// Fields for the JSON object of the file.
+    pub json: Map<String, Value>,
+    /// The status the file fails with, though it was processed.
+    pub failure: Option<Status>,
+}
+
+/// Whether `path` is named like a Dwarf Fortress data file.
+pub(crate) fn is_data_file(path: &Path) -> bool {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some(e) => e.eq_ignore_ascii_case("sav") || e.eq_ignore_ascii_case("dat"),
+        None => false,
+    }
+}
+
+/// Whether `path` starts with the header of a data file, or is a solid one.
+pub(crate) fn has_header(path: &Path) -> bool {
+    let mut header = [0; 8];
+    let read = File::open(path).and_then(|mut file| file.read_exact(&mut header));
+    read.is_ok() && (Header::parse(&header).is_ok() || header == *solid::MAGIC)
+}
+
+/// Whether `path` is left out of directories by one of the `exclude`
+/// patterns, which match its name or the whole of it.
+pub(crate) fn excluded(path: &Path, exclude: &[Pattern]) -> bool {
+    let name = path.file_name().and_then(|name| name.to_str());
+    exclude
+        .iter()
+        .any(|pattern| name.is_some_and(|name| pattern.matches(name)) || pattern.matches_path(path))
+}
+
+/// Adds the data files in `dir` to `files`, and those in its subdirectories
+/// if `recursive`. Files that do not start with a header, and those
+/// `exclude`d, are skipped.
+fn walk(
+    dir: &Path,
+    recursive: bool,
+    exclude: &[Pattern],
+    files: &mut Vec<PathBuf>,
+) -> Result<(), Failure> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir).map_err(|e| at(dir, e))? {
+        entries.push(entry.map_err(|e| at(dir, e))?.path());
+    }
+    entries.sort();
+    for path in entries {
+        if excluded(&path, exclude) {
+            continue;
+        }
+        if path.is_dir() {
+            if recursive {
+                walk(&path, recursive, exclude, files)?;
+            }
+        } else if path.is_file() && is_data_file(&path) && has_header(&path) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Adds the files in `dir` and its subdirectories to `files`, but for those
+/// `exclude`d.
+pub(crate) fn all_files(
+    dir: &Path,
+    exclude: &[Pattern],
+    files: &mut Vec<PathBuf>,
+) -> Result<(), Failure> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir).map_err(|e| at(dir, e))? {
+        entries.push(entry.map_err(|e| at(dir, e))?.path());
+    }
+    entries.sort();
+    for path in entries {
+        if excluded(&path, exclude) {
+            continue;
+        }
+        if path.is_dir() {
+            all_files(&path, exclude, files)?;
+        } else if path.is_file() {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Expands the inputs that are patterns like `region1/*.dat` rather than the
+/// names of files, for shells that leave that to the program.
+pub(crate) fn glob_all(inputs: Vec<PathBuf>) -> Result<Vec<PathBuf>, Failure> {
+    let mut expanded = Vec::new();
+    for input in inputs {
+        expanded.extend(glob(input)?);
+    }
+    Ok(expanded)
+}
+
+fn glob(input: PathBuf) -> Result<Vec<PathBuf>, Failure> {
+    let pattern = match input.to_str() {
+        Some(s) if !input.exists() && s.contains(['*', '?', '[']) => s,
+        _ => return Ok(vec![input]),
+    };
+    let paths = glob::glob(pattern).map_err(|e| at(&input, e))?;
+    let paths = paths
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| at(&input, e))?;
+    if paths.is_empty() {
+        return Err(Failure::new(Status::Usage, at(&input, "No files match")));
+    }
+    Ok(paths)
+}
+
+/// Lists the files of `inputs`, where a directory stands for the `*.sav` and
+/// `*.dat` files in it, and in its subdirectories if `recursive`, but for
+/// those `exclude`d. Patterns are expanded.
+pub(crate) fn expand(
+    inputs: Vec<PathBuf>,
+    recursive: bool,
+    exclude: &[Pattern],
+) -> Result<Batch, Failure> {
+    let mut report = inputs.len() > 1;
+    let expanded = glob_all(inputs)?;
+    report |= expanded.len() > 1;
+    let (mut files, mut dirs) = (Vec::new(), Vec::new());
+    for input in expanded {
+        if input.is_dir() {
+            report = true;
+            walk(&input, recursive, exclude, &mut files)?;
+            dirs.push(input);
+        } else {
+            files.push(input);
+        }
+    }
+    Ok(Batch {
+        files,
+        dirs,
+        report,
+        json: false,
+        stdout_taken: false,
+        verdicts: false,
+        verbosity: Verbosity::default(),
+    })
+}
+
+impl Batch {
+    /// The path of `file` relative to the directory it was found in, or its
+    /// name if it was an input itself.
+    pub(crate) fn relative<'a>(&self, file: &'a Path) -> &'a Path {
+        let dir = self.dirs.iter().find_map(|dir| file.strip_prefix(dir).ok());
+        dir.or_else(|| file.file_name().map(Path::new))
+            .unwrap_or(file)
+    }
+
+    /// Fails if two of the files would be written to the same path in `dir`,
+    /// where they go by `relative`: inputs with the same name in different
+    /// directories, or files at the same place in two directories.
+    pub(crate) fn check_destinations(&self, dir: &Path) -> Result<(), Failure> {
+        let mut destinations = BTreeMap::new();
+        for file in &self.files {
+            let output = dir.join(self.relative(file));
+            if let Some(other) = destinations.insert(output.clone(), file) {
+                let message = format!(
+                    "{} and {} would both be written to {}",
+                    other.display(),
+                    file.display(),
+                    output.display()
+                );
+                return Err(Failure::new(Status::Usage, message));
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs `f` on each file on `threads` threads, printing what it reports
+    /// in the order of the files and, for a batch, the total. `f` gets a
+    /// progress bar for the file. Returns the exit status, which is that of
+    /// the first error if every file failed, and `Partial` if only some did.
+    pub(crate) fn run<F>(&self, threads: usize, f: F) -> Status
+    where
+        F: Fn(&Path, &ProgressBar) -> Result<Done, Failure> + Sync,
+    {
+        // Log lines would break up the bars.
+        let bars = !self.verbosity.quiet && self.verbosity.verbose == 0;
+        let progress = Progress::new(self.files.len(), bars);
+        let next = AtomicUsize::new(0);
+        let (tx, rx) = mpsc::channel();
+        let (mut failed, mut bytes_in, mut bytes_out) = (0, 0, 0);
+        let mut status = Status::Success;
+        thread::scope(|scope| {
+            for _ in 0..threads.min(self.files.len()) {
+                let (tx, next, f, progress) = (tx.clone(), &next, &f, &progress);
+                scope.spawn(move || loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(path) = self.files.get(i) else { break };
+                    info!("{}: starting", path.display());
+                    let bar = progress.start(path);
+                    let result = f(path, &bar);
+                    progress.done(bar);
+                    if tx.send((i, result)).is_err() {
+                        break;
+                    }
+                });
+            }
+            drop(tx);
+            // Results that arrive out of order wait for those before them.
+            let mut waiting = BTreeMap::new();
+            let mut printed = 0;
+            for (i, result) in rx {
+                waiting.insert(i, result);
+                while let Some(result) = waiting.remove(&printed) {
+                    let path = &self.files[printed];
+                    match result {
+                        Ok(done) => {
+                            if let Some(failure) = done.failure {
+                                if failed == 0 {
+                                    status = failure;
+                                }
+                                failed += 1;
+                            }
+                            if let Some(ref summary) = done.summary {
+                                bytes_in += summary.bytes_in;
+                                bytes_out += summary.bytes_out;
+                            }
+                            self.print_done(&progress, path, done);
+                        }
+                        Err(e) => {
+                            match self.json {
+                                true => {
+                                    let object = json!({
+                                        "file": path,
+                                        "ok": false,
+                                        "error": e.message,
+                                        "status": e.status as i32,
+                                    });
+                                    self.print(&progress, &object.to_string());
+                                }
+                                false => {
+                                    if self.verdicts {
+                                        let line = format!("{}: FAILED", path.display());
+                                        self.print(&progress, &line);
+                                    }
+                                    progress.eprintln(&e.message)
+                                }
+                            }
+                            if failed == 0 {
+                                status = e.status;
+                            }
+                            failed += 1;
+                        }
+                    }
+                    printed += 1;
+                }
+            }
+        });
+        progress.finish();
+        if self.report && self.json {
+            let total = json!({
+                "files": self.files.len(),
+                "failed": failed,
+                "bytes_in": bytes_in,
+                "bytes_out": bytes_out,
+            });
+            self.print(&progress, &total.to_string());
+        } else if self.report {
+            let mut total = format!("{} files", self.files.len());
+            if bytes_in > 0 {
+                total += &format!(", {} -> {} bytes", bytes_in, bytes_out);
+            }
+            if failed > 0 {
+                total += &format!(", {} failed", failed);
+            }
+            self.print(&progress, &total);
+        }
+        match failed {
+            0 => Status::Success,
+            n if n < self.files.len() => Status::Partial,
+            _ => status,
+        }
+    }
+
+    /// Prints a report on standard output, or standard error if it is taken.
+    /// Only JSON reports are printed with `--quiet`.
+    fn print(&self, progress: &Progress, line: &str) {
+        if self.verbosity.quiet && !self.json {
+            return;
+        }
+        match self.stdout_taken {
+            true => progress.eprintln(line),
+            false => progress.println(line),
+        }
+    }
+
+    fn print_done(&self, progress: &Progress, path: &Path, done: Done) {
+        if self.json {
+            let mut object = Map::new();
+            object.insert("file".into(), json!(path));
+            object.insert("ok".into(), json!(done.failure.is_none()));
+            object.extend(done.json);
+            if let Some(summary) = done.summary {
+                object.insert("summary".into(), json!(summary));
+            }
+            if let Some(stats) = done.stats {
+                object.insert("stats".into(), json!(stats));
+            }
+            self.print(progress, &Value::Object(object).to_string());
+            return;
+        }
+        if let Some(line) = done.line {
+            self.print(progress, &format!("{}: {}", path.display(), line));
+        }
+        if let (Some(stats), false) = (done.stats, self.verbosity.quiet) {
+            progress.eprintln(&format!("{}:\n{}", path.display(), stats));
+        }
+    }
+}
+
+/// The default number of threads for a batch.
+pub(crate) fn default_threads() -> usize {
+    thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn batch(files: &[&str], dirs: &[&str]) -> Batch {
+        Batch {
+            files: files.iter().map(PathBuf::from).collect(),
+            dirs: dirs.iter().map(PathBuf::from).collect(),
+            report: false,
+            json: false,
+            stdout_taken: false,
+            verdicts: false,
+            verbosity: Verbosity::default(),
+        }
+    }
+
+    #[test]
+    fn destinations_test() {
+        let out = Path::new("out");
+        let files = [
+            "region1/world.sav",
+            "region2/art/world.sav",
+            "notes/world.dat",
+        ];
+        let distinct = batch(&files, &["region1", "region2"]);
+        assert_eq!(
+            distinct.relative(Path::new("region2/art/world.sav")),
+            Path::new("art/world.sav")
+        );
+        assert!(distinct.check_destinations(out).is_ok());
+
+        let same_name = batch(&["region1/world.sav", "region2/world.sav"], &[]);
+        let e = same_name.check_destinations(out).unwrap_err();
+        assert_eq!(e.status, Status::Usage);
+        assert_eq!(
+            e.message,
+            format!(
+                "region1/world.sav and region2/world.sav would both be written to {}",
+                out.join("world.sav").display()
+            )
+        );
+        let same_place = batch(&["a/world.sav", "b/world.sav"], &["a", "b"]);
+        assert!(same_place.check_destinations(out).is_err());
+        let twice = batch(&["world.sav", "world.sav"], &[]);
+        assert!(twice.check_destinations(out).is_err());
+    }
+}
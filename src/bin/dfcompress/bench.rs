@@ -0,0 +1,279 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+//! The `bench` subcommand, which compresses a sample of a file with each
+//! format and level to pick one.
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Instant;
+
+use clap::ValueEnum;
+use dfcompress::{Options, Target, CHUNK_SIZE};
+use log::info;
+use serde_json::json;
+
+use super::convert::failed;
+use super::exit::{Failure, Status};
+use super::{is_stdio, open, Bench, Format};
+
+/// The number of pieces the sample is taken in, spread over the file.
+const PIECES: u64 = 8;
+
+/// A compression level as the command line gives it.
+#[derive(Clone, Copy)]
+enum Level {
+    Exact(u32),
+    Best,
+}
+
+/// The formats and levels tried: each zlib level, and a few levels of the
+/// other codecs.
+fn settings() -> Vec<(Format, Level)> {
+    Format::value_variants()
+        .iter()
+        .flat_map(|&format| levels(format).into_iter().map(move |level| (format, level)))
+        .collect()
+}
+
+fn levels(format: Format) -> Vec<Level> {
+    match format {
+        // The best zlib level is 9.
+        Format::DfZlib => (1..=8).map(Level::Exact).chain([Level::Best]).collect(),
+        #[cfg(feature = "libdeflate")]
+        Format::Libdeflate => vec![
+            Level::Exact(1),
+            Level::Exact(6),
+            Level::Exact(9),
+            Level::Best,
+        ],
+        #[cfg(feature = "zstd")]
+        Format::Zstd => vec![
+            Level::Exact(1),
+            Level::Exact(3),
+            Level::Exact(9),
+            Level::Best,
+        ],
+        #[cfg(feature = "lz4")]
+        Format::Lz4 => vec![Level::Exact(1)],
+        Format::Raw | Format::Gzip => vec![],
+    }
+}
+
+/// How a setting did on the sample.
+struct Run {
+    format: Format,
+    level: Level,
+    size: u64,
+    ratio: f64,
+    /// Megabytes of the sample compressed and decompressed per second.
+    compress: f64,
+    decompress: f64,
+}
+
+impl Run {
+    /// The options that select the setting.
+    fn flags(&self) -> String {
+        let level = match self.level {
+            Level::Exact(level) => format!("--level {}", level),
+            Level::Best => "--best".to_string(),
+        };
+        match self.format {
+            Format::DfZlib => level,
+            format => format!("--format {} {}", format.name(), level),
+        }
+    }
+}
+
+impl Format {
+    fn name(self) -> String {
+        self.to_possible_value()
+            .map_or_else(String::new, |v| v.get_name().to_string())
+    }
+}
+
+impl Bench {
+    /// Prints how each setting does on a sample of the file, and the one
+    /// recommended.
+    pub(crate) fn run(self) -> Status {
+        match self.bench() {
+            Ok(()) => Status::Success,
+            Err(e) => {
+                eprintln!("{}", e);
+                e.status
+            }
+        }
+    }
+
+    fn bench(&self) -> Result<(), Failure> {
+        let path = &self.file;
+        if is_stdio(path) {
+            let message = "bench reads its file twice, so it cannot be standard input";
+            return Err(Failure::new(Status::Usage, message));
+        }
+        let sample = sample(path, self.sample as u64)?;
+        let payload = sample.len().saturating_sub(8);
+        if !self.json {
+            println!(
+                "{}: a sample of {} bytes in {} pieces",
+                path.display(),
+                payload,
+                PIECES
+            );
+            println!(
+                "{:>10} {:>8} {:>10} {:>7} {:>12} {:>14}",
+                "format", "level", "size", "ratio", "compress", "decompress"
+            );
+        }
+        let mut runs = Vec::new();
+        for (format, level) in settings() {
+            let run = measure(&sample, format, level).map_err(|e| failed(path, e))?;
+            info!(
+                "{}: {} in {:.0} MB/s",
+                path.display(),
+                run.flags(),
+                run.compress
+            );
+            let level_name = match level {
+                Level::Exact(level) => level.to_string(),
+                Level::Best => "best".to_string(),
+            };
+            match self.json {
+                true => println!(
+                    "{}",
+                    json!({
+                        "format": format.name(),
+                        "level": level_name,
+                        "size": run.size,
+                        "ratio": run.ratio,
+                        "compress_mb_s": run.compress,
+                        "decompress_mb_s": run.decompress,
+                        "df_readable": format.df_readable(),
+                    })
+                ),
+                false => println!(
+                    "{:>10} {:>8} {:>10} {:>7.4} {:>7.1} MB/s {:>9.1} MB/s",
+                    format.name(),
+                    level_name,
+                    run.size,
+                    run.ratio,
+                    run.compress,
+                    run.decompress
+                ),
+            }
+            runs.push(run);
+        }
+        let best = recommend(&runs);
+        match self.json {
+            true => println!(
+                "{}",
+                json!({
+                    "file": path,
+                    "sample": payload,
+                    "recommended": best.flags(),
+                })
+            ),
+            false => println!(
+                "recommended: {} (ratio {:.4}, {:.1} MB/s)",
+                best.flags(),
+                best.ratio,
+                best.compress
+            ),
+        }
+        Ok(())
+    }
+}
+
+/// The fastest setting that Dwarf Fortress reads whose output is within 1%
+/// of the smallest such.
+fn recommend(runs: &[Run]) -> &Run {
+    let readable = || runs.iter().filter(|run| run.format.df_readable());
+    let smallest = readable().map(|run| run.size).min().unwrap_or(0);
+    readable()
+        .filter(|run| run.size as f64 <= smallest as f64 * 1.01)
+        .max_by(|a, b| a.compress.total_cmp(&b.compress))
+        .expect("zlib is always tried")
+}
+
+/// Compresses `sample` with the setting, and decompresses the result.
+fn measure(sample: &[u8], format: Format, level: Level) -> dfcompress::Result<Run> {
+    let options = Options::new().codec(format.codec().unwrap_or_default());
+    let options = match level {
+        Level::Exact(level) => options.level(level),
+        Level::Best => options.best(),
+    };
+    let mut compressed = Vec::new();
+    let start = Instant::now();
+    dfcompress::recode_with(sample, &mut compressed, Target::Compressed, &options)?;
+    let compress = start.elapsed().as_secs_f64();
+    let start = Instant::now();
+    dfcompress::recode_with(
+        &compressed[..],
+        io::sink(),
+        Target::Uncompressed,
+        &Options::new(),
+    )?;
+    let decompress = start.elapsed().as_secs_f64();
+    let payload = sample.len().saturating_sub(8) as f64;
+    let speed = |seconds: f64| payload / seconds.max(1e-9) / 1e6;
+    Ok(Run {
+        format,
+        level,
+        size: compressed.len() as u64,
+        ratio: compressed.len() as f64 / sample.len() as f64,
+        compress: speed(compress),
+        decompress: speed(decompress),
+    })
+}
+
+/// Decompresses `path` to take up to `size` bytes of its payload, in pieces
+/// of whole chunks spread evenly over it, after its header.
+fn sample(path: &Path, size: u64) -> Result<Vec<u8>, Failure> {
+    let options = Options::new();
+    let summary = dfcompress::recode_with(open(path)?, io::sink(), Target::Uncompressed, &options)
+        .map_err(|e| failed(path, e))?;
+    let payload = summary.bytes_out.saturating_sub(8);
+    let piece = size.div_ceil(PIECES).div_ceil(CHUNK_SIZE as u64) * CHUNK_SIZE as u64;
+    let mut ranges = vec![(0, 8)];
+    match payload <= piece * PIECES {
+        true => ranges.push((8, 8 + payload)),
+        false => ranges.extend((0..PIECES).map(|i| {
+            let start = 8 + (payload - piece) * i / (PIECES - 1);
+            (start, start + piece)
+        })),
+    }
+    let mut sampler = Sampler {
+        ranges,
+        position: 0,
+        data: Vec::new(),
+    };
+    dfcompress::recode_with(open(path)?, &mut sampler, Target::Uncompressed, &options)
+        .map_err(|e| failed(path, e))?;
+    Ok(sampler.data)
+}
+
+/// Keeps the bytes written in `ranges`, the starts and ends of ranges in
+/// order and apart.
+struct Sampler {
+    ranges: Vec<(u64, u64)>,
+    position: u64,
+    data: Vec<u8>,
+}
+
+impl Write for Sampler {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let end = self.position + buf.len() as u64;
+        for &(start, stop) in &self.ranges {
+            let (start, stop) = (start.max(self.position), stop.min(end));
+            if start < stop {
+                let offset = (start - self.position) as usize;
+                self.data
+                    .extend_from_slice(&buf[offset..offset + (stop - start) as usize]);
+            }
+        }
+        self.position = end;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,79 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+//! Keeping off files that Dwarf Fortress is writing, or that another
+//! dfcompress is converting, before replacing them in place.
+use std::fs::{self, File, TryLockError};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use log::warn;
+
+use super::at;
+use super::exit::{Failure, Status};
+
+/// How recently a file must have been modified to seem still being written.
+const SETTLE: Duration = Duration::from_secs(2);
+
+/// How often a busy file is looked at again with `--wait`.
+const POLL: Duration = Duration::from_millis(500);
+
+/// Why Dwarf Fortress seems to be writing `path`, if it does: a temporary
+/// file of its own is next to it, or it was just modified.
+fn writing(path: &Path) -> Option<String> {
+    let dir = match path.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+    // Those of dfcompress start with a dot.
+    let temp = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .find(|entry| {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            name.ends_with(".tmp") && !name.starts_with('.')
+        });
+    if let Some(temp) = temp {
+        let name = temp.file_name().to_string_lossy().into_owned();
+        return Some(format!("Dwarf Fortress may be saving, as {} exists", name));
+    }
+    let modified = fs::metadata(path).and_then(|m| m.modified()).ok()?;
+    match SystemTime::now().duration_since(modified) {
+        Ok(age) if age >= SETTLE => None,
+        // A time in the future is as suspect.
+        _ => Some("Dwarf Fortress may be writing it, as it was just modified".to_string()),
+    }
+}
+
+/// Takes an advisory lock on `file`, opened from `path`, once nothing seems
+/// to be writing it: waiting for that if `wait`, or else failing. The lock
+/// lasts until the file is closed.
+pub(crate) fn lock_settled(path: &Path, file: &File, wait: bool) -> Result<(), Failure> {
+    let mut waiting = false;
+    loop {
+        let reason = match file.try_lock() {
+            Ok(()) => match writing(path) {
+                None => return Ok(()),
+                Some(reason) => {
+                    file.unlock().map_err(|e| at(path, e))?;
+                    reason
+                }
+            },
+            Err(TryLockError::WouldBlock) => {
+                "Another dfcompress may be converting it, as it is locked".to_string()
+            }
+            Err(TryLockError::Error(e)) => return Err(at(path, e)),
+        };
+        if !wait {
+            let message = format!("{}: {}; use --wait to wait for it", path.display(), reason);
+            return Err(Failure::new(Status::Failure, message));
+        }
+        if !waiting {
+            warn!("{}: waiting: {}", path.display(), reason);
+            waiting = true;
+        }
+        thread::sleep(POLL);
+    }
+}
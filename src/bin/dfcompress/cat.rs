@@ -0,0 +1,138 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+//! The `cat` subcommand, which joins the payloads of data files into one
+//! uncompressed data file.
+use std::io::{self, BufWriter, Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+
+use dfcompress::{Chunks, Compression, Header};
+use log::warn;
+use serde_json::json;
+
+use super::convert::{check_distinct, create, failed};
+use super::exit::{Failure, Status};
+use super::{at, is_stdio, open, to_terminal, Cat, Verbosity};
+
+/// What was joined.
+struct Joined {
+    /// The data files read, counting those joined within an input.
+    files: u64,
+    /// The bytes written, with the header.
+    bytes: u64,
+}
+
+impl Cat {
+    /// Joins the inputs, and reports how many files were.
+    pub(crate) fn run(self, verbosity: Verbosity) -> Status {
+        if self.output.is_none() && to_terminal(self.stdout) {
+            return Status::Usage;
+        }
+        let output = self.output.clone().unwrap_or_else(|| PathBuf::from("-"));
+        let result = match is_stdio(&output) {
+            true => join(&self.inputs, io::stdout().lock()),
+            false => self.join_file(&output),
+        };
+        let joined = match result {
+            Ok(joined) => joined,
+            Err(e) => {
+                eprintln!("{}", e);
+                return e.status;
+            }
+        };
+        let report = match self.json {
+            true => json!({"files": joined.files, "size": joined.bytes}).to_string(),
+            false if verbosity.quiet => return Status::Success,
+            false => format!("joined {} files, {} bytes", joined.files, joined.bytes),
+        };
+        match is_stdio(&output) {
+            true => eprintln!("{}", report),
+            false => println!("{}", report),
+        }
+        Status::Success
+    }
+
+    /// Like `join`, into the file `output`, which is removed if that fails.
+    fn join_file(&self, output: &Path) -> Result<Joined, Failure> {
+        for input in self.inputs.iter().filter(|input| !is_stdio(input)) {
+            check_distinct(input, output).map_err(|e| Failure::new(Status::Usage, e))?;
+        }
+        create(output, self.force, |file, _| {
+            let mut writer = BufWriter::new(file);
+            let joined = join(&self.inputs, &mut writer)?;
+            let file = writer
+                .into_inner()
+                .map_err(|e| at(output, e.into_error()))?;
+            file.sync_all().map_err(|e| at(output, e))?;
+            Ok(joined)
+        })
+    }
+}
+
+/// Writes the header of the first of `inputs`, uncompressed, and the
+/// payloads of all of them to `output`. An input may itself be several data
+/// files joined by `cat`, if they are compressed with zlib.
+fn join<W: Write>(inputs: &[PathBuf], mut output: W) -> Result<Joined, Failure> {
+    let written = |e: io::Error| Failure::new(Status::Io, format!("Writing the output: {}", e));
+    let mut joined = Joined { files: 0, bytes: 0 };
+    let mut version = None;
+    for input in inputs {
+        let mut reader = open(input)?;
+        let header = Header::read(&mut reader).map_err(|e| failed(input, e))?;
+        let first = *version.get_or_insert(header.version);
+        if joined.files == 0 {
+            let header = Header::new(first, Compression::Uncompressed).to_bytes();
+            output.write_all(&header).map_err(written)?;
+            joined.bytes += 8;
+        }
+        if header.version != first {
+            warn!(
+                "{}: version {} differs from {}",
+                input.display(),
+                header.version,
+                first
+            );
+        }
+        if !header.compression.is_compressed() {
+            joined.files += 1;
+            joined.bytes += io::copy(&mut reader, &mut output).map_err(|e| at(input, e))?;
+            continue;
+        }
+        let reader = Cursor::new(header.to_bytes()).chain(reader);
+        let mut chunks = Chunks::new(reader).map_err(|e| failed(input, e))?;
+        chunks.set_concatenated(true);
+        let mut current = chunks.header();
+        let mut codec = current.compression.codec().unwrap_or_default().build(None);
+        let mut buf = Vec::new();
+        while let Some(chunk) = chunks.next() {
+            let chunk = chunk.map_err(|e| Failure::new(Status::Corrupt, failed(input, e)))?;
+            if chunks.header() != current {
+                current = chunks.header();
+                if current.version != first {
+                    warn!(
+                        "{}: the file at offset {} has version {}, not {}",
+                        input.display(),
+                        chunk.offset - 8,
+                        current.version,
+                        first
+                    );
+                }
+                codec = current.compression.codec().unwrap_or_default().build(None);
+            }
+            buf.clear();
+            codec.decompress(&chunk.data, &mut buf).map_err(|e| {
+                let message = format!(
+                    "{}: {} in the chunk at offset {}",
+                    input.display(),
+                    e,
+                    chunk.offset
+                );
+                Failure::new(Status::Corrupt, message)
+            })?;
+            output.write_all(&buf).map_err(written)?;
+            joined.bytes += buf.len() as u64;
+        }
+        joined.files += chunks.files();
+    }
+    output.flush().map_err(written)?;
+    Ok(joined)
+}
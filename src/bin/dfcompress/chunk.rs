@@ -0,0 +1,318 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+//! The `chunk` subcommand, which reads and replaces single chunks of
+//! compressed files.
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use dfcompress::{ChunkWriter, Chunks, RawChunk, CHUNK_SIZE};
+use log::{info, warn};
+
+use super::busy::lock_settled;
+use super::convert::{copy_attributes, create, temp_path};
+use super::exit::{Cause, Failure, Status};
+use super::interrupt::remove_on_interrupt;
+use super::{
+    at, gunzip, is_stdio, open, to_terminal, Chunk, ChunkCommand, ChunkGet, ChunkSet, Verbosity,
+};
+
+impl Chunk {
+    pub(crate) fn run(self, verbosity: Verbosity) -> Status {
+        match self.command {
+            ChunkCommand::Get(get) => get.run(),
+            ChunkCommand::Set(set) => set.run(verbosity),
+        }
+    }
+}
+
+impl ChunkGet {
+    fn run(self) -> Status {
+        if self.output.is_none() && to_terminal(self.stdout) {
+            return Status::Usage;
+        }
+        match self.get() {
+            Ok(()) => Status::Success,
+            Err(e) => {
+                eprintln!("{}", e);
+                e.status
+            }
+        }
+    }
+
+    /// Writes the chunk, decompressed unless `--raw`.
+    fn get(&self) -> Result<(), Failure> {
+        let mut chunks = Chunks::new(open(&self.file)?).map_err(|e| at(&self.file, e))?;
+        let header = chunks.header();
+        let chunk = find(&mut chunks, &self.file, self.index)?;
+        let data = match self.raw {
+            true => chunk.data,
+            false => {
+                let mut codec = header.compression.codec().unwrap_or_default().build(None);
+                let mut data = Vec::new();
+                codec
+                    .decompress(&chunk.data, &mut data)
+                    .map_err(|e| damaged(&self.file, self.index, &chunk, e.into()))?;
+                data
+            }
+        };
+        info!(
+            "{}: chunk {} at offset {}, {} bytes",
+            self.file.display(),
+            self.index,
+            chunk.offset,
+            data.len()
+        );
+        match self.output.as_deref().filter(|output| !is_stdio(output)) {
+            Some(output) => write_file(output, &data, self.force),
+            None => {
+                let mut stdout = io::stdout().lock();
+                stdout
+                    .write_all(&data)
+                    .and_then(|()| stdout.flush())
+                    .map_err(|e| Failure::new(Status::Io, format!("Writing the chunk: {}", e)))
+            }
+        }
+    }
+}
+
+impl ChunkSet {
+    fn run(self, verbosity: Verbosity) -> Status {
+        match self.set() {
+            Ok((old, new)) => {
+                if !verbosity.quiet {
+                    println!(
+                        "{}: chunk {} replaced, {} -> {} bytes",
+                        self.file.display(),
+                        self.index,
+                        old,
+                        new
+                    );
+                }
+                Status::Success
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                e.status
+            }
+        }
+    }
+
+    /// Rewrites the file with the new chunk, through a temporary file that
+    /// replaces it. Returns the stored sizes of the old chunk and the new.
+    fn set(&self) -> Result<(u64, u64), Failure> {
+        let path = &self.file;
+        if is_stdio(path) {
+            let message = "chunk set rewrites a file, not standard input";
+            return Err(Failure::new(Status::Usage, message));
+        }
+        let mut patch = Vec::new();
+        open(&self.from)?
+            .read_to_end(&mut patch)
+            .map_err(|e| at(&self.from, e))?;
+        let file = File::open(path).map_err(|e| at(path, e))?;
+        lock_settled(path, &file, self.wait)?;
+        let reader = gunzip(Box::new(BufReader::new(file)), path)?;
+        let mut chunks = Chunks::new(reader).map_err(|e| at(path, e))?;
+        let header = chunks.header();
+        let mut codec = header.compression.codec().unwrap_or_default().build(None);
+        let (payload, len) = match self.raw {
+            true => {
+                let mut data = Vec::new();
+                codec.decompress(&patch, &mut data).map_err(|e| {
+                    let compression = format!("{:?}", header.compression).to_lowercase();
+                    let message = format!("Not a {} chunk: {}", compression, e);
+                    Failure::new(Status::Usage, at(&self.from, message.as_str()))
+                })?;
+                (patch, data.len())
+            }
+            false => {
+                let mut payload = Vec::new();
+                codec
+                    .compress(&patch, &mut payload)
+                    .map_err(|e| Failure::new(Status::Failure, e))?;
+                let len = patch.len();
+                (payload, len)
+            }
+        };
+        if len > CHUNK_SIZE {
+            warn!(
+                "{}: the chunk holds {} bytes, more than the {} Dwarf Fortress writes",
+                path.display(),
+                len,
+                CHUNK_SIZE
+            );
+        }
+        let temp = temp_path(path);
+        let file = File::options()
+            .write(true)
+            .create_new(true)
+            .open(&temp)
+            .map_err(|e| at(&temp, e))?;
+        let _temp = remove_on_interrupt(&temp);
+        let result = splice(&mut chunks, path, self.index, &payload, file, &temp);
+        // Which unlocks the file.
+        drop(chunks);
+        let result = result.and_then(|old| {
+            fs::rename(&temp, path).map_err(|e| at(path, e))?;
+            Ok((old, payload.len() as u64 + 4))
+        });
+        if result.is_err() {
+            let _ = fs::remove_file(&temp);
+        }
+        result
+    }
+}
+
+/// Writes the chunks of `path` to `file`, created at `temp`, with `payload`
+/// in place of chunk `index`, and gives it the permissions and times of
+/// `path`. Returns the stored size of the old chunk.
+fn splice<R: io::Read>(
+    chunks: &mut Chunks<R>,
+    path: &Path,
+    index: u64,
+    payload: &[u8],
+    file: File,
+    temp: &Path,
+) -> Result<u64, Failure> {
+    let metadata = fs::metadata(path).map_err(|e| at(path, e))?;
+    let written = |e: dfcompress::Error| at(temp, e);
+    let mut writer = ChunkWriter::new(BufWriter::new(file), chunks.header()).map_err(written)?;
+    let mut i = 0;
+    let mut old = None;
+    while let Some(chunk) = next(chunks, path, i)? {
+        match i == index {
+            true => {
+                writer.write_chunk(payload).map_err(written)?;
+                old = Some(chunk.stored_len());
+            }
+            false => writer.write_chunk(&chunk.data).map_err(written)?,
+        }
+        i += 1;
+    }
+    let Some(old) = old else {
+        let message = format!("There is no chunk {}, only {}", index, i);
+        return Err(Failure::new(Status::Usage, at(path, message.as_str())));
+    };
+    let file = writer
+        .into_inner()
+        .into_inner()
+        .map_err(|e| at(temp, e.into_error()))?;
+    copy_attributes(&metadata, &file)
+        .and_then(|()| file.sync_all())
+        .map_err(|e| at(temp, e))?;
+    Ok(old)
+}
+
+/// Reads up to chunk `index` of `chunks`, the chunks of `path`.
+pub(crate) fn find<R: io::Read>(
+    chunks: &mut Chunks<R>,
+    path: &Path,
+    index: u64,
+) -> Result<RawChunk, Failure> {
+    let mut i = 0;
+    while let Some(chunk) = next(chunks, path, i)? {
+        if i == index {
+            return Ok(chunk);
+        }
+        i += 1;
+    }
+    let message = format!("There is no chunk {}, only {}", index, i);
+    Err(Failure::new(Status::Usage, at(path, message.as_str())))
+}
+
+/// Reads chunk `i` of `chunks`, the chunks of `path`, if there is one.
+fn next<R: io::Read>(
+    chunks: &mut Chunks<R>,
+    path: &Path,
+    i: u64,
+) -> Result<Option<RawChunk>, Failure> {
+    match chunks.next().transpose() {
+        Ok(chunk) => Ok(chunk),
+        Err(e) => {
+            let status = match e.status() {
+                Status::Io => Status::Io,
+                _ => Status::Corrupt,
+            };
+            let message = format!("{} in chunk {}", e, i);
+            Err(Failure::new(status, at(path, message.as_str())))
+        }
+    }
+}
+
+/// Describes an error decompressing chunk `index` of `path`.
+fn damaged(path: &Path, index: u64, chunk: &RawChunk, e: dfcompress::Error) -> Failure {
+    let message = format!("{} in chunk {} at offset {}", e, index, chunk.offset);
+    Failure::new(Status::Corrupt, at(path, message.as_str()))
+}
+
+/// Writes `data` to the file `output`, which is removed if that fails.
+fn write_file(output: &Path, data: &[u8], force: bool) -> Result<(), Failure> {
+    create(output, force, |file, _| {
+        (&file)
+            .write_all(data)
+            .and_then(|()| file.sync_all())
+            .map_err(|e| at(output, e))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::scratch;
+    use super::*;
+
+    #[test]
+    fn splice_test() {
+        let dir = scratch("splice");
+        let path = dir.join("world.sav");
+        let mut data = dfcompress::Header::new(1625, dfcompress::Compression::Uncompressed)
+            .to_bytes()
+            .to_vec();
+        data.resize(8 + 2 * CHUNK_SIZE + 500, b'w');
+        let compressed = dfcompress::compress_bytes(&data).unwrap();
+        fs::write(&path, &compressed).unwrap();
+        let hour_ago = std::time::SystemTime::now() - std::time::Duration::from_secs(3600);
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(hour_ago)
+            .unwrap();
+        let mut codec = dfcompress::Codec::Zlib.build(None);
+        let mut payload = Vec::new();
+        codec.compress(b"patched", &mut payload).unwrap();
+
+        let splice_into = |index: u64, temp: &Path| {
+            let mut chunks = Chunks::new(&compressed[..]).unwrap();
+            let file = File::create(temp).unwrap();
+            splice(&mut chunks, &path, index, &payload, file, temp)
+        };
+        let temp = dir.join("spliced");
+        let old = splice_into(1, &temp).unwrap();
+        assert_eq!(
+            old,
+            Chunks::new(&compressed[..])
+                .unwrap()
+                .nth(1)
+                .unwrap()
+                .unwrap()
+                .stored_len()
+        );
+        let mut expected = data[..8 + CHUNK_SIZE].to_vec();
+        expected.extend_from_slice(b"patched");
+        expected.extend_from_slice(&data[8 + 2 * CHUNK_SIZE..]);
+        let modified = |path: &Path| fs::metadata(path).unwrap().modified().unwrap();
+        assert_eq!(modified(&temp), modified(&path));
+        let spliced = fs::read(&temp).unwrap();
+        assert_eq!(dfcompress::decompress_bytes(&spliced).unwrap(), expected);
+
+        let e = splice_into(3, &dir.join("missing")).err().unwrap();
+        assert_eq!(e.status, Status::Usage);
+        assert!(
+            e.message.ends_with("There is no chunk 3, only 3"),
+            "{}",
+            e.message
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,512 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+//! The `completions` subcommand, which prints shell completion scripts
+//! generated from the definition of the arguments.
+use std::fmt::Write;
+
+use clap::{Arg, ArgAction, Command, CommandFactory, ValueEnum};
+
+use super::exit::Status;
+use super::{Cli, Completions};
+
+/// The shells that completions are written for.
+#[derive(Clone, Copy, ValueEnum)]
+pub(crate) enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+    Powershell,
+}
+
+impl Completions {
+    pub(crate) fn run(self) -> Status {
+        let script = match self.shell {
+            Shell::Bash => bash(&cli()),
+            Shell::Zsh => zsh(&cli()),
+            Shell::Fish => fish(&cli()),
+            Shell::Powershell => powershell(&cli()),
+        };
+        print!("{}", script);
+        Status::Success
+    }
+}
+
+/// The definition of the program's arguments, with the global ones given to
+/// every subcommand.
+pub(crate) fn cli() -> Command {
+    let mut cli = Cli::command();
+    cli.build();
+    cli
+}
+
+/// A command, and the names of the commands leading to it, its own last.
+pub(crate) struct Node<'a> {
+    pub path: Vec<&'a str>,
+    pub command: &'a Command,
+}
+
+impl Node<'_> {
+    fn key(&self) -> String {
+        self.path.join(" ")
+    }
+
+    /// The subcommands, which are not hidden.
+    fn subcommands(&self) -> impl Iterator<Item = &Command> {
+        self.command.get_subcommands().filter(|c| !c.is_hide_set())
+    }
+
+    /// The options and flags, which are not hidden.
+    fn options(&self) -> impl Iterator<Item = &Arg> {
+        self.command
+            .get_arguments()
+            .filter(|a| !a.is_positional() && !a.is_hide_set())
+    }
+}
+
+/// Lists `command` and its subcommands, depth first.
+pub(crate) fn nodes(command: &Command) -> Vec<Node<'_>> {
+    let mut nodes = Vec::new();
+    walk(vec![command.get_name()], command, &mut nodes);
+    nodes
+}
+
+fn walk<'a>(path: Vec<&'a str>, command: &'a Command, nodes: &mut Vec<Node<'a>>) {
+    let node = Node { path, command };
+    let path = node.path.clone();
+    nodes.push(node);
+    // The subcommands of `help` are those of its parent again.
+    if command.get_name() == "help" {
+        return;
+    }
+    for child in command.get_subcommands().filter(|c| !c.is_hide_set()) {
+        let mut path = path.clone();
+        path.push(child.get_name());
+        walk(path, child, nodes);
+    }
+}
+
+/// The names a subcommand is given by.
+fn names(command: &Command) -> impl Iterator<Item = &str> {
+    std::iter::once(command.get_name()).chain(command.get_visible_aliases())
+}
+
+/// The flags of an option, such as `-l` and `--level`.
+fn flags(arg: &Arg) -> Vec<String> {
+    let short = arg.get_short().map(|c| format!("-{}", c));
+    let long = arg.get_long().map(|s| format!("--{}", s));
+    short.into_iter().chain(long).collect()
+}
+
+/// The values an option takes, if it takes one of a list.
+fn values(arg: &Arg) -> Vec<String> {
+    arg.get_possible_values()
+        .iter()
+        .filter(|v| !v.is_hide_set())
+        .map(|v| v.get_name().to_string())
+        .collect()
+}
+
+/// The first line of the help of `arg`.
+fn help(arg: &Arg) -> String {
+    let help = arg.get_help().map(|h| h.to_string()).unwrap_or_default();
+    help.lines().next().unwrap_or_default().to_string()
+}
+
+/// The first line of the description of `command`.
+fn about(command: &Command) -> String {
+    let about = command
+        .get_about()
+        .map(|h| h.to_string())
+        .unwrap_or_default();
+    about.lines().next().unwrap_or_default().to_string()
+}
+
+fn repeats(arg: &Arg) -> bool {
+    matches!(arg.get_action(), ArgAction::Append | ArgAction::Count)
+}
+
+fn bash(cli: &Command) -> String {
+    let name = cli.get_name();
+    let nodes = nodes(cli);
+    let mut s = String::new();
+    writeln!(s, "_{}() {{", name).unwrap();
+    s += "    local cur=\"${COMP_WORDS[COMP_CWORD]}\" prev=\"${COMP_WORDS[COMP_CWORD-1]}\"\n";
+    writeln!(s, "    local path=\"{}\" words i", name).unwrap();
+    s += "    for ((i = 1; i < COMP_CWORD; i++)); do\n";
+    s += "        case \"$path ${COMP_WORDS[i]}\" in\n";
+    for node in &nodes[1..] {
+        let parent = node.path[..node.path.len() - 1].join(" ");
+        for alias in names(node.command) {
+            writeln!(
+                s,
+                "            \"{} {}\") path=\"{}\" ;;",
+                parent,
+                alias,
+                node.key()
+            )
+            .unwrap();
+        }
+    }
+    s += "        esac\n    done\n";
+    s += "    case \"$path $prev\" in\n";
+    for node in &nodes {
+        for arg in node.options().filter(|a| a.get_action().takes_values()) {
+            let patterns: Vec<_> = flags(arg)
+                .iter()
+                .map(|flag| format!("\"{} {}\"", node.key(), flag))
+                .collect();
+            let reply = match values(arg) {
+                values if values.is_empty() => "compgen -f -- \"$cur\"".to_string(),
+                values => format!("compgen -W \"{}\" -- \"$cur\"", values.join(" ")),
+            };
+            writeln!(
+                s,
+                "        {}) COMPREPLY=($({})); return ;;",
+                patterns.join("|"),
+                reply
+            )
+            .unwrap();
+        }
+    }
+    s += "    esac\n";
+    s += "    case \"$path\" in\n";
+    for node in &nodes {
+        let mut words: Vec<String> = node.options().flat_map(flags).collect();
+        words.extend(node.subcommands().flat_map(names).map(String::from));
+        writeln!(
+            s,
+            "        \"{}\") words=\"{}\" ;;",
+            node.key(),
+            words.join(" ")
+        )
+        .unwrap();
+    }
+    s += "    esac\n";
+    s += "    COMPREPLY=($(compgen -W \"$words\" -- \"$cur\"))\n";
+    s += "    if [[ $cur != -* ]]; then\n";
+    s += "        COMPREPLY+=($(compgen -f -- \"$cur\"))\n";
+    s += "    fi\n";
+    s += "}\n";
+    writeln!(s, "complete -o filenames -F _{} {}", name, name).unwrap();
+    s
+}
+
+/// Quotes `text` in single quotes for zsh, escaping what `_arguments` reads.
+fn zsh_quote(text: &str) -> String {
+    let mut quoted = String::new();
+    for c in text.chars() {
+        match c {
+            '\'' => quoted += "'\\''",
+            '[' | ']' | ':' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            _ => quoted.push(c),
+        }
+    }
+    quoted
+}
+
+fn zsh_function(path: &[&str]) -> String {
+    format!("_{}", path.join("__"))
+}
+
+fn zsh(cli: &Command) -> String {
+    let name = cli.get_name();
+    let mut s = format!("#compdef {}\n", name);
+    for node in nodes(cli) {
+        let function = zsh_function(&node.path);
+        writeln!(s, "\n{}() {{", function).unwrap();
+        s += "    local context state state_descr line\n";
+        s += "    typeset -A opt_args\n";
+        s += "    _arguments -s -S -C \\\n";
+        for arg in node.options() {
+            let repeat = if repeats(arg) { "*" } else { "" };
+            let value = match (arg.get_action().takes_values(), values(arg)) {
+                (false, _) => String::new(),
+                (true, values) if values.is_empty() => ":VALUE:_files".to_string(),
+                (true, values) => format!(":VALUE:({})", values.join(" ")),
+            };
+            for flag in flags(arg) {
+                let suffix = match (value.is_empty(), flag.starts_with("--")) {
+                    (true, _) => "",
+                    (false, true) => "=",
+                    (false, false) => "+",
+                };
+                writeln!(
+                    s,
+                    "        '{}{}{}[{}]{}' \\",
+                    repeat,
+                    flag,
+                    suffix,
+                    zsh_quote(&help(arg)),
+                    value
+                )
+                .unwrap();
+            }
+        }
+        let subcommands: Vec<_> = node.subcommands().collect();
+        if subcommands.is_empty() {
+            s += "        '*:FILE:_files'\n";
+            s += "}\n";
+            continue;
+        }
+        writeln!(s, "        ': :{}_commands' \\", function).unwrap();
+        s += "        '*:: :->args'\n";
+        s += "    case $state in\n";
+        s += "        args)\n";
+        s += "            case $words[1] in\n";
+        for sub in &subcommands {
+            let mut path = node.path.clone();
+            path.push(sub.get_name());
+            let aliases: Vec<_> = names(sub).collect();
+            writeln!(
+                s,
+                "                ({}) {} ;;",
+                aliases.join("|"),
+                zsh_function(&path)
+            )
+            .unwrap();
+        }
+        s += "            esac\n";
+        s += "            ;;\n";
+        s += "    esac\n";
+        s += "}\n";
+        writeln!(s, "\n{}_commands() {{", function).unwrap();
+        s += "    local commands; commands=(\n";
+        for sub in &subcommands {
+            for alias in names(sub) {
+                writeln!(s, "        '{}:{}'", alias, zsh_quote(&about(sub))).unwrap();
+            }
+        }
+        s += "    )\n";
+        writeln!(
+            s,
+            "    _describe -t commands '{} commands' commands",
+            node.key()
+        )
+        .unwrap();
+        s += "}\n";
+    }
+    writeln!(s, "\n_{} \"$@\"", name).unwrap();
+    s
+}
+
+/// Quotes `text` in single quotes for fish.
+fn fish_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+fn fish(cli: &Command) -> String {
+    let name = cli.get_name();
+    let nodes = nodes(cli);
+    let function = format!("__fish_{}_path", name);
+    let mut s = format!("function {}\n", function);
+    writeln!(s, "    set -l path {}", name).unwrap();
+    s += "    for word in (commandline -opc)[2..-1]\n";
+    s += "        switch \"$path $word\"\n";
+    for node in &nodes[1..] {
+        let parent = node.path[..node.path.len() - 1].join(" ");
+        let cases: Vec<_> = names(node.command)
+            .map(|alias| fish_quote(&format!("{} {}", parent, alias)))
+            .collect();
+        writeln!(s, "            case {}", cases.join(" ")).unwrap();
+        writeln!(s, "                set path {}", fish_quote(&node.key())).unwrap();
+    }
+    s += "        end\n";
+    s += "    end\n";
+    s += "    echo $path\n";
+    s += "end\n\n";
+    for node in &nodes {
+        let condition = format!(
+            "-n {}",
+            fish_quote(&format!("test ({}) = '{}'", function, node.key()))
+        );
+        for arg in node.options() {
+            let mut line = format!("complete -c {} {}", name, condition);
+            if let Some(c) = arg.get_short() {
+                write!(line, " -s {}", c).unwrap();
+            }
+            if let Some(long) = arg.get_long() {
+                write!(line, " -l {}", long).unwrap();
+            }
+            if arg.get_action().takes_values() {
+                match values(arg) {
+                    values if values.is_empty() => line += " -r",
+                    values => write!(line, " -x -a {}", fish_quote(&values.join(" "))).unwrap(),
+                }
+            }
+            write!(line, " -d {}", fish_quote(&help(arg))).unwrap();
+            writeln!(s, "{}", line).unwrap();
+        }
+        for sub in node.subcommands() {
+            for alias in names(sub) {
+                writeln!(
+                    s,
+                    "complete -c {} {} -f -a {} -d {}",
+                    name,
+                    condition,
+                    alias,
+                    fish_quote(&about(sub))
+                )
+                .unwrap();
+            }
+        }
+    }
+    s
+}
+
+/// Quotes `text` in single quotes for PowerShell.
+fn powershell_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "''"))
+}
+
+fn powershell(cli: &Command) -> String {
+    let name = cli.get_name();
+    let nodes = nodes(cli);
+    let mut s = String::from("using namespace System.Management.Automation\n");
+    s += "using namespace System.Management.Automation.Language\n\n";
+    writeln!(
+        s,
+        "Register-ArgumentCompleter -Native -CommandName {} -ScriptBlock {{",
+        powershell_quote(name)
+    )
+    .unwrap();
+    s += "    param($wordToComplete, $commandAst, $cursorPosition)\n";
+    s += "    $paths = @{\n";
+    for node in &nodes[1..] {
+        let parent = node.path[..node.path.len() - 1].join(" ");
+        for alias in names(node.command) {
+            writeln!(
+                s,
+                "        {} = {}",
+                powershell_quote(&format!("{} {}", parent, alias)),
+                powershell_quote(&node.key())
+            )
+            .unwrap();
+        }
+    }
+    s += "    }\n";
+    writeln!(s, "    $path = {}", powershell_quote(name)).unwrap();
+    s += "    foreach ($element in $commandAst.CommandElements | Select-Object -Skip 1) {\n";
+    s += "        if ($element.Extent.EndOffset -ge $cursorPosition) { break }\n";
+    s += "        if ($element -is [StringConstantExpressionAst]) {\n";
+    s += "            $next = $paths[\"$path $($element.Value)\"]\n";
+    s += "            if ($next) { $path = $next }\n";
+    s += "        }\n";
+    s += "    }\n";
+    s += "    $completions = @(switch ($path) {\n";
+    for node in &nodes {
+        writeln!(s, "        {} {{", powershell_quote(&node.key())).unwrap();
+        for arg in node.options() {
+            let help = match help(arg) {
+                help if help.is_empty() => flags(arg).join(", "),
+                help => help,
+            };
+            for flag in flags(arg) {
+                writeln!(
+                    s,
+                    "            [CompletionResult]::new({}, {}, [CompletionResultType]::ParameterName, {})",
+                    powershell_quote(&flag),
+                    powershell_quote(&flag),
+                    powershell_quote(&help)
+                )
+                .unwrap();
+            }
+        }
+        for sub in node.subcommands() {
+            let about = match about(sub) {
+                about if about.is_empty() => sub.get_name().to_string(),
+                about => about,
+            };
+            for alias in names(sub) {
+                writeln!(
+                    s,
+                    "            [CompletionResult]::new({}, {}, [CompletionResultType]::ParameterValue, {})",
+                    powershell_quote(alias),
+                    powershell_quote(alias),
+                    powershell_quote(&about)
+                )
+                .unwrap();
+            }
+        }
+        s += "            break\n";
+        s += "        }\n";
+    }
+    s += "    })\n";
+    s += "    $completions.Where{ $_.CompletionText -like \"$wordToComplete*\" } |\n";
+    s += "        Sort-Object -Property ListItemText\n";
+    s += "}\n";
+    s
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// The paths of `command` and its subcommands, with the long flags of
+    /// each.
+    pub(crate) fn commands(command: &Command, path: &str, all: &mut Vec<(String, Vec<String>)>) {
+        let longs = command
+            .get_arguments()
+            .filter(|a| !a.is_hide_set())
+            .filter_map(|a| a.get_long().map(String::from))
+            .collect();
+        all.push((path.to_string(), longs));
+        if command.get_name() == "help" {
+            return;
+        }
+        for sub in command.get_subcommands().filter(|c| !c.is_hide_set()) {
+            commands(sub, &format!("{} {}", path, sub.get_name()), all);
+        }
+    }
+
+    /// The lines of `script` from the one starting with `start` to the next
+    /// one starting with `end`.
+    fn section<'a>(script: &'a str, start: &str, end: &str) -> Vec<&'a str> {
+        let mut lines = script.lines().skip_while(|line| !line.starts_with(start));
+        let first = lines.next().unwrap_or_else(|| panic!("no {:?}", start));
+        std::iter::once(first)
+            .chain(lines.take_while(|line| !line.starts_with(end)))
+            .collect()
+    }
+
+    #[test]
+    fn completions_test() {
+        Cli::command().debug_assert();
+        let cli = cli();
+        let mut all = Vec::new();
+        commands(&cli, cli.get_name(), &mut all);
+        assert!(all.len() > 20);
+        let (bash, zsh) = (bash(&cli), zsh(&cli));
+        let mut checked = 0;
+        for (path, longs) in &all {
+            let words = format!("        \"{}\") words=\"", path);
+            let words = section(&bash, &words, "    ")[0];
+            let function = zsh_function(&path.split(' ').collect::<Vec<_>>());
+            let function = section(&zsh, &format!("{}() {{", function), "}").join("\n");
+            if path != cli.get_name() {
+                let (parent, name) = path.rsplit_once(' ').unwrap();
+                assert!(bash.contains(&format!("\"{} {}\") path=\"{}\"", parent, name, path)));
+            }
+            for long in longs {
+                let flag = format!("--{}", long);
+                assert!(
+                    words.split(['"', ' ']).any(|w| w == flag),
+                    "bash {} {}",
+                    path,
+                    flag
+                );
+                let quoted = [format!("'{}", flag), format!("'*{}", flag)];
+                assert!(
+                    quoted.iter().any(|q| function.contains(q.as_str())),
+                    "zsh {} {}",
+                    path,
+                    flag
+                );
+                checked += 1;
+            }
+        }
+        assert!(checked > 100, "{}", checked);
+    }
+}
@@ -0,0 +1,247 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+//! The configuration file, whose settings are the defaults of the flags.
+use std::env;
+use std::fs;
+use std::io;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+
+use dfcompress::Target;
+use glob::Pattern;
+use serde::Deserialize;
+
+use super::exit::{Failure, Status};
+#[cfg(feature = "watch")]
+use super::Watch;
+use super::{at, Backup, BackupCommand, Convert, Files, Format, List, Pack, Rotate};
+#[cfg(feature = "zstd")]
+use super::{Dict, DictCommand};
+
+/// The settings of the configuration file, all of them optional.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct Config {
+    /// The compression level, as `--level`.
+    level: Option<u32>,
+    /// The files to process at once, as `--threads`.
+    threads: Option<NonZeroUsize>,
+    /// The format to compress to, as `--format`; decompressing is not
+    /// affected.
+    format: Option<Format>,
+    /// The suffix of the backups that `--in-place` keeps, as `--backup`.
+    backup: Option<String>,
+    /// The patterns of files and directories to leave out of directories,
+    /// as `--exclude`.
+    exclude: Vec<String>,
+    /// The save directory that `watch` watches and `rotate` rotates.
+    save_dir: Option<PathBuf>,
+    /// Where `watch` keeps its backups, as its `--output-dir`.
+    #[cfg(feature = "watch")]
+    backup_dir: Option<PathBuf>,
+    #[serde(skip)]
+    patterns: Vec<Pattern>,
+}
+
+/// Where the configuration file is unless `--config` says otherwise:
+/// `dfcompress/config.toml` in the user's configuration directory.
+fn default_path() -> Option<PathBuf> {
+    let dir = match cfg!(windows) {
+        true => env::var_os("APPDATA").map(PathBuf::from),
+        false => env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config"))),
+    };
+    dir.map(|dir| dir.join("dfcompress").join("config.toml"))
+}
+
+impl Config {
+    /// Reads the configuration file `path`, or the default one if there is
+    /// one.
+    pub(crate) fn load(path: Option<&Path>) -> Result<Config, Failure> {
+        let (path, default) = match path {
+            Some(path) => (path.to_path_buf(), false),
+            None => match default_path() {
+                Some(path) => (path, true),
+                None => return Ok(Config::default()),
+            },
+        };
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if default && e.kind() == io::ErrorKind::NotFound => {
+                return Ok(Config::default())
+            }
+            Err(e) => return Err(at(&path, e)),
+        };
+        let invalid = |e: &dyn std::fmt::Display| {
+            Failure::new(Status::Usage, format!("{}: {}", path.display(), e))
+        };
+        let mut config: Config = toml::from_str(&text).map_err(|e| invalid(&e))?;
+        if config.level.is_some_and(|level| level > 9) {
+            return Err(invalid(&"level must be from 0 to 9"));
+        }
+        config.patterns = config
+            .exclude
+            .iter()
+            .map(|pattern| Pattern::new(pattern))
+            .collect::<Result<_, _>>()
+            .map_err(|e| invalid(&e))?;
+        Ok(config)
+    }
+}
+
+impl Convert {
+    /// Takes the settings that the flags leave unset from `config`, for a
+    /// conversion to `target`.
+    pub(crate) fn configure(&mut self, config: &Config, target: Target) {
+        if self.level.is_none() && !self.fast && !self.best {
+            self.level = config.level;
+        }
+        self.threads = self.threads.or(config.threads);
+        // --dict picks the format.
+        #[cfg(feature = "zstd")]
+        let dict = self.dict.is_some();
+        #[cfg(not(feature = "zstd"))]
+        let dict = false;
+        if target == Target::Compressed && !dict {
+            self.format = self.format.or(config.format);
+        }
+        if self.in_place && self.backup.is_none() {
+            self.backup.clone_from(&config.backup);
+        }
+        if self.exclude.is_empty() {
+            self.exclude.clone_from(&config.patterns);
+        }
+    }
+}
+
+impl Files {
+    /// Takes the settings that the flags leave unset from `config`.
+    pub(crate) fn configure(&mut self, config: &Config) {
+        self.threads = self.threads.or(config.threads);
+        if self.exclude.is_empty() {
+            self.exclude.clone_from(&config.patterns);
+        }
+    }
+}
+
+impl List {
+    /// Takes the settings that the flags leave unset from `config`.
+    pub(crate) fn configure(&mut self, config: &Config) {
+        if self.exclude.is_empty() {
+            self.exclude.clone_from(&config.patterns);
+        }
+    }
+}
+
+impl Pack {
+    /// Takes the settings that the flags leave unset from `config`.
+    pub(crate) fn configure(&mut self, config: &Config) {
+        if self.exclude.is_empty() {
+            self.exclude.clone_from(&config.patterns);
+        }
+    }
+}
+
+impl Rotate {
+    /// Takes the settings that the flags leave unset from `config`.
+    pub(crate) fn configure(&mut self, config: &Config) {
+        self.threads = self.threads.or(config.threads);
+        if self.dir.is_none() {
+            self.dir.clone_from(&config.save_dir);
+        }
+    }
+}
+
+impl Backup {
+    /// Takes the settings that the flags leave unset from `config`.
+    pub(crate) fn configure(&mut self, config: &Config) {
+        if let BackupCommand::Create(ref mut create) = self.command {
+            create.threads = create.threads.or(config.threads);
+            if create.exclude.is_empty() {
+                create.exclude.clone_from(&config.patterns);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl Dict {
+    /// Takes the settings that the flags leave unset from `config`.
+    pub(crate) fn configure(&mut self, config: &Config) {
+        let DictCommand::Train(ref mut train) = self.command;
+        if train.exclude.is_empty() {
+            train.exclude.clone_from(&config.patterns);
+        }
+    }
+}
+
+#[cfg(feature = "watch")]
+impl Watch {
+    /// Takes the settings that the flags leave unset from `config`.
+    pub(crate) fn configure(&mut self, config: &Config) {
+        self.threads = self.threads.or(config.threads);
+        if self.dir.is_none() {
+            self.dir.clone_from(&config.save_dir);
+        }
+        if self.output_dir.is_none() {
+            self.output_dir.clone_from(&config.backup_dir);
+        }
+        if self.exclude.is_empty() {
+            self.exclude.clone_from(&config.patterns);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::scratch;
+    use super::*;
+
+    /// Loads a configuration file holding `text`.
+    fn load(dir: &Path, text: &str) -> Result<Config, Failure> {
+        let path = dir.join("config.toml");
+        fs::write(&path, text).unwrap();
+        Config::load(Some(&path))
+    }
+
+    #[test]
+    fn load_test() {
+        let dir = scratch("config");
+        let config = load(
+            &dir,
+            "level = 9\nthreads = 2\nformat = \"gzip\"\nbackup = \".orig\"\n\
+             exclude = [\"*.txt\", \"art\"]\nsave-dir = \"save\"\n",
+        )
+        .unwrap();
+        assert_eq!(config.level, Some(9));
+        assert_eq!(config.threads, NonZeroUsize::new(2));
+        assert!(config.format == Some(Format::Gzip));
+        assert_eq!(config.backup.as_deref(), Some(".orig"));
+        assert!(config.patterns[0].matches("notes.txt"));
+        assert!(config.patterns[1].matches("art"));
+        assert_eq!(config.save_dir, Some(PathBuf::from("save")));
+
+        let config = load(&dir, "").unwrap();
+        assert!(config.level.is_none() && config.patterns.is_empty());
+
+        for text in [
+            "level = 10",
+            "levle = 1",
+            "threads = 0",
+            "format = \"rar\"",
+            "exclude = [\"[\"]",
+            "level = ",
+        ] {
+            let e = load(&dir, text).err().unwrap();
+            assert_eq!(e.status, Status::Usage, "{}", text);
+            assert!(e.message.contains("config.toml"), "{}", e.message);
+        }
+
+        let missing = dir.join("missing.toml");
+        let e = Config::load(Some(&missing)).err().unwrap();
+        assert_eq!(e.status, Status::Io);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
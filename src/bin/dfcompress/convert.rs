@@ -0,0 +1,599 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+//! Converting files.
+use std::fs::{self, File, FileTimes};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use dfcompress::{recode_with, Compression, DecompressReader, Header, Options, Summary, Target};
+use flate2::write::GzEncoder;
+use log::info;
+
+use super::archive::ArchiveCodec;
+use super::busy::lock_settled;
+use super::exit::{Cause, Failure, Status};
+use super::interrupt::{remove_on_interrupt, restore_on_interrupt};
+use super::solid;
+use super::volumes::Volumes;
+use super::{at, gunzip, is_stdio, open};
+
+/// Fails if `output` is the same file as `input`, which would be truncated
+/// before it is read.
+pub(crate) fn check_distinct(input: &Path, output: &Path) -> io::Result<()> {
+    if let (Ok(a), Ok(b)) = (input.canonicalize(), output.canonicalize()) {
+        if a == b {
+            let message = format!("{} is also the input", output.display());
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+        }
+    }
+    Ok(())
+}
+
+/// Whether `output` exists and was modified no earlier than `input`. An
+/// output gets the time of its input, so a later input has changed since.
+pub(crate) fn is_up_to_date(input: &Path, output: &Path) -> bool {
+    let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified());
+    match (modified(input), modified(output)) {
+        (Ok(input), Ok(output)) => output >= input,
+        _ => false,
+    }
+}
+
+/// How output files are written.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct WriteOptions {
+    /// Whether to overwrite an existing output file.
+    pub force: bool,
+    /// Whether to check that the output file decompresses to the same data
+    /// as the input.
+    pub verify: bool,
+    /// Whether to convert into nothing, only to see how it goes.
+    pub dry_run: bool,
+    /// The level to gzip the converted file at, if it is to be gzipped.
+    pub gzip: Option<u32>,
+    /// The codec of the stream to write a solid file with, if one is to be
+    /// written.
+    pub solid: Option<ArchiveCodec>,
+    /// The size of the volumes to cut the output into, if it is to be cut.
+    pub split: Option<u64>,
+    /// Whether to leave out the header, writing only the payload.
+    pub strip_header: bool,
+    /// The save version of a header to put before the input, which is then
+    /// only a payload.
+    pub add_header: Option<u32>,
+    /// Whether to wait for a file that Dwarf Fortress seems to be writing
+    /// before replacing it, instead of failing.
+    pub wait: bool,
+    /// Whether to leave a file converted in place as it is if converting it
+    /// makes it no smaller.
+    pub only_smaller: bool,
+}
+
+/// Counts the bytes written to a writer.
+pub(crate) struct Counted<W> {
+    pub inner: W,
+    pub count: u64,
+}
+
+impl<W: Write> Write for Counted<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Drops the first `skip` bytes written to a writer.
+struct Skipped<W> {
+    inner: W,
+    skip: usize,
+}
+
+impl<W: Write> Write for Skipped<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.skip > 0 {
+            let n = buf.len().min(self.skip);
+            self.skip -= n;
+            return Ok(n);
+        }
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Converts `reader` into `writer` like `recode_with`, leaving out the
+/// header and gzipping the result, or writing a solid file, if `write` says
+/// so.
+fn recode_to<R: io::Read, W: Write>(
+    reader: R,
+    writer: W,
+    target: Target,
+    options: &Options,
+    write: WriteOptions,
+) -> dfcompress::Result<Summary> {
+    if let Some(codec) = write.solid {
+        let counted = Counted {
+            inner: writer,
+            count: 0,
+        };
+        let mut stream = solid::create(codec, counted)?;
+        let mut summary = recode_with(reader, &mut stream, Target::Uncompressed, options)?;
+        summary.bytes_out = stream.finish()?.count;
+        return Ok(summary);
+    }
+    let skip = if write.strip_header { 8 } else { 0 };
+    let Some(level) = write.gzip else {
+        let writer = Skipped {
+            inner: writer,
+            skip,
+        };
+        let mut summary = recode_with(reader, writer, target, options)?;
+        summary.bytes_out -= skip as u64;
+        return Ok(summary);
+    };
+    let writer = Counted {
+        inner: writer,
+        count: 0,
+    };
+    let mut gzip = GzEncoder::new(writer, flate2::Compression::new(level));
+    let stripped = Skipped {
+        inner: &mut gzip,
+        skip,
+    };
+    let mut summary = recode_with(reader, stripped, target, options)?;
+    summary.bytes_out = gzip.finish()?.count;
+    Ok(summary)
+}
+
+/// Converts `input` into `output`, removing `output` if that fails.
+pub(crate) fn convert(
+    input: &Path,
+    output: &Path,
+    target: Target,
+    options: &Options,
+    write: WriteOptions,
+) -> Result<Summary, Failure> {
+    let metadata = attributes(input);
+    let mut reader = open(input)?;
+    if let Some(version) = write.add_header {
+        let header = Header::new(version, Compression::Uncompressed).to_bytes();
+        reader = Box::new(io::Cursor::new(header).chain(reader));
+    }
+    if write.dry_run {
+        if !is_stdio(output) && !write.force && output.exists() {
+            return Err(at(output, "Already exists; use --force to overwrite"));
+        }
+        let result = recode_to(reader, io::sink(), target, options, write);
+        return result.map_err(|e| failed(input, e));
+    }
+    if is_stdio(output) {
+        let stdout = io::stdout();
+        let result = recode_to(reader, stdout.lock(), target, options, write);
+        return result.map_err(|e| failed(input, e));
+    }
+    if !is_stdio(input) {
+        check_distinct(input, output).map_err(|e| Failure::new(Status::Usage, e))?;
+    }
+    if let Some(size) = write.split {
+        return write_volumes(reader, input, output, size, target, options, write);
+    }
+    create(output, write.force, |file, path| {
+        let input = (input, metadata.as_ref());
+        write_file(reader, input, file, path, target, options, write)
+    })
+}
+
+/// Like `write_file`, into volumes of `size` bytes of `output`, which are
+/// removed if that fails.
+fn write_volumes<R: io::Read>(
+    reader: R,
+    input: &Path,
+    output: &Path,
+    size: u64,
+    target: Target,
+    options: &Options,
+    write: WriteOptions,
+) -> Result<Summary, Failure> {
+    let mut volumes = Volumes::new(output, size, write.force)?;
+    let result = match recode_to(reader, &mut volumes, target, options, write) {
+        Ok(summary) => volumes.finish().map(|_| summary),
+        Err(e) => Err(failed(input, e)),
+    };
+    let result = result.and_then(|summary| match write.verify {
+        true => compare(input, output).map(|()| summary),
+        false => Ok(summary),
+    });
+    if result.is_err() {
+        volumes.remove();
+    }
+    result
+}
+
+/// Writes the file `output` with `write_to`, which is given the file created
+/// and its path. `output` must not exist unless `force`; if it does, the
+/// file written is a temporary one next to it that replaces it once
+/// `write_to` succeeds, so that a failure leaves it as it was. Otherwise the
+/// file written is removed if `write_to` fails.
+pub(crate) fn create<T>(
+    output: &Path,
+    force: bool,
+    write_to: impl FnOnce(File, &Path) -> Result<T, Failure>,
+) -> Result<T, Failure> {
+    let replace = force && output.exists();
+    let path = match replace {
+        true => temp_path(output),
+        false => output.to_path_buf(),
+    };
+    let file = File::options()
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::AlreadyExists if !force => {
+                at(output, "Already exists; use --force to overwrite")
+            }
+            _ => at(&path, e),
+        })?;
+    let _path = remove_on_interrupt(&path);
+    let result = write_to(file, &path).and_then(|value| match replace {
+        true => fs::rename(&path, output)
+            .map(|()| value)
+            .map_err(|e| at(output, e)),
+        false => Ok(value),
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&path);
+    }
+    result
+}
+
+/// The path `path` is moved to by `--backup`: the same with `suffix`.
+pub(crate) fn backup_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Fails unless zlib is compressed the same way on every platform, which
+/// zlib-ng does not do, as it picks its code by the CPU.
+pub(crate) fn check_deterministic() -> Result<(), Failure> {
+    match dfcompress::backend().compress {
+        "miniz_oxide" => Ok(()),
+        name => {
+            let message = format!(
+                "--deterministic needs a build that compresses with miniz_oxide, not {}",
+                name
+            );
+            Err(Failure::new(Status::Usage, message))
+        }
+    }
+}
+
+/// The temporary file next to `path` that replaces it.
+pub(crate) fn temp_path(path: &Path) -> PathBuf {
+    let mut name = std::ffi::OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(format!(".{}.tmp", process::id()));
+    path.with_file_name(name)
+}
+
+/// Converts `path` into a temporary file next to it, which then replaces it.
+/// The original is moved to `backup_path(path, suffix)` first if a `backup`
+/// suffix is given. `path` is left as it was if anything fails, or if
+/// Dwarf Fortress seems to be writing it, or if `write.only_smaller` and the
+/// converted file is no smaller; the summary then has the size of `path` as
+/// that of the output.
+pub(crate) fn convert_in_place(
+    path: &Path,
+    target: Target,
+    options: &Options,
+    write: WriteOptions,
+    backup: Option<&str>,
+) -> Result<Summary, Failure> {
+    if write.dry_run {
+        let result = recode_to(open(path)?, io::sink(), target, options, write);
+        return result.map_err(|e| failed(path, e));
+    }
+    let metadata = attributes(path);
+    // The lock lasts as long as the reader, until the file is replaced.
+    let file = File::open(path).map_err(|e| at(path, e))?;
+    lock_settled(path, &file, write.wait)?;
+    let reader = gunzip(Box::new(io::BufReader::new(file)), path)?;
+    let temp = temp_path(path);
+    let file = File::options()
+        .write(true)
+        .create_new(true)
+        .open(&temp)
+        .map_err(|e| at(&temp, e))?;
+    let _temp = remove_on_interrupt(&temp);
+    let write = WriteOptions {
+        force: false,
+        ..write
+    };
+    let input = (path, metadata.as_ref());
+    let mut summary = write_file(reader, input, file, &temp, target, options, write)?;
+    let size = fs::metadata(path).map_err(|e| at(path, e))?.len();
+    if write.only_smaller && summary.bytes_out >= size {
+        info!(
+            "{}: {} bytes converted, no smaller; left as it is",
+            path.display(),
+            summary.bytes_out
+        );
+        let _ = fs::remove_file(&temp);
+        summary.bytes_out = size;
+        return Ok(summary);
+    }
+    let backup = backup.map(|suffix| backup_path(path, suffix));
+    let _restore = match backup {
+        Some(ref backup) => {
+            fs::rename(path, backup).map_err(|e| {
+                let _ = fs::remove_file(&temp);
+                at(backup, e)
+            })?;
+            Some(restore_on_interrupt(backup, path))
+        }
+        None => None,
+    };
+    fs::rename(&temp, path).map_err(|e| {
+        if let Some(ref backup) = backup {
+            let _ = fs::rename(backup, path);
+        }
+        let _ = fs::remove_file(&temp);
+        at(path, e)
+    })?;
+    Ok(summary)
+}
+
+/// The permissions and times of `input` for its output to get, if it is a
+/// file. They are taken before it is opened, as reading it changes its
+/// access time; if they cannot be, opening it fails too.
+fn attributes(input: &Path) -> Option<fs::Metadata> {
+    match is_stdio(input) {
+        true => None,
+        false => fs::metadata(input).ok(),
+    }
+}
+
+/// Converts `reader` into `file`, created at `path`, which is removed if that
+/// fails. `input` is its path and its `attributes`, which the file gets. It
+/// is synced to disk on success, and then verified if asked.
+fn write_file<R: io::Read>(
+    reader: R,
+    (input, metadata): (&Path, Option<&fs::Metadata>),
+    file: File,
+    path: &Path,
+    target: Target,
+    options: &Options,
+    write: WriteOptions,
+) -> Result<Summary, Failure> {
+    let _output = remove_on_interrupt(path);
+    let mut writer = BufWriter::new(file);
+    let result = match recode_to(reader, &mut writer, target, options, write) {
+        Ok(summary) => writer
+            .into_inner()
+            .map_err(|e| e.into_error())
+            .and_then(|file| {
+                if let Some(metadata) = metadata {
+                    copy_attributes(metadata, &file)?;
+                }
+                file.sync_all()
+            })
+            .map(|()| summary)
+            .map_err(|e| at(path, e)),
+        Err(e) => Err(failed(input, e)),
+    };
+    let result = result.and_then(|summary| match write.verify {
+        true => compare(input, path).map(|()| summary),
+        false => Ok(summary),
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(path);
+    }
+    result
+}
+
+/// Gives `file` the permissions and times in `metadata`.
+pub(crate) fn copy_attributes(metadata: &fs::Metadata, file: &File) -> io::Result<()> {
+    let mut times = FileTimes::new().set_modified(metadata.modified()?);
+    if let Ok(accessed) = metadata.accessed() {
+        times = times.set_accessed(accessed);
+    }
+    file.set_times(times)?;
+    file.set_permissions(metadata.permissions())
+}
+
+/// Describes an error of the conversion of `input`.
+pub(crate) fn failed(input: &Path, e: dfcompress::Error) -> Failure {
+    match is_stdio(input) {
+        true => Failure::new(e.status(), e),
+        false => at(input, e),
+    }
+}
+
+/// Checks that `output` decompresses to the same data file as `input`.
+fn compare(input: &Path, output: &Path) -> Result<(), Failure> {
+    let decompress = |path: &Path| {
+        let file = open(path)?;
+        DecompressReader::new(file).map_err(|e| at(path, e))
+    };
+    let (mut a, mut b) = (decompress(input)?, decompress(output)?);
+    let differs = || {
+        at(
+            output,
+            "Verification failed: the data differs from the input",
+        )
+    };
+    if a.version() != b.version() {
+        return Err(differs());
+    }
+    let (mut buf_a, mut buf_b) = (vec![0; 1 << 16], vec![0; 1 << 16]);
+    loop {
+        let n = fill(&mut a, &mut buf_a).map_err(|e| at(input, e))?;
+        let m = fill(&mut b, &mut buf_b).map_err(|e| at(output, e))?;
+        if buf_a[..n] != buf_b[..m] {
+            return Err(differs());
+        }
+        if n < buf_a.len() {
+            return Ok(());
+        }
+    }
+}
+
+/// Reads until `buf` is full or the input ends, returning the bytes read.
+pub(crate) fn fill<R: io::Read>(r: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match r.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(k) => n += k,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::scratch;
+    use super::super::volumes::volume_path;
+    use super::*;
+
+    #[test]
+    fn failed_convert_test() {
+        let dir = scratch("failed-convert");
+        let mut data = Header::new(1625, Compression::Uncompressed)
+            .to_bytes()
+            .to_vec();
+        data.resize(50008, b'f');
+        let compressed = dfcompress::compress_bytes(&data).unwrap();
+        let input = dir.join("world.sav");
+        fs::write(&input, &compressed[..compressed.len() - 1]).unwrap();
+        let output = dir.join("world.dat");
+        fs::write(&output, b"original").unwrap();
+        for n in 1..6 {
+            fs::write(volume_path(&output, n), b"volume").unwrap();
+        }
+        let listing = || {
+            let mut names: Vec<_> = fs::read_dir(&dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name())
+                .collect();
+            names.sort();
+            names
+        };
+        let before = listing();
+        let convert_to = |split| {
+            let write = WriteOptions {
+                force: true,
+                split,
+                ..WriteOptions::default()
+            };
+            convert(
+                &input,
+                &output,
+                Target::Uncompressed,
+                &Options::new(),
+                write,
+            )
+        };
+
+        let e = convert_to(None).err().unwrap();
+        assert_eq!(e.status, Status::Corrupt);
+        assert_eq!(fs::read(&output).unwrap(), b"original");
+        let e = convert_to(Some(20000)).err().unwrap();
+        assert_eq!(e.status, Status::Corrupt);
+        assert_eq!(fs::read(volume_path(&output, 5)).unwrap(), b"volume");
+        assert_eq!(listing(), before);
+
+        fs::write(&input, &compressed).unwrap();
+        convert_to(None).unwrap();
+        assert_eq!(fs::read(&output).unwrap(), data);
+        convert_to(Some(30000)).unwrap();
+        assert_eq!(fs::read(volume_path(&output, 2)).unwrap(), &data[30000..]);
+        assert!(!volume_path(&output, 3).exists());
+        assert_eq!(listing().len(), 4);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn truncated_status_test() {
+        let dir = scratch("truncated-status");
+        let mut data = Header::new(1625, Compression::Uncompressed)
+            .to_bytes()
+            .to_vec();
+        data.resize(50008, b't');
+        let compressed = dfcompress::compress_bytes(&data).unwrap();
+        let input = dir.join("world.sav");
+        fs::write(&input, &compressed[..compressed.len() - 1]).unwrap();
+        // Copied chunks are as damaged as decompressed ones.
+        for target in [Target::Compressed, Target::Uncompressed] {
+            let output = dir.join("out.sav");
+            let options = Options::new();
+            let e = convert(&input, &output, target, &options, WriteOptions::default());
+            assert_eq!(e.err().unwrap().status, Status::Corrupt, "{:?}", target);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn times_test() {
+        use std::time::{Duration, SystemTime};
+
+        let dir = scratch("times");
+        let data =
+            dfcompress::compress_bytes(&Header::new(1625, Compression::Uncompressed).to_bytes())
+                .unwrap();
+        let input = dir.join("world.sav");
+        let now = SystemTime::now();
+        let accessed = now - Duration::from_secs(2 * 86400);
+        let modified = now - Duration::from_secs(3 * 86400);
+        let times = FileTimes::new()
+            .set_accessed(accessed)
+            .set_modified(modified);
+        let set_times = || {
+            fs::write(&input, &data).unwrap();
+            File::options()
+                .write(true)
+                .open(&input)
+                .unwrap()
+                .set_times(times)
+                .unwrap();
+        };
+        // Those of the input before it was read, which reading it may change.
+        let output = dir.join("world.dat");
+        set_times();
+        convert(
+            &input,
+            &output,
+            Target::Uncompressed,
+            &Options::new(),
+            WriteOptions::default(),
+        )
+        .unwrap();
+        let metadata = fs::metadata(&output).unwrap();
+        assert_eq!(metadata.modified().unwrap(), modified);
+        assert_eq!(metadata.accessed().unwrap(), accessed);
+        set_times();
+        convert_in_place(
+            &input,
+            Target::Uncompressed,
+            &Options::new(),
+            WriteOptions::default(),
+            None,
+        )
+        .unwrap();
+        let metadata = fs::metadata(&input).unwrap();
+        assert_eq!(metadata.modified().unwrap(), modified);
+        assert_eq!(metadata.accessed().unwrap(), accessed);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
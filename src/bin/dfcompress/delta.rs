@@ -0,0 +1,108 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+//! The `delta` subcommand, which encodes a save as a delta against an older
+//! one, and turns it back.
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use super::convert::{create, failed};
+use super::exit::{Failure, Status};
+use super::Verbosity;
+use super::{at, is_stdio, open, to_terminal, Delta, DeltaApply, DeltaCommand, DeltaCreate};
+
+impl Delta {
+    pub(crate) fn run(self, verbosity: Verbosity) -> Status {
+        let (output, stdout) = match self.command {
+            DeltaCommand::Create(ref create) => (&create.output, create.stdout),
+            DeltaCommand::Apply(ref apply) => (&apply.output, apply.stdout),
+        };
+        if output.is_none() && to_terminal(stdout) {
+            return Status::Usage;
+        }
+        let result = match self.command {
+            DeltaCommand::Create(create) => create.create(),
+            DeltaCommand::Apply(apply) => apply.apply(),
+        };
+        match result {
+            Ok(report) => {
+                if !verbosity.quiet {
+                    // The delta or the save may be on standard output.
+                    eprintln!("{}", report);
+                }
+                Status::Success
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                e.status
+            }
+        }
+    }
+}
+
+impl DeltaCreate {
+    fn create(&self) -> Result<String, Failure> {
+        let base = read(&self.base)?;
+        let new = read(&self.new)?;
+        let delta = dfcompress::delta_create(&base, &new).map_err(|e| {
+            // Tell which input is not a data file.
+            match dfcompress::Header::parse(&base) {
+                Ok(_) => failed(&self.new, e),
+                Err(_) => failed(&self.base, e),
+            }
+        })?;
+        write(self.output.as_deref(), self.force, &delta)?;
+        Ok(format!(
+            "{}: {} bytes -> a delta of {} bytes against {}",
+            self.new.display(),
+            new.len(),
+            delta.len(),
+            self.base.display()
+        ))
+    }
+}
+
+impl DeltaApply {
+    fn apply(&self) -> Result<String, Failure> {
+        let base = read(&self.base)?;
+        let delta = read(&self.delta)?;
+        let save = dfcompress::delta_apply(&base, &delta).map_err(|e| match e.kind() {
+            dfcompress::ErrorKind::NotADelta
+            | dfcompress::ErrorKind::DeltaCorrupt
+            | dfcompress::ErrorKind::DeltaMismatch => failed(&self.delta, e),
+            _ => failed(&self.base, e),
+        })?;
+        write(self.output.as_deref(), self.force, &save)?;
+        Ok(format!(
+            "{}: a delta of {} bytes -> {} bytes",
+            self.delta.display(),
+            delta.len(),
+            save.len()
+        ))
+    }
+}
+
+/// Reads all of `path`, or standard input if it is `-`.
+fn read(path: &Path) -> Result<Vec<u8>, Failure> {
+    let mut data = Vec::new();
+    open(path)?
+        .read_to_end(&mut data)
+        .map_err(|e| at(path, e))?;
+    Ok(data)
+}
+
+/// Writes `data` to `output`, or standard output if there is none. A file
+/// is removed if that fails.
+fn write(output: Option<&Path>, force: bool, data: &[u8]) -> Result<(), Failure> {
+    let Some(output) = output.filter(|output| !is_stdio(output)) else {
+        let mut stdout = io::stdout().lock();
+        return stdout
+            .write_all(data)
+            .and_then(|()| stdout.flush())
+            .map_err(|e| Failure::new(Status::Io, format!("Writing to standard output: {}", e)));
+    };
+    create(output, force, |mut file, _| {
+        file.write_all(data)
+            .and_then(|()| file.sync_all())
+            .map_err(|e| at(output, e))
+    })
+}
@@ -0,0 +1,163 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+//! The `dict` subcommand, which trains a zstd dictionary on the chunks of the
+//! data files of region folders, for `--dict` to compress the many small
+//! files of a folder better.
+use std::fs;
+use std::io::{self, Write};
+use std::mem;
+use std::path::Path;
+use std::sync::Arc;
+
+use dfcompress::{Dictionary, Options, Target, CHUNK_SIZE};
+
+use super::batch::{all_files, has_header, is_data_file};
+use super::convert::{create, failed};
+use super::exit::{Failure, Status};
+use super::{at, open, Dict, DictCommand, DictTrain, Verbosity};
+
+/// The bytes of samples to train on for each byte of the dictionary, as zstd
+/// recommends.
+const SAMPLE_BYTES: usize = 100;
+
+impl Dict {
+    pub(crate) fn run(self, verbosity: Verbosity) -> Status {
+        let result = match self.command {
+            DictCommand::Train(train) => train.train(),
+        };
+        match result {
+            Ok(report) => {
+                if !verbosity.quiet {
+                    println!("{}", report);
+                }
+                Status::Success
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                e.status
+            }
+        }
+    }
+}
+
+impl DictTrain {
+    fn train(&self) -> Result<String, Failure> {
+        let mut files = Vec::new();
+        all_files(&self.dir, &self.exclude, &mut files)?;
+        files.retain(|path| is_data_file(path) && has_header(path));
+        files.sort();
+        let mut samples = Samples::new(self.size.saturating_mul(SAMPLE_BYTES));
+        for path in &files {
+            let reader = open(path)?;
+            samples.skip = 8;
+            dfcompress::recode_with(reader, &mut samples, Target::Uncompressed, &Options::new())
+                .map_err(|e| failed(path, e))?;
+            samples.finish();
+        }
+        if samples.samples.is_empty() {
+            return Err(at(&self.dir, "No data files to train on"));
+        }
+        let dictionary = Dictionary::train(&samples.samples, self.size).map_err(|e| {
+            let message = format!("{}: Cannot train a dictionary: {}", self.dir.display(), e);
+            Failure::new(Status::Failure, message)
+        })?;
+        create(&self.output, self.force, |mut file, _| {
+            file.write_all(dictionary.as_bytes())
+                .and_then(|()| file.sync_all())
+                .map_err(|e| at(&self.output, e))
+        })?;
+        Ok(format!(
+            "{}: {} bytes, trained on {} chunks of {} data files",
+            self.output.display(),
+            dictionary.as_bytes().len(),
+            samples.samples.len(),
+            files.len()
+        ))
+    }
+}
+
+/// Reads the dictionary of `--dict`.
+pub(crate) fn load(path: &Path) -> Result<Arc<Dictionary>, Failure> {
+    let data = fs::read(path).map_err(|e| at(path, e))?;
+    if data.is_empty() {
+        return Err(at(path, "The dictionary is empty"));
+    }
+    Ok(Arc::new(Dictionary::new(data)))
+}
+
+/// The decompressed chunks of data files, written to it one file after
+/// another, taken as samples. Past twice `limit` bytes of them, every other
+/// one is dropped and only every other chunk is taken from then on, so that
+/// the samples are spread over all the files.
+struct Samples {
+    samples: Vec<Vec<u8>>,
+    bytes: usize,
+    limit: usize,
+    /// One chunk in `stride` is taken.
+    stride: usize,
+    /// The chunks seen, taken or not.
+    seen: usize,
+    /// The bytes of the header of a file left to leave out.
+    skip: usize,
+    chunk: Vec<u8>,
+}
+
+impl Samples {
+    fn new(limit: usize) -> Samples {
+        Samples {
+            samples: Vec::new(),
+            bytes: 0,
+            limit,
+            stride: 1,
+            seen: 0,
+            skip: 0,
+            chunk: Vec::with_capacity(CHUNK_SIZE),
+        }
+    }
+
+    /// Takes the last chunk of a file, which may be short.
+    fn finish(&mut self) {
+        if !self.chunk.is_empty() {
+            self.take();
+        }
+    }
+
+    fn take(&mut self) {
+        let chunk = mem::replace(&mut self.chunk, Vec::with_capacity(CHUNK_SIZE));
+        if self.seen.is_multiple_of(self.stride) {
+            self.bytes += chunk.len();
+            self.samples.push(chunk);
+        }
+        self.seen += 1;
+        if self.bytes > self.limit.saturating_mul(2) {
+            let mut keep = false;
+            self.samples.retain(|_| {
+                keep = !keep;
+                keep
+            });
+            self.bytes = self.samples.iter().map(Vec::len).sum();
+            self.stride *= 2;
+        }
+    }
+}
+
+impl Write for Samples {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let skipped = self.skip.min(data.len());
+        self.skip -= skipped;
+        let mut rest = &data[skipped..];
+        while !rest.is_empty() {
+            let n = (CHUNK_SIZE - self.chunk.len()).min(rest.len());
+            self.chunk.extend_from_slice(&rest[..n]);
+            rest = &rest[n..];
+            if self.chunk.len() == CHUNK_SIZE {
+                self.take();
+            }
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
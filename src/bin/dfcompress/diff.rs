@@ -0,0 +1,375 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+//! The `diff` subcommand, which compares two data files by what they
+//! decompress to, or two folders of them file by file.
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use dfcompress::{DecompressReader, CHUNK_SIZE};
+use serde::Serialize;
+use serde_json::json;
+
+use super::batch::{all_files, has_header, is_data_file};
+use super::convert::{failed, fill};
+use super::exit::{Failure, Status};
+use super::{at, is_stdio, open, Diff, Verbosity};
+
+/// A file being compared, read a piece at a time.
+struct Side<'a> {
+    path: &'a Path,
+    reader: DecompressReader<Box<dyn io::Read>>,
+    buf: Vec<u8>,
+    len: usize,
+    pos: usize,
+    /// The chunk the piece is from, if the file is compressed.
+    chunk: Option<u64>,
+}
+
+impl<'a> Side<'a> {
+    fn open(path: &'a Path) -> Result<Side<'a>, Failure> {
+        let reader = DecompressReader::new(open(path)?).map_err(|e| failed(path, e))?;
+        Ok(Side {
+            path,
+            reader,
+            buf: vec![0; 1 << 16],
+            len: 0,
+            pos: 0,
+            chunk: None,
+        })
+    }
+
+    /// Reads the next piece if nothing is left of this one, and returns the
+    /// bytes left of it, which are none at the end. The pieces of a
+    /// compressed file are never from two chunks.
+    fn fill(&mut self) -> Result<usize, Failure> {
+        if self.pos == self.len {
+            self.len = self
+                .reader
+                .read(&mut self.buf)
+                .map_err(|e| at(self.path, e))?;
+            self.pos = 0;
+            if self.reader.header().compression.is_compressed() {
+                self.chunk = Some(self.reader.chunks().saturating_sub(1));
+            }
+        }
+        Ok(self.len - self.pos)
+    }
+
+    fn rest(&self, n: usize) -> &[u8] {
+        &self.buf[self.pos..self.pos + n]
+    }
+}
+
+/// Bytes that differ, at offsets in the decompressed files.
+#[derive(Serialize)]
+struct Range {
+    start: u64,
+    end: u64,
+    /// The first and last chunks of each file that the bytes are in, if that
+    /// file is compressed and has them.
+    chunks_a: Option<[u64; 2]>,
+    chunks_b: Option<[u64; 2]>,
+}
+
+/// Adds `chunk` to the chunks of a range.
+fn extend(chunks: &mut Option<[u64; 2]>, chunk: Option<u64>) {
+    match (chunks.as_mut(), chunk) {
+        (Some(chunks), Some(chunk)) => chunks[1] = chunk,
+        (None, Some(chunk)) => *chunks = Some([chunk, chunk]),
+        (_, None) => (),
+    }
+}
+
+/// The ranges that differ, the last of which may go on.
+#[derive(Default)]
+struct Ranges(Vec<Range>);
+
+impl Ranges {
+    /// Notes that the bytes from `start` to `end` differ, in the chunks `a`
+    /// and `b`.
+    fn mark(&mut self, start: u64, end: u64, a: Option<u64>, b: Option<u64>) {
+        match self.0.last_mut() {
+            Some(last) if last.end == start => last.end = end,
+            _ => self.0.push(Range {
+                start,
+                end,
+                chunks_a: None,
+                chunks_b: None,
+            }),
+        }
+        let last = self.0.last_mut().unwrap();
+        extend(&mut last.chunks_a, a);
+        extend(&mut last.chunks_b, b);
+    }
+}
+
+/// Describes the chunks of `file` that a range is in.
+fn chunks(chunks: Option<[u64; 2]>, file: impl fmt::Display) -> Option<String> {
+    match chunks? {
+        [first, last] if first == last => Some(format!("chunk {} of {}", first, file)),
+        [first, last] => Some(format!("chunks {}-{} of {}", first, last, file)),
+    }
+}
+
+impl Diff {
+    /// Compares the files, exiting with `Failure` if they differ.
+    pub(crate) fn run(self, verbosity: Verbosity) -> Status {
+        match self.diff(verbosity) {
+            Ok(true) => Status::Success,
+            Ok(false) => Status::Failure,
+            Err(e) => {
+                eprintln!("{}", e);
+                e.status
+            }
+        }
+    }
+
+    /// Prints how the files differ, and returns whether they are the same.
+    fn diff(&self, verbosity: Verbosity) -> Result<bool, Failure> {
+        match (self.a.is_dir(), self.b.is_dir()) {
+            (true, true) => return self.diff_folders(verbosity),
+            (false, false) => (),
+            _ => {
+                let message = "diff compares two files or two folders";
+                return Err(Failure::new(Status::Usage, message));
+            }
+        }
+        if self.a == self.b && is_stdio(&self.a) {
+            return Err(Failure::new(
+                Status::Usage,
+                "Only one file can be standard input",
+            ));
+        }
+        let (mut a, mut b) = (Side::open(&self.a)?, Side::open(&self.b)?);
+        let versions = (a.reader.version(), b.reader.version());
+        let mut ranges = Ranges::default();
+        // Offsets count the header, as in the decompressed file.
+        let mut offset = 8;
+        loop {
+            let (na, nb) = (a.fill()?, b.fill()?);
+            let n = match (na, nb) {
+                (0, 0) => break,
+                // What is left of the longer file differs.
+                (0, n) | (n, 0) => {
+                    let (ca, cb) = (
+                        (na > 0).then_some(a.chunk).flatten(),
+                        (nb > 0).then_some(b.chunk).flatten(),
+                    );
+                    ranges.mark(offset, offset + n as u64, ca, cb);
+                    n
+                }
+                (na, nb) => {
+                    let n = na.min(nb);
+                    let (sa, sb) = (a.rest(n), b.rest(n));
+                    let mut i = 0;
+                    while sa[i..] != sb[i..] {
+                        while sa[i] == sb[i] {
+                            i += 1;
+                        }
+                        let start = i;
+                        while i < n && sa[i] != sb[i] {
+                            i += 1;
+                        }
+                        let (start, end) = (offset + start as u64, offset + i as u64);
+                        ranges.mark(start, end, a.chunk, b.chunk);
+                    }
+                    n
+                }
+            };
+            a.pos += n.min(na);
+            b.pos += n.min(nb);
+            offset += n as u64;
+        }
+        let ranges = ranges.0;
+        let same = ranges.is_empty() && versions.0 == versions.1;
+        let bytes: u64 = ranges.iter().map(|range| range.end - range.start).sum();
+        if self.json {
+            let object = json!({
+                "a": self.a,
+                "b": self.b,
+                "identical": same,
+                "versions": [versions.0, versions.1],
+                "bytes": bytes,
+                "ranges": ranges,
+            });
+            println!("{}", object);
+            return Ok(same);
+        }
+        if verbosity.quiet {
+            return Ok(same);
+        }
+        let (name_a, name_b) = (self.a.display(), self.b.display());
+        if same {
+            println!("{} and {} are identical", name_a, name_b);
+            return Ok(true);
+        }
+        println!("{} and {} differ", name_a, name_b);
+        if versions.0 != versions.1 {
+            println!("  versions {} and {}", versions.0, versions.1);
+        }
+        for range in &ranges {
+            let mut line = format!(
+                "  bytes {}-{} ({} bytes)",
+                range.start,
+                range.end - 1,
+                range.end - range.start
+            );
+            let chunks = [
+                chunks(range.chunks_a, &name_a),
+                chunks(range.chunks_b, &name_b),
+            ];
+            let chunks: Vec<_> = chunks.into_iter().flatten().collect();
+            if !chunks.is_empty() {
+                line += &format!(", in {}", chunks.join(" and "));
+            }
+            println!("{}", line);
+        }
+        println!("{} bytes differ in {} ranges", bytes, ranges.len());
+        Ok(false)
+    }
+
+    /// Prints the files added to, removed from and changed between the
+    /// folders, and returns whether they are the same.
+    fn diff_folders(&self, verbosity: Verbosity) -> Result<bool, Failure> {
+        let (a, b) = (relative_files(&self.a)?, relative_files(&self.b)?);
+        let added: Vec<_> = b.difference(&a).collect();
+        let removed: Vec<_> = a.difference(&b).collect();
+        let mut changed = Vec::new();
+        let mut unchanged = 0;
+        for file in a.intersection(&b) {
+            match compare(&self.a.join(file), &self.b.join(file))? {
+                Some(mut change) => {
+                    change.file = file.clone();
+                    changed.push(change);
+                }
+                None => unchanged += 1,
+            }
+        }
+        let same = added.is_empty() && removed.is_empty() && changed.is_empty();
+        if self.json {
+            let object = json!({
+                "a": self.a,
+                "b": self.b,
+                "identical": same,
+                "added": added,
+                "removed": removed,
+                "changed": changed,
+                "unchanged": unchanged,
+            });
+            println!("{}", object);
+            return Ok(same);
+        }
+        if verbosity.quiet {
+            return Ok(same);
+        }
+        let (name_a, name_b) = (self.a.display(), self.b.display());
+        if same {
+            println!("{} and {} are identical", name_a, name_b);
+            return Ok(true);
+        }
+        println!("{} and {} differ", name_a, name_b);
+        for file in &added {
+            println!("  added   {}", file.display());
+        }
+        for file in &removed {
+            println!("  removed {}", file.display());
+        }
+        for change in &changed {
+            let mut line = format!("  changed {}", change.file.display());
+            if let Some([a, b]) = change.versions {
+                line += &format!(", versions {} and {}", a, b);
+            }
+            if let Some([differ, of]) = change.chunks {
+                let percent = differ as f64 * 100.0 / of.max(1) as f64;
+                line += &format!(": {} of {} chunks ({:.1}%)", differ, of, percent);
+            }
+            println!("{}", line);
+        }
+        println!(
+            "{} files changed, {} added, {} removed, {} unchanged",
+            changed.len(),
+            added.len(),
+            removed.len(),
+            unchanged
+        );
+        Ok(false)
+    }
+}
+
+/// A file that differs between two folders.
+#[derive(Serialize)]
+struct Change {
+    /// The path of the file in the folders.
+    file: PathBuf,
+    /// The save versions of a data file, if they differ.
+    versions: Option<[u32; 2]>,
+    /// For a data file, the number of chunks of `CHUNK_SIZE` bytes of the
+    /// payloads that differ, and of the longer payload.
+    chunks: Option<[u64; 2]>,
+}
+
+/// The paths of the files in `dir`, relative to it.
+fn relative_files(dir: &Path) -> Result<BTreeSet<PathBuf>, Failure> {
+    let mut files = Vec::new();
+    all_files(dir, &[], &mut files)?;
+    let files = files
+        .iter()
+        .map(|path| path.strip_prefix(dir).unwrap_or(path));
+    Ok(files.map(Path::to_path_buf).collect())
+}
+
+/// Compares the files `a` and `b`: data files by what they decompress to,
+/// chunk by chunk, and others byte by byte. Returns how they differ, if
+/// they do, with an empty `file`.
+fn compare(a: &Path, b: &Path) -> Result<Option<Change>, Failure> {
+    let data = |path: &Path| is_data_file(path) && has_header(path);
+    let mut change = Change {
+        file: PathBuf::new(),
+        versions: None,
+        chunks: None,
+    };
+    if !(data(a) && data(b)) {
+        let open = |path: &Path| {
+            File::open(path)
+                .map(BufReader::new)
+                .map_err(|e| at(path, e))
+        };
+        let (mut ra, mut rb) = (open(a)?, open(b)?);
+        let (mut ba, mut bb) = (vec![0; 1 << 16], vec![0; 1 << 16]);
+        loop {
+            let n = fill(&mut ra, &mut ba).map_err(|e| at(a, e))?;
+            let m = fill(&mut rb, &mut bb).map_err(|e| at(b, e))?;
+            if ba[..n] != bb[..m] {
+                return Ok(Some(change));
+            }
+            if n < ba.len() {
+                return Ok(None);
+            }
+        }
+    }
+    let decompress = |path: &Path| DecompressReader::new(open(path)?).map_err(|e| failed(path, e));
+    let (mut ra, mut rb) = (decompress(a)?, decompress(b)?);
+    let versions = [ra.version(), rb.version()];
+    let (mut ba, mut bb) = (vec![0; CHUNK_SIZE], vec![0; CHUNK_SIZE]);
+    let (mut differ, mut of) = (0, 0);
+    loop {
+        let n = fill(&mut ra, &mut ba).map_err(|e| at(a, e))?;
+        let m = fill(&mut rb, &mut bb).map_err(|e| at(b, e))?;
+        if n == 0 && m == 0 {
+            break;
+        }
+        of += 1;
+        if ba[..n] != bb[..m] {
+            differ += 1;
+        }
+    }
+    if differ == 0 && versions[0] == versions[1] {
+        return Ok(None);
+    }
+    change.versions = (versions[0] != versions[1]).then_some(versions);
+    change.chunks = Some([differ, of]);
+    Ok(Some(change))
+}
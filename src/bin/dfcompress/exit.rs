@@ -0,0 +1,133 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+//! Exit statuses, and the errors that lead to them.
+use std::fmt;
+use std::io;
+
+use dfcompress::ErrorKind;
+
+/// The exit status of the program.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Status {
+    Success = 0,
+    /// An error that fits none of the other statuses.
+    Failure = 1,
+    /// The command line does not make sense, as for clap's own errors.
+    Usage = 2,
+    /// An input does not have the header of a data file.
+    NotDataFile = 3,
+    /// An input has a header but the data after it is damaged or truncated.
+    Corrupt = 4,
+    /// Reading or writing a file failed.
+    Io = 5,
+    /// Some files of a batch failed, but not all of them.
+    Partial = 6,
+}
+
+/// An error message, and the status it leads to.
+#[derive(Debug)]
+pub(crate) struct Failure {
+    pub status: Status,
+    pub message: String,
+}
+
+impl Failure {
+    pub(crate) fn new(status: Status, message: impl fmt::Display) -> Failure {
+        Failure {
+            status,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// An error, which knows the status it leads to.
+pub(crate) trait Cause: fmt::Display {
+    fn status(&self) -> Status;
+}
+
+impl Cause for dfcompress::Error {
+    fn status(&self) -> Status {
+        match self.kind() {
+            ErrorKind::Io(e) if e.kind() != io::ErrorKind::InvalidData => Status::Io,
+            // Input whose header does not read is not a data file at all.
+            _ if self.is_header() => Status::NotDataFile,
+            ErrorKind::CompressionUnknown(_) | ErrorKind::NotADelta | ErrorKind::VersionIsZero => {
+                Status::NotDataFile
+            }
+            ErrorKind::Io(_)
+            | ErrorKind::UnexpectedEof
+            | ErrorKind::ChunkTooLarge(_)
+            | ErrorKind::DeltaCorrupt => Status::Corrupt,
+            _ => Status::Failure,
+        }
+    }
+}
+
+impl Cause for io::Error {
+    fn status(&self) -> Status {
+        // Readers of the library pass on its errors as `io::Error`s.
+        match self
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<dfcompress::Error>())
+        {
+            Some(e) => e.status(),
+            None => Status::Io,
+        }
+    }
+}
+
+impl Cause for Failure {
+    fn status(&self) -> Status {
+        self.status
+    }
+}
+
+impl Cause for glob::PatternError {
+    fn status(&self) -> Status {
+        Status::Usage
+    }
+}
+
+impl Cause for glob::GlobError {
+    fn status(&self) -> Status {
+        Status::Io
+    }
+}
+
+impl Cause for &str {
+    fn status(&self) -> Status {
+        Status::Failure
+    }
+}
+
+#[cfg(feature = "watch")]
+impl Cause for notify::Error {
+    fn status(&self) -> Status {
+        Status::Io
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_test() {
+        let short = dfcompress::Header::parse(&[0x59, 0x06, 0]).unwrap_err();
+        assert_eq!(short.status(), Status::NotDataFile);
+        let unknown = dfcompress::Header::parse(&[0x59, 0x06, 0, 0, 99, 0, 0, 0]).unwrap_err();
+        assert_eq!(unknown.status(), Status::NotDataFile);
+        // Past the header, input cut short is damaged, whether the chunk it
+        // is cut in is known or not.
+        let eof = dfcompress::Error::from(ErrorKind::UnexpectedEof);
+        assert_eq!(eof.status(), Status::Corrupt);
+        let wrapped = io::Error::from(dfcompress::Header::read(&[1, 0][..]).unwrap_err());
+        assert_eq!(wrapped.status(), Status::NotDataFile);
+    }
+}
@@ -0,0 +1,235 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+//! The `header hexdump` subcommand, which explains the first bytes of a
+//! file, to tell whether it is a data file at all.
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, IsTerminal, Read};
+use std::path::Path;
+
+use dfcompress::{Compression, Header};
+
+use super::exit::{Failure, Status};
+use super::{archive, at, is_stdio, solid, HeaderCommand, HeaderHexdump, Headers};
+
+/// The bytes read: the header, the length of the first chunk, and the start
+/// of its data.
+const READ: usize = 8 + 4 + 16;
+
+impl Headers {
+    pub(crate) fn run(self) -> Status {
+        match self.command {
+            HeaderCommand::Hexdump(hexdump) => hexdump.run(),
+        }
+    }
+}
+
+impl HeaderHexdump {
+    fn run(self) -> Status {
+        match start(&self.file) {
+            Ok((bytes, size)) => {
+                let (dump, status) = dump(&bytes, size);
+                print!("{}", dump);
+                status
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                e.status
+            }
+        }
+    }
+}
+
+/// Reads the first bytes of `path`, as they are, and its size if known.
+fn start(path: &Path) -> Result<(Vec<u8>, Option<u64>), Failure> {
+    let mut bytes = Vec::new();
+    let size = match is_stdio(path) {
+        true if io::stdin().is_terminal() => {
+            let message = "Refusing to read a data file from a terminal; give a file or a pipe";
+            return Err(Failure::new(Status::Usage, message));
+        }
+        true => {
+            io::stdin()
+                .lock()
+                .take(READ as u64)
+                .read_to_end(&mut bytes)
+                .map_err(|e| at(path, e))?;
+            None
+        }
+        false => {
+            let file = File::open(path).map_err(|e| at(path, e))?;
+            let size = file.metadata().map_err(|e| at(path, e))?.len();
+            file.take(READ as u64)
+                .read_to_end(&mut bytes)
+                .map_err(|e| at(path, e))?;
+            Some(size)
+        }
+    };
+    Ok((bytes, size))
+}
+
+/// One line of the dump: the offset, the bytes and what they mean.
+fn line(dump: &mut String, offset: usize, bytes: &[u8], meaning: &str) {
+    let hex: Vec<_> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    writeln!(dump, "{:08x}  {:<12} {}", offset, hex.join(" "), meaning).unwrap();
+}
+
+/// The little-endian word at offset `i` of `bytes`.
+fn word(bytes: &[u8], i: usize) -> u32 {
+    u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap())
+}
+
+/// Explains `bytes`, the start of a file of `size` bytes. Returns the dump,
+/// and `Status::NotDataFile` unless it looks like a data file.
+fn dump(bytes: &[u8], size: Option<u64>) -> (String, Status) {
+    let mut dump = String::new();
+    if let Some(size) = size {
+        writeln!(dump, "{} bytes", size).unwrap();
+    }
+    let other = [
+        (
+            &[0x1f, 0x8b][..],
+            "a gzipped file, which dfcompress reads as well",
+        ),
+        (&solid::MAGIC[..], "a solid file of dfcompress --solid"),
+        (&archive::MAGIC[..], "an archive of dfcompress pack"),
+    ];
+    if let Some((magic, what)) = other.iter().find(|(magic, _)| bytes.starts_with(magic)) {
+        line(&mut dump, 0, magic, &format!("magic of {}", what));
+        writeln!(dump, "Not a data file itself: {}", what).unwrap();
+        return (dump, Status::NotDataFile);
+    }
+    if bytes.len() < 8 {
+        line(&mut dump, 0, bytes, "too short for a header");
+        dump.push_str("Not a data file: it is shorter than a header\n");
+        return (dump, Status::NotDataFile);
+    }
+    let version = word(bytes, 0);
+    let meaning = match Header::new(version, Compression::Uncompressed).df_release() {
+        _ if version == 0 => "save version 0, which is never written".to_string(),
+        Some(release) => format!("save version {}, Dwarf Fortress {}", version, release),
+        None => format!("save version {}, of no known release", version),
+    };
+    line(&mut dump, 0, &bytes[..4], &meaning);
+    let flag = word(bytes, 4);
+    let compression = Compression::from_u32(flag);
+    let meaning = match compression {
+        Some(Compression::Uncompressed) => "compression 0, uncompressed".to_string(),
+        Some(Compression::Zlib) => "compression 1, zlib chunks".to_string(),
+        Some(other) => format!("compression {}, {:?} chunks of dfcompress", flag, other),
+        None => format!("compression {}, which is unknown", flag),
+    };
+    line(&mut dump, 4, &bytes[4..8], &meaning);
+    let verdict = match (Header::parse(bytes), compression) {
+        (Err(e), _) => Err(format!("Not a data file: {}", e)),
+        (Ok(_), Some(Compression::Uncompressed)) => {
+            if bytes.len() > 8 {
+                let end = bytes.len().min(24);
+                line(&mut dump, 8, &bytes[8..end], "start of the payload");
+            }
+            Ok("An uncompressed data file".to_string())
+        }
+        (Ok(_), _) => chunk(&mut dump, bytes, size),
+    };
+    let status = match verdict {
+        Ok(verdict) => {
+            let known = Header::new(version, Compression::Uncompressed).df_release();
+            dump.push_str(&verdict);
+            if known.is_none() {
+                dump.push_str(", though of an unknown version");
+            }
+            Status::Success
+        }
+        Err(verdict) => {
+            dump.push_str(&verdict);
+            Status::NotDataFile
+        }
+    };
+    dump.push('\n');
+    (dump, status)
+}
+
+/// Explains the framing of the first chunk, after the header in `bytes`.
+fn chunk(dump: &mut String, bytes: &[u8], size: Option<u64>) -> Result<String, String> {
+    if bytes.len() < 12 {
+        // A compressed file without chunks is still valid.
+        return match bytes.len() {
+            8 => Ok("A compressed data file without chunks".to_string()),
+            _ => {
+                line(dump, 8, &bytes[8..], "too short for the length of a chunk");
+                Err("Damaged: the length of the first chunk is cut short".to_string())
+            }
+        };
+    }
+    let len = word(bytes, 8) as u64;
+    let end = 12 + len;
+    let fits = size.is_none_or(|size| end <= size);
+    let meaning = match size {
+        Some(size) => format!(
+            "length of chunk 0: {} bytes, up to offset {} of {}",
+            len, end, size
+        ),
+        None => format!("length of chunk 0: {} bytes, up to offset {}", len, end),
+    };
+    line(dump, 8, &bytes[8..12], &meaning);
+    if !fits {
+        return Err("Damaged or not a data file: the first chunk is longer than the file".into());
+    }
+    let data = &bytes[12..bytes.len().min(end as usize)];
+    if data.len() < 2 {
+        return Err("Damaged: the first chunk is cut short".into());
+    }
+    let compression = Compression::from_u32(word(bytes, 4));
+    let verdict = match compression {
+        Some(Compression::Zlib) => {
+            let (meaning, valid) = zlib(data[0], data[1]);
+            line(dump, 12, &data[..2], &meaning);
+            match valid {
+                true => Ok("A compressed data file".to_string()),
+                false => Err("Damaged or not a data file: chunk 0 is not zlib".to_string()),
+            }
+        }
+        _ => Ok("A compressed data file, in a format of dfcompress".to_string()),
+    };
+    let start = match compression {
+        Some(Compression::Zlib) => 2,
+        _ => 0,
+    };
+    if data.len() > start {
+        line(
+            dump,
+            12 + start,
+            &data[start..],
+            "start of the data of chunk 0",
+        );
+    }
+    verdict
+}
+
+/// What the zlib header `cmf`, `flg` says, and whether it is valid.
+fn zlib(cmf: u8, flg: u8) -> (String, bool) {
+    let check = (cmf as u16 * 256 + flg as u16).is_multiple_of(31);
+    if cmf & 0x0f != 8 || cmf >> 4 > 7 {
+        return ("no zlib header: not deflate".to_string(), false);
+    }
+    let window = 1 << ((cmf >> 4) + 8);
+    let level = match flg >> 6 {
+        0 => "fastest",
+        1 => "fast",
+        2 => "default",
+        _ => "best",
+    };
+    let mut meaning = format!(
+        "zlib header: deflate, {}K window, {} level",
+        window / 1024,
+        level
+    );
+    if flg & 0x20 != 0 {
+        meaning += ", preset dictionary";
+    }
+    meaning += match check {
+        true => ", check passes",
+        false => ", check FAILS",
+    };
+    (meaning, check && flg & 0x20 == 0)
+}
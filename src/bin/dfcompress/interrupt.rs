@@ -0,0 +1,155 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+//! Undoing what is half done when the program is interrupted, by Ctrl-C or
+//! a signal to terminate: partly written files are removed, and a save
+//! moved aside for `--backup` is moved back.
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+
+use log::warn;
+
+enum Action {
+    Remove(PathBuf),
+    Restore { backup: PathBuf, path: PathBuf },
+}
+
+/// What to undo, in the order it was to be done.
+static PENDING: Mutex<Vec<(u64, Action)>> = Mutex::new(Vec::new());
+static NEXT: AtomicU64 = AtomicU64::new(0);
+
+/// Something to undo if the program is interrupted before this is dropped.
+#[must_use]
+pub(crate) struct Pending(u64);
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        let mut pending = PENDING.lock().unwrap_or_else(PoisonError::into_inner);
+        pending.retain(|&(id, _)| id != self.0);
+    }
+}
+
+fn push(action: Action) -> Pending {
+    let id = NEXT.fetch_add(1, Ordering::Relaxed);
+    let mut pending = PENDING.lock().unwrap_or_else(PoisonError::into_inner);
+    pending.push((id, action));
+    Pending(id)
+}
+
+/// Removes `path`, which is being written, if the program is interrupted.
+pub(crate) fn remove_on_interrupt(path: &Path) -> Pending {
+    push(Action::Remove(path.to_path_buf()))
+}
+
+/// Moves `backup` back to `path` if the program is interrupted before
+/// `path` is replaced.
+pub(crate) fn restore_on_interrupt(backup: &Path, path: &Path) -> Pending {
+    push(Action::Restore {
+        backup: backup.to_path_buf(),
+        path: path.to_path_buf(),
+    })
+}
+
+/// Undoes what is pending and exits. The lock is kept, so that no other
+/// thread goes on to finish what is undone.
+fn interrupted() -> ! {
+    let pending = PENDING.lock().unwrap_or_else(PoisonError::into_inner);
+    for (_, action) in pending.iter().rev() {
+        match action {
+            Action::Remove(path) => {
+                let _ = fs::remove_file(path);
+            }
+            Action::Restore { backup, path } => {
+                if !path.exists() {
+                    let _ = fs::rename(backup, path);
+                }
+            }
+        }
+    }
+    eprintln!("Interrupted");
+    process::exit(130);
+}
+
+/// Handles interruptions from now on.
+pub(crate) fn install() {
+    if let Err(e) = imp::install() {
+        warn!("Ctrl-C will leave partly written files: {}", e);
+    }
+}
+
+#[cfg(unix)]
+mod imp {
+    use std::fs::File;
+    use std::io::{self, Read};
+    use std::os::fd::FromRawFd;
+    use std::sync::atomic::{AtomicI32, Ordering};
+    use std::thread;
+
+    /// The end of a pipe that the signal handler writes to, waking up the
+    /// thread that undoes what is pending, which a handler cannot do itself.
+    static PIPE: AtomicI32 = AtomicI32::new(-1);
+
+    extern "C" fn handle(_: libc::c_int) {
+        let byte = [0u8];
+        // SAFETY: write is async-signal-safe, and the byte outlives the call.
+        unsafe { libc::write(PIPE.load(Ordering::Relaxed), byte.as_ptr().cast(), 1) };
+    }
+
+    pub(super) fn install() -> io::Result<()> {
+        let mut fds = [0; 2];
+        // SAFETY: fds has room for the two descriptors.
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        PIPE.store(fds[1], Ordering::Relaxed);
+        // SAFETY: the descriptor was just opened, and nothing else owns it.
+        let mut pipe = unsafe { File::from_raw_fd(fds[0]) };
+        thread::spawn(move || {
+            if pipe.read(&mut [0]).is_ok_and(|n| n > 0) {
+                super::interrupted();
+            }
+        });
+        let handler = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        for signal in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+            // SAFETY: the handler only does what is safe in one.
+            if unsafe { libc::signal(signal, handler) } == libc::SIG_ERR {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+mod imp {
+    use std::io;
+
+    type Handler = unsafe extern "system" fn(u32) -> i32;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn SetConsoleCtrlHandler(handler: Option<Handler>, add: i32) -> i32;
+    }
+
+    /// Runs on a thread of its own, so it can undo what is pending itself.
+    unsafe extern "system" fn handle(_: u32) -> i32 {
+        super::interrupted()
+    }
+
+    pub(super) fn install() -> io::Result<()> {
+        // SAFETY: the handler stays valid for as long as the program runs.
+        match unsafe { SetConsoleCtrlHandler(Some(handle), 1) } {
+            0 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+mod imp {
+    pub(super) fn install() -> std::io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,126 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+//! The `list` subcommand, which prints the chunk table of compressed files.
+use std::path::Path;
+
+use dfcompress::{Chunks, IndexEntry};
+use serde_json::{json, Value};
+
+use super::batch::expand;
+use super::exit::{Cause, Failure, Status};
+use super::{at, open, List};
+
+impl List {
+    /// Lists the files one after another, printing each chunk as soon as it
+    /// is read, so that the chunks before a damaged one are listed.
+    pub(crate) fn run(self) -> Status {
+        let batch = match expand(self.files, self.recursive, &self.exclude) {
+            Ok(batch) => batch,
+            Err(e) => {
+                eprintln!("{}", e);
+                return e.status;
+            }
+        };
+        let (mut failed, mut status) = (0, Status::Success);
+        for path in &batch.files {
+            if let Err(e) = list(path, self.json) {
+                match self.json {
+                    true => {
+                        let object = json!({
+                            "file": path,
+                            "ok": false,
+                            "error": e.message,
+                            "status": e.status as i32,
+                        });
+                        println!("{}", object);
+                    }
+                    false => eprintln!("{}", e),
+                }
+                if failed == 0 {
+                    status = e.status;
+                }
+                failed += 1;
+            }
+        }
+        match failed {
+            0 => Status::Success,
+            n if n < batch.files.len() => Status::Partial,
+            _ => status,
+        }
+    }
+}
+
+/// Prints the header and the chunks of `path`.
+fn list(path: &Path, json: bool) -> Result<(), Failure> {
+    let mut chunks = Chunks::new(open(path)?).map_err(|e| at(path, e))?;
+    let header = chunks.header();
+    let mut codec = header.compression.codec().unwrap_or_default().build(None);
+    let compression = format!("{:?}", header.compression).to_lowercase();
+    if !json {
+        println!(
+            "{}: version {}, {}",
+            path.display(),
+            header.version,
+            compression
+        );
+        println!(
+            "{:>6} {:>10} {:>10} {:>12}",
+            "chunk", "offset", "compressed", "decompressed"
+        );
+    }
+    let (mut i, mut offset, mut decompressed_offset) = (0, 8, 0);
+    let mut buf = Vec::new();
+    loop {
+        // Errors past the header mean that the chunks are damaged.
+        let damaged = |e: dfcompress::Error| {
+            let status = match e.status() {
+                Status::Io => Status::Io,
+                _ => Status::Corrupt,
+            };
+            let message = format!("{} in chunk {} at offset {}", e, i, offset);
+            Failure::new(status, at(path, message.as_str()))
+        };
+        let Some(chunk) = chunks.next() else { break };
+        let chunk = chunk.map_err(damaged)?;
+        buf.clear();
+        codec
+            .decompress(&chunk.data, &mut buf)
+            .map_err(|e| damaged(e.into()))?;
+        let entry = IndexEntry {
+            offset,
+            compressed_len: chunk.data.len() as u64,
+            decompressed_offset,
+            decompressed_len: buf.len() as u64,
+        };
+        match json {
+            true => {
+                let mut object = json!({"file": path, "chunk": i});
+                if let (Value::Object(object), Value::Object(entry)) = (&mut object, json!(entry)) {
+                    object.extend(entry);
+                }
+                println!("{}", object);
+            }
+            false => println!(
+                "{:>6} {:>10} {:>10} {:>12}",
+                i, entry.offset, entry.compressed_len, entry.decompressed_len
+            ),
+        }
+        i += 1;
+        offset = entry.payload_offset() + entry.compressed_len;
+        decompressed_offset = entry.decompressed_end();
+    }
+    match json {
+        true => {
+            let object = json!({
+                "file": path,
+                "ok": true,
+                "header": header,
+                "chunks": i,
+                "decompressed_len": decompressed_offset,
+            });
+            println!("{}", object);
+        }
+        false => println!("{} chunks, {} bytes decompressed", i, decompressed_offset),
+    }
+    Ok(())
+}
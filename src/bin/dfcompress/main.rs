@@ -0,0 +1,1737 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+//! The `dfcompress` program. Run as `dfuncompress`, such as through a link,
+//! it is `dfcompress decompress`.
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, IsTerminal, Read};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
+use dfcompress::{Codec, Compression, Header, Options, Target, CHUNK_SIZE};
+use flate2::read::MultiGzDecoder;
+use glob::Pattern;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+use archive::ArchiveCodec;
+use batch::{default_threads, expand, glob_all, Batch, Done};
+use config::Config;
+use convert::{
+    backup_path, check_deterministic, convert, convert_in_place, is_up_to_date, WriteOptions,
+};
+use exit::{Cause, Failure, Status};
+use memory::budget;
+use progress::with_bar;
+use rotate::Policy;
+use stats::measure;
+use volumes::Series;
+
+mod archive;
+mod backup;
+mod batch;
+mod bench;
+mod busy;
+mod cat;
+mod chunk;
+mod completions;
+mod config;
+mod convert;
+mod delta;
+#[cfg(feature = "zstd")]
+mod dict;
+mod diff;
+mod exit;
+mod hexdump;
+mod interrupt;
+mod list;
+mod man;
+mod memory;
+mod pack;
+mod progress;
+mod range;
+mod repair;
+mod rotate;
+mod slim;
+mod solid;
+mod stats;
+mod store;
+mod tail;
+mod unpack;
+mod validate;
+mod volumes;
+#[cfg(feature = "watch")]
+mod watch;
+
+/// Converts Dwarf Fortress data files between compressed and uncompressed.
+#[derive(Parser)]
+#[command(name = "dfcompress", version, args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Decompress instead of compressing, like `dfcompress decompress`
+    #[arg(short, long)]
+    decompress: bool,
+    /// Without a subcommand, the input is compressed.
+    #[command(flatten)]
+    convert: Convert,
+    #[command(flatten)]
+    verbosity: Verbosity,
+    /// Read the defaults of the flags from FILE instead of
+    /// dfcompress/config.toml in the user's configuration directory
+    #[arg(long, global = true, value_name = "FILE")]
+    config: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Compress a data file
+    Compress(Convert),
+    /// Decompress a data file
+    #[command(alias = "uncompress")]
+    Decompress(Convert),
+    /// Print the header, size and chunks of data files
+    Info(Files),
+    /// Check that data files decompress without errors, writing nothing
+    #[command(visible_alias = "test")]
+    Verify(Files),
+    /// Check the header, framing, zlib streams and checksums of data files,
+    /// listing what is wrong with them and where
+    Check(Files),
+    /// Print the offset and sizes of each chunk of compressed data files
+    List(List),
+    /// Compare two data files by what they decompress to, printing the byte
+    /// ranges and chunks that differ, or the files of two region folders
+    Diff(Diff),
+    /// Encode a save as a delta against an older one, or apply a delta to
+    /// get the save back
+    Delta(Delta),
+    /// Decompress data files into one, their payloads one after another
+    Cat(Cat),
+    /// Read or replace single chunks of a compressed data file
+    Chunk(Chunk),
+    /// Look at the header of a file, to tell whether it is a data file
+    Header(Headers),
+    /// Decompress only the last chunks of a compressed data file, where a
+    /// crashed save is usually damaged
+    Tail(Tail),
+    /// Salvage what decompresses of a damaged file, and report what is lost
+    Repair(Repair),
+    /// Compress a sample of a data file with each format and level, and
+    /// recommend one
+    Bench(Bench),
+    /// Put a region folder into one archive, compressed as a whole to share it
+    Pack(Pack),
+    /// Turn an archive of `pack` back into a region folder
+    Unpack(Unpack),
+    /// Keep the newest autosaves of each region of a save directory,
+    /// compressed at the best level, and archive or remove the older ones
+    Rotate(Rotate),
+    /// Recompress the data files of a region folder as small as Dwarf
+    /// Fortress still loads them, or pack it into an archive
+    Slim(Slim),
+    /// Check that a region folder has the files Dwarf Fortress needs to load
+    /// it, and that they agree
+    Validate(Validate),
+    /// Take snapshots of region folders into a store that keeps each chunk
+    /// once, list, restore and prune them
+    Backup(Backup),
+    /// Train a zstd dictionary on the chunks of the data files of region
+    /// folders, for --dict
+    #[cfg(feature = "zstd")]
+    Dict(Dict),
+    /// Back up the folders of a save directory as Dwarf Fortress writes them
+    #[cfg(feature = "watch")]
+    Watch(Watch),
+    /// Print a completion script for SHELL
+    Completions(Completions),
+    /// Print the manual page, in roff
+    Man,
+}
+
+/// Decompresses a Dwarf Fortress data file, like `dfcompress decompress`.
+#[derive(Parser)]
+#[command(name = "dfuncompress", version)]
+struct Dfuncompress {
+    #[command(flatten)]
+    convert: Convert,
+    #[command(flatten)]
+    verbosity: Verbosity,
+    /// Read the defaults of the flags from FILE instead of
+    /// dfcompress/config.toml in the user's configuration directory
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+}
+
+#[derive(Args, Clone, Copy, Default)]
+struct Verbosity {
+    /// Print nothing but errors (and JSON reports)
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Log what is done with each file, and with -vv with each chunk, instead
+    /// of drawing progress bars. RUST_LOG filters the log further
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    verbose: u8,
+}
+
+#[derive(Args)]
+struct Convert {
+    /// The data files to convert, or `-` for standard input (the default).
+    /// With --in-place or --output-dir, directories stand for the data files
+    /// in them
+    #[arg(value_name = "INPUT")]
+    inputs: Vec<PathBuf>,
+    /// Where to write the result, or `-` for standard output (the default)
+    #[arg(short, long, value_name = "OUTPUT")]
+    output: Option<PathBuf>,
+    /// Write to standard output even if it is a terminal
+    #[arg(short = 'c', long, conflicts_with = "output")]
+    stdout: bool,
+    /// Replace each input with the result, once it is complete
+    #[arg(short = 'i', long, conflicts_with = "output")]
+    in_place: bool,
+    /// Write the results into DIR, under the same paths relative to the
+    /// directories given as inputs
+    #[arg(short = 'O', long, value_name = "DIR", conflicts_with_all = ["output", "in_place"])]
+    output_dir: Option<PathBuf>,
+    /// With --in-place, wait for files that Dwarf Fortress seems to be
+    /// writing, instead of failing
+    #[arg(long, requires = "in_place")]
+    wait: bool,
+    /// With --in-place, keep each original as the same name with SUFFIX
+    /// appended
+    #[arg(
+        long,
+        value_name = "SUFFIX",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = ".bak",
+        requires = "in_place"
+    )]
+    backup: Option<String>,
+    /// Cut the output into volumes of SIZE, such as 8M, named OUTPUT.001,
+    /// OUTPUT.002 and so on. The first is read as the whole series
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, requires = "output")]
+    split: Option<usize>,
+    /// Overwrite the output file if it exists
+    #[arg(short, long)]
+    force: bool,
+    /// With --output-dir, skip the inputs whose output is as new as they
+    /// are, and overwrite the outputs that are older
+    #[arg(long, requires = "output_dir")]
+    if_newer: bool,
+    /// Keep the input file (the default)
+    #[arg(short, long, overrides_with = "rm")]
+    keep: bool,
+    /// Remove the input file once it has been converted
+    #[arg(long, conflicts_with = "in_place", overrides_with = "keep")]
+    rm: bool,
+    /// Look for data files in subdirectories too
+    #[arg(short, long)]
+    recursive: bool,
+    /// Leave out the files and directories matching PATTERN, such as
+    /// `*.txt`, when looking in directories. May be given more than once
+    #[arg(long, value_name = "PATTERN")]
+    exclude: Vec<Pattern>,
+    /// The number of files to process at once (default: one per CPU)
+    #[arg(short = 'T', long, value_name = "N")]
+    threads: Option<NonZeroUsize>,
+    /// The compression level, from 0 (store) to 9 (best)
+    #[arg(short, long, value_parser = clap::value_parser!(u32).range(0..=9))]
+    level: Option<u32>,
+    /// Compress faster, like Dwarf Fortress itself
+    #[arg(long, conflicts_with_all = ["level", "best"])]
+    fast: bool,
+    /// Compress best, taking more time
+    #[arg(long, conflicts_with = "level")]
+    best: bool,
+    /// The uncompressed bytes in each chunk, such as 8k (default: 20000,
+    /// the most Dwarf Fortress is known to read)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    chunk_size: Option<usize>,
+    /// Keep the memory in use below SIZE, such as 64M, by converting fewer
+    /// files and chunks at once and refusing chunks that decompress to more
+    /// than it allows
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_memory: Option<usize>,
+    /// The format to write (default: df-zlib, or raw to decompress).
+    /// Inputs in any of them are recognized
+    #[arg(long, alias = "backend", value_name = "FORMAT")]
+    format: Option<Format>,
+    /// When decompressing, leave out the 8-byte header and write only the
+    /// payload, for tools that read the bare world data
+    #[arg(long, conflicts_with_all = ["in_place", "verify"])]
+    strip_header: bool,
+    /// Take the input as a bare payload, and put a header of --save-version
+    /// before it
+    #[arg(
+        long,
+        requires = "save_version",
+        conflicts_with_all = ["in_place", "output_dir", "verify", "strip_header"]
+    )]
+    add_header: bool,
+    /// The save version of the header that --add-header puts, such as 1625
+    #[arg(long, value_name = "N", requires = "add_header")]
+    save_version: Option<u32>,
+    /// Compress the whole file as one zlib stream, or zstd with --format
+    /// zstd, which compresses better but which Dwarf Fortress cannot read.
+    /// Converting the result writes chunks again
+    #[arg(
+        long,
+        conflicts_with_all = ["in_place", "strip_header", "level", "fast", "best", "chunk_size"]
+    )]
+    solid: bool,
+    /// Write the same bytes for the same input and options on every run and
+    /// platform, compressing chunks again even if they are in the format
+    /// already. Only df-zlib, raw and gzip can be written
+    #[arg(long)]
+    deterministic: bool,
+    /// When decompressing, write only the bytes of the decompressed file
+    /// from OFFSET on, such as 0x4000, counting the header
+    #[arg(
+        long,
+        value_name = "OFFSET",
+        value_parser = parse_offset,
+        conflicts_with_all = RANGE_CONFLICTS
+    )]
+    offset: Option<u64>,
+    /// When decompressing, write only LENGTH bytes of the decompressed file,
+    /// such as 16k, without reading the chunks after them
+    #[arg(
+        long,
+        value_name = "LENGTH",
+        value_parser = parse_offset,
+        conflicts_with_all = RANGE_CONFLICTS
+    )]
+    length: Option<u64>,
+    /// Print sizes, chunk sizes and the time taken to standard error
+    #[arg(long)]
+    stats: bool,
+    /// Report in JSON, one object per file
+    #[arg(long)]
+    json: bool,
+    /// Check that the output decompresses to the same data as the input
+    /// before keeping it
+    #[arg(long)]
+    verify: bool,
+    /// Convert without writing or removing anything, reporting what would
+    /// be done
+    #[arg(short = 'n', long, conflicts_with = "verify")]
+    dry_run: bool,
+    /// Compress with the zstd dictionary FILE of `dict train`, or decompress
+    /// files compressed with it, which need it. Compressing with it writes
+    /// --format zstd, which Dwarf Fortress cannot read
+    #[cfg(feature = "zstd")]
+    #[arg(long, value_name = "FILE", conflicts_with_all = DICT_CONFLICTS)]
+    dict: Option<PathBuf>,
+}
+
+/// The options of `Convert` that do not go with `--offset` and `--length`.
+const RANGE_CONFLICTS: [&str; 13] = [
+    "in_place",
+    "output_dir",
+    "rm",
+    "split",
+    "format",
+    "solid",
+    "deterministic",
+    "strip_header",
+    "add_header",
+    "verify",
+    "dry_run",
+    "stats",
+    "json",
+];
+
+/// The options of `Convert` that do not go with `--dict`, which is left out
+/// of `RANGE_CONFLICTS` as it only exists with the `zstd` feature.
+#[cfg(feature = "zstd")]
+const DICT_CONFLICTS: [&str; 4] = ["solid", "deterministic", "offset", "length"];
+
+/// The formats that files can be converted to.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Format {
+    /// Zlib chunks, as Dwarf Fortress writes them
+    DfZlib,
+    /// Zlib chunks by libdeflate, which Dwarf Fortress reads as well
+    #[cfg(feature = "libdeflate")]
+    Libdeflate,
+    /// Zstandard chunks, which only dfcompress reads
+    #[cfg(feature = "zstd")]
+    Zstd,
+    /// LZ4 chunks, which only dfcompress reads
+    #[cfg(feature = "lz4")]
+    Lz4,
+    /// Uncompressed, as Dwarf Fortress reads and writes too
+    Raw,
+    /// The uncompressed file in gzip, for other tools
+    Gzip,
+}
+
+impl Format {
+    /// The codec of the chunks, if the format has them.
+    fn codec(self) -> Option<Codec> {
+        match self {
+            Format::DfZlib => Some(Codec::Zlib),
+            #[cfg(feature = "libdeflate")]
+            Format::Libdeflate => Some(Codec::Libdeflate),
+            #[cfg(feature = "zstd")]
+            Format::Zstd => Some(Codec::Zstd),
+            #[cfg(feature = "lz4")]
+            Format::Lz4 => Some(Codec::Lz4),
+            Format::Raw | Format::Gzip => None,
+        }
+    }
+
+    /// Whether Dwarf Fortress reads files in this format.
+    fn df_readable(self) -> bool {
+        self.codec().map_or(self == Format::Raw, |c| {
+            c.compression() == Compression::Zlib
+        })
+    }
+}
+
+#[derive(Args)]
+struct Files {
+    /// The data files or directories of them, or `-` for standard input
+    #[arg(required = true, value_name = "FILE")]
+    files: Vec<PathBuf>,
+    /// Look for data files in subdirectories too
+    #[arg(short, long)]
+    recursive: bool,
+    /// Leave out the files and directories matching PATTERN, such as
+    /// `*.txt`, when looking in directories. May be given more than once
+    #[arg(long, value_name = "PATTERN")]
+    exclude: Vec<Pattern>,
+    /// The number of files to process at once (default: one per CPU)
+    #[arg(short = 'T', long, value_name = "N")]
+    threads: Option<NonZeroUsize>,
+    /// Report in JSON, one object per file
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args)]
+struct List {
+    /// The data files or directories of them, or `-` for standard input
+    #[arg(required = true, value_name = "FILE")]
+    files: Vec<PathBuf>,
+    /// Look for data files in subdirectories too
+    #[arg(short, long)]
+    recursive: bool,
+    /// Leave out the files and directories matching PATTERN, such as
+    /// `*.txt`, when looking in directories. May be given more than once
+    #[arg(long, value_name = "PATTERN")]
+    exclude: Vec<Pattern>,
+    /// Print one JSON object per chunk, and one per file after its chunks
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args)]
+struct Diff {
+    /// The first file, or `-` for standard input, or a region folder to
+    /// compare file by file
+    #[arg(value_name = "A")]
+    a: PathBuf,
+    /// The second file, or `-` for standard input, or a region folder
+    #[arg(value_name = "B")]
+    b: PathBuf,
+    /// Report in JSON
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args)]
+struct Cat {
+    /// The data files, or `-` for standard input. Each may be compressed
+    /// files joined by the cat program
+    #[arg(value_name = "INPUT", required = true)]
+    inputs: Vec<PathBuf>,
+    /// Where to write the joined file, or `-` for standard output (the
+    /// default)
+    #[arg(short, long, value_name = "OUTPUT")]
+    output: Option<PathBuf>,
+    /// Write to standard output even if it is a terminal
+    #[arg(short = 'c', long, conflicts_with = "output")]
+    stdout: bool,
+    /// Overwrite the output file if it exists
+    #[arg(short, long)]
+    force: bool,
+    /// Report in JSON
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args)]
+struct Chunk {
+    #[command(subcommand)]
+    command: ChunkCommand,
+}
+
+#[derive(Subcommand)]
+enum ChunkCommand {
+    /// Write chunk N of a file, decompressed unless --raw
+    Get(ChunkGet),
+    /// Replace chunk N of a file with the compressed contents of another
+    Set(ChunkSet),
+}
+
+#[derive(Args)]
+struct ChunkGet {
+    /// The compressed data file, or `-` for standard input
+    #[arg(value_name = "FILE")]
+    file: PathBuf,
+    /// The chunk, counting from 0 as `list` does
+    #[arg(value_name = "N")]
+    index: u64,
+    /// Where to write the chunk, or `-` for standard output (the default)
+    #[arg(short, long, value_name = "OUTPUT")]
+    output: Option<PathBuf>,
+    /// Write to standard output even if it is a terminal
+    #[arg(short = 'c', long, conflicts_with = "output")]
+    stdout: bool,
+    /// Overwrite the output file if it exists
+    #[arg(short, long)]
+    force: bool,
+    /// Write the chunk as stored, compressed, without its length
+    #[arg(long)]
+    raw: bool,
+}
+
+#[derive(Args)]
+struct Headers {
+    #[command(subcommand)]
+    command: HeaderCommand,
+}
+
+#[derive(Subcommand)]
+enum HeaderCommand {
+    /// Print the header and the framing of the first chunk in hex, with
+    /// what each value means
+    Hexdump(HeaderHexdump),
+}
+
+#[derive(Args)]
+struct HeaderHexdump {
+    /// The file, or `-` for standard input
+    #[arg(value_name = "FILE")]
+    file: PathBuf,
+}
+
+#[derive(Args)]
+struct Delta {
+    #[command(subcommand)]
+    command: DeltaCommand,
+}
+
+#[derive(Subcommand)]
+enum DeltaCommand {
+    /// Encode NEW as a delta against BASE, of what they decompress to
+    Create(DeltaCreate),
+    /// Make the save that a delta was created from, against the same BASE
+    Apply(DeltaApply),
+}
+
+#[derive(Args)]
+struct DeltaCreate {
+    /// The older data file, such as the autosave before
+    #[arg(value_name = "BASE")]
+    base: PathBuf,
+    /// The newer data file, or `-` for standard input
+    #[arg(value_name = "NEW")]
+    new: PathBuf,
+    /// Where to write the delta, or `-` for standard output (the default)
+    #[arg(short, long, value_name = "PATCH")]
+    output: Option<PathBuf>,
+    /// Write to standard output even if it is a terminal
+    #[arg(short = 'c', long, conflicts_with = "output")]
+    stdout: bool,
+    /// Overwrite the output file if it exists
+    #[arg(short, long)]
+    force: bool,
+}
+
+#[derive(Args)]
+struct DeltaApply {
+    /// The data file the delta was created against
+    #[arg(value_name = "BASE")]
+    base: PathBuf,
+    /// The delta, or `-` for standard input
+    #[arg(value_name = "PATCH")]
+    delta: PathBuf,
+    /// Where to write the save, or `-` for standard output (the default)
+    #[arg(short, long, value_name = "OUTPUT")]
+    output: Option<PathBuf>,
+    /// Write to standard output even if it is a terminal
+    #[arg(short = 'c', long, conflicts_with = "output")]
+    stdout: bool,
+    /// Overwrite the output file if it exists
+    #[arg(short, long)]
+    force: bool,
+}
+
+#[derive(Args)]
+struct Tail {
+    /// The compressed data file, or `-` for standard input
+    #[arg(value_name = "FILE")]
+    file: PathBuf,
+    /// The number of chunks
+    #[arg(short = 'n', long, value_name = "N", default_value_t = NonZeroUsize::MIN)]
+    chunks: NonZeroUsize,
+    /// Where to write the chunks, or `-` for standard output (the default)
+    #[arg(short, long, value_name = "OUTPUT")]
+    output: Option<PathBuf>,
+    /// Write to standard output even if it is a terminal
+    #[arg(short = 'c', long, conflicts_with = "output")]
+    stdout: bool,
+    /// Overwrite the output file if it exists
+    #[arg(short, long)]
+    force: bool,
+}
+
+#[derive(Args)]
+struct ChunkSet {
+    /// The compressed data file, which is rewritten
+    #[arg(value_name = "FILE")]
+    file: PathBuf,
+    /// The chunk, counting from 0 as `list` does
+    #[arg(value_name = "N")]
+    index: u64,
+    /// The new contents of the chunk, or `-` for standard input
+    #[arg(long, value_name = "PATCH")]
+    from: PathBuf,
+    /// Take the contents as already compressed, as `chunk get --raw` writes
+    #[arg(long)]
+    raw: bool,
+    /// Wait for the file if Dwarf Fortress seems to be writing it, instead
+    /// of failing
+    #[arg(long)]
+    wait: bool,
+}
+
+#[derive(Args)]
+struct Completions {
+    #[arg(value_name = "SHELL")]
+    shell: completions::Shell,
+}
+
+#[derive(Args)]
+struct Repair {
+    /// The damaged file, or `-` for standard input (the default)
+    #[arg(value_name = "INPUT")]
+    input: Option<PathBuf>,
+    /// Where to write what is salvaged, or `-` for standard output (the
+    /// default)
+    #[arg(short, long, value_name = "OUTPUT")]
+    output: Option<PathBuf>,
+    /// Write to standard output even if it is a terminal
+    #[arg(short = 'c', long, conflicts_with = "output")]
+    stdout: bool,
+    /// Overwrite the output file if it exists
+    #[arg(short, long)]
+    force: bool,
+    /// Skip chunks that do not decompress instead of stopping at the first
+    #[arg(long)]
+    skip_bad: bool,
+    /// Write the data uncompressed
+    #[arg(short, long)]
+    decompress: bool,
+    /// Report in JSON
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args)]
+struct Bench {
+    /// The data file to sample
+    #[arg(value_name = "FILE")]
+    file: PathBuf,
+    /// The uncompressed bytes to sample, in pieces spread over the file
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, default_value = "4M")]
+    sample: usize,
+    /// Report in JSON, one object per setting and one for the recommendation
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args)]
+struct Pack {
+    /// The region folder to pack
+    #[arg(value_name = "DIR")]
+    dir: PathBuf,
+    /// Where to write the archive, such as region1.dfpack, or `-` for
+    /// standard output (the default)
+    #[arg(short, long, value_name = "OUTPUT")]
+    output: Option<PathBuf>,
+    /// Write to standard output even if it is a terminal
+    #[arg(short = 'c', long, conflicts_with = "output")]
+    stdout: bool,
+    /// Overwrite the output file if it exists
+    #[arg(short, long)]
+    force: bool,
+    /// How to compress the archive
+    #[arg(long, value_name = "CODEC")]
+    codec: Option<ArchiveCodec>,
+    /// Cut the archive into volumes of SIZE, such as 8M, named
+    /// OUTPUT.001, OUTPUT.002 and so on, which unpack reads from the first
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, requires = "output")]
+    split: Option<usize>,
+    /// Write the same archive for the same files on every run and platform,
+    /// leaving out their modification times and compressing with zlib
+    #[arg(long)]
+    deterministic: bool,
+    /// Leave out the files and directories matching PATTERN, such as
+    /// `*.txt`. May be given more than once
+    #[arg(long, value_name = "PATTERN")]
+    exclude: Vec<Pattern>,
+    /// Report in JSON
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args)]
+struct Unpack {
+    /// The archive, or `-` for standard input
+    #[arg(value_name = "ARCHIVE")]
+    archive: PathBuf,
+    /// The region folder to write (default: the archive's name without
+    /// .dfpack)
+    #[arg(short = 'O', long, value_name = "DIR")]
+    output_dir: Option<PathBuf>,
+    /// Overwrite files of the folder that exist
+    #[arg(short, long)]
+    force: bool,
+    /// Report in JSON
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args)]
+struct Rotate {
+    /// The save directory, whose autosaves are named like region1-spr-1051
+    /// (default: save-dir in the configuration file)
+    #[arg(value_name = "SAVEDIR")]
+    dir: Option<PathBuf>,
+    /// The number of the newest autosaves of each region to keep
+    #[arg(short = 'k', long, value_name = "N", default_value = "3")]
+    keep: NonZeroUsize,
+    /// What to do with the older autosaves
+    #[arg(long, value_enum, default_value_t = Policy::Archive)]
+    policy: Policy,
+    /// Where to write the archives of the older autosaves, as
+    /// FOLDER.dfpack (default: SAVEDIR)
+    #[arg(long, value_name = "DIR")]
+    archive_dir: Option<PathBuf>,
+    /// Change nothing, reporting what would be done
+    #[arg(short = 'n', long)]
+    dry_run: bool,
+    /// Wait for files that Dwarf Fortress seems to be writing, instead of
+    /// failing
+    #[arg(long)]
+    wait: bool,
+    /// The number of files to process at once (default: one per CPU)
+    #[arg(short = 'T', long, value_name = "N")]
+    threads: Option<NonZeroUsize>,
+    /// Report in JSON, one object per file and folder and one for the total
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args)]
+struct Slim {
+    /// The region folder, or a save directory of them
+    #[arg(value_name = "DIR")]
+    dir: PathBuf,
+    /// Pack the folder into one archive, with the codec that compresses
+    /// best, instead of recompressing its files in place
+    #[arg(long)]
+    archive: bool,
+    /// Where to write the archive of --archive (default: DIR.dfpack)
+    #[arg(short, long, value_name = "OUTPUT", requires = "archive")]
+    output: Option<PathBuf>,
+    /// Overwrite the archive if it exists
+    #[arg(short, long, requires = "archive")]
+    force: bool,
+    /// Check that each file decompresses to the same data as before
+    /// replacing it
+    #[arg(long, conflicts_with = "archive")]
+    verify: bool,
+    /// Recompress without replacing anything, reporting what would be saved
+    #[arg(short = 'n', long, conflicts_with_all = ["verify", "archive"])]
+    dry_run: bool,
+    /// Wait for files that Dwarf Fortress seems to be writing, instead of
+    /// failing
+    #[arg(long, conflicts_with = "archive")]
+    wait: bool,
+    /// Leave out the files and directories matching PATTERN, such as
+    /// `*.txt`. May be given more than once
+    #[arg(long, value_name = "PATTERN")]
+    exclude: Vec<Pattern>,
+    /// The number of files to process at once (default: one per CPU)
+    #[arg(short = 'T', long, value_name = "N")]
+    threads: Option<NonZeroUsize>,
+    /// Report in JSON, one object per file and one for the total
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args)]
+struct Validate {
+    /// The region folder, such as save/region1
+    #[arg(value_name = "DIR")]
+    dir: PathBuf,
+    /// Report in JSON
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args)]
+struct Backup {
+    #[command(subcommand)]
+    command: BackupCommand,
+}
+
+#[derive(Subcommand)]
+enum BackupCommand {
+    /// Take a snapshot of a region folder
+    Create(BackupCreate),
+    /// List the snapshots in a store, and what the store takes
+    List(BackupList),
+    /// Write the files of a snapshot into a region folder that Dwarf
+    /// Fortress loads
+    Restore(BackupRestore),
+    /// Remove the snapshots that the policies do not keep, leaving their
+    /// chunks to gc
+    Prune(BackupPrune),
+    /// Remove the chunks that no snapshot has
+    Gc(BackupGc),
+}
+
+#[derive(Args)]
+struct BackupCreate {
+    /// The region folder, such as save/region1
+    #[arg(value_name = "DIR")]
+    dir: PathBuf,
+    /// The store, a directory that is created if need be
+    #[arg(short, long, value_name = "STORE")]
+    store: PathBuf,
+    /// The name of the snapshot (default: the folder's, and the time in UTC)
+    #[arg(long, value_name = "NAME")]
+    name: Option<String>,
+    /// How to compress the chunks that are new to the store
+    #[arg(long, value_name = "CODEC")]
+    codec: Option<ArchiveCodec>,
+    /// Leave out the files and directories matching PATTERN, such as
+    /// `*.txt`. May be given more than once
+    #[arg(long, value_name = "PATTERN")]
+    exclude: Vec<Pattern>,
+    /// The number of files to process at once (default: one per CPU)
+    #[arg(short = 'T', long, value_name = "N")]
+    threads: Option<NonZeroUsize>,
+    /// Report in JSON, one object per file and one for the snapshot
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args)]
+struct BackupList {
+    /// The store
+    #[arg(short, long, value_name = "STORE")]
+    store: PathBuf,
+    /// Report in JSON, one object per snapshot and one for the store
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args)]
+struct BackupRestore {
+    /// The name of the snapshot, as `backup list` prints it
+    #[arg(value_name = "SNAPSHOT")]
+    snapshot: String,
+    /// The store
+    #[arg(short, long, value_name = "STORE")]
+    store: PathBuf,
+    /// The region folder to write
+    #[arg(short, long, value_name = "DIR")]
+    output: PathBuf,
+    /// Overwrite files of the folder that exist
+    #[arg(short, long)]
+    force: bool,
+    /// Report in JSON
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args)]
+struct BackupPrune {
+    /// The store
+    #[arg(short, long, value_name = "STORE")]
+    store: PathBuf,
+    /// Keep the newest N snapshots of each folder
+    #[arg(long, value_name = "N")]
+    keep_last: Option<NonZeroUsize>,
+    /// Keep the newest snapshot of each of the last N days that have one,
+    /// in UTC
+    #[arg(long, value_name = "N")]
+    keep_daily: Option<NonZeroUsize>,
+    /// Keep the newest snapshot of each of the last N weeks that have one
+    #[arg(long, value_name = "N")]
+    keep_weekly: Option<NonZeroUsize>,
+    /// Keep the newest snapshot of each of the last N months that have one
+    #[arg(long, value_name = "N")]
+    keep_monthly: Option<NonZeroUsize>,
+    /// Remove nothing, reporting what would be removed
+    #[arg(short = 'n', long)]
+    dry_run: bool,
+    /// Report in JSON, one object per snapshot and one for the store
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args)]
+struct BackupGc {
+    /// The store
+    #[arg(short, long, value_name = "STORE")]
+    store: PathBuf,
+    /// Remove nothing, reporting what would be freed
+    #[arg(short = 'n', long)]
+    dry_run: bool,
+    /// Report in JSON
+    #[arg(long)]
+    json: bool,
+}
+
+#[cfg(feature = "zstd")]
+#[derive(Args)]
+struct Dict {
+    #[command(subcommand)]
+    command: DictCommand,
+}
+
+#[cfg(feature = "zstd")]
+#[derive(Subcommand)]
+enum DictCommand {
+    /// Train a dictionary on the decompressed chunks of the data files in
+    /// DIR and its subdirectories
+    Train(DictTrain),
+}
+
+#[cfg(feature = "zstd")]
+#[derive(Args)]
+struct DictTrain {
+    /// A region folder, or a save directory of them
+    #[arg(value_name = "DIR")]
+    dir: PathBuf,
+    /// Where to write the dictionary
+    #[arg(short, long, value_name = "FILE")]
+    output: PathBuf,
+    /// The most bytes the dictionary takes
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, default_value = "110k")]
+    size: usize,
+    /// Leave out the files and directories matching PATTERN, such as
+    /// `*.txt`. May be given more than once
+    #[arg(long, value_name = "PATTERN")]
+    exclude: Vec<Pattern>,
+    /// Overwrite the output file if it exists
+    #[arg(short, long)]
+    force: bool,
+}
+
+#[cfg(feature = "watch")]
+#[derive(Args)]
+struct Watch {
+    /// The save directory, whose folders are the saved worlds (default:
+    /// save-dir in the configuration file)
+    #[arg(value_name = "SAVEDIR")]
+    dir: Option<PathBuf>,
+    /// Where to keep the backups, one folder for each world (default:
+    /// backup-dir in the configuration file)
+    #[arg(short = 'O', long, value_name = "DIR")]
+    output_dir: Option<PathBuf>,
+    /// How long a folder must go unchanged before it is backed up
+    #[arg(long, value_name = "SECONDS", default_value_t = 10)]
+    settle: u64,
+    /// Leave out the files and directories matching PATTERN, such as
+    /// `*.txt`, when backing up folders. May be given more than once
+    #[arg(long, value_name = "PATTERN")]
+    exclude: Vec<Pattern>,
+    /// The number of files to process at once (default: one per CPU)
+    #[arg(short = 'T', long, value_name = "N")]
+    threads: Option<NonZeroUsize>,
+    /// Report in JSON, one object per file
+    #[arg(long)]
+    json: bool,
+}
+
+fn is_stdio(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+/// Whether data files would be written to a terminal, which is refused with
+/// a message saying so unless `forced` by `--stdout`. A Windows console is
+/// always refused, since it only takes text.
+fn to_terminal(forced: bool) -> bool {
+    if !io::stdout().is_terminal() {
+        return false;
+    }
+    if cfg!(windows) {
+        eprintln!("The Windows console cannot take a data file; use -o FILE or a pipe");
+        return true;
+    }
+    if !forced {
+        eprintln!("Refusing to write a data file to a terminal; use -o FILE, a pipe, or --stdout");
+    }
+    !forced
+}
+
+/// Parses a size like `20000`, `64k`, `1M` or `2G`, where `k` is 1024 bytes,
+/// `M` 1024 `k` and `G` 1024 `M`, for `--chunk-size` and the like.
+fn parse_size(s: &str) -> Result<usize, String> {
+    let (digits, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, ""),
+    };
+    let unit = match unit {
+        "" => 1,
+        "k" | "K" => 1 << 10,
+        "m" | "M" => 1 << 20,
+        "g" | "G" => 1 << 30,
+        _ => return Err(format!("unknown unit {:?}; use k, M or G", unit)),
+    };
+    let size = digits.parse::<usize>().map_err(|e| e.to_string())?;
+    match size.checked_mul(unit) {
+        Some(0) => Err("cannot be zero".into()),
+        Some(size) => Ok(size),
+        None => Err("too large".into()),
+    }
+}
+
+/// Parses an offset such as `0x4000`, `16384` or `16k`.
+fn parse_offset(s: &str) -> Result<u64, String> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).map_err(|e| e.to_string()),
+        None if s == "0" => Ok(0),
+        None => parse_size(s).map(|size| size as u64),
+    }
+}
+
+/// Names `path` in messages.
+fn name(path: &Path) -> String {
+    match is_stdio(path) {
+        true => "standard output".to_string(),
+        false => path.display().to_string(),
+    }
+}
+
+/// Prefixes an error with the path it is about.
+fn at(path: &Path, e: impl Cause) -> Failure {
+    Failure::new(e.status(), format!("{}: {}", path.display(), e))
+}
+
+/// Opens `path` for reading, or standard input if it is `-`. A gzipped file
+/// is read through gzip, and the first of the volumes of `--split`, or
+/// their missing output, as all of them.
+fn open(path: &Path) -> Result<Box<dyn io::Read>, Failure> {
+    let reader: Box<dyn BufRead> = match is_stdio(path) {
+        // A console would mangle the bytes on Windows, and typing a data file
+        // is never meant.
+        true if io::stdin().is_terminal() => {
+            let message = "Refusing to read a data file from a terminal; give a file or a pipe";
+            return Err(Failure::new(Status::Usage, message));
+        }
+        // Standard input and output are binary on every platform, as Rust
+        // never translates line endings.
+        true => Box::new(io::stdin().lock()),
+        false => match volumes::series(path) {
+            Some(output) => Box::new(BufReader::new(Series::open(&output)?)),
+            None => Box::new(BufReader::new(File::open(path).map_err(|e| at(path, e))?)),
+        },
+    };
+    gunzip(reader, path)
+}
+
+/// Reads `reader`, the contents of `path`, through gzip if it is gzipped,
+/// and as the data file it holds if it is a solid file.
+fn gunzip(mut reader: Box<dyn BufRead>, path: &Path) -> Result<Box<dyn io::Read>, Failure> {
+    let start = reader.fill_buf().map_err(|e| at(path, e))?;
+    if start.starts_with(&[0x1f, 0x8b]) {
+        return Ok(Box::new(MultiGzDecoder::new(reader)));
+    }
+    if start.starts_with(solid::MAGIC) {
+        return Ok(Box::new(solid::open(reader).map_err(|e| at(path, e))?));
+    }
+    Ok(reader)
+}
+
+impl Convert {
+    fn options(&self, format: Format) -> Options {
+        // Chunks of the codec already are copied as they are, unless the
+        // options ask for them to be compressed another way.
+        let recompress = self.deterministic
+            || self.level.is_some()
+            || self.fast
+            || self.best
+            || self.chunk_size.is_some_and(|size| size != CHUNK_SIZE);
+        let options = Options::new()
+            .codec(format.codec().unwrap_or_default())
+            .recompress(recompress);
+        let options = match self.chunk_size {
+            Some(size) => options.chunk_size(size),
+            None => options,
+        };
+        match self.level {
+            Some(level) => options.level(level),
+            None if self.fast => options.fast(),
+            None if self.best => options.best(),
+            None => options,
+        }
+    }
+
+    /// The codec of the stream of `--solid`, if it is given.
+    fn solid(&self) -> Option<ArchiveCodec> {
+        match self.format {
+            _ if !self.solid => None,
+            #[cfg(feature = "zstd")]
+            Some(Format::Zstd) => Some(ArchiveCodec::Zstd),
+            _ => Some(ArchiveCodec::Zlib),
+        }
+    }
+
+    /// The gzip level that the compression level options ask for.
+    fn gzip_level(&self) -> u32 {
+        match self.level {
+            Some(level) => level,
+            None if self.fast => 1,
+            None if self.best => 9,
+            None => 6,
+        }
+    }
+
+    fn run(self, target: Target, verbosity: Verbosity) -> Status {
+        let format = match (self.format, target) {
+            #[cfg(feature = "zstd")]
+            (None, Target::Compressed) if self.dict.is_some() => Format::Zstd,
+            (Some(format), Target::Compressed) => format,
+            (Some(format @ (Format::Raw | Format::Gzip)), Target::Uncompressed) => format,
+            (Some(_), Target::Uncompressed) => {
+                eprintln!("Decompressing writes raw or gzip files");
+                return Status::Usage;
+            }
+            (None, Target::Compressed) => Format::DfZlib,
+            (None, Target::Uncompressed) => Format::Raw,
+        };
+        #[cfg(feature = "zstd")]
+        let dictionary = match self.dict {
+            Some(_) if target == Target::Compressed && format != Format::Zstd => {
+                eprintln!("--dict compresses with --format zstd");
+                return Status::Usage;
+            }
+            Some(ref path) => match dict::load(path) {
+                Ok(dictionary) => Some(dictionary),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return e.status;
+                }
+            },
+            None => None,
+        };
+        if self.offset.is_some() || self.length.is_some() {
+            if target == Target::Compressed {
+                eprintln!("--offset and --length only apply when decompressing");
+                return Status::Usage;
+            }
+            return self.run_range();
+        }
+        if format.df_readable() && self.chunk_size.is_some_and(|size| size > CHUNK_SIZE) {
+            eprintln!(
+                "Dwarf Fortress is only known to read chunks of up to {} bytes; \
+                 larger ones need another --format",
+                CHUNK_SIZE
+            );
+            return Status::Usage;
+        }
+        if self.deterministic {
+            let pinned = match format {
+                Format::DfZlib | Format::Gzip => check_deterministic(),
+                Format::Raw => Ok(()),
+                #[allow(unreachable_patterns)]
+                _ => Err(Failure::new(
+                    Status::Usage,
+                    "--deterministic only writes df-zlib, raw or gzip",
+                )),
+            };
+            if let Err(e) = pinned {
+                eprintln!("{}", e);
+                return e.status;
+            }
+        }
+        let streamed = match format {
+            Format::DfZlib => true,
+            #[cfg(feature = "zstd")]
+            Format::Zstd => true,
+            _ => false,
+        };
+        if self.solid && !streamed {
+            eprintln!("--solid compresses with df-zlib or zstd");
+            return Status::Usage;
+        }
+        // A solid file holds the data file uncompressed.
+        let target = match format.codec() {
+            Some(_) if !self.solid => Target::Compressed,
+            _ => Target::Uncompressed,
+        };
+        if self.strip_header && target == Target::Compressed {
+            eprintln!("--strip-header only applies when decompressing");
+            return Status::Usage;
+        }
+        let batch = self.in_place || self.output_dir.is_some();
+        let threads = match batch {
+            true => self.threads.map_or_else(default_threads, NonZeroUsize::get),
+            false => 1,
+        };
+        let chunk_size = self.chunk_size.unwrap_or(CHUNK_SIZE);
+        let (options, threads) = match self
+            .max_memory
+            .map(|m| budget(m, format, chunk_size, threads))
+        {
+            None => (self.options(format), threads),
+            Some(Ok(budget)) => (budget.limit(self.options(format)), budget.files),
+            Some(Err(e)) => {
+                eprintln!("{}", e);
+                return Status::Usage;
+            }
+        };
+        // Chunks in zstd already are compressed again with the dictionary.
+        #[cfg(feature = "zstd")]
+        let options = match dictionary {
+            Some(dictionary) => options.dictionary(dictionary).recompress(true),
+            None => options,
+        };
+        let gzip = (format == Format::Gzip).then(|| self.gzip_level());
+        let solid = self.solid();
+        if batch {
+            return self.run_batch(target, options, threads, gzip, solid, verbosity);
+        }
+        if self.output.is_none() && !self.dry_run && to_terminal(self.stdout) {
+            return Status::Usage;
+        }
+        let output = self.output.unwrap_or_else(|| PathBuf::from("-"));
+        let mut inputs = match glob_all(self.inputs) {
+            Ok(inputs) => inputs,
+            Err(e) => {
+                eprintln!("{}", e);
+                return e.status;
+            }
+        };
+        if inputs.is_empty() {
+            inputs.push(PathBuf::from("-"));
+        }
+        if let Some(dir) = inputs.iter().find(|p| p.is_dir()) {
+            eprintln!(
+                "{} is a directory, which needs --in-place or --output-dir",
+                dir.display()
+            );
+            return Status::Usage;
+        }
+        if self.rm && inputs.iter().any(|p| is_stdio(p)) {
+            eprintln!("--rm needs an input file");
+            return Status::Usage;
+        }
+        if inputs.len() > 1 {
+            eprintln!("Only one input can be written to {}", name(&output));
+            return Status::Usage;
+        }
+        if self.split.is_some() && is_stdio(&output) {
+            eprintln!("--split needs an output file");
+            return Status::Usage;
+        }
+        if self.verify && (is_stdio(&inputs[0]) || is_stdio(&output)) {
+            eprintln!("--verify needs input and output files");
+            return Status::Usage;
+        }
+        let batch = Batch {
+            files: inputs,
+            dirs: Vec::new(),
+            report: false,
+            json: self.json,
+            stdout_taken: is_stdio(&output) && !self.dry_run,
+            verdicts: false,
+            verbosity,
+        };
+        let write = WriteOptions {
+            force: self.force,
+            verify: self.verify,
+            dry_run: self.dry_run,
+            gzip,
+            solid,
+            split: self.split.map(|size| size as u64),
+            strip_header: self.strip_header,
+            add_header: self.save_version,
+            wait: self.wait,
+            only_smaller: false,
+        };
+        batch.run(1, |input, bar| {
+            let (summary, stats) = measure(&options, bar, target, self.stats, |options| {
+                convert(input, &output, target, options, write)
+            })?;
+            if self.rm && !self.dry_run {
+                fs::remove_file(input).map_err(|e| at(input, e))?;
+            }
+            let line = self.dry_run.then(|| {
+                let rm = if self.rm {
+                    ", then remove the input"
+                } else {
+                    ""
+                };
+                let output = name(&output);
+                format!(
+                    "would write {} bytes to {}{}",
+                    summary.bytes_out, output, rm
+                )
+            });
+            Ok(Done {
+                line,
+                summary: Some(summary),
+                stats,
+                json: dry_run_json(self.dry_run),
+                failure: None,
+            })
+        })
+    }
+
+    /// Converts each file of a batch in place, or into `--output-dir`, on
+    /// `threads` threads, gzipping them at the `gzip` level if one is given,
+    /// or writing solid files with the `solid` codec.
+    fn run_batch(
+        self,
+        target: Target,
+        options: Options,
+        threads: usize,
+        gzip: Option<u32>,
+        solid: Option<ArchiveCodec>,
+        verbosity: Verbosity,
+    ) -> Status {
+        let flag = match self.in_place {
+            true => "--in-place",
+            false => "--output-dir",
+        };
+        if self.inputs.is_empty() || self.inputs.iter().any(|p| is_stdio(p)) {
+            eprintln!("{} needs input files", flag);
+            return Status::Usage;
+        }
+        let mut batch = match expand(self.inputs, self.recursive, &self.exclude) {
+            Ok(batch) => batch,
+            Err(e) => {
+                eprintln!("{}", e);
+                return e.status;
+            }
+        };
+        if let Some(ref dir) = self.output_dir {
+            if let Err(e) = batch.check_destinations(dir) {
+                eprintln!("{}", e);
+                return e.status;
+            }
+        }
+        batch.json = self.json;
+        batch.verbosity = verbosity;
+        let write = WriteOptions {
+            force: self.force || self.if_newer,
+            verify: self.verify,
+            dry_run: self.dry_run,
+            gzip,
+            solid,
+            split: self.split.map(|size| size as u64),
+            strip_header: self.strip_header,
+            add_header: self.save_version,
+            wait: self.wait,
+            only_smaller: false,
+        };
+        batch.run(threads, |path, bar| {
+            let output = self
+                .output_dir
+                .as_ref()
+                .map(|dir| dir.join(batch.relative(path)));
+            if let (Some(output), true) = (&output, self.if_newer) {
+                if is_up_to_date(path, output) {
+                    let mut json = dry_run_json(self.dry_run);
+                    json.insert("skipped".into(), Value::Bool(true));
+                    return Ok(Done {
+                        line: batch.report.then(|| "up to date, skipped".to_string()),
+                        json,
+                        ..Done::default()
+                    });
+                }
+            }
+            let (summary, stats) = measure(&options, bar, target, self.stats, |options| {
+                let Some(ref output) = output else {
+                    let backup = self.backup.as_deref();
+                    return convert_in_place(path, target, options, write, backup);
+                };
+                if let (Some(dir), false) = (output.parent(), self.dry_run) {
+                    fs::create_dir_all(dir).map_err(|e| at(dir, e))?;
+                }
+                convert(path, output, target, options, write)
+            })?;
+            if self.rm && !self.dry_run {
+                fs::remove_file(path).map_err(|e| at(path, e))?;
+            }
+            let line = match (self.dry_run, output) {
+                (true, Some(output)) => format!(
+                    "would write {} bytes to {}",
+                    summary.bytes_out,
+                    output.display()
+                ),
+                (true, None) => format!(
+                    "would replace {} bytes with {} bytes{}",
+                    summary.bytes_in,
+                    summary.bytes_out,
+                    match self.backup {
+                        Some(ref suffix) => {
+                            let backup = backup_path(path, suffix);
+                            format!(", keeping the original as {}", backup.display())
+                        }
+                        None => String::new(),
+                    }
+                ),
+                (false, Some(output)) => format!(
+                    "{} -> {} bytes in {}",
+                    summary.bytes_in,
+                    summary.bytes_out,
+                    output.display()
+                ),
+                (false, None) => format!("{} -> {} bytes", summary.bytes_in, summary.bytes_out),
+            };
+            Ok(Done {
+                line: (batch.report || self.dry_run).then_some(line),
+                summary: Some(summary),
+                stats,
+                json: dry_run_json(self.dry_run),
+                failure: None,
+            })
+        })
+    }
+}
+
+/// The JSON fields that mark the report of a dry run.
+fn dry_run_json(dry_run: bool) -> Map<String, Value> {
+    let mut json = Map::new();
+    if dry_run {
+        json.insert("dry_run".into(), Value::Bool(true));
+    }
+    json
+}
+
+impl Files {
+    /// Runs `f` on each file, printing what it returns, and `FAILED` for the
+    /// files it fails on if `verdicts`.
+    fn each<F>(self, verbosity: Verbosity, verdicts: bool, f: F) -> Status
+    where
+        F: Fn(Box<dyn io::Read>, &Options) -> dfcompress::Result<Done> + Sync,
+    {
+        let mut batch = match expand(self.files, self.recursive, &self.exclude) {
+            Ok(batch) => batch,
+            Err(e) => {
+                eprintln!("{}", e);
+                return e.status;
+            }
+        };
+        batch.json = self.json;
+        batch.verdicts = verdicts;
+        batch.verbosity = verbosity;
+        let threads = self.threads.map_or_else(default_threads, NonZeroUsize::get);
+        let options = Options::new();
+        batch.run(threads, |path, bar| {
+            let options = with_bar(&options, bar, None);
+            f(open(path)?, &options).map_err(|e| at(path, e))
+        })
+    }
+}
+
+fn info(mut r: Box<dyn io::Read>, options: &Options) -> dfcompress::Result<Done> {
+    let mut bytes = [0; 8];
+    r.read_exact(&mut bytes)?;
+    let header = Header::parse(&bytes)?;
+    // Decompressing counts the chunks and the bytes they decompress to.
+    let r = (&bytes[..]).chain(r);
+    let summary = dfcompress::recode_with(r, io::sink(), Target::Uncompressed, options)?;
+    let compressed = header.compression.is_compressed();
+    let compression = format!("{:?}", header.compression).to_lowercase();
+    let release = header.df_release().unwrap_or("unknown release");
+    let mut line = format!(
+        "version {} ({}), {} ({}), {} bytes",
+        header.version,
+        release,
+        compression,
+        header.compression.to_u32(),
+        summary.bytes_in
+    );
+    if compressed {
+        line += &format!(
+            ", {} chunks, {} bytes uncompressed",
+            summary.chunks, summary.bytes_out
+        );
+    }
+    let mut json = Map::new();
+    json.insert("header".into(), json!(header));
+    json.insert("release".into(), json!(header.df_release()));
+    json.insert("size".into(), json!(summary.bytes_in));
+    if compressed {
+        json.insert("chunks".into(), json!(summary.chunks));
+        json.insert("uncompressed_size".into(), json!(summary.bytes_out));
+    }
+    Ok(Done {
+        line: Some(line),
+        json,
+        ..Done::default()
+    })
+}
+
+fn verify(r: Box<dyn io::Read>, options: &Options) -> dfcompress::Result<Done> {
+    let summary = dfcompress::recode_with(r, io::sink(), Target::Uncompressed, options)?;
+    let line = format!(
+        "OK, {} chunks, {} bytes uncompressed",
+        summary.chunks, summary.bytes_out
+    );
+    Ok(Done {
+        line: Some(line),
+        summary: Some(summary),
+        ..Done::default()
+    })
+}
+
+fn check(r: Box<dyn io::Read>, _: &Options) -> dfcompress::Result<Done> {
+    let report = dfcompress::check(r)?;
+    let counted = |n: usize, what: &str| match n {
+        1 => format!("1 {}", what),
+        n => format!("{} {}s", n, what),
+    };
+    let uncompressed = report
+        .header
+        .is_some_and(|h| !h.compression.is_compressed());
+    let mut line = match (report.findings.is_empty(), uncompressed) {
+        (true, true) => "OK, uncompressed".to_string(),
+        (true, false) => format!("OK, {} chunks", report.chunks),
+        (false, _) => format!(
+            "{}, {}",
+            counted(report.errors(), "error"),
+            counted(report.warnings(), "warning")
+        ),
+    };
+    for finding in &report.findings {
+        line += &format!("\n  {} at offset {}", finding.severity, finding.offset);
+        if let Some(chunk) = finding.chunk {
+            line += &format!(" (chunk {})", chunk);
+        }
+        line += &format!(": {}", finding.description);
+    }
+    let mut json = Map::new();
+    json.insert("header".into(), json!(report.header));
+    json.insert("chunks".into(), json!(report.chunks));
+    json.insert("findings".into(), json!(report.findings));
+    Ok(Done {
+        line: Some(line),
+        json,
+        failure: match report.header {
+            None => Some(Status::NotDataFile),
+            Some(_) => (!report.is_ok()).then_some(Status::Corrupt),
+        },
+        ..Done::default()
+    })
+}
+
+impl Verbosity {
+    /// Logs to standard error at the level asked for.
+    fn init(self) {
+        let level = match (self.quiet, self.verbose) {
+            (true, _) => log::LevelFilter::Error,
+            (false, 0) => log::LevelFilter::Warn,
+            (false, 1) => log::LevelFilter::Debug,
+            (false, _) => log::LevelFilter::Trace,
+        };
+        env_logger::Builder::new()
+            .filter_level(level)
+            .parse_default_env()
+            .format_timestamp(None)
+            .init();
+    }
+}
+
+/// Reads the configuration file, or exits if that fails.
+fn config(path: Option<&Path>) -> Config {
+    Config::load(path).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(e.status as i32);
+    })
+}
+
+/// Runs `dfcompress`, or `dfuncompress` when run through a link of that
+/// name.
+// The `dfuncompress` program, which includes this file, calls `run` instead.
+#[allow(dead_code)]
+pub fn main() {
+    let program = std::env::args_os().next().map(PathBuf::from);
+    match program.and_then(|p| p.file_stem().map(|s| s == "dfuncompress")) {
+        Some(true) => run(Some(Target::Uncompressed)),
+        _ => run(None),
+    }
+}
+
+/// Runs the program: `dfuncompress`, which only converts to `target`, if
+/// there is one, and `dfcompress` with its subcommands otherwise.
+pub fn run(target: Option<Target>) {
+    if let Some(target) = target {
+        let mut cli = Dfuncompress::parse();
+        cli.verbosity.init();
+        interrupt::install();
+        let config = config(cli.config.as_deref());
+        cli.convert.configure(&config, target);
+        let status = cli.convert.run(target, cli.verbosity);
+        process::exit(status as i32);
+    }
+    let cli = Cli::parse();
+    let verbosity = cli.verbosity;
+    verbosity.init();
+    interrupt::install();
+    let config = config(cli.config.as_deref());
+    let convert = |mut convert: Convert, target| {
+        convert.configure(&config, target);
+        convert.run(target, verbosity)
+    };
+    let status = match cli.command {
+        None if cli.decompress => convert(cli.convert, Target::Uncompressed),
+        None => convert(cli.convert, Target::Compressed),
+        Some(Command::Compress(c)) => convert(c, Target::Compressed),
+        Some(Command::Decompress(c)) => convert(c, Target::Uncompressed),
+        Some(Command::Info(mut files)) => {
+            files.configure(&config);
+            files.each(verbosity, false, info)
+        }
+        Some(Command::Verify(mut files)) => {
+            files.configure(&config);
+            files.each(verbosity, true, verify)
+        }
+        Some(Command::Check(mut files)) => {
+            files.configure(&config);
+            files.each(verbosity, false, check)
+        }
+        Some(Command::List(mut list)) => {
+            list.configure(&config);
+            list.run()
+        }
+        Some(Command::Diff(diff)) => diff.run(verbosity),
+        Some(Command::Delta(delta)) => delta.run(verbosity),
+        Some(Command::Cat(cat)) => cat.run(verbosity),
+        Some(Command::Chunk(chunk)) => chunk.run(verbosity),
+        Some(Command::Header(headers)) => headers.run(),
+        Some(Command::Tail(tail)) => tail.run(verbosity),
+        Some(Command::Repair(repair)) => repair.run(verbosity),
+        Some(Command::Bench(bench)) => bench.run(),
+        Some(Command::Pack(mut pack)) => {
+            pack.configure(&config);
+            pack.run(verbosity)
+        }
+        Some(Command::Unpack(unpack)) => unpack.run(verbosity),
+        Some(Command::Slim(slim)) => slim.run(verbosity),
+        Some(Command::Rotate(mut rotate)) => {
+            rotate.configure(&config);
+            rotate.run(verbosity)
+        }
+        Some(Command::Validate(validate)) => validate.run(),
+        Some(Command::Backup(mut backup)) => {
+            backup.configure(&config);
+            backup.run(verbosity)
+        }
+        #[cfg(feature = "zstd")]
+        Some(Command::Dict(mut dict)) => {
+            dict.configure(&config);
+            dict.run(verbosity)
+        }
+        #[cfg(feature = "watch")]
+        Some(Command::Watch(mut watch)) => {
+            watch.configure(&config);
+            watch.run(verbosity)
+        }
+        Some(Command::Completions(completions)) => completions.run(),
+        Some(Command::Man) => man::run(),
+    };
+    process::exit(status as i32);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A new, empty directory for the test `name` to write in.
+    pub(crate) fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dfcompress-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn parse_size_test() {
+        assert_eq!(parse_size("20000"), Ok(20000));
+        assert_eq!(parse_size("64k"), Ok(64 << 10));
+        assert_eq!(parse_size("64K"), Ok(64 << 10));
+        assert_eq!(parse_size("1M"), Ok(1 << 20));
+        assert_eq!(parse_size("2g"), Ok(2 << 30));
+        assert_eq!(parse_size("0").unwrap_err(), "cannot be zero");
+        assert_eq!(parse_size("0k").unwrap_err(), "cannot be zero");
+        assert!(parse_size("1T").unwrap_err().starts_with("unknown unit"));
+        assert!(parse_size("1kb").unwrap_err().starts_with("unknown unit"));
+        assert!(parse_size("").is_err());
+        assert!(parse_size("k").is_err());
+        assert!(parse_size("-1").is_err());
+        let huge = format!("{}G", usize::MAX);
+        assert_eq!(parse_size(&huge).unwrap_err(), "too large");
+    }
+
+    #[test]
+    fn level_test() {
+        let dir = scratch("level");
+        let mut data = dfcompress::Header::new(1625, dfcompress::Compression::Uncompressed)
+            .to_bytes()
+            .to_vec();
+        data.extend((0..150_000u64).map(|i| (i * i / 7 % 251) as u8));
+        let mut compressed = Vec::new();
+        let fast = Options::new().fast();
+        dfcompress::recode_with(&data[..], &mut compressed, Target::Compressed, &fast).unwrap();
+        let input = dir.join("world.sav");
+        fs::write(&input, &compressed).unwrap();
+        for flags in [&["--level", "9"][..], &["--best"], &["--chunk-size", "10k"]] {
+            let output = dir.join("out.sav");
+            let args = ["dfcompress", "-q", "-f", "-o"]
+                .into_iter()
+                .map(String::from);
+            let args = args
+                .chain([output.display().to_string(), input.display().to_string()])
+                .chain(flags.iter().map(|flag| flag.to_string()));
+            let cli = Cli::try_parse_from(args).unwrap();
+            assert_eq!(
+                cli.convert.run(Target::Compressed, cli.verbosity),
+                Status::Success
+            );
+            let out = fs::read(&output).unwrap();
+            assert_ne!(out, compressed, "{:?}", flags);
+            assert_eq!(dfcompress::decompress_bytes(&out).unwrap(), data);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parse_offset_test() {
+        assert_eq!(parse_offset("0"), Ok(0));
+        assert_eq!(parse_offset("16384"), Ok(16384));
+        assert_eq!(parse_offset("16k"), Ok(16384));
+        assert_eq!(parse_offset("0x4000"), Ok(0x4000));
+        assert_eq!(parse_offset("0X4000"), Ok(0x4000));
+        assert_eq!(parse_offset("0xffffffffffffffff"), Ok(u64::MAX));
+        assert!(parse_offset("0x").is_err());
+        assert!(parse_offset("0xg").is_err());
+        assert!(parse_offset("0k").is_err());
+    }
+}
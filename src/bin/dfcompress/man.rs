@@ -0,0 +1,175 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+//! The `man` subcommand, which prints the manual page generated from the
+//! definition of the arguments.
+use std::fmt::Write;
+
+use clap::{Arg, Command};
+
+use super::completions::{cli, nodes};
+use super::exit::Status;
+
+/// Prints the page, in roff.
+pub(crate) fn run() -> Status {
+    print!("{}", page(&cli()));
+    Status::Success
+}
+
+/// Escapes `text` for roff, as the body of a line.
+fn roff(text: &str) -> String {
+    let mut escaped = String::new();
+    for line in text.lines() {
+        if line.starts_with(['.', '\'']) {
+            escaped += "\\&";
+        }
+        escaped += &line.replace('\\', "\\e").replace('-', "\\-");
+        escaped.push('\n');
+    }
+    escaped
+}
+
+/// The paragraphs of the long help of `arg`, with its possible values.
+fn arg_help(arg: &Arg) -> String {
+    let help = arg
+        .get_long_help()
+        .or(arg.get_help())
+        .map(|h| h.to_string())
+        .unwrap_or_default();
+    let values: Vec<_> = arg
+        .get_possible_values()
+        .into_iter()
+        .filter(|v| !v.is_hide_set() && arg.get_action().takes_values())
+        .map(|v| match v.get_help() {
+            Some(help) => format!("{}: {}", v.get_name(), help),
+            None => v.get_name().to_string(),
+        })
+        .collect();
+    match values.is_empty() {
+        true => help,
+        false => format!("{}\n\nValues:\n{}", help, values.join("\n")),
+    }
+}
+
+/// The heading of `arg`, such as `-l, --level LEVEL`.
+fn arg_heading(arg: &Arg) -> String {
+    let flags: Vec<_> = arg
+        .get_short()
+        .map(|c| format!("\\fB\\-{}\\fR", c))
+        .into_iter()
+        .chain(
+            arg.get_long()
+                .map(|s| format!("\\fB\\-\\-{}\\fR", s.replace('-', "\\-"))),
+        )
+        .collect();
+    let mut heading = flags.join(", ");
+    let names = arg.get_value_names().unwrap_or_default();
+    let value = match (arg.is_positional(), names.first()) {
+        (_, Some(name)) => name.to_string(),
+        (true, None) => arg.get_id().to_string().to_uppercase(),
+        (false, None) => String::new(),
+    };
+    if arg.is_positional() || arg.get_action().takes_values() {
+        if !heading.is_empty() {
+            heading.push(' ');
+        }
+        write!(heading, "\\fI{}\\fR", value.replace('-', "\\-")).unwrap();
+    }
+    heading
+}
+
+/// Lists the arguments of `command`.
+fn args(command: &Command, page: &mut String) {
+    for arg in command.get_arguments().filter(|a| !a.is_hide_set()) {
+        writeln!(page, ".TP\n{}", arg_heading(arg)).unwrap();
+        for (i, paragraph) in arg_help(arg).split("\n\n").enumerate() {
+            if i > 0 {
+                page.push_str(".IP\n");
+            }
+            page.push_str(&roff(paragraph));
+        }
+    }
+}
+
+/// Writes the page of `cli` and all its subcommands.
+fn page(cli: &Command) -> String {
+    let name = cli.get_name();
+    let version = cli.get_version().unwrap_or_default();
+    let about = cli.get_about().map(|h| h.to_string()).unwrap_or_default();
+    let mut page = String::new();
+    writeln!(
+        page,
+        ".TH {} 1 \"\" \"{} {}\"",
+        name.to_uppercase(),
+        name,
+        version
+    )
+    .unwrap();
+    writeln!(page, ".SH NAME\n{} \\- {}", name, roff(&about).trim_end()).unwrap();
+    page.push_str(".SH SYNOPSIS\n");
+    page.push_str(&usage(cli));
+    page.push_str(".SH DESCRIPTION\n");
+    let long_about = cli.get_long_about().map(|h| h.to_string());
+    page.push_str(&roff(&long_about.unwrap_or(about)));
+    page.push_str(".SH OPTIONS\n");
+    args(cli, &mut page);
+    page.push_str(".SH COMMANDS\n");
+    for node in nodes(cli).iter().skip(1) {
+        let command = node.command;
+        writeln!(page, ".SS \"{}\"", node.path.join(" ")).unwrap();
+        let aliases: Vec<_> = command.get_visible_aliases().collect();
+        page.push_str(&usage(command));
+        if !aliases.is_empty() {
+            page.push_str(".br\n");
+            page.push_str(&roff(&format!("Also: {}", aliases.join(", "))));
+        }
+        page.push_str(".PP\n");
+        let about = command
+            .get_long_about()
+            .or(command.get_about())
+            .map(|h| h.to_string())
+            .unwrap_or_default();
+        page.push_str(&roff(&about));
+        args(command, &mut page);
+    }
+    page.push_str(".SH EXIT STATUS\n");
+    page.push_str(&roff(EXIT_STATUS));
+    page
+}
+
+/// The usage lines of `command`, without the `Usage:` before them, in roff.
+fn usage(command: &Command) -> String {
+    let usage = command.clone().render_usage().to_string();
+    let usage = usage.strip_prefix("Usage: ").unwrap_or(&usage);
+    let lines: Vec<_> = usage.lines().map(|line| roff(line.trim())).collect();
+    lines.join(".br\n")
+}
+
+/// What the statuses of `exit::Status` mean.
+const EXIT_STATUS: &str = "0 on success, 1 for another error or if the files \
+of diff differ, 2 for a usage error, 3 if an input is not a data file, 4 if \
+one is damaged or truncated, 5 if reading or writing failed, and 6 if only \
+some files of a batch failed.";
+
+#[cfg(test)]
+mod tests {
+    use super::super::completions::tests::commands;
+    use super::*;
+
+    #[test]
+    fn page_test() {
+        let cli = cli();
+        let page = page(&cli);
+        let mut all = Vec::new();
+        commands(&cli, cli.get_name(), &mut all);
+        for (path, longs) in &all[1..] {
+            let heading = format!(".SS \"{}\"\n", path);
+            let start = page.find(&heading).unwrap_or_else(|| panic!("{}", path));
+            let section = &page[start + heading.len()..];
+            let section = &section[..section.find(".SS ").unwrap_or(section.len())];
+            for long in longs {
+                let flag = format!("\\fB\\-\\-{}\\fR", long.replace('-', "\\-"));
+                assert!(section.contains(&flag), "{} --{}", path, long);
+            }
+        }
+    }
+}
@@ -0,0 +1,82 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+//! Sharing out the memory of `--max-memory`.
+use dfcompress::{Options, CHUNK_SIZE};
+
+use super::Format;
+
+/// The chunk buffers of each thread converting a file: with the `parallel`
+/// feature, four chunks are read ahead for each thread, and each has an
+/// uncompressed and a compressed copy.
+const BUFFERS: usize = 8;
+
+/// How many files and chunks to convert at once within a memory limit.
+pub(crate) struct Budget {
+    /// The files converted at once.
+    pub files: usize,
+    /// The threads converting the chunks of each file.
+    #[cfg(feature = "parallel")]
+    pub chunk_threads: usize,
+    /// The most a chunk may decompress to.
+    pub max_chunk: usize,
+}
+
+impl Budget {
+    /// Limits the chunks of `options` to the budget.
+    pub(crate) fn limit(&self, options: Options) -> Options {
+        let options = options.max_chunk_size(self.max_chunk);
+        #[cfg(feature = "parallel")]
+        let options = options.threads(self.chunk_threads);
+        options
+    }
+}
+
+impl Format {
+    /// About the most memory the codec of the format needs for a chunk.
+    fn codec_memory(self) -> usize {
+        match self {
+            Format::DfZlib | Format::Gzip => 512 << 10,
+            #[cfg(feature = "libdeflate")]
+            Format::Libdeflate => 1 << 20,
+            #[cfg(feature = "zstd")]
+            Format::Zstd => 64 << 20,
+            #[cfg(feature = "lz4")]
+            Format::Lz4 => 64 << 10,
+            Format::Raw => 0,
+        }
+    }
+}
+
+/// Shares out `limit` bytes between up to `files` files at once and the
+/// chunks of each, which hold `chunk_size` bytes.
+pub(crate) fn budget(
+    limit: usize,
+    format: Format,
+    chunk_size: usize,
+    files: usize,
+) -> Result<Budget, String> {
+    // Decompression needs the zlib state and chunks of Dwarf Fortress's size
+    // whatever the output.
+    let codec = format.codec_memory().max(Format::DfZlib.codec_memory());
+    let chunk_size = chunk_size.max(CHUNK_SIZE);
+    let thread = codec + BUFFERS * chunk_size;
+    if limit < thread {
+        return Err(format!(
+            "--max-memory must be at least {} bytes for chunks of {} bytes",
+            thread, chunk_size
+        ));
+    }
+    let files = files.clamp(1, limit / thread);
+    let per_file = limit / files;
+    // Without the `parallel` feature, each file is converted on one thread.
+    let chunk_threads = match cfg!(feature = "parallel") {
+        true => per_file / thread,
+        false => 1,
+    };
+    Ok(Budget {
+        files,
+        #[cfg(feature = "parallel")]
+        chunk_threads,
+        max_chunk: (per_file / chunk_threads - codec) / BUFFERS,
+    })
+}
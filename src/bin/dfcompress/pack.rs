@@ -0,0 +1,249 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+//! The `pack` subcommand, which puts a region folder into one archive.
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use dfcompress::{Compression, Header, Options, Target};
+use log::info;
+use serde_json::json;
+
+use super::archive::{ArchiveCodec, Encoder, Kind, Manifest, Member, MAGIC};
+use super::batch::{all_files, is_data_file};
+use super::convert::{check_deterministic, create, failed, Counted};
+use super::exit::{Failure, Status};
+use super::volumes::Volumes;
+use super::{at, is_stdio, open, to_terminal, Pack, Verbosity};
+
+impl Pack {
+    /// Packs the folder, and reports the sizes.
+    pub(crate) fn run(self, verbosity: Verbosity) -> Status {
+        if self.output.is_none() && to_terminal(self.stdout) {
+            return Status::Usage;
+        }
+        match self.pack() {
+            Ok(report) if !verbosity.quiet || self.json => {
+                // The archive may be on standard output.
+                match self.output.as_deref().is_none_or(is_stdio) {
+                    true => eprintln!("{}", report),
+                    false => println!("{}", report),
+                }
+                Status::Success
+            }
+            Ok(_) => Status::Success,
+            Err(e) => {
+                eprintln!("{}", e);
+                e.status
+            }
+        }
+    }
+
+    fn pack(&self) -> Result<String, Failure> {
+        let (manifest, bytes) = self.write()?;
+        let uncompressed: u64 = manifest.members.iter().map(|m| m.size).sum();
+        Ok(match self.json {
+            true => json!({
+                "dir": self.dir,
+                "files": manifest.members.len(),
+                "uncompressed_size": uncompressed,
+                "size": bytes,
+            })
+            .to_string(),
+            false => format!(
+                "{}: {} files, {} bytes uncompressed -> {} bytes",
+                self.dir.display(),
+                manifest.members.len(),
+                uncompressed,
+                bytes
+            ),
+        })
+    }
+
+    /// Writes the archive. Returns its manifest and size.
+    pub(crate) fn write(&self) -> Result<(Manifest, u64), Failure> {
+        let mut files = Vec::new();
+        all_files(&self.dir, &self.exclude, &mut files)?;
+        let codec = match self.deterministic {
+            true => {
+                check_deterministic()?;
+                match self.codec {
+                    None | Some(ArchiveCodec::Zlib) => ArchiveCodec::Zlib,
+                    #[allow(unreachable_patterns)]
+                    Some(_) => {
+                        let message = "--deterministic only packs with --codec zlib";
+                        return Err(Failure::new(Status::Usage, message));
+                    }
+                }
+            }
+            false => self.codec.unwrap_or_default(),
+        };
+        let mut members = files
+            .iter()
+            .map(|path| survey(&self.dir, path))
+            .collect::<Result<Vec<_>, _>>()?;
+        if self.deterministic {
+            for member in &mut members {
+                member.modified = None;
+            }
+        }
+        let output = self.output.clone().unwrap_or_else(|| PathBuf::from("-"));
+        if self.split.is_some() && is_stdio(&output) {
+            return Err(Failure::new(Status::Usage, "--split needs an output file"));
+        }
+        let manifest = Manifest { members };
+        let bytes = match is_stdio(&output) {
+            true => write_archive(&files, &manifest, codec, io::stdout().lock())?.0,
+            false => match self.split {
+                Some(size) => {
+                    pack_volumes(&files, &manifest, codec, &output, size as u64, self.force)?
+                }
+                None => pack_file(&files, &manifest, codec, &output, self.force)?,
+            },
+        };
+        Ok((manifest, bytes))
+    }
+}
+
+/// Describes the file `path` in the folder `dir`, decompressing a data file
+/// to learn its size.
+pub(crate) fn survey(dir: &Path, path: &Path) -> Result<Member, Failure> {
+    let mut member = describe(dir, path)?;
+    if member.kind == Kind::Compressed {
+        let reader = open(path)?;
+        let summary =
+            dfcompress::recode_with(reader, io::sink(), Target::Uncompressed, &Options::new())
+                .map_err(|e| failed(path, e))?;
+        member.size = summary.bytes_out;
+    }
+    Ok(member)
+}
+
+/// When a file was last modified, in seconds and nanoseconds since the Unix
+/// epoch, as members keep it.
+pub(crate) fn modified(metadata: &fs::Metadata) -> Option<(u64, u32)> {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|time| (time.as_secs(), time.subsec_nanos()))
+}
+
+/// Describes the file `path` in the folder `dir` like `survey`, but with the
+/// size of the file itself for a compressed data file.
+pub(crate) fn describe(dir: &Path, path: &Path) -> Result<Member, Failure> {
+    let relative = path.strip_prefix(dir).unwrap_or(path);
+    let mut names = Vec::new();
+    for component in relative.components() {
+        match component {
+            Component::Normal(name) => names.push(
+                name.to_str()
+                    .ok_or_else(|| at(path, "The name is not valid UTF-8"))?,
+            ),
+            _ => return Err(at(path, "Not in the folder")),
+        }
+    }
+    let metadata = fs::metadata(path).map_err(|e| at(path, e))?;
+    let mut member = Member {
+        path: names.join("/"),
+        size: metadata.len(),
+        kind: Kind::Other,
+        version: None,
+        modified: modified(&metadata),
+    };
+    let mut header = [0; 8];
+    let read = File::open(path).and_then(|mut file| file.read_exact(&mut header));
+    let header = match read.ok().and_then(|()| Header::parse(&header).ok()) {
+        Some(header) if is_data_file(path) => header,
+        _ => return Ok(member),
+    };
+    member.version = Some(header.version);
+    member.kind = match header.compression {
+        Compression::Uncompressed => Kind::Uncompressed,
+        _ => Kind::Compressed,
+    };
+    Ok(member)
+}
+
+/// Like `write_archive`, into the file `output`, which is removed if that
+/// fails.
+fn pack_file(
+    files: &[PathBuf],
+    manifest: &Manifest,
+    codec: ArchiveCodec,
+    output: &Path,
+    force: bool,
+) -> Result<u64, Failure> {
+    create(output, force, |file, _| {
+        let (bytes, writer) = write_archive(files, manifest, codec, BufWriter::new(file))?;
+        let file = writer
+            .into_inner()
+            .map_err(|e| at(output, e.into_error()))?;
+        file.sync_all().map_err(|e| at(output, e))?;
+        Ok(bytes)
+    })
+}
+
+/// Like `write_archive`, into volumes of `size` bytes of `output`, which are
+/// removed if that fails.
+fn pack_volumes(
+    files: &[PathBuf],
+    manifest: &Manifest,
+    codec: ArchiveCodec,
+    output: &Path,
+    size: u64,
+    force: bool,
+) -> Result<u64, Failure> {
+    let mut volumes = Volumes::new(output, size, force)?;
+    let result = write_archive(files, manifest, codec, &mut volumes).map(|(bytes, _)| bytes);
+    let result = result.and_then(|bytes| volumes.finish().map(|_| bytes));
+    if result.is_err() {
+        volumes.remove();
+    }
+    result
+}
+
+/// Writes the archive of the `files` that `manifest` describes to `output`.
+/// Returns the bytes written, and `output`.
+fn write_archive<W: Write>(
+    files: &[PathBuf],
+    manifest: &Manifest,
+    codec: ArchiveCodec,
+    output: W,
+) -> Result<(u64, W), Failure> {
+    let written = |e: io::Error| Failure::new(Status::Io, format!("Writing the archive: {}", e));
+    let mut output = Counted {
+        inner: output,
+        count: 0,
+    };
+    output.write_all(MAGIC).map_err(written)?;
+    output.write_all(&[codec as u8]).map_err(written)?;
+    let mut stream = Encoder::new(codec, output).map_err(written)?;
+    let json = serde_json::to_vec(manifest).expect("serializable");
+    stream
+        .write_all(&(json.len() as u32).to_le_bytes())
+        .map_err(written)?;
+    stream.write_all(&json).map_err(written)?;
+    for (path, member) in files.iter().zip(&manifest.members) {
+        info!("{}: packing {} bytes", path.display(), member.size);
+        let size = match member.kind {
+            Kind::Compressed => {
+                let reader = open(path)?;
+                dfcompress::recode_with(reader, &mut stream, Target::Uncompressed, &Options::new())
+                    .map_err(|e| failed(path, e))?
+                    .bytes_out
+            }
+            Kind::Uncompressed | Kind::Other => {
+                let mut file = File::open(path).map_err(|e| at(path, e))?;
+                io::copy(&mut file, &mut stream).map_err(|e| at(path, e))?
+            }
+        };
+        if size != member.size {
+            return Err(at(path, "Changed while it was packed"));
+        }
+    }
+    let mut output = stream.finish().map_err(written)?;
+    output.flush().map_err(written)?;
+    Ok((output.count, output.inner))
+}
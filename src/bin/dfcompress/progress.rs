@@ -0,0 +1,97 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+//! Progress bars on standard error, which are only drawn on a terminal.
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use dfcompress::Options;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+
+use super::is_stdio;
+use super::stats::Stats;
+
+pub(crate) struct Progress {
+    bars: MultiProgress,
+    /// Counts the files done, if there are several.
+    files: Option<ProgressBar>,
+}
+
+impl Progress {
+    /// Bars for `files` files, which are not drawn unless `draw`.
+    pub(crate) fn new(files: usize, draw: bool) -> Progress {
+        let target = match draw {
+            true => ProgressDrawTarget::stderr(),
+            false => ProgressDrawTarget::hidden(),
+        };
+        let bars = MultiProgress::with_draw_target(target);
+        let files = (files > 1).then(|| {
+            let style =
+                ProgressStyle::with_template("{bar:40} {pos}/{len} files").expect("valid template");
+            bars.add(ProgressBar::new(files as u64).with_style(style))
+        });
+        Progress { bars, files }
+    }
+
+    /// A bar for the bytes of `path` read so far.
+    pub(crate) fn start(&self, path: &Path) -> ProgressBar {
+        let len = match is_stdio(path) {
+            true => None,
+            false => fs::metadata(path).ok().map(|m| m.len()),
+        };
+        let template = match len {
+            Some(_) => "{bar:40} {bytes}/{total_bytes} {wide_msg}",
+            None => "{spinner} {bytes} {wide_msg}",
+        };
+        let style = ProgressStyle::with_template(template).expect("valid template");
+        let bar = match len {
+            Some(len) => ProgressBar::new(len),
+            None => ProgressBar::new_spinner(),
+        };
+        let bar = bar
+            .with_style(style)
+            .with_message(path.display().to_string());
+        self.bars.add(bar)
+    }
+
+    /// Removes the bar of a file.
+    pub(crate) fn done(&self, bar: ProgressBar) {
+        bar.finish_and_clear();
+        self.bars.remove(&bar);
+        if let Some(ref files) = self.files {
+            files.inc(1);
+        }
+    }
+
+    /// Prints `line` to standard output without mixing it up with the bars.
+    pub(crate) fn println(&self, line: &str) {
+        self.bars.suspend(|| println!("{}", line));
+    }
+
+    /// Prints an error without mixing it up with the bars.
+    pub(crate) fn eprintln(&self, line: &str) {
+        self.bars.suspend(|| eprintln!("{}", line));
+    }
+
+    pub(crate) fn finish(&self) {
+        if let Some(ref files) = self.files {
+            files.finish_and_clear();
+        }
+    }
+}
+
+/// `options` that also move `bar` along, and note the chunks in `stats`.
+pub(crate) fn with_bar(
+    options: &Options,
+    bar: &ProgressBar,
+    stats: Option<&Arc<Mutex<Stats>>>,
+) -> Options {
+    let bar = bar.clone();
+    let stats = stats.cloned();
+    options.clone().on_progress(move |bytes_in, bytes_out, _| {
+        bar.set_position(bytes_in);
+        if let Some(ref stats) = stats {
+            stats.lock().unwrap().chunk(bytes_in, bytes_out);
+        }
+    })
+}
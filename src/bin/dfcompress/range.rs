@@ -0,0 +1,118 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+//! Decompressing only the bytes from `--offset` on, or `--length` of them.
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use dfcompress::{decompress_range, Compression, Header};
+use log::{info, warn};
+
+use super::batch::glob_all;
+use super::convert::{create, failed};
+use super::exit::{Failure, Status};
+use super::{at, is_stdio, open, to_terminal, Convert};
+
+impl Convert {
+    /// Writes the range of the decompressed file that `--offset` and
+    /// `--length` ask for.
+    pub(crate) fn run_range(self) -> Status {
+        if self.output.is_none() && to_terminal(self.stdout) {
+            return Status::Usage;
+        }
+        match self.range() {
+            Ok(()) => Status::Success,
+            Err(e) => {
+                eprintln!("{}", e);
+                e.status
+            }
+        }
+    }
+
+    fn range(&self) -> Result<(), Failure> {
+        let mut inputs = glob_all(self.inputs.clone())?;
+        let input = match inputs.len() {
+            0 => PathBuf::from("-"),
+            1 => inputs.remove(0),
+            _ => {
+                let message = "--offset and --length take one input";
+                return Err(Failure::new(Status::Usage, message));
+            }
+        };
+        let offset = self.offset.unwrap_or(0);
+        let written = match self.output.as_deref().filter(|output| !is_stdio(output)) {
+            Some(output) => write_file(&input, output, offset, self.length, self.force)?,
+            None => {
+                let mut stdout = io::stdout().lock();
+                let written = extract(&input, &mut stdout, offset, self.length)?;
+                stdout
+                    .flush()
+                    .map_err(|e| Failure::new(Status::Io, format!("Writing the range: {}", e)))?;
+                written
+            }
+        };
+        info!(
+            "{}: {} bytes from offset {}",
+            input.display(),
+            written,
+            offset
+        );
+        if self.length.is_some_and(|length| written < length) {
+            warn!(
+                "{}: the decompressed file ends {} bytes after offset {}",
+                input.display(),
+                written,
+                offset
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Writes the bytes of the decompressed `input` from `offset` on, up to
+/// `length` of them, to `w`. Returns the number written. The offsets count
+/// the header, as those of `diff` do.
+fn extract<W: Write>(
+    input: &Path,
+    mut w: W,
+    offset: u64,
+    length: Option<u64>,
+) -> Result<u64, Failure> {
+    let mut reader = open(input)?;
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes).map_err(|e| at(input, e))?;
+    let header = Header::parse(&bytes).map_err(|e| at(input, e))?;
+    // That of the decompressed file.
+    let uncompressed = Header::new(header.version, Compression::Uncompressed).to_bytes();
+    let end = length.map_or(u64::MAX, |length| offset.saturating_add(length));
+    let written = |e: io::Error| Failure::new(Status::Io, format!("Writing the range: {}", e));
+    let head = &uncompressed[offset.min(8) as usize..end.min(8) as usize];
+    w.write_all(head).map_err(written)?;
+    let length = length.map(|_| end.saturating_sub(offset.max(8)));
+    let payload = decompress_range(
+        (&bytes[..]).chain(reader),
+        w,
+        offset.saturating_sub(8),
+        length,
+    )
+    .map_err(|e| failed(input, e))?;
+    Ok(head.len() as u64 + payload)
+}
+
+/// Like `extract`, into the file `output`, which is removed if that fails.
+fn write_file(
+    input: &Path,
+    output: &Path,
+    offset: u64,
+    length: Option<u64>,
+    force: bool,
+) -> Result<u64, Failure> {
+    create(output, force, |file, _| {
+        let mut writer = BufWriter::new(file);
+        let written = extract(input, &mut writer, offset, length)?;
+        let file = writer
+            .into_inner()
+            .map_err(|e| at(output, e.into_error()))?;
+        file.sync_all().map_err(|e| at(output, e))?;
+        Ok(written)
+    })
+}
@@ -0,0 +1,258 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+//! The `repair` subcommand, which salvages what it can of a damaged file.
+use std::fmt;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use dfcompress::{Chunks, Compression, ErrorKind, Header, Options, Recoder, Target};
+use serde::Serialize;
+
+use super::convert::{check_distinct, create, failed, fill};
+use super::exit::{Cause, Failure, Status};
+use super::{at, is_stdio, open, to_terminal, Repair, Verbosity};
+
+/// A chunk that did not decompress.
+#[derive(Serialize)]
+struct Loss {
+    chunk: u64,
+    /// The offset in the input of the chunk's length prefix.
+    offset: u64,
+    error: String,
+    /// The bytes that were decompressed before the error, which are kept.
+    recovered: u64,
+}
+
+/// What was salvaged of a file.
+#[derive(Serialize)]
+struct Salvage {
+    version: u32,
+    /// The chunks that decompressed entirely.
+    chunks: u64,
+    /// The decompressed bytes written, without the header.
+    bytes: u64,
+    lost: Vec<Loss>,
+    /// The bytes of the input after the last chunk read, which were given
+    /// up on.
+    unread: u64,
+}
+
+impl fmt::Display for Salvage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "recovered {} chunks and {} bytes of version {}",
+            self.chunks, self.bytes, self.version
+        )?;
+        for loss in &self.lost {
+            write!(
+                f,
+                "\nlost chunk {} at offset {}: {} ({} bytes of it recovered)",
+                loss.chunk, loss.offset, loss.error, loss.recovered
+            )?;
+        }
+        if self.unread > 0 {
+            write!(f, "\ngave up on the {} bytes after it", self.unread)?;
+        }
+        Ok(())
+    }
+}
+
+impl Repair {
+    /// Salvages the input, printing what was lost. Exits with `Corrupt` if
+    /// anything was, even though the output is kept.
+    pub(crate) fn run(self, verbosity: Verbosity) -> Status {
+        if self.output.is_none() && to_terminal(self.stdout) {
+            return Status::Usage;
+        }
+        let input = self.input.unwrap_or_else(|| PathBuf::from("-"));
+        let output = self.output.unwrap_or_else(|| PathBuf::from("-"));
+        let target = match self.decompress {
+            true => Target::Uncompressed,
+            false => Target::Compressed,
+        };
+        let result = match is_stdio(&output) {
+            true => repair(&input, io::stdout().lock(), target, self.skip_bad),
+            false => repair_file(&input, &output, self.force, target, self.skip_bad),
+        };
+        let salvage = match result {
+            Ok(salvage) => salvage,
+            Err(e) => {
+                eprintln!("{}", e);
+                return e.status;
+            }
+        };
+        let report = match self.json {
+            true => Some(serde_json::to_string(&salvage).expect("serializable")),
+            false => (!verbosity.quiet).then(|| salvage.to_string()),
+        };
+        match (report, is_stdio(&output)) {
+            (Some(report), true) => eprintln!("{}", report),
+            (Some(report), false) => println!("{}", report),
+            (None, _) => (),
+        }
+        match salvage.lost.is_empty() {
+            true => Status::Success,
+            false => Status::Corrupt,
+        }
+    }
+}
+
+/// Like `repair`, into the file `output`, which is removed if that fails.
+fn repair_file(
+    input: &Path,
+    output: &Path,
+    force: bool,
+    target: Target,
+    skip_bad: bool,
+) -> Result<Salvage, Failure> {
+    if !is_stdio(input) {
+        check_distinct(input, output).map_err(|e| Failure::new(Status::Usage, e))?;
+    }
+    create(output, force, |file, _| {
+        let mut writer = BufWriter::new(file);
+        let salvage = repair(input, &mut writer, target, skip_bad)?;
+        let file = writer
+            .into_inner()
+            .map_err(|e| at(output, e.into_error()))?;
+        file.sync_all().map_err(|e| at(output, e))?;
+        Ok(salvage)
+    })
+}
+
+/// Writes the chunks of `input` that decompress to `output`, stopping at the
+/// first that does not unless `skip_bad`.
+fn repair<W: Write>(
+    input: &Path,
+    mut output: W,
+    target: Target,
+    skip_bad: bool,
+) -> Result<Salvage, Failure> {
+    let chunks = Chunks::new(open(input)?).map_err(|e| failed(input, e))?;
+    let header = chunks.header();
+    let mut reader = chunks.into_inner();
+    let mut codec = header.compression.codec().unwrap_or_default().build(None);
+    // The salvaged data is fed to the recoder as an uncompressed file.
+    let mut recoder = Recoder::with_options(target, &Options::new());
+    let mut push = |data: &[u8]| {
+        recoder.push(data)?;
+        output.write_all(recoder.output())?;
+        recoder.output().clear();
+        dfcompress::Result::Ok(())
+    };
+    let mut salvage = Salvage {
+        version: header.version,
+        chunks: 0,
+        bytes: 0,
+        lost: Vec::new(),
+        unread: 0,
+    };
+    let written = |e: dfcompress::Error| Failure::new(e.status(), e);
+    push(&Header::new(header.version, Compression::Uncompressed).to_bytes()).map_err(written)?;
+    let (mut i, mut offset) = (0, 8);
+    let (mut chunk, mut buf) = (Vec::new(), Vec::new());
+    loop {
+        let len = match read_chunk(&mut reader, &mut chunk) {
+            Ok(Some(len)) => len,
+            Ok(None) => break,
+            Err(e) => {
+                salvage.lost.push(Loss {
+                    chunk: i,
+                    offset,
+                    error: dfcompress::Error::from(e).to_string(),
+                    recovered: 0,
+                });
+                break;
+            }
+        };
+        // What there is of a chunk cut short is decompressed as far as it
+        // goes.
+        let cut = (chunk.len() as u64) < len;
+        buf.clear();
+        let result = codec.decompress_to(&chunk, &mut buf, usize::MAX);
+        push(&buf).map_err(written)?;
+        salvage.bytes += buf.len() as u64;
+        let error = match (result, cut) {
+            (_, true) => Some(dfcompress::Error::from(ErrorKind::UnexpectedEof)),
+            (Err(e), false) => Some(e.into()),
+            (Ok(()), false) => None,
+        };
+        match error {
+            Some(e) => {
+                salvage.lost.push(Loss {
+                    chunk: i,
+                    offset,
+                    error: e.to_string(),
+                    recovered: buf.len() as u64,
+                });
+                if cut || !skip_bad {
+                    break;
+                }
+            }
+            None => salvage.chunks += 1,
+        }
+        i += 1;
+        offset += 4 + len;
+    }
+    salvage.unread = io::copy(&mut reader, &mut io::sink()).map_err(|e| at(input, e))?;
+    recoder.finish().map_err(written)?;
+    output
+        .write_all(recoder.output())
+        .map_err(|e| Failure::new(e.status(), e))?;
+    Ok(salvage)
+}
+
+/// Reads the next chunk of `reader` into `buf`, keeping what there is of one
+/// cut short by the end of the input. Returns the length its prefix gives,
+/// or `None` at the end of the input.
+fn read_chunk<R: Read>(reader: &mut R, buf: &mut Vec<u8>) -> io::Result<Option<u64>> {
+    let mut prefix = [0; 4];
+    match fill(reader, &mut prefix)? {
+        0 => return Ok(None),
+        4 => (),
+        _ => return Err(io::ErrorKind::UnexpectedEof.into()),
+    }
+    let len = u32::from_le_bytes(prefix) as u64;
+    buf.clear();
+    reader.take(len).read_to_end(buf)?;
+    Ok(Some(len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::scratch;
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn truncated_repair_test() {
+        let dir = scratch("repair");
+        let mut data = Header::new(1625, Compression::Uncompressed)
+            .to_bytes()
+            .to_vec();
+        data.extend((0..50000usize).map(|i| (i * i % 251) as u8));
+        let compressed = dfcompress::compress_bytes(&data).unwrap();
+        let last = Chunks::new(&compressed[..])
+            .unwrap()
+            .nth(2)
+            .unwrap()
+            .unwrap();
+        let cut = last.offset as usize + 4 + last.data.len() / 2;
+        let input = dir.join("world.sav");
+        fs::write(&input, &compressed[..cut]).unwrap();
+
+        let mut output = Vec::new();
+        let salvage = repair(&input, &mut output, Target::Uncompressed, false).unwrap();
+        assert_eq!(salvage.chunks, 2);
+        assert_eq!(salvage.lost.len(), 1);
+        let loss = &salvage.lost[0];
+        assert_eq!((loss.chunk, loss.offset), (2, last.offset));
+        assert_eq!(loss.error, "Unexpected end-of-file");
+        assert!(loss.recovered > 0);
+        assert_eq!(salvage.bytes, 40000 + loss.recovered);
+        assert_eq!(salvage.unread, 0);
+        assert_eq!(output.len() as u64, 8 + salvage.bytes);
+        assert_eq!(output, data[..output.len()]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,423 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+//! The `rotate` subcommand, which keeps the newest seasonal autosaves of each
+//! region in a save directory, compressed at the best level, and archives or
+//! removes the older ones.
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use clap::ValueEnum;
+use dfcompress::{Compression, Header, Options, Target};
+use log::info;
+use serde_json::{json, Value};
+
+use super::archive::open_archive;
+use super::batch::{all_files, default_threads, expand, Done};
+use super::convert::{convert_in_place, WriteOptions};
+use super::exit::{Failure, Status};
+use super::progress::with_bar;
+use super::{at, dry_run_json, Pack, Rotate, Verbosity};
+
+/// What becomes of the autosaves older than those kept.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum Policy {
+    /// Pack each into an archive, then remove it (the default)
+    Archive,
+    /// Remove them
+    Prune,
+}
+
+/// The seasons of the names of autosaves, in the order of the year.
+const SEASONS: [&str; 4] = ["spr", "sum", "aut", "win"];
+
+/// An autosave folder, named `REGION-SEASON-YEAR` as Dwarf Fortress names
+/// them with `[AUTOBACKUP:YES]`, such as `region1-spr-1051`.
+struct Autosave {
+    path: PathBuf,
+    region: String,
+    year: u32,
+    season: usize,
+}
+
+impl Autosave {
+    fn parse(path: PathBuf) -> Option<Autosave> {
+        let name = path.file_name()?.to_str()?;
+        let mut parts = name.rsplitn(3, '-');
+        let year = parts.next()?;
+        if year.is_empty() || !year.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let year = year.parse().ok()?;
+        let season = parts.next()?;
+        let season = SEASONS.iter().position(|&s| s == season)?;
+        let region = parts
+            .next()
+            .filter(|region| !region.is_empty())?
+            .to_string();
+        Some(Autosave {
+            path,
+            region,
+            year,
+            season,
+        })
+    }
+}
+
+/// Splits the autosaves among `folders` into the `keep` newest of each region
+/// and the older ones, leaving out the folders that are not autosaves.
+fn choose(folders: Vec<PathBuf>, keep: usize) -> (Vec<PathBuf>, Vec<PathBuf>) {
+    let mut regions: BTreeMap<String, Vec<Autosave>> = BTreeMap::new();
+    for autosave in folders.into_iter().filter_map(Autosave::parse) {
+        regions
+            .entry(autosave.region.clone())
+            .or_default()
+            .push(autosave);
+    }
+    let (mut kept, mut older) = (Vec::new(), Vec::new());
+    for autosaves in regions.values_mut() {
+        autosaves.sort_by_key(|autosave| std::cmp::Reverse((autosave.year, autosave.season)));
+        let newest = keep.min(autosaves.len());
+        kept.extend(autosaves.drain(..newest).map(|autosave| autosave.path));
+        older.extend(autosaves.drain(..).map(|autosave| autosave.path));
+    }
+    (kept, older)
+}
+
+/// The bytes of the files in `dir`.
+fn folder_size(dir: &Path) -> Result<u64, Failure> {
+    let mut files = Vec::new();
+    all_files(dir, &[], &mut files)?;
+    let mut size = 0;
+    for path in &files {
+        size += fs::metadata(path).map_err(|e| at(path, e))?.len();
+    }
+    Ok(size)
+}
+
+/// Whether `path` is compressed with zlib at the best level already, as the
+/// zlib header of its first chunk says.
+fn at_best_level(path: &Path) -> bool {
+    let mut bytes = Vec::new();
+    let read = File::open(path).and_then(|file| file.take(14).read_to_end(&mut bytes));
+    let zlib = read.is_ok()
+        && bytes.len() == 14
+        && Header::parse(&bytes).is_ok_and(|header| header.compression == Compression::Zlib);
+    zlib && bytes[13] >> 6 == 3
+}
+
+/// Checks that the archive `path` reads to its end, with the members of its
+/// manifest in full, before the folder in it is removed.
+fn check_archive(path: &Path) -> Result<(), Failure> {
+    let file = File::open(path).map_err(|e| at(path, e))?;
+    let (manifest, mut stream) = open_archive(BufReader::new(file)).map_err(|e| at(path, e))?;
+    let size: u64 = manifest.members.iter().map(|member| member.size).sum();
+    let read = io::copy(&mut stream, &mut io::sink()).map_err(|e| at(path, e))?;
+    match read == size {
+        true => Ok(()),
+        false => Err(at(path, "The archive does not hold the whole folder")),
+    }
+}
+
+impl Rotate {
+    pub(crate) fn run(self, verbosity: Verbosity) -> Status {
+        match self.rotate(verbosity) {
+            Ok(status) => status,
+            Err(e) => {
+                eprintln!("{}", e);
+                e.status
+            }
+        }
+    }
+
+    fn rotate(&self, verbosity: Verbosity) -> Result<Status, Failure> {
+        let dir = self.dir.as_ref().ok_or_else(|| {
+            let message = "rotate needs a SAVEDIR, or save-dir in the configuration file";
+            Failure::new(Status::Usage, message)
+        })?;
+        let mut folders = Vec::new();
+        for entry in fs::read_dir(dir).map_err(|e| at(dir, e))? {
+            let path = entry.map_err(|e| at(dir, e))?.path();
+            if path.is_dir() {
+                folders.push(path);
+            }
+        }
+        let (kept, older) = choose(folders, self.keep.get());
+        let report = |line: String| {
+            if !verbosity.quiet || self.json {
+                println!("{}", line);
+            }
+        };
+        let (mut status, mut saved) = (Status::Success, 0);
+        if !kept.is_empty() {
+            let (batch_status, batch_saved) = self.compress(kept.clone(), verbosity)?;
+            status = batch_status;
+            saved += batch_saved;
+        }
+        let mut rotated = 0;
+        for folder in &older {
+            match self.rotate_folder(folder) {
+                Ok((line, freed)) => {
+                    report(line);
+                    rotated += 1;
+                    saved += freed;
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    if status == Status::Success {
+                        status = Status::Partial;
+                    }
+                }
+            }
+        }
+        let action = match self.policy {
+            Policy::Archive => "archived",
+            Policy::Prune => "removed",
+        };
+        report(match self.json {
+            true => json!({
+                "dir": dir,
+                "kept": kept.len(),
+                action: rotated,
+                "saved": saved,
+                "dry_run": self.dry_run,
+            })
+            .to_string(),
+            false => format!(
+                "{}: {} autosaves kept, {} {}, saving {} bytes",
+                dir.display(),
+                kept.len(),
+                rotated,
+                action,
+                saved
+            ),
+        });
+        Ok(status)
+    }
+
+    /// Compresses the data files of the `kept` autosaves at the best level,
+    /// but for those that are already. Returns the status of the batch and
+    /// the bytes saved.
+    fn compress(&self, kept: Vec<PathBuf>, verbosity: Verbosity) -> Result<(Status, u64), Failure> {
+        let mut batch = expand(kept, true, &[])?;
+        batch.files.retain(|path| !at_best_level(path));
+        batch.report = false;
+        batch.json = self.json;
+        batch.verdicts = true;
+        batch.verbosity = verbosity;
+        let threads = self.threads.map_or_else(default_threads, NonZeroUsize::get);
+        let options = Options::new().best().recompress(true);
+        let write = WriteOptions {
+            dry_run: self.dry_run,
+            wait: self.wait,
+            only_smaller: true,
+            ..WriteOptions::default()
+        };
+        let saved = AtomicU64::new(0);
+        let status = batch.run(threads, |path, bar| {
+            let options = with_bar(&options, bar, None);
+            let summary = convert_in_place(path, Target::Compressed, &options, write, None)?;
+            let freed = summary.bytes_in.saturating_sub(summary.bytes_out);
+            saved.fetch_add(freed, Ordering::Relaxed);
+            Ok(Done {
+                line: Some(format!(
+                    "{} -> {} bytes",
+                    summary.bytes_in, summary.bytes_out
+                )),
+                summary: Some(summary),
+                json: dry_run_json(self.dry_run),
+                ..Done::default()
+            })
+        });
+        Ok((status, saved.into_inner()))
+    }
+
+    /// Archives or removes the autosave `folder`, as the policy says.
+    /// Returns the report and the bytes freed.
+    fn rotate_folder(&self, folder: &Path) -> Result<(String, u64), Failure> {
+        let size = folder_size(folder)?;
+        let mut object = dry_run_json(self.dry_run);
+        object.insert("folder".into(), json!(folder));
+        object.insert("size".into(), json!(size));
+        let (line, freed) = match self.policy {
+            Policy::Prune => {
+                if !self.dry_run {
+                    info!("{}: removing", folder.display());
+                    fs::remove_dir_all(folder).map_err(|e| at(folder, e))?;
+                }
+                object.insert("action".into(), json!("removed"));
+                let line = match self.dry_run {
+                    true => format!("{} bytes, would be removed", size),
+                    false => format!("{} bytes, removed", size),
+                };
+                (line, size)
+            }
+            Policy::Archive => {
+                let archives = self.archive_dir.as_deref().or(self.dir.as_deref());
+                let name = folder.file_name().expect("named").to_owned();
+                let mut output = archives.expect("a save directory").join(name);
+                output.as_mut_os_string().push(".dfpack");
+                let bytes = match self.dry_run {
+                    true => 0,
+                    false => self.archive(folder, &output)?,
+                };
+                object.insert("action".into(), json!("archived"));
+                object.insert("archive".into(), json!(output));
+                object.insert("archive_size".into(), json!(bytes));
+                let line = match self.dry_run {
+                    true => format!(
+                        "{} bytes, would be archived into {}",
+                        size,
+                        output.display()
+                    ),
+                    false => format!(
+                        "{} -> {} bytes, archived into {}",
+                        size,
+                        bytes,
+                        output.display()
+                    ),
+                };
+                (line, size.saturating_sub(bytes))
+            }
+        };
+        let line = match self.json {
+            true => Value::Object(object).to_string(),
+            false => format!("{}: {}", folder.display(), line),
+        };
+        Ok((line, freed))
+    }
+
+    /// Packs `folder` into the archive `output`, checks the archive and
+    /// removes the folder. Returns the size of the archive.
+    fn archive(&self, folder: &Path, output: &Path) -> Result<u64, Failure> {
+        if let Some(dir) = output.parent() {
+            fs::create_dir_all(dir).map_err(|e| at(dir, e))?;
+        }
+        let pack = Pack {
+            dir: folder.to_path_buf(),
+            output: Some(output.to_path_buf()),
+            stdout: false,
+            force: false,
+            codec: None,
+            split: None,
+            deterministic: false,
+            exclude: Vec::new(),
+            json: false,
+        };
+        let (_, bytes) = pack.write()?;
+        check_archive(output)?;
+        info!("{}: archived; removing", folder.display());
+        fs::remove_dir_all(folder).map_err(|e| at(folder, e))?;
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::scratch;
+    use super::*;
+
+    #[test]
+    fn parse_test() {
+        let parse = |name: &str| {
+            Autosave::parse(PathBuf::from("save").join(name))
+                .map(|autosave| (autosave.region, autosave.season, autosave.year))
+        };
+        assert_eq!(parse("region1-spr-1051"), Some(("region1".into(), 0, 1051)));
+        assert_eq!(parse("region1-win-0"), Some(("region1".into(), 3, 0)));
+        assert_eq!(parse("my-fort-aut-250"), Some(("my-fort".into(), 2, 250)));
+        for name in [
+            "region1",
+            "current",
+            "region1-spr",
+            "-spr-1051",
+            "region1-spring-1051",
+            "region1-SPR-1051",
+            "region1-spr-",
+            "region1-spr-+1051",
+            "region1-spr-1051a",
+            "region1-spr-1051.dfpack",
+            "region1-spr-99999999999",
+            "spr-1051",
+        ] {
+            assert_eq!(parse(name), None, "{}", name);
+        }
+    }
+
+    #[test]
+    fn choose_test() {
+        let folders = [
+            "region1",
+            "region1-aut-1050",
+            "region1-spr-1051",
+            "region2-win-1049",
+            "current",
+            "region1-sum-1051",
+            "region1-win-1050",
+            "region1-spr-1051-copy",
+            "notes",
+        ];
+        let folders: Vec<PathBuf> = folders.iter().map(PathBuf::from).collect();
+        let names = |paths: Vec<PathBuf>| -> Vec<String> {
+            paths.iter().map(|p| p.display().to_string()).collect()
+        };
+        let (kept, older) = choose(folders.clone(), 2);
+        assert_eq!(
+            names(kept),
+            ["region1-sum-1051", "region1-spr-1051", "region2-win-1049"]
+        );
+        assert_eq!(names(older), ["region1-win-1050", "region1-aut-1050"]);
+        let (kept, older) = choose(folders, 10);
+        assert_eq!(kept.len(), 5);
+        assert!(older.is_empty());
+    }
+
+    #[test]
+    fn prune_test() {
+        let dir = scratch("rotate");
+        for name in [
+            "region1",
+            "region1-spr-1051",
+            "region1-sum-1051",
+            "region1-aut-1050",
+            "region1-spr-1051-copy",
+        ] {
+            fs::create_dir(dir.join(name)).unwrap();
+            fs::write(dir.join(name).join("notes.txt"), name).unwrap();
+        }
+        fs::write(dir.join("region1-win-1049"), b"a file").unwrap();
+        let rotate = Rotate {
+            dir: Some(dir.clone()),
+            keep: NonZeroUsize::new(1).unwrap(),
+            policy: Policy::Prune,
+            archive_dir: None,
+            dry_run: false,
+            wait: false,
+            threads: None,
+            json: false,
+        };
+        let verbosity = Verbosity {
+            quiet: true,
+            ..Verbosity::default()
+        };
+        assert_eq!(rotate.rotate(verbosity).unwrap(), Status::Success);
+        let mut left: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        left.sort();
+        assert_eq!(
+            left,
+            [
+                "region1",
+                "region1-spr-1051-copy",
+                "region1-sum-1051",
+                "region1-win-1049"
+            ]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
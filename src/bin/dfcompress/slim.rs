@@ -0,0 +1,206 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+//! The `slim` subcommand, which recompresses a region folder as small as
+//! it goes while Dwarf Fortress still loads it, or packs it into an archive.
+use std::fs::{self, File};
+use std::io::Read;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use dfcompress::{Compression, Header, Options, Target};
+use serde_json::{json, Value};
+
+use super::batch::{all_files, default_threads, expand, Done};
+use super::convert::{convert_in_place, WriteOptions};
+use super::exit::{Failure, Status};
+use super::progress::with_bar;
+use super::{at, dry_run_json, Pack, Slim, Verbosity};
+
+/// The bytes of the files slimmed, before and after.
+#[derive(Default)]
+struct Total {
+    files: usize,
+    before: u64,
+    after: u64,
+}
+
+impl Total {
+    /// Describes the savings, for the report of `dir`.
+    fn report(&self, dir: &Path, json: bool) -> String {
+        let saved = self.before.saturating_sub(self.after);
+        match json {
+            true => json!({
+                "dir": dir,
+                "files": self.files,
+                "bytes_in": self.before,
+                "bytes_out": self.after,
+                "saved": saved,
+            })
+            .to_string(),
+            false => format!(
+                "{}: {} files, {} -> {} bytes, saving {} bytes ({:.1}%)",
+                dir.display(),
+                self.files,
+                self.before,
+                self.after,
+                saved,
+                percent(saved, self.before)
+            ),
+        }
+    }
+}
+
+fn percent(part: u64, whole: u64) -> f64 {
+    match whole {
+        0 => 0.0,
+        _ => part as f64 * 100.0 / whole as f64,
+    }
+}
+
+impl Slim {
+    pub(crate) fn run(self, verbosity: Verbosity) -> Status {
+        if self.archive {
+            return match self.pack() {
+                Ok(report) => {
+                    if !verbosity.quiet || self.json {
+                        println!("{}", report);
+                    }
+                    Status::Success
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    e.status
+                }
+            };
+        }
+        let mut batch = match expand(vec![self.dir.clone()], true, &self.exclude) {
+            Ok(batch) => batch,
+            Err(e) => {
+                eprintln!("{}", e);
+                return e.status;
+            }
+        };
+        // The total is one of savings, printed below.
+        batch.report = false;
+        batch.json = self.json;
+        batch.verdicts = true;
+        batch.verbosity = verbosity;
+        let threads = self.threads.map_or_else(default_threads, NonZeroUsize::get);
+        let options = Options::new().level(9).recompress(true);
+        let write = WriteOptions {
+            verify: self.verify,
+            dry_run: self.dry_run,
+            wait: self.wait,
+            only_smaller: true,
+            ..WriteOptions::default()
+        };
+        let total = Mutex::new(Total::default());
+        let status = batch.run(threads, |path, bar| {
+            if let Some(reason) = skipped(path)? {
+                let mut json = dry_run_json(self.dry_run);
+                json.insert("skipped".into(), Value::Bool(true));
+                return Ok(Done {
+                    line: Some(format!("{}, left as it is", reason)),
+                    json,
+                    ..Done::default()
+                });
+            }
+            let options = with_bar(&options, bar, None);
+            let summary = convert_in_place(path, Target::Compressed, &options, write, None)?;
+            let (before, after) = (summary.bytes_in, summary.bytes_out);
+            let mut total = total.lock().unwrap();
+            total.files += 1;
+            total.before += before;
+            total.after += after;
+            let line = match after < before {
+                true => format!(
+                    "{} -> {} bytes, saving {:.1}%",
+                    before,
+                    after,
+                    percent(before - after, before)
+                ),
+                false => format!("{} bytes, already as small; left as it is", before),
+            };
+            Ok(Done {
+                line: Some(line),
+                summary: Some(summary),
+                json: dry_run_json(self.dry_run),
+                ..Done::default()
+            })
+        });
+        if !verbosity.quiet || self.json {
+            println!(
+                "{}",
+                total.into_inner().unwrap().report(&self.dir, self.json)
+            );
+        }
+        status
+    }
+
+    /// Packs the folder with the codec that compresses best. Returns the
+    /// report of the savings.
+    fn pack(&self) -> Result<String, Failure> {
+        let mut files = Vec::new();
+        all_files(&self.dir, &self.exclude, &mut files)?;
+        let mut before = 0;
+        for path in &files {
+            before += fs::metadata(path).map_err(|e| at(path, e))?.len();
+        }
+        let output = self.output.clone().unwrap_or_else(|| {
+            let mut name = self.dir.as_os_str().to_owned();
+            name.push(".dfpack");
+            PathBuf::from(name)
+        });
+        let pack = Pack {
+            dir: self.dir.clone(),
+            output: Some(output.clone()),
+            stdout: false,
+            force: self.force,
+            codec: None,
+            split: None,
+            deterministic: false,
+            exclude: self.exclude.clone(),
+            json: self.json,
+        };
+        let (manifest, after) = pack.write()?;
+        let saved = before.saturating_sub(after);
+        Ok(match self.json {
+            true => json!({
+                "dir": self.dir,
+                "output": output,
+                "files": manifest.members.len(),
+                "bytes_in": before,
+                "bytes_out": after,
+                "saved": saved,
+            })
+            .to_string(),
+            false => format!(
+                "{}: {} files, {} bytes -> {} bytes in {}, saving {} bytes ({:.1}%)",
+                self.dir.display(),
+                manifest.members.len(),
+                before,
+                after,
+                output.display(),
+                saved,
+                percent(saved, before)
+            ),
+        })
+    }
+}
+
+/// Why the data file `path` is not recompressed, if it is not: only zlib
+/// files are, as that is what Dwarf Fortress reads compressed.
+fn skipped(path: &Path) -> Result<Option<String>, Failure> {
+    let mut bytes = Vec::new();
+    let file = File::open(path).map_err(|e| at(path, e))?;
+    file.take(8)
+        .read_to_end(&mut bytes)
+        .map_err(|e| at(path, e))?;
+    Ok(match Header::parse(&bytes) {
+        Ok(header) if header.compression == Compression::Zlib => None,
+        Ok(header) if !header.compression.is_compressed() => Some("uncompressed".into()),
+        Ok(header) => Some(format!("compressed with {:?}", header.compression)),
+        Err(_) => Some("not a compressed data file as it is".into()),
+    })
+}
@@ -0,0 +1,47 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+//! The solid files of `--solid`: a data file compressed as one stream rather
+//! than in chunks, which compresses better but which Dwarf Fortress cannot
+//! read.
+//!
+//! A solid file is `MAGIC`, a byte naming the codec of the stream as in an
+//! archive, and the stream, which holds the uncompressed data file, header
+//! and all. It is read like any other data file, so converting it writes
+//! chunks again.
+use std::io::{self, Read, Write};
+
+use super::archive::{ArchiveCodec, Decoder, Encoder};
+use super::exit::{Failure, Status};
+
+/// The start of every solid file, ending in the version of the format.
+pub(crate) const MAGIC: &[u8; 8] = b"dfsolid\x01";
+
+/// Writes the start of a solid file to `writer`, returning the compressor of
+/// the stream that follows.
+pub(crate) fn create<W: Write>(codec: ArchiveCodec, mut writer: W) -> io::Result<Encoder<W>> {
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[codec as u8])?;
+    Encoder::new(codec, writer)
+}
+
+/// Reads the start of a solid file from `reader`, returning the stream of
+/// the data file it holds.
+pub(crate) fn open<R: Read>(mut reader: R) -> Result<Decoder<R>, Failure> {
+    let mut start = [0; 9];
+    reader
+        .read_exact(&mut start)
+        .map_err(|e| Failure::new(Status::Io, e))?;
+    if start[..8] != MAGIC[..] {
+        return Err(Failure::new(Status::NotDataFile, "Not a solid file"));
+    }
+    match ArchiveCodec::from_byte(start[8]) {
+        Some(codec) => Decoder::new(codec, reader).map_err(|e| Failure::new(Status::Io, e)),
+        None => {
+            let message = format!(
+                "The solid file's codec {} is unknown, or not built in",
+                start[8]
+            );
+            Err(Failure::new(Status::NotDataFile, message))
+        }
+    }
+}
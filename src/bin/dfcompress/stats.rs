@@ -0,0 +1,131 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+//! The report of `--stats`.
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use dfcompress::{Options, Summary, Target};
+use indicatif::{HumanBytes, ProgressBar};
+use serde::Serialize;
+
+use super::exit::Failure;
+use super::progress::with_bar;
+
+/// Runs the conversion `f` to `target` with `options` that move `bar` along.
+/// Returns its summary and, if `stats`, the report of `--stats`.
+pub(crate) fn measure<F>(
+    options: &Options,
+    bar: &ProgressBar,
+    target: Target,
+    stats: bool,
+    f: F,
+) -> Result<(Summary, Option<Report>), Failure>
+where
+    F: FnOnce(&Options) -> Result<Summary, Failure>,
+{
+    let stats = stats.then(|| Arc::new(Mutex::new(Stats::new(target))));
+    let start = Instant::now();
+    let summary = f(&with_bar(options, bar, stats.as_ref()))?;
+    let report = stats.map(|stats| stats.lock().unwrap().report(&summary, start.elapsed()));
+    Ok((summary, report))
+}
+
+/// The sizes of the compressed chunks of a conversion, gathered from its
+/// progress callback.
+pub(crate) struct Stats {
+    target: Target,
+    /// Where the last chunk ended in the compressed file.
+    end: u64,
+    chunks: u64,
+    min: u64,
+    max: u64,
+}
+
+impl Stats {
+    pub(crate) fn new(target: Target) -> Stats {
+        Stats {
+            target,
+            end: 8,
+            chunks: 0,
+            min: u64::MAX,
+            max: 0,
+        }
+    }
+
+    /// Notes the chunk that the progress callback reported.
+    pub(crate) fn chunk(&mut self, bytes_in: u64, bytes_out: u64) {
+        let end = match self.target {
+            Target::Compressed => bytes_out,
+            Target::Uncompressed => bytes_in,
+        };
+        // The length prefix is not part of the chunk.
+        let len = end.saturating_sub(self.end + 4);
+        self.end = end;
+        self.chunks += 1;
+        self.min = self.min.min(len);
+        self.max = self.max.max(len);
+    }
+
+    /// Describes the conversion `summary`, which took `elapsed`.
+    pub(crate) fn report(&self, summary: &Summary, elapsed: Duration) -> Report {
+        let compressed = self.end - 8 - 4 * self.chunks;
+        let uncompressed = summary.bytes_in.max(summary.bytes_out);
+        let seconds = elapsed.as_secs_f64();
+        Report {
+            bytes_in: summary.bytes_in,
+            bytes_out: summary.bytes_out,
+            ratio: summary.ratio(),
+            chunks: self.chunks,
+            min_chunk: (self.chunks > 0).then_some(self.min),
+            avg_chunk: compressed.checked_div(self.chunks),
+            max_chunk: (self.chunks > 0).then_some(self.max),
+            seconds,
+            bytes_per_second: (uncompressed as f64 / seconds.max(1e-9)) as u64,
+        }
+    }
+}
+
+/// The statistics of a conversion. Chunk sizes are of compressed chunks.
+#[derive(Serialize)]
+pub(crate) struct Report {
+    bytes_in: u64,
+    bytes_out: u64,
+    ratio: f64,
+    chunks: u64,
+    min_chunk: Option<u64>,
+    avg_chunk: Option<u64>,
+    max_chunk: Option<u64>,
+    seconds: f64,
+    /// Uncompressed bytes per second.
+    bytes_per_second: u64,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "  input:      {}", HumanBytes(self.bytes_in))?;
+        writeln!(
+            f,
+            "  output:     {} ({:.1}%)",
+            HumanBytes(self.bytes_out),
+            100.0 * self.ratio
+        )?;
+        match (self.min_chunk, self.avg_chunk, self.max_chunk) {
+            (Some(min), Some(avg), Some(max)) => writeln!(
+                f,
+                "  chunks:     {}, {} on average (min {}, max {})",
+                self.chunks,
+                HumanBytes(avg),
+                HumanBytes(min),
+                HumanBytes(max)
+            )?,
+            _ => writeln!(f, "  chunks:     none")?,
+        }
+        write!(
+            f,
+            "  time:       {:.2?} ({}/s)",
+            Duration::from_secs_f64(self.seconds),
+            HumanBytes(self.bytes_per_second)
+        )
+    }
+}
@@ -0,0 +1,499 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+//! The content-addressed store of `backup`: the contents of the files of
+//! region folders, decompressed and cut into chunks of `CHUNK_SIZE` bytes,
+//! each kept once however many snapshots have it.
+//!
+//! A store is a directory with `blobs/`, where the chunk whose XXH3-128 hash
+//! is `HASH`, written as 32 hex digits, is `blobs/HA/HASH`: a byte naming
+//! the codec, as in archives, and the chunk compressed with it. And it has
+//! `snapshots/`, where the snapshot `NAME` is `NAME.json`, a manifest
+//! listing each file with the hashes of its chunks.
+use std::fs::{self, File};
+use std::io::{self, Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use std::slice;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use dfcompress::CHUNK_SIZE;
+use serde::{Deserialize, Serialize};
+use twox_hash::XxHash3_128;
+
+use super::archive::{ArchiveCodec, Decoder, Encoder, Member};
+use super::at;
+use super::exit::{Failure, Status};
+use super::interrupt::{remove_on_interrupt, Pending};
+
+/// A store of snapshots.
+pub(crate) struct Store {
+    root: PathBuf,
+}
+
+/// A snapshot of a folder.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Snapshot {
+    pub name: String,
+    /// The folder it is of, canonical, or as it was given to older versions.
+    pub source: PathBuf,
+    /// When it was taken, in seconds since the Unix epoch.
+    pub created: u64,
+    pub files: Vec<Entry>,
+}
+
+/// A file of a snapshot.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Entry {
+    #[serde(flatten)]
+    pub member: Member,
+    /// The hashes of the chunks of its contents, as `pack` would store them.
+    pub chunks: Vec<String>,
+}
+
+impl Snapshot {
+    /// The bytes of its files, decompressed.
+    pub(crate) fn size(&self) -> u64 {
+        self.files.iter().map(|entry| entry.member.size).sum()
+    }
+
+    pub(crate) fn chunks(&self) -> usize {
+        self.files.iter().map(|entry| entry.chunks.len()).sum()
+    }
+}
+
+/// The hash of `data` that names its blob.
+pub(crate) fn hash(data: &[u8]) -> String {
+    format!("{:032x}", XxHash3_128::oneshot(data))
+}
+
+/// The seconds since the Unix epoch.
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
+/// The date of `days` since the Unix epoch: the year, month and day.
+pub(crate) fn civil(days: i64) -> (i64, u32, u32) {
+    // From Howard Hinnant's `civil_from_days`.
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+/// The time `seconds` since the Unix epoch, in UTC, as `2026-10-14 14:30:00`.
+pub(crate) fn date(seconds: u64) -> String {
+    let (year, month, day) = civil((seconds / 86400) as i64);
+    let time = seconds % 86400;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+/// Holds the lock of a store until it is dropped, so that `gc` cannot remove
+/// the chunks a snapshot is being taken with.
+pub(crate) struct Lock {
+    path: PathBuf,
+    _pending: Pending,
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Distinguishes the temporary files that blobs are written to.
+static TEMP: AtomicU64 = AtomicU64::new(0);
+
+impl Store {
+    /// Opens the store at `root`, creating it if `create`.
+    pub(crate) fn open(root: &Path, create: bool) -> Result<Store, Failure> {
+        let store = Store {
+            root: root.to_path_buf(),
+        };
+        for dir in [store.root.join("blobs"), store.root.join("snapshots")] {
+            match create {
+                true => fs::create_dir_all(&dir).map_err(|e| at(&dir, e))?,
+                false if dir.is_dir() => (),
+                false => return Err(at(root, "Not a backup store")),
+            }
+        }
+        Ok(store)
+    }
+
+    /// Takes the lock of the store, for changing what it holds.
+    pub(crate) fn lock(&self) -> Result<Lock, Failure> {
+        let path = self.root.join("lock");
+        match File::options().write(true).create_new(true).open(&path) {
+            Ok(mut file) => {
+                let _ = writeln!(file, "{}", std::process::id());
+                Ok(Lock {
+                    _pending: remove_on_interrupt(&path),
+                    path,
+                })
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                let message = "In use by another backup or gc; remove the lock if none is running";
+                Err(at(&path, message))
+            }
+            Err(e) => Err(at(&path, e)),
+        }
+    }
+
+    /// The blob of the chunk `hash`. The hashes of a manifest are checked,
+    /// so that a damaged one cannot name a file outside of `blobs/`.
+    fn blob_path(&self, hash: &str) -> Result<PathBuf, Failure> {
+        if !valid_hash(hash) {
+            let message = format!(
+                "{}: {:?} is not the hash of a chunk",
+                self.root.display(),
+                hash
+            );
+            return Err(Failure::new(Status::Corrupt, message));
+        }
+        Ok(self.root.join("blobs").join(&hash[..2]).join(hash))
+    }
+
+    /// The manifest of the snapshot `name`, which must be one that
+    /// `valid_name` takes.
+    fn snapshot_path(&self, name: &str) -> Result<PathBuf, Failure> {
+        if !valid_name(name) {
+            let message = format!("{} cannot name a snapshot", name);
+            return Err(Failure::new(Status::Usage, message));
+        }
+        Ok(self.root.join("snapshots").join(format!("{}.json", name)))
+    }
+
+    /// Stores the chunk `data`, compressed with `codec`, unless it is stored
+    /// already. Returns its hash and the bytes written to the store.
+    pub(crate) fn put(&self, data: &[u8], codec: ArchiveCodec) -> io::Result<(String, u64)> {
+        let hash = hash(data);
+        let path = self.blob_path(&hash).expect("a hash of 32 hex digits");
+        if path.is_file() {
+            return Ok((hash, 0));
+        }
+        let dir = path.parent().expect("in blobs");
+        fs::create_dir_all(dir).map_err(|e| with_path(dir, e))?;
+        // Another thread may write the same blob, with the same contents.
+        let n = TEMP.fetch_add(1, Ordering::Relaxed);
+        let temp = dir.join(format!(".{}.{}.{}.tmp", hash, std::process::id(), n));
+        let _temp = remove_on_interrupt(&temp);
+        let result = (|| {
+            let mut file = File::create(&temp)?;
+            file.write_all(&[codec as u8])?;
+            let mut stream = Encoder::new(codec, file)?;
+            stream.write_all(data)?;
+            let file = stream.finish()?;
+            file.sync_all()?;
+            let size = file.metadata()?.len();
+            fs::rename(&temp, &path)?;
+            Ok(size)
+        })();
+        match result {
+            Ok(size) => Ok((hash, size)),
+            Err(e) => {
+                let _ = fs::remove_file(&temp);
+                Err(with_path(&path, e))
+            }
+        }
+    }
+
+    /// Reads the chunk `hash`, checking that it has the hash.
+    pub(crate) fn get(&self, hash: &str) -> Result<Vec<u8>, Failure> {
+        let path = self.blob_path(hash)?;
+        let damaged = |e: &dyn std::fmt::Display| {
+            Failure::new(Status::Corrupt, format!("{}: {}", path.display(), e))
+        };
+        let mut file = File::open(&path).map_err(|e| at(&path, e))?;
+        let mut codec = [0; 1];
+        file.read_exact(&mut codec).map_err(|e| damaged(&e))?;
+        let codec = ArchiveCodec::from_byte(codec[0])
+            .ok_or_else(|| damaged(&"The codec is unknown, or not built in"))?;
+        let mut data = Vec::with_capacity(CHUNK_SIZE);
+        Decoder::new(codec, file)
+            .and_then(|stream| stream.take(CHUNK_SIZE as u64 + 1).read_to_end(&mut data))
+            .map_err(|e| damaged(&e))?;
+        if self::hash(&data) != hash {
+            return Err(damaged(&"The chunk does not have its hash"));
+        }
+        Ok(data)
+    }
+
+    /// Reads the `chunks` one after another, checking each.
+    pub(crate) fn contents<'a>(&'a self, chunks: &'a [String]) -> Contents<'a> {
+        Contents {
+            store: self,
+            chunks: chunks.iter(),
+            chunk: Cursor::new(Vec::new()),
+            error: None,
+        }
+    }
+
+    /// The hashes of the blobs, with the bytes each takes.
+    pub(crate) fn blobs(&self) -> Result<Vec<(String, u64)>, Failure> {
+        let mut blobs = Vec::new();
+        let root = self.root.join("blobs");
+        for dir in fs::read_dir(&root).map_err(|e| at(&root, e))? {
+            let dir = dir.map_err(|e| at(&root, e))?.path();
+            if !dir.is_dir() {
+                continue;
+            }
+            for blob in fs::read_dir(&dir).map_err(|e| at(&dir, e))? {
+                let blob = blob.map_err(|e| at(&dir, e))?;
+                let name = blob.file_name().to_string_lossy().into_owned();
+                if valid_hash(&name) {
+                    let size = blob.metadata().map_err(|e| at(&blob.path(), e))?.len();
+                    blobs.push((name, size));
+                }
+            }
+        }
+        blobs.sort();
+        Ok(blobs)
+    }
+
+    /// Removes the blob of the chunk `hash`.
+    pub(crate) fn remove_blob(&self, hash: &str) -> Result<(), Failure> {
+        let path = self.blob_path(hash)?;
+        fs::remove_file(&path).map_err(|e| at(&path, e))
+    }
+
+    /// Removes the manifest of the snapshot `name`, leaving its chunks.
+    pub(crate) fn remove_snapshot(&self, name: &str) -> Result<(), Failure> {
+        let path = self.snapshot_path(name)?;
+        fs::remove_file(&path).map_err(|e| at(&path, e))
+    }
+
+    /// Whether there is a snapshot `name`.
+    pub(crate) fn has_snapshot(&self, name: &str) -> bool {
+        self.snapshot_path(name).is_ok_and(|path| path.exists())
+    }
+
+    /// Reads the snapshot `name`, whose manifest must name it the same.
+    pub(crate) fn snapshot(&self, name: &str) -> Result<Snapshot, Failure> {
+        let path = self.snapshot_path(name)?;
+        let json = match fs::read(&path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let message = format!("There is no snapshot {}", name);
+                return Err(Failure::new(Status::Usage, message));
+            }
+            Err(e) => return Err(at(&path, e)),
+        };
+        let damaged = |e: &dyn std::fmt::Display| {
+            let message = format!("{}: The manifest is damaged: {}", path.display(), e);
+            Failure::new(Status::Corrupt, message)
+        };
+        let snapshot: Snapshot = serde_json::from_slice(&json).map_err(|e| damaged(&e))?;
+        if snapshot.name != name {
+            return Err(damaged(&format!("it names the snapshot {}", snapshot.name)));
+        }
+        Ok(snapshot)
+    }
+
+    /// Reads the snapshots, from the oldest.
+    pub(crate) fn snapshots(&self) -> Result<Vec<Snapshot>, Failure> {
+        let dir = self.root.join("snapshots");
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&dir).map_err(|e| at(&dir, e))? {
+            let path = entry.map_err(|e| at(&dir, e))?.path();
+            if path.extension().is_some_and(|e| e == "json") {
+                if let Some(name) = path.file_stem().and_then(|name| name.to_str()) {
+                    if valid_name(name) {
+                        names.push(name.to_string());
+                    }
+                }
+            }
+        }
+        let mut snapshots = names
+            .iter()
+            .map(|name| self.snapshot(name))
+            .collect::<Result<Vec<_>, _>>()?;
+        snapshots.sort_by(|a, b| (a.created, &a.name).cmp(&(b.created, &b.name)));
+        Ok(snapshots)
+    }
+
+    /// Writes the manifest of `snapshot`, which must be new.
+    pub(crate) fn save(&self, snapshot: &Snapshot) -> Result<(), Failure> {
+        let path = self.snapshot_path(&snapshot.name)?;
+        let temp = path.with_extension("json.tmp");
+        let _temp = remove_on_interrupt(&temp);
+        let json = serde_json::to_vec(snapshot).expect("serializable");
+        let result = File::create(&temp)
+            .and_then(|mut file| file.write_all(&json).and_then(|()| file.sync_all()))
+            .and_then(|()| fs::rename(&temp, &path));
+        if let Err(e) = result {
+            let _ = fs::remove_file(&temp);
+            return Err(at(&path, e));
+        }
+        Ok(())
+    }
+}
+
+/// Whether `hash` is one that names a blob: 32 lowercase hex digits.
+fn valid_hash(hash: &str) -> bool {
+    hash.len() == 32 && hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Whether `name` can name a snapshot: a file name that is not hidden.
+pub(crate) fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && !name.contains(['/', '\\'])
+        && Path::new(name).components().count() == 1
+}
+
+/// Cuts what is written to it into chunks of `CHUNK_SIZE` bytes, stored in a
+/// store.
+pub(crate) struct Chunker<'a> {
+    store: &'a Store,
+    codec: ArchiveCodec,
+    buf: Vec<u8>,
+    /// The hashes of the chunks.
+    pub chunks: Vec<String>,
+    /// The bytes written to the store for new chunks.
+    pub stored: u64,
+    /// The number of new chunks.
+    pub new: usize,
+}
+
+impl<'a> Chunker<'a> {
+    pub(crate) fn new(store: &'a Store, codec: ArchiveCodec) -> Chunker<'a> {
+        Chunker {
+            store,
+            codec,
+            buf: Vec::with_capacity(CHUNK_SIZE),
+            chunks: Vec::new(),
+            stored: 0,
+            new: 0,
+        }
+    }
+
+    fn store_chunk(&mut self) -> io::Result<()> {
+        let (hash, stored) = self.store.put(&self.buf, self.codec)?;
+        self.chunks.push(hash);
+        if stored > 0 {
+            self.stored += stored;
+            self.new += 1;
+        }
+        self.buf.clear();
+        Ok(())
+    }
+
+    /// Stores the last chunk, which may be short.
+    pub(crate) fn finish(mut self) -> io::Result<Chunker<'a>> {
+        if !self.buf.is_empty() {
+            self.store_chunk()?;
+        }
+        Ok(self)
+    }
+}
+
+impl Write for Chunker<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(CHUNK_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&buf[..n]);
+        if self.buf.len() == CHUNK_SIZE {
+            self.store_chunk()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The contents of a file of a snapshot, read from its chunks.
+pub(crate) struct Contents<'a> {
+    store: &'a Store,
+    chunks: slice::Iter<'a, String>,
+    chunk: Cursor<Vec<u8>>,
+    /// Why a chunk could not be read, which reading fails with.
+    pub error: Option<Failure>,
+}
+
+impl Read for Contents<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.chunk.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            let Some(hash) = self.chunks.next() else {
+                return Ok(0);
+            };
+            match self.store.get(hash) {
+                Ok(data) => self.chunk = Cursor::new(data),
+                Err(e) => {
+                    let error = io::Error::other(e.message.clone());
+                    self.error = Some(e);
+                    return Err(error);
+                }
+            }
+        }
+    }
+}
+
+/// Prefixes `e` with the file it is about.
+fn with_path(path: &Path, e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::scratch;
+    use super::*;
+
+    #[test]
+    fn valid_hash_test() {
+        assert!(valid_hash(&hash(b"chunk")));
+        assert!(valid_hash("0123456789abcdef0123456789abcdef"));
+        assert!(!valid_hash("0123456789ABCDEF0123456789ABCDEF"));
+        assert!(!valid_hash("0123456789abcdef"));
+        assert!(!valid_hash("../../../../../../../../../etc/x"));
+        assert!(!valid_hash("é"));
+        assert!(!valid_hash(""));
+    }
+
+    #[test]
+    fn damaged_manifest_test() {
+        let dir = scratch("store");
+        let store = Store::open(&dir, true).unwrap();
+        for hash in ["", "é", "../x", "../../../../../../../../../etc/x"] {
+            assert_eq!(store.get(hash).unwrap_err().status, Status::Corrupt);
+            assert_eq!(store.remove_blob(hash).unwrap_err().status, Status::Corrupt);
+        }
+        for name in ["../x", ".hidden", ""] {
+            assert_eq!(store.snapshot(name).err().unwrap().status, Status::Usage);
+            assert!(!store.has_snapshot(name));
+        }
+        let snapshot = Snapshot {
+            name: "other".to_string(),
+            source: dir.clone(),
+            created: 0,
+            files: Vec::new(),
+        };
+        store.save(&snapshot).unwrap();
+        fs::rename(
+            dir.join("snapshots/other.json"),
+            dir.join("snapshots/one.json"),
+        )
+        .unwrap();
+        assert_eq!(store.snapshot("one").err().unwrap().status, Status::Corrupt);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,226 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+//! The `tail` subcommand, which decompresses only the last chunks of a
+//! compressed file.
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use dfcompress::{ChunkCodec, Chunks, Header};
+use log::{info, warn};
+
+use super::convert::create;
+use super::exit::{Failure, Status};
+use super::{at, is_stdio, open, to_terminal, Tail, Verbosity};
+
+/// A chunk found by its length prefix.
+struct Frame {
+    /// The index of the chunk, counting from 0.
+    index: u64,
+    /// The offset of the length prefix.
+    offset: u64,
+    /// The data, cut short if the file ends first.
+    data: Vec<u8>,
+    /// Whether the file ends before the data does.
+    truncated: bool,
+}
+
+/// The last chunks of a file, and how many there are in all.
+struct Last {
+    frames: VecDeque<Frame>,
+    count: u64,
+}
+
+impl Tail {
+    pub(crate) fn run(self, verbosity: Verbosity) -> Status {
+        if self.output.is_none() && to_terminal(self.stdout) {
+            return Status::Usage;
+        }
+        match self.tail(verbosity) {
+            Ok(status) => status,
+            Err(e) => {
+                eprintln!("{}", e);
+                e.status
+            }
+        }
+    }
+
+    /// Writes the last chunks, decompressed, and reports the damage found in
+    /// them. Returns `Status::Corrupt` if there is any.
+    fn tail(&self, verbosity: Verbosity) -> Result<Status, Failure> {
+        let path = &self.file;
+        let (header, last) = match direct(path)? {
+            Some(file) => walk(file, path, self.chunks.get())?,
+            None => read(path, self.chunks.get())?,
+        };
+        let Some(first) = last.frames.front() else {
+            return Err(at(path, "There are no chunks"));
+        };
+        if !verbosity.quiet {
+            eprintln!(
+                "{}: chunks {} to {} of {}, from offset {}",
+                path.display(),
+                first.index,
+                last.count - 1,
+                last.count,
+                first.offset
+            );
+        }
+        let mut codec = header.compression.codec().unwrap_or_default().build(None);
+        match self.output.as_deref().filter(|output| !is_stdio(output)) {
+            Some(output) => create(output, self.force, |mut file, _| {
+                let status =
+                    write(&last, &mut *codec, path, &mut file).map_err(|e| at(output, e))?;
+                file.sync_all().map_err(|e| at(output, e))?;
+                Ok(status)
+            }),
+            None => {
+                let stdout = io::stdout();
+                let mut stdout = stdout.lock();
+                let status = write(&last, &mut *codec, path, &mut stdout)
+                    .and_then(|status| stdout.flush().map(|()| status))
+                    .map_err(|e| Failure::new(Status::Io, format!("Writing the chunks: {}", e)))?;
+                Ok(status)
+            }
+        }
+    }
+}
+
+/// Opens `path` to seek over its chunks if it is a compressed data file as
+/// it is, rather than standard input, gzipped or in volumes.
+fn direct(path: &Path) -> Result<Option<File>, Failure> {
+    if is_stdio(path) || !path.is_file() {
+        return Ok(None);
+    }
+    let mut file = File::open(path).map_err(|e| at(path, e))?;
+    let mut header = [0; 8];
+    let compressed = file
+        .read_exact(&mut header)
+        .ok()
+        .and_then(|()| Header::parse(&header).ok())
+        .is_some_and(|header| header.compression.is_compressed());
+    Ok(compressed.then_some(file))
+}
+
+/// Finds the last `n` chunks of `file`, the compressed data file `path`, by
+/// seeking from one length prefix to the next, and reads them.
+fn walk(mut file: File, path: &Path, n: usize) -> Result<(Header, Last), Failure> {
+    let size = file.metadata().map_err(|e| at(path, e))?.len();
+    file.seek(SeekFrom::Start(0)).map_err(|e| at(path, e))?;
+    let header = Header::read(&mut file).map_err(|e| at(path, e))?;
+    // The offsets and lengths of the last chunks.
+    let mut frames = VecDeque::new();
+    let mut count = 0;
+    let mut offset = 8;
+    while offset < size {
+        if size - offset < 4 {
+            warn!(
+                "{}: {} bytes after the last chunk",
+                path.display(),
+                size - offset
+            );
+            break;
+        }
+        let mut prefix = [0; 4];
+        file.seek(SeekFrom::Start(offset))
+            .and_then(|_| file.read_exact(&mut prefix))
+            .map_err(|e| at(path, e))?;
+        let len = u32::from_le_bytes(prefix) as u64;
+        frames.push_back((count, offset, len));
+        if frames.len() > n {
+            frames.pop_front();
+        }
+        count += 1;
+        offset += 4 + len;
+    }
+    let mut last = Last {
+        frames: VecDeque::new(),
+        count,
+    };
+    for (index, offset, len) in frames {
+        let available = len.min(size - offset - 4);
+        let mut data = vec![0; available as usize];
+        file.seek(SeekFrom::Start(offset + 4))
+            .and_then(|_| file.read_exact(&mut data))
+            .map_err(|e| at(path, e))?;
+        last.frames.push_back(Frame {
+            index,
+            offset,
+            data,
+            truncated: available < len,
+        });
+    }
+    Ok((header, last))
+}
+
+/// Like `walk`, reading through `path` to its end, for files that cannot be
+/// seeked in. The chunks end at the first that cannot be read whole.
+fn read(path: &Path, n: usize) -> Result<(Header, Last), Failure> {
+    let chunks = Chunks::new(open(path)?).map_err(|e| at(path, e))?;
+    let header = chunks.header();
+    let mut last = Last {
+        frames: VecDeque::new(),
+        count: 0,
+    };
+    for chunk in chunks {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                warn!("{}: {} after chunk {}", path.display(), e, last.count);
+                break;
+            }
+        };
+        last.frames.push_back(Frame {
+            index: last.count,
+            offset: chunk.offset,
+            data: chunk.data,
+            truncated: false,
+        });
+        if last.frames.len() > n {
+            last.frames.pop_front();
+        }
+        last.count += 1;
+    }
+    Ok((header, last))
+}
+
+/// Writes the chunks of `last`, decompressed by `codec`, to `w`, and what
+/// is left of those that fail to. Returns `Status::Corrupt` if any do.
+fn write<W: Write>(
+    last: &Last,
+    codec: &mut dyn ChunkCodec,
+    path: &Path,
+    w: &mut W,
+) -> io::Result<Status> {
+    let mut status = Status::Success;
+    let mut buf = Vec::new();
+    for frame in &last.frames {
+        buf.clear();
+        let result = codec.decompress(&frame.data, &mut buf);
+        info!(
+            "{}: chunk {} at offset {}, {} bytes",
+            path.display(),
+            frame.index,
+            frame.offset,
+            buf.len()
+        );
+        let damage = match (result, frame.truncated) {
+            (_, true) => Some("the file ends within it".to_string()),
+            (Err(e), false) => Some(e.to_string()),
+            (Ok(()), false) => None,
+        };
+        if let Some(damage) = damage {
+            eprintln!(
+                "{}: chunk {} at offset {}: {}",
+                path.display(),
+                frame.index,
+                frame.offset,
+                damage
+            );
+            status = Status::Corrupt;
+        }
+        w.write_all(&buf)?;
+    }
+    Ok(status)
+}
@@ -0,0 +1,153 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+//! The `unpack` subcommand, which turns an archive of `pack` back into a
+//! region folder that Dwarf Fortress loads.
+use std::fmt;
+use std::fs::{self, FileTimes};
+use std::io::{self, BufWriter, Cursor, Read, Write};
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+use dfcompress::{Header, Options, Target};
+use log::info;
+use serde_json::json;
+
+use super::archive::{open_archive, Kind, Member};
+use super::convert::create;
+use super::exit::{Cause, Failure, Status};
+use super::volumes::series;
+use super::{at, is_stdio, open, Unpack, Verbosity};
+
+impl Unpack {
+    /// Unpacks the archive, and reports what was.
+    pub(crate) fn run(self, verbosity: Verbosity) -> Status {
+        match self.unpack() {
+            Ok(report) => {
+                if !verbosity.quiet || self.json {
+                    println!("{}", report);
+                }
+                Status::Success
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                e.status
+            }
+        }
+    }
+
+    fn unpack(&self) -> Result<String, Failure> {
+        let archive = &self.archive;
+        // The first of the volumes of --split is named after the archive.
+        let named = series(archive).unwrap_or_else(|| archive.clone());
+        let dir = match (&self.output_dir, named.extension()) {
+            (Some(dir), _) => dir.clone(),
+            (None, Some(extension)) if extension == "dfpack" && !is_stdio(archive) => {
+                named.with_extension("")
+            }
+            _ => {
+                let message = "unpack needs --output-dir unless the archive is named *.dfpack";
+                return Err(Failure::new(Status::Usage, message));
+            }
+        };
+        let (manifest, mut stream) =
+            open_archive(open(archive)?).map_err(|e| in_archive(archive, e))?;
+        fs::create_dir_all(&dir).map_err(|e| at(&dir, e))?;
+        for member in &manifest.members {
+            let path = member.path_in(&dir).ok_or_else(|| {
+                in_archive(archive, at(Path::new(&member.path), "Not in the folder"))
+            })?;
+            info!("{}: unpacking {} bytes", path.display(), member.size);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).map_err(|e| at(parent, e))?;
+            }
+            let contents = (&mut stream).take(member.size);
+            unpack_file(contents, member, &path, self.force).map_err(|e| in_archive(archive, e))?;
+        }
+        // The checksum of the stream is read at its end.
+        let rest = io::copy(&mut stream, &mut io::sink())
+            .map_err(|e| in_archive(archive, Failure::new(Status::Corrupt, e)))?;
+        if rest > 0 {
+            let message = format!("{} bytes after the last member", rest);
+            return Err(in_archive(archive, Failure::new(Status::Corrupt, message)));
+        }
+        Ok(match self.json {
+            true => json!({
+                "archive": archive,
+                "dir": dir,
+                "files": manifest.members.len(),
+            })
+            .to_string(),
+            false => format!(
+                "{}: {} files unpacked into {}",
+                archive.display(),
+                manifest.members.len(),
+                dir.display()
+            ),
+        })
+    }
+}
+
+/// Prefixes an error about the archive with its name.
+fn in_archive(archive: &Path, e: Failure) -> Failure {
+    match is_stdio(archive) {
+        true => e,
+        false => at(archive, e),
+    }
+}
+
+/// Writes `member`, whose contents `reader` has, to `path`, recompressing a
+/// data file that was compressed. The file is removed if that fails.
+pub(crate) fn unpack_file<R: Read>(
+    reader: R,
+    member: &Member,
+    path: &Path,
+    force: bool,
+) -> Result<(), Failure> {
+    create(path, force, |file, _| {
+        let writer = write_member(reader, member, path, BufWriter::new(file))?;
+        let file = writer.into_inner().map_err(|e| at(path, e.into_error()))?;
+        if let Some((seconds, nanos)) = member.modified {
+            let modified = UNIX_EPOCH + Duration::new(seconds, nanos);
+            file.set_times(FileTimes::new().set_modified(modified))
+                .map_err(|e| at(path, e))?;
+        }
+        file.sync_all().map_err(|e| at(path, e))
+    })
+}
+
+/// Writes the contents of `member` from `reader` to `writer`, checking that
+/// they are whole.
+fn write_member<R: Read, W: Write>(
+    mut reader: R,
+    member: &Member,
+    path: &Path,
+    mut writer: W,
+) -> Result<W, Failure> {
+    let damaged =
+        |e: &dyn fmt::Display| Failure::new(Status::Corrupt, format!("{}: {}", path.display(), e));
+    let written = match member.kind {
+        Kind::Compressed => {
+            let mut header = [0; 8];
+            reader.read_exact(&mut header).map_err(|e| damaged(&e))?;
+            let version = Header::parse(&header).map_err(|e| damaged(&e))?.version;
+            if Some(version) != member.version {
+                return Err(damaged(&"The save version differs from the manifest"));
+            }
+            let reader = Cursor::new(header).chain(&mut reader);
+            // The header was read, so any error but writing is damage.
+            dfcompress::recode_with(reader, &mut writer, Target::Compressed, &Options::new())
+                .map_err(|e| match e.status() {
+                    Status::Io => at(path, e),
+                    _ => damaged(&e),
+                })?
+                .bytes_in
+        }
+        Kind::Uncompressed | Kind::Other => {
+            io::copy(&mut reader, &mut writer).map_err(|e| damaged(&e))?
+        }
+    };
+    if written != member.size {
+        return Err(damaged(&"The contents are cut short"));
+    }
+    Ok(writer)
+}
@@ -0,0 +1,261 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+//! The `validate` subcommand, which checks that a region folder has the
+//! files Dwarf Fortress needs to load it, and that they agree. Dwarf
+//! Fortress leaves a folder out of its list, or fails to load it, without
+//! saying why.
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+
+use dfcompress::{Compression, Header, Severity};
+use serde_json::json;
+
+use super::archive;
+use super::batch::is_data_file;
+use super::exit::{Failure, Status};
+use super::{at, open, solid, Validate};
+
+/// The world files, one of which a region folder has: `world.sav` once a
+/// game is saved, and `world.dat` of a world just generated.
+const WORLD: [&str; 2] = ["world.sav", "world.dat"];
+
+/// Something wrong with a file of the folder.
+struct Problem {
+    /// The name of the file, in the folder.
+    file: String,
+    severity: Severity,
+    description: String,
+}
+
+/// The data files of a folder, and what is wrong with it.
+#[derive(Default)]
+struct Survey {
+    /// The world file, and its save version.
+    world: Option<(String, u32)>,
+    /// The file the versions of the others are compared with, and its
+    /// version: the world file, or else the first data file.
+    first: Option<(String, u32)>,
+    data_files: usize,
+    problems: Vec<Problem>,
+}
+
+impl Survey {
+    fn push(&mut self, file: &str, severity: Severity, description: String) {
+        self.problems.push(Problem {
+            file: file.to_string(),
+            severity,
+            description,
+        });
+    }
+
+    fn errors(&self) -> usize {
+        let errors = self
+            .problems
+            .iter()
+            .filter(|p| p.severity == Severity::Error);
+        errors.count()
+    }
+}
+
+impl Validate {
+    pub(crate) fn run(self) -> Status {
+        let survey = match survey(&self.dir) {
+            Ok(survey) => survey,
+            Err(e) => {
+                eprintln!("{}", e);
+                return e.status;
+            }
+        };
+        match self.json {
+            true => println!("{}", json_report(&self.dir, &survey)),
+            false => print!("{}", text_report(&self.dir, &survey)),
+        }
+        match survey.errors() {
+            0 => Status::Success,
+            _ => Status::Corrupt,
+        }
+    }
+}
+
+/// Looks at the files of the region folder `dir`.
+fn survey(dir: &Path) -> Result<Survey, Failure> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir).map_err(|e| at(dir, e))? {
+        let path = entry.map_err(|e| at(dir, e))?.path();
+        if path.is_file() && is_data_file(&path) {
+            files.push(path);
+        }
+    }
+    files.sort();
+    let mut survey = Survey::default();
+    let world = WORLD
+        .iter()
+        .map(|name| dir.join(name))
+        .find(|path| path.is_file());
+    match world {
+        Some(ref path) => world_file(path, &mut survey),
+        None => {
+            let description = "there is no world.sav or world.dat, without which Dwarf \
+                               Fortress does not list the folder"
+                .to_string();
+            survey.push("world.sav", Severity::Error, description);
+        }
+    }
+    for path in files {
+        survey.data_files += 1;
+        if Some(&path) != world.as_ref() {
+            data_file(&path, &mut survey);
+        }
+    }
+    Ok(survey)
+}
+
+/// The name of `path` in its folder.
+fn name(path: &Path) -> String {
+    path.file_name().map_or_else(
+        || path.display().to_string(),
+        |name| name.to_string_lossy().into(),
+    )
+}
+
+/// Reads the header of the data file `path`, noting what keeps Dwarf
+/// Fortress from reading it.
+fn header(path: &Path, survey: &mut Survey) -> Option<Header> {
+    let file = name(path);
+    let mut bytes = Vec::new();
+    let read = File::open(path).and_then(|f| f.take(8).read_to_end(&mut bytes));
+    if let Err(e) = read {
+        survey.push(&file, Severity::Error, format!("cannot be read: {}", e));
+        return None;
+    }
+    let other = [
+        (&[0x1f, 0x8b][..], "it is gzipped"),
+        (
+            &solid::MAGIC[..],
+            "it is a solid file of dfcompress --solid",
+        ),
+        (&archive::MAGIC[..], "it is an archive of dfcompress pack"),
+    ];
+    let description = match Header::parse(&bytes) {
+        _ if bytes.is_empty() => "is empty, as Dwarf Fortress never writes a data file".to_string(),
+        Ok(header) => match header.compression {
+            Compression::Uncompressed | Compression::Zlib => return Some(header),
+            other => format!(
+                "is compressed with {:?}, which Dwarf Fortress cannot read; \
+                 convert it with dfcompress",
+                other
+            ),
+        },
+        Err(_) => match other.iter().find(|(magic, _)| bytes.starts_with(magic)) {
+            Some((_, what)) => format!("{}, which Dwarf Fortress cannot read", what),
+            None => "does not start with the header of a data file".to_string(),
+        },
+    };
+    survey.push(&file, Severity::Error, description);
+    None
+}
+
+/// Checks the world file `path`, which must decompress in full.
+fn world_file(path: &Path, survey: &mut Survey) {
+    let file = name(path);
+    let Some(header) = header(path, survey) else {
+        return;
+    };
+    survey.world = Some((file.clone(), header.version));
+    survey.first = survey.world.clone();
+    if header.df_release().is_none() {
+        let description = format!("save version {} is of no known release", header.version);
+        survey.push(&file, Severity::Warning, description);
+    }
+    let report = match open(path).map(dfcompress::check) {
+        Ok(Ok(report)) => report,
+        Ok(Err(e)) => {
+            survey.push(&file, Severity::Error, format!("cannot be read: {}", e));
+            return;
+        }
+        // The error names the file already.
+        Err(e) => {
+            survey.push(&file, Severity::Error, e.message);
+            return;
+        }
+    };
+    for finding in report.findings {
+        let description = format!("at offset {}: {}", finding.offset, finding.description);
+        survey.push(&file, finding.severity, description);
+    }
+}
+
+/// Checks the data file `path` other than the world file: that Dwarf
+/// Fortress reads it, and that it is of the same version as the others.
+fn data_file(path: &Path, survey: &mut Survey) {
+    let file = name(path);
+    let Some(header) = header(path, survey) else {
+        return;
+    };
+    match survey.first {
+        Some((ref first, version)) if header.version != version => {
+            let description = format!(
+                "save version {}, but {} is of version {}",
+                header.version, first, version
+            );
+            survey.push(&file, Severity::Error, description);
+        }
+        Some(_) => (),
+        None => survey.first = Some((file, header.version)),
+    }
+}
+
+fn text_report(dir: &Path, survey: &Survey) -> String {
+    let mut report = String::new();
+    for problem in &survey.problems {
+        report += &format!(
+            "{}: {}: {}\n",
+            dir.join(&problem.file).display(),
+            problem.severity,
+            problem.description
+        );
+    }
+    let verdict = match (survey.errors(), &survey.world) {
+        (0, Some((world, version))) => {
+            let release = Header::new(*version, Compression::Uncompressed).df_release();
+            format!(
+                "complete: {} and {} other data files, of version {} ({})",
+                world,
+                survey.data_files - 1,
+                version,
+                release.unwrap_or("unknown release")
+            )
+        }
+        (1, _) => "1 problem, for which Dwarf Fortress may not load it".to_string(),
+        (errors, _) => format!(
+            "{} problems, for which Dwarf Fortress may not load it",
+            errors
+        ),
+    };
+    report + &format!("{}: {}\n", dir.display(), verdict)
+}
+
+fn json_report(dir: &Path, survey: &Survey) -> String {
+    let problems: Vec<_> = survey
+        .problems
+        .iter()
+        .map(|problem| {
+            json!({
+                "file": problem.file,
+                "severity": problem.severity,
+                "description": problem.description,
+            })
+        })
+        .collect();
+    let world = survey.world.as_ref();
+    json!({
+        "dir": dir,
+        "ok": survey.errors() == 0,
+        "world": world.map(|(world, _)| world),
+        "version": world.map(|&(_, version)| version),
+        "data_files": survey.data_files,
+        "problems": problems,
+    })
+    .to_string()
+}
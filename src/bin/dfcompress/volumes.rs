@@ -0,0 +1,262 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+//! The volumes of `--split`: an output cut into files of a fixed size,
+//! `OUTPUT.001`, `OUTPUT.002` and so on, which are read back as one.
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use log::info;
+
+use super::at;
+use super::convert::temp_path;
+use super::exit::{Cause, Failure};
+use super::interrupt::{remove_on_interrupt, Pending};
+
+/// Volume `n` of `output`, counting from 1.
+pub(crate) fn volume_path(output: &Path, n: u32) -> PathBuf {
+    let mut name = output.as_os_str().to_owned();
+    name.push(format!(".{:03}", n));
+    PathBuf::from(name)
+}
+
+/// The output whose volumes start with `path`: that without `.001`, or
+/// `path` itself if it is missing and its first volume is not.
+pub(crate) fn series(path: &Path) -> Option<PathBuf> {
+    if path.extension().is_some_and(|e| e == "001") {
+        return Some(path.with_extension(""));
+    }
+    match !path.exists() && volume_path(path, 1).is_file() {
+        true => Some(path.to_path_buf()),
+        false => None,
+    }
+}
+
+/// Reads the volumes of an output one after another, up to the first that
+/// is missing.
+pub(crate) struct Series {
+    output: PathBuf,
+    next: u32,
+    file: BufReader<File>,
+}
+
+impl Series {
+    pub(crate) fn open(output: &Path) -> Result<Series, Failure> {
+        let first = volume_path(output, 1);
+        let file = File::open(&first).map_err(|e| at(&first, e))?;
+        Ok(Series {
+            output: output.to_path_buf(),
+            next: 2,
+            file: BufReader::new(file),
+        })
+    }
+}
+
+impl Read for Series {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.file.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            let path = volume_path(&self.output, self.next);
+            self.file = match File::open(&path) {
+                Ok(file) => BufReader::new(file),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+                Err(e) => return Err(with_path(&path, e)),
+            };
+            self.next += 1;
+        }
+    }
+}
+
+/// Writes volumes of `size` bytes of an output, creating each once it is
+/// written to. They are removed if the program is interrupted before this
+/// is dropped.
+pub(crate) struct Volumes {
+    output: PathBuf,
+    size: u64,
+    /// Whether there are volumes already, which the new ones are written
+    /// next to until `finish` puts them in their place.
+    replace: bool,
+    left: u64,
+    file: Option<BufWriter<File>>,
+    written: Vec<(PathBuf, Pending)>,
+}
+
+impl Volumes {
+    /// Starts writing the volumes of `output`. The volumes there already are
+    /// replaced when the new ones are finished if `force`, and refused
+    /// otherwise.
+    pub(crate) fn new(output: &Path, size: u64, force: bool) -> Result<Volumes, Failure> {
+        let first = volume_path(output, 1);
+        let replace = first.exists();
+        if replace && !force {
+            return Err(at(&first, "Already exists; use --force to overwrite"));
+        }
+        Ok(Volumes {
+            output: output.to_path_buf(),
+            size,
+            replace,
+            left: 0,
+            file: None,
+            written: Vec::new(),
+        })
+    }
+
+    /// Syncs the volume being written, if any, to disk.
+    fn close(&mut self) -> io::Result<()> {
+        let Some(file) = self.file.take() else {
+            return Ok(());
+        };
+        let path = &self.written.last().expect("created").0;
+        let file = file
+            .into_inner()
+            .map_err(|e| with_path(path, e.into_error()))?;
+        file.sync_all().map_err(|e| with_path(path, e))?;
+        info!("{}: {} bytes", path.display(), self.size - self.left);
+        Ok(())
+    }
+
+    /// Goes on to the next volume.
+    fn next_volume(&mut self) -> io::Result<()> {
+        self.close()?;
+        let path = volume_path(&self.output, self.written.len() as u32 + 1);
+        let path = match self.replace {
+            true => temp_path(&path),
+            false => path,
+        };
+        let file = File::options()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .map_err(|e| with_path(&path, e))?;
+        let pending = remove_on_interrupt(&path);
+        self.written.push((path, pending));
+        self.file = Some(BufWriter::new(file));
+        self.left = self.size;
+        Ok(())
+    }
+
+    /// Ends the last volume, creating the first if nothing was written, and
+    /// puts the volumes in place of those there were. Returns the number of
+    /// volumes.
+    pub(crate) fn finish(&mut self) -> Result<usize, Failure> {
+        // The errors name the volume already.
+        let failed = |e: io::Error| Failure::new(e.status(), e);
+        if self.written.is_empty() {
+            self.next_volume().map_err(failed)?;
+        }
+        self.close().map_err(failed)?;
+        if self.replace {
+            for (n, written) in (1..).zip(&mut self.written) {
+                let path = volume_path(&self.output, n);
+                fs::rename(&written.0, &path).map_err(|e| at(&path, e))?;
+                *written = (path.clone(), remove_on_interrupt(&path));
+            }
+            // Those past the last are left of the old volumes.
+            let mut n = self.written.len() as u32 + 1;
+            while volume_path(&self.output, n).exists() {
+                let path = volume_path(&self.output, n);
+                fs::remove_file(&path).map_err(|e| at(&path, e))?;
+                n += 1;
+            }
+            self.replace = false;
+        }
+        Ok(self.written.len())
+    }
+
+    /// Removes the volumes written.
+    pub(crate) fn remove(mut self) {
+        self.file = None;
+        for (path, _) in &self.written {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+impl Write for Volumes {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.left == 0 {
+            self.next_volume()?;
+        }
+        let n = self.left.min(buf.len() as u64) as usize;
+        let file = self.file.as_mut().expect("created");
+        let n = file.write(&buf[..n])?;
+        self.left -= n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.file.as_mut() {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Prefixes `e` with the volume it is about.
+fn with_path(path: &Path, e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::scratch;
+    use super::*;
+
+    #[test]
+    fn volumes_test() {
+        let dir = scratch("volumes");
+        let output = dir.join("world.dat");
+        let data: Vec<u8> = (0..2500u32).map(|i| i as u8).collect();
+        let mut volumes = Volumes::new(&output, 1000, false).unwrap();
+        volumes.write_all(&data).unwrap();
+        assert_eq!(volumes.finish().unwrap(), 3);
+        drop(volumes);
+        let sizes: Vec<u64> = (1..4)
+            .map(|n| fs::metadata(volume_path(&output, n)).unwrap().len())
+            .collect();
+        assert_eq!(sizes, [1000, 1000, 500]);
+        assert!(!volume_path(&output, 4).exists());
+
+        assert_eq!(series(&volume_path(&output, 1)), Some(output.clone()));
+        assert_eq!(series(&output), Some(output.clone()));
+        assert_eq!(series(&volume_path(&output, 2)), None);
+        let mut read = Vec::new();
+        Series::open(&output)
+            .unwrap()
+            .read_to_end(&mut read)
+            .unwrap();
+        assert_eq!(read, data);
+
+        let refused = Volumes::new(&output, 1000, false).err().unwrap();
+        assert!(
+            refused.message.contains("world.dat.001"),
+            "{}",
+            refused.message
+        );
+        let mut volumes = Volumes::new(&output, 2000, true).unwrap();
+        volumes.write_all(&data[..10]).unwrap();
+        assert_eq!(volumes.finish().unwrap(), 1);
+        drop(volumes);
+        assert_eq!(fs::read(volume_path(&output, 1)).unwrap(), &data[..10]);
+        assert!(!volume_path(&output, 2).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn empty_volumes_test() {
+        let dir = scratch("empty-volumes");
+        let output = dir.join("world.dat");
+        let mut volumes = Volumes::new(&output, 1000, false).unwrap();
+        assert_eq!(volumes.finish().unwrap(), 1);
+        drop(volumes);
+        assert_eq!(fs::read(volume_path(&output, 1)).unwrap(), b"");
+        assert_eq!(series(&output.with_extension("sav")), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
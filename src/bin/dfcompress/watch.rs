@@ -0,0 +1,142 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+//! The `watch` subcommand, which backs up the folders of a save directory
+//! once Dwarf Fortress has finished writing them.
+use std::collections::BTreeMap;
+use std::fs;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use dfcompress::{Options, Target};
+use log::info;
+use notify::{EventKind, RecursiveMode, Watcher};
+
+use super::batch::{all_files, default_threads, has_header, is_data_file, Batch, Done};
+use super::convert::{convert, WriteOptions};
+use super::exit::{Failure, Status};
+use super::progress::with_bar;
+use super::{at, Verbosity, Watch};
+
+impl Watch {
+    /// Backs up each folder of the save directory that changes, once it has
+    /// not changed for `--settle` seconds. Runs until interrupted.
+    pub(crate) fn run(self, verbosity: Verbosity) -> Status {
+        match self.watch(verbosity) {
+            Ok(()) => Status::Success,
+            Err(e) => {
+                eprintln!("{}", e);
+                e.status
+            }
+        }
+    }
+
+    fn watch(&self, verbosity: Verbosity) -> Result<(), Failure> {
+        let usage = |message| Failure::new(Status::Usage, message);
+        let dir = self
+            .dir
+            .as_ref()
+            .ok_or_else(|| usage("watch needs a SAVEDIR, or save-dir in the configuration file"))?;
+        let backups = self.output_dir.as_ref().ok_or_else(|| {
+            usage("watch needs --output-dir, or backup-dir in the configuration file")
+        })?;
+        fs::create_dir_all(backups).map_err(|e| at(backups, e))?;
+        let backups = backups.canonicalize().map_err(|e| at(backups, e))?;
+        let dir = dir.canonicalize().map_err(|e| at(dir, e))?;
+        let (tx, rx) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(tx).map_err(|e| at(&dir, e))?;
+        watcher
+            .watch(&dir, RecursiveMode::Recursive)
+            .map_err(|e| at(&dir, e))?;
+        let settle = Duration::from_secs(self.settle);
+        // The folders that changed, and when they last did.
+        let mut changed = BTreeMap::new();
+        loop {
+            match rx.recv_timeout(Duration::from_secs(1)) {
+                // Reading the files to back them up is not a change.
+                Ok(Ok(event)) if matches!(event.kind, EventKind::Access(_)) => (),
+                Ok(Ok(event)) => {
+                    for path in event.paths {
+                        if path.starts_with(&backups) {
+                            continue;
+                        }
+                        let Ok(relative) = path.strip_prefix(&dir) else {
+                            continue;
+                        };
+                        if let Some(folder) = relative.components().next() {
+                            changed.insert(dir.join(folder), Instant::now());
+                        }
+                    }
+                }
+                Ok(Err(e)) => eprintln!("{}", at(&dir, e)),
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(Failure::new(Status::Io, at(&dir, "Stopped watching")));
+                }
+            }
+            let settled: Vec<PathBuf> = changed
+                .iter()
+                .filter(|(_, time)| time.elapsed() >= settle)
+                .map(|(folder, _)| folder.clone())
+                .collect();
+            for folder in settled {
+                changed.remove(&folder);
+                if folder.is_dir() {
+                    self.back_up(
+                        &folder,
+                        &backups.join(folder.file_name().unwrap()),
+                        verbosity,
+                    );
+                }
+            }
+        }
+    }
+
+    /// Copies `folder` to `backup`, compressing its data files at the best
+    /// level, and reports on each file.
+    fn back_up(&self, folder: &Path, backup: &Path, verbosity: Verbosity) {
+        info!("{}: backing up to {}", folder.display(), backup.display());
+        let mut files = Vec::new();
+        if let Err(e) = all_files(folder, &self.exclude, &mut files) {
+            eprintln!("{}", e);
+            return;
+        }
+        let batch = Batch {
+            files,
+            dirs: vec![folder.to_path_buf()],
+            report: true,
+            json: self.json,
+            stdout_taken: false,
+            verdicts: false,
+            verbosity,
+        };
+        let threads = self.threads.map_or_else(default_threads, NonZeroUsize::get);
+        let options = Options::new().best();
+        let write = WriteOptions {
+            force: true,
+            ..WriteOptions::default()
+        };
+        batch.run(threads, |path, bar| {
+            let output = backup.join(batch.relative(path));
+            if let Some(dir) = output.parent() {
+                fs::create_dir_all(dir).map_err(|e| at(dir, e))?;
+            }
+            if !is_data_file(path) || !has_header(path) {
+                let bytes = fs::copy(path, &output).map_err(|e| at(path, e))?;
+                return Ok(Done {
+                    line: Some(format!("copied {} bytes", bytes)),
+                    ..Done::default()
+                });
+            }
+            let options = with_bar(&options, bar, None);
+            let summary = convert(path, &output, Target::Compressed, &options, write)?;
+            let line = format!("{} -> {} bytes", summary.bytes_in, summary.bytes_out);
+            Ok(Done {
+                line: Some(line),
+                summary: Some(summary),
+                ..Done::default()
+            })
+        });
+    }
+}
@@ -1,21 +1,11 @@
 // Copyright 2018, Mathias Rav <m@git.strova.dk>
 // SPDX-License-Identifier: LGPL-2.1+
-extern crate dfcompress;
-use std::io;
-use std::process;
+//! The old name of `dfcompress decompress`.
+use dfcompress::Target;
+
+#[path = "dfcompress/main.rs"]
+mod program;
 
 fn main() {
-    process::exit({
-        let raw_stdin = io::stdin();
-        let stdin = raw_stdin.lock();
-        let raw_stdout = io::stdout();
-        let stdout = raw_stdout.lock();
-        match dfcompress::dfuncompress(stdin, stdout) {
-            Ok(()) => 0,
-            Err(e) => {
-                eprintln!("{}", e);
-                1
-            }
-        }
-    });
+    program::run(Some(Target::Uncompressed));
 }
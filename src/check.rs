@@ -0,0 +1,300 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+use std::fmt;
+use std::io::{self, Read};
+
+use flate2::{Decompress, FlushDecompress, Status};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use super::{Compression, ErrorKind, Header, Result, CHUNK_SIZE};
+
+/// The most a chunk is decompressed to before checking it gives up.
+const MAX_CHUNK: usize = 64 << 20;
+
+/// How bad a finding of `check` is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum Severity {
+    /// Unusual, but the data is intact.
+    Warning,
+    /// Data is lost, or the file cannot be read.
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// Something `check` found wrong with a file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Finding {
+    pub severity: Severity,
+    /// The offset in the file it is at.
+    pub offset: u64,
+    /// The chunk it is in, counting from 0, if it is in one.
+    pub chunk: Option<u64>,
+    pub description: String,
+}
+
+/// What `check` found.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct CheckReport {
+    /// The header, unless it is invalid.
+    pub header: Option<Header>,
+    /// The number of chunks, including damaged ones.
+    pub chunks: u64,
+    /// The bytes the payload decompresses to, as far as it does.
+    pub bytes_out: u64,
+    /// The findings, in the order of their offsets.
+    pub findings: Vec<Finding>,
+}
+
+impl CheckReport {
+    /// Whether nothing worse than a warning was found.
+    pub fn is_ok(&self) -> bool {
+        self.errors() == 0
+    }
+
+    pub fn errors(&self) -> usize {
+        self.count(Severity::Error)
+    }
+
+    pub fn warnings(&self) -> usize {
+        self.count(Severity::Warning)
+    }
+
+    fn count(&self, severity: Severity) -> usize {
+        self.findings
+            .iter()
+            .filter(|f| f.severity == severity)
+            .count()
+    }
+
+    fn push(&mut self, severity: Severity, offset: u64, chunk: Option<u64>, description: String) {
+        self.findings.push(Finding {
+            severity,
+            offset,
+            chunk,
+            description,
+        });
+    }
+}
+
+/// Checks the data file `r` from end to end: that the header is plausible,
+/// that the chunks are framed consistently up to the end of the file, and
+/// that each decompresses. Zlib chunks are checked further, for a valid
+/// header, an intact deflate stream, a matching adler32 checksum and
+/// nothing after it. Fails only if reading fails.
+pub fn check<R: Read>(mut r: R) -> Result<CheckReport> {
+    let mut report = CheckReport::default();
+    let mut bytes = Vec::new();
+    (&mut r).take(8).read_to_end(&mut bytes)?;
+    let header = match Header::parse(&bytes) {
+        Ok(header) => header,
+        Err(e) => {
+            let description = match e.kind() {
+                ErrorKind::UnexpectedEof => {
+                    format!("only {} bytes, too short for a header", bytes.len())
+                }
+                _ => format!("not a valid header: {}", e),
+            };
+            report.push(Severity::Error, 0, None, description);
+            return Ok(report);
+        }
+    };
+    report.header = Some(header);
+    if header.df_release().is_none() {
+        let description = format!("save version {} is of no known release", header.version);
+        report.push(Severity::Warning, 0, None, description);
+    }
+    if !header.compression.is_compressed() {
+        report.bytes_out = io::copy(&mut r, &mut io::sink())?;
+        return Ok(report);
+    }
+    let mut codec = header.compression.codec().unwrap_or_default().build(None);
+    let mut offset = 8;
+    let mut data = Vec::new();
+    let mut out = Vec::new();
+    loop {
+        let mut prefix = Vec::new();
+        (&mut r).take(4).read_to_end(&mut prefix)?;
+        let chunk = Some(report.chunks);
+        match prefix.len() {
+            0 => break,
+            4 => (),
+            n => {
+                let description = format!("{} bytes after the last chunk, too few for a length", n);
+                report.push(Severity::Error, offset, None, description);
+                break;
+            }
+        }
+        let len = u32::from_le_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]) as u64;
+        data.clear();
+        (&mut r).take(len).read_to_end(&mut data)?;
+        if (data.len() as u64) < len {
+            let description = format!(
+                "the length claims {} bytes, but only {} follow: the file is cut short, \
+                 or this is garbage after the last chunk",
+                len,
+                data.len()
+            );
+            report.push(Severity::Error, offset, chunk, description);
+            report.chunks += 1;
+            break;
+        }
+        out.clear();
+        let payload = offset + 4;
+        let found = match header.compression {
+            Compression::Zlib => zlib(&data, &mut out),
+            _ => match codec.decompress(&data, &mut out) {
+                Ok(()) => Vec::new(),
+                Err(e) => vec![(Severity::Error, 0, format!("does not decompress: {}", e))],
+            },
+        };
+        for (severity, at, description) in found {
+            report.push(severity, payload + at, chunk, description);
+        }
+        if out.len() > CHUNK_SIZE {
+            let description = format!(
+                "decompresses to {} bytes, more than the {} Dwarf Fortress writes",
+                out.len(),
+                CHUNK_SIZE
+            );
+            report.push(Severity::Warning, offset, chunk, description);
+        }
+        report.bytes_out += out.len() as u64;
+        report.chunks += 1;
+        offset = payload + len;
+    }
+    Ok(report)
+}
+
+/// Checks the zlib stream `data`, decompressing it into `out`. Returns the
+/// findings, at offsets in `data`.
+fn zlib(data: &[u8], out: &mut Vec<u8>) -> Vec<(Severity, u64, String)> {
+    let error = |at: u64, description: String| vec![(Severity::Error, at, description)];
+    let [cmf, flg, ..] = *data else {
+        return error(0, "too short for a zlib header".to_string());
+    };
+    if cmf & 0x0f != 8 || cmf >> 4 > 7 {
+        return error(0, format!("not a zlib stream: {:02x} {:02x}", cmf, flg));
+    }
+    if !(cmf as u16 * 256 + flg as u16).is_multiple_of(31) {
+        return error(0, "the check of the zlib header fails".to_string());
+    }
+    if flg & 0x20 != 0 {
+        return error(0, "the zlib stream needs a preset dictionary".to_string());
+    }
+    let deflate = &data[2..];
+    let mut inflate = Decompress::new(false);
+    loop {
+        if out.len() >= MAX_CHUNK {
+            return error(2, format!("decompresses to more than {} bytes", MAX_CHUNK));
+        }
+        out.reserve(CHUNK_SIZE.max(out.len()));
+        let consumed = inflate.total_in() as usize;
+        let status = inflate.decompress_vec(&deflate[consumed..], out, FlushDecompress::None);
+        match status {
+            Ok(Status::StreamEnd) => break,
+            Ok(_) if out.len() < out.capacity() && inflate.total_in() as usize == deflate.len() => {
+                return error(
+                    data.len() as u64,
+                    "the deflate stream is cut short".to_string(),
+                );
+            }
+            Ok(_) => (),
+            Err(e) => {
+                let at = 2 + inflate.total_in();
+                return error(at, format!("the deflate stream is damaged: {}", e));
+            }
+        }
+    }
+    let end = inflate.total_in() as usize;
+    let rest = &deflate[end..];
+    let at = 2 + end as u64;
+    if rest.len() < 4 {
+        return error(at, "the adler32 checksum is cut short".to_string());
+    }
+    let stored = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]);
+    let computed = adler32(out);
+    let mut found = Vec::new();
+    if stored != computed {
+        let description = format!(
+            "the adler32 checksum is {:08x}, but the data's is {:08x}",
+            stored, computed
+        );
+        found.push((Severity::Error, at, description));
+    }
+    if rest.len() > 4 {
+        let description = format!("{} bytes after the zlib stream", rest.len() - 4);
+        found.push((Severity::Warning, at + 4, description));
+    }
+    found
+}
+
+/// The adler32 checksum of `data`, which ends a zlib stream.
+fn adler32(data: &[u8]) -> u32 {
+    const MOD: u32 = 65521;
+    let (mut a, mut b) = (1u32, 0u32);
+    // Sums of 5552 bytes cannot overflow before they are reduced.
+    for block in data.chunks(5552) {
+        for &byte in block {
+            a += byte as u32;
+            b += a;
+        }
+        a %= MOD;
+        b %= MOD;
+    }
+    (b << 16) | a
+}
+
+#[test]
+fn adler32_test() {
+    assert_eq!(adler32(b""), 1);
+    assert_eq!(adler32(b"Wikipedia"), 0x11e60398);
+}
+
+#[test]
+fn check_test() {
+    let mut data = crate::fixture::empty_file();
+    data.extend((0..45000u32).map(|i| (i * 7 % 256) as u8));
+    let compressed = crate::compress_bytes(&data).unwrap();
+    let report = check(&compressed[..]).unwrap();
+    assert!(report.findings.is_empty());
+    assert_eq!((report.chunks, report.bytes_out), (3, 45000));
+    assert!(check(&data[..]).unwrap().findings.is_empty());
+
+    // A flipped byte at the end of the first chunk's data breaks its checksum.
+    let first = u32::from_le_bytes(compressed[8..12].try_into().unwrap()) as usize;
+    let mut damaged = compressed.clone();
+    damaged[12 + first - 1] ^= 1;
+    let report = check(&damaged[..]).unwrap();
+    assert_eq!(report.errors(), 1);
+    assert_eq!(report.findings[0].chunk, Some(0));
+    assert!(report.findings[0].description.contains("adler32"));
+    assert_eq!(report.chunks, 3);
+
+    let mut garbage = compressed.clone();
+    garbage.extend(b"\x01\x02");
+    let report = check(&garbage[..]).unwrap();
+    assert_eq!(report.findings[0].offset, compressed.len() as u64);
+    assert_eq!(report.findings[0].chunk, None);
+
+    let truncated = &compressed[..compressed.len() - 10];
+    let report = check(truncated).unwrap();
+    assert_eq!(report.errors(), 1);
+    assert_eq!(report.findings[0].chunk, Some(2));
+
+    let report = check(&[0x59, 0x06, 0, 0, 9, 0, 0, 0][..]).unwrap();
+    assert_eq!(report.header, None);
+    assert!(!report.is_ok());
+}
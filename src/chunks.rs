@@ -0,0 +1,254 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+use std::io;
+
+use std::io::Read;
+
+use super::{read_chunk, read_u32_or_eof, write_u32, Compression, ErrorKind, Header, Result};
+
+/// A chunk of a compressed data file, as stored in the file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RawChunk {
+    /// The offset in the file of the chunk's length prefix.
+    pub offset: u64,
+    /// The compressed payload, without the length prefix.
+    pub data: Vec<u8>,
+}
+
+impl RawChunk {
+    /// The number of bytes the chunk occupies in the file.
+    pub fn stored_len(&self) -> u64 {
+        4 + self.data.len() as u64
+    }
+}
+
+/// Iterates over the chunks of a compressed data file without
+/// decompressing them.
+pub struct Chunks<R> {
+    inner: R,
+    header: Header,
+    offset: u64,
+    done: bool,
+    concatenated: bool,
+    files: u64,
+}
+
+impl<R: io::Read> Chunks<R> {
+    /// Reads the header from `inner`, which must be compressed.
+    pub fn new(mut inner: R) -> Result<Chunks<R>> {
+        let header = Header::read(&mut inner)?;
+        if !header.compression.is_compressed() {
+            return Err(ErrorKind::NotCompressed.into());
+        }
+        Ok(Chunks::from_parts(inner, header))
+    }
+
+    /// Creates an iterator for `inner` positioned just after `header`.
+    pub(crate) fn from_parts(inner: R, header: Header) -> Chunks<R> {
+        Chunks {
+            inner,
+            header,
+            offset: 8,
+            done: false,
+            concatenated: false,
+            files: 1,
+        }
+    }
+
+    /// The header of the data file whose chunks are being read.
+    pub fn header(&self) -> Header {
+        self.header
+    }
+
+    /// Reads on past the end of the data file into the compressed data files
+    /// that follow it, as `cat` joins them, skipping their headers. A header
+    /// can only be told from a chunk after zlib chunks, which never start
+    /// like a compression flag, so no file is recognized after one of another
+    /// compression.
+    pub fn set_concatenated(&mut self, concatenated: bool) {
+        self.concatenated = concatenated;
+    }
+
+    /// The number of data files read so far, with `set_concatenated`.
+    pub fn files(&self) -> u64 {
+        self.files
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: io::Read> Iterator for Chunks<R> {
+    type Item = Result<RawChunk>;
+
+    fn next(&mut self) -> Option<Result<RawChunk>> {
+        if self.done {
+            return None;
+        }
+        let mut data = Vec::new();
+        let result = match self.concatenated && self.header.compression == Compression::Zlib {
+            true => self.read_chunk_or_header(&mut data),
+            false => read_chunk(&mut self.inner, &mut data),
+        };
+        match result {
+            Ok(true) => {
+                let chunk = RawChunk {
+                    offset: self.offset,
+                    data,
+                };
+                self.offset += chunk.stored_len();
+                Some(Ok(chunk))
+            }
+            Ok(false) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl<R: io::Read> Chunks<R> {
+    /// Reads a chunk into `buf` like `read_chunk`, first skipping the headers
+    /// of the data files that follow.
+    fn read_chunk_or_header(&mut self, buf: &mut Vec<u8>) -> Result<bool> {
+        loop {
+            let n = match read_u32_or_eof(&mut self.inner)? {
+                Some(n) => n as u64,
+                None => return Ok(false),
+            };
+            buf.clear();
+            (&mut self.inner).take(n.min(4)).read_to_end(buf)?;
+            let flag = match buf[..] {
+                [a, b, c, d] => u32::from_le_bytes([a, b, c, d]),
+                _ => u32::MAX,
+            };
+            let Some(compression) = Compression::from_u32(flag) else {
+                let rest = n - buf.len() as u64;
+                if (&mut self.inner).take(rest).read_to_end(buf)? as u64 != rest {
+                    return Err(ErrorKind::UnexpectedEof.into());
+                }
+                return Ok(true);
+            };
+            if !compression.is_compressed() {
+                return Err(ErrorKind::NotCompressed.into());
+            }
+            self.header = Header::new(n as u32, compression);
+            self.offset += 8;
+            self.files += 1;
+            if compression != Compression::Zlib {
+                return read_chunk(&mut self.inner, buf);
+            }
+        }
+    }
+}
+
+/// Writes a compressed data file from already compressed chunk payloads.
+pub struct ChunkWriter<W> {
+    inner: W,
+    header: Header,
+}
+
+impl<W: io::Write> ChunkWriter<W> {
+    /// Writes `header`, which must be compressed, to `inner`.
+    pub fn new(mut inner: W, header: Header) -> Result<ChunkWriter<W>> {
+        if !header.compression.is_compressed() {
+            return Err(ErrorKind::NotCompressed.into());
+        }
+        header.write(&mut inner)?;
+        Ok(ChunkWriter { inner, header })
+    }
+
+    pub fn header(&self) -> Header {
+        self.header
+    }
+
+    /// Writes `payload` with its length prefix.
+    pub fn write_chunk(&mut self, payload: &[u8]) -> Result<()> {
+        if payload.len() > u32::MAX as usize {
+            return Err(ErrorKind::ChunkTooLarge(payload.len() as u64).into());
+        }
+        write_u32(&mut self.inner, payload.len() as u32)?;
+        self.inner.write_all(payload)?;
+        Ok(())
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+#[test]
+fn chunks_test() {
+    let mut data = crate::fixture::empty_file();
+    data.resize(45008, b'c');
+    let compressed = crate::compress_bytes(&data).unwrap();
+    let chunks: Vec<RawChunk> = Chunks::new(&compressed[..])
+        .unwrap()
+        .collect::<Result<_>>()
+        .unwrap();
+    assert_eq!(chunks.len(), 3);
+    assert_eq!(chunks[0].offset, 8);
+    assert_eq!(chunks[1].offset, 8 + chunks[0].stored_len());
+    let end = chunks[2].offset + chunks[2].stored_len();
+    assert_eq!(end, compressed.len() as u64);
+
+    let truncated = &compressed[..compressed.len() - 1];
+    let mut chunks = Chunks::new(truncated).unwrap();
+    assert!(chunks.nth(2).unwrap().is_err());
+    assert!(chunks.next().is_none());
+    assert!(Chunks::new(&data[..]).is_err());
+}
+
+#[test]
+fn concatenated_test() {
+    let mut a = crate::fixture::empty_file();
+    a.resize(30008, b'a');
+    let mut b = vec![0x5a, 0x06, 0, 0, 0, 0, 0, 0];
+    b.resize(10008, b'b');
+    let mut joined = crate::compress_bytes(&a).unwrap();
+    let first = joined.len() as u64;
+    joined.extend(crate::compress_bytes(&b).unwrap());
+    let mut chunks = Chunks::new(&joined[..]).unwrap();
+    chunks.set_concatenated(true);
+    let second = chunks.nth(1).unwrap().unwrap();
+    assert_eq!(second.offset + second.stored_len(), first);
+    assert_eq!(chunks.files(), 1);
+    let last = chunks.next().unwrap().unwrap();
+    assert_eq!(last.offset, first + 8);
+    assert_eq!(chunks.header().version, 0x065a);
+    assert_eq!(chunks.files(), 2);
+    assert!(chunks.next().is_none());
+
+    let mut chunks = Chunks::new(&joined[..]).unwrap();
+    assert!(chunks.nth(2).unwrap().is_err());
+}
+
+#[test]
+fn chunk_writer_test() {
+    let mut data = crate::fixture::empty_file();
+    data.extend((0..60000).map(|i| (i / 20000) as u8));
+    let compressed = crate::compress_bytes(&data).unwrap();
+    let mut chunks = Chunks::new(&compressed[..]).unwrap();
+    let mut writer = ChunkWriter::new(Vec::new(), chunks.header()).unwrap();
+    let first = chunks.next().unwrap().unwrap();
+    for chunk in chunks {
+        writer.write_chunk(&chunk.unwrap().data).unwrap();
+    }
+    writer.write_chunk(&first.data).unwrap();
+    let reordered = crate::decompress_bytes(&writer.into_inner()).unwrap();
+    assert_eq!(reordered[8..20008], data[20008..40008]);
+    assert_eq!(reordered[40008..], data[8..20008]);
+}
@@ -0,0 +1,818 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+use flate2::Compression as ZlibLevel;
+use flate2::{Compress, Decompress, FlushCompress, FlushDecompress, Status};
+#[cfg(feature = "libdeflate")]
+use libdeflater::{CompressionLvl, Compressor, DecompressionError, Decompressor};
+#[cfg(feature = "zstd")]
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+#[cfg(feature = "zstd")]
+use std::sync::{Arc, Mutex, PoisonError};
+#[cfg(feature = "zstd")]
+use zstd::dict::{DecoderDictionary, EncoderDictionary};
+
+use super::{Compression, Error, ErrorKind};
+
+/// Compression of individual chunks.
+///
+/// The framing of chunks (length prefixes and the file header) is handled by
+/// the caller; a codec only ever sees a single chunk at a time.
+pub trait ChunkCodec {
+    /// The header compression field written for files using this codec.
+    /// There is no default, as the header of a file is trusted to tell what
+    /// its chunks are.
+    fn compression(&self) -> Compression;
+
+    /// Compresses `input`, appending the chunk payload to `output`.
+    fn compress(&mut self, input: &[u8], output: &mut Vec<u8>) -> io::Result<()>;
+
+    /// Decompresses the chunk payload `input`, appending the data to `output`.
+    fn decompress(&mut self, input: &[u8], output: &mut Vec<u8>) -> io::Result<()>;
+
+    /// Like `decompress`, but fails with `ErrorKind::LimitExceeded` instead
+    /// of appending more than `limit` bytes to `output`.
+    ///
+    /// The default implementation checks the limit only after decompressing.
+    fn decompress_limited(
+        &mut self,
+        input: &[u8],
+        output: &mut Vec<u8>,
+        limit: usize,
+    ) -> io::Result<()> {
+        let start = output.len();
+        self.decompress(input, output)?;
+        if output.len() - start > limit {
+            output.truncate(start + limit);
+            return Err(limit_exceeded());
+        }
+        Ok(())
+    }
+
+    /// Like `decompress_limited`, but writes the data to `output`. Whatever
+    /// was decompressed is written even if an error occurs.
+    ///
+    /// The default implementation decompresses the chunk into a buffer first,
+    /// as lz4 does; the zlib, libdeflate and zstd codecs stream the data
+    /// through a fixed-size buffer.
+    fn decompress_to(
+        &mut self,
+        input: &[u8],
+        output: &mut dyn Write,
+        limit: usize,
+    ) -> io::Result<()> {
+        let mut buf = Vec::new();
+        let result = self.decompress_limited(input, &mut buf, limit);
+        output.write_all(&buf)?;
+        result
+    }
+}
+
+fn limit_exceeded() -> io::Error {
+    Error::from(ErrorKind::LimitExceeded).into()
+}
+
+/// Writes to `output`, failing once more than `left` bytes are written.
+struct Limited<W> {
+    output: W,
+    left: usize,
+}
+
+impl<W: Write> Write for Limited<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if data.len() > self.left {
+            return Err(limit_exceeded());
+        }
+        let n = self.output.write(data)?;
+        self.left -= n;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+impl<C: ChunkCodec + ?Sized> ChunkCodec for &mut C {
+    fn compression(&self) -> Compression {
+        (**self).compression()
+    }
+
+    fn compress(&mut self, input: &[u8], output: &mut Vec<u8>) -> io::Result<()> {
+        (**self).compress(input, output)
+    }
+
+    fn decompress(&mut self, input: &[u8], output: &mut Vec<u8>) -> io::Result<()> {
+        (**self).decompress(input, output)
+    }
+
+    fn decompress_limited(
+        &mut self,
+        input: &[u8],
+        output: &mut Vec<u8>,
+        limit: usize,
+    ) -> io::Result<()> {
+        (**self).decompress_limited(input, output, limit)
+    }
+
+    fn decompress_to(
+        &mut self,
+        input: &[u8],
+        output: &mut dyn Write,
+        limit: usize,
+    ) -> io::Result<()> {
+        (**self).decompress_to(input, output, limit)
+    }
+}
+
+impl<C: ChunkCodec + ?Sized> ChunkCodec for Box<C> {
+    fn compression(&self) -> Compression {
+        (**self).compression()
+    }
+
+    fn compress(&mut self, input: &[u8], output: &mut Vec<u8>) -> io::Result<()> {
+        (**self).compress(input, output)
+    }
+
+    fn decompress(&mut self, input: &[u8], output: &mut Vec<u8>) -> io::Result<()> {
+        (**self).decompress(input, output)
+    }
+
+    fn decompress_limited(
+        &mut self,
+        input: &[u8],
+        output: &mut Vec<u8>,
+        limit: usize,
+    ) -> io::Result<()> {
+        (**self).decompress_limited(input, output, limit)
+    }
+
+    fn decompress_to(
+        &mut self,
+        input: &[u8],
+        output: &mut dyn Write,
+        limit: usize,
+    ) -> io::Result<()> {
+        (**self).decompress_to(input, output, limit)
+    }
+}
+
+/// The implementations of zlib that this build uses, as chosen by the
+/// `zlib-ng` and `libdeflate` features.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Backend {
+    /// The implementation behind `Codec::Zlib`, which compresses by default.
+    pub compress: &'static str,
+    /// The implementation that decompresses zlib chunks.
+    pub decompress: &'static str,
+}
+
+/// Returns the zlib implementations of this build.
+pub fn backend() -> Backend {
+    let compress = if cfg!(feature = "zlib-ng") {
+        "zlib-ng"
+    } else {
+        "miniz_oxide"
+    };
+    let decompress = if cfg!(feature = "libdeflate") {
+        "libdeflate"
+    } else {
+        compress
+    };
+    Backend {
+        compress,
+        decompress,
+    }
+}
+
+/// The built-in codecs, selectable with `Options::codec`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Codec {
+    #[default]
+    Zlib,
+    /// Zlib through libdeflate. Readable by Dwarf Fortress, but the output
+    /// differs from that of `Zlib`.
+    #[cfg(feature = "libdeflate")]
+    Libdeflate,
+    #[cfg(feature = "zstd")]
+    Zstd,
+    #[cfg(feature = "lz4")]
+    Lz4,
+}
+
+impl Codec {
+    pub fn compression(self) -> Compression {
+        match self {
+            Codec::Zlib => Compression::Zlib,
+            #[cfg(feature = "libdeflate")]
+            Codec::Libdeflate => Compression::Zlib,
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => Compression::Zstd,
+            #[cfg(feature = "lz4")]
+            Codec::Lz4 => Compression::Lz4,
+        }
+    }
+
+    /// The level that compresses fastest, 1 for zlib and zstd.
+    pub fn fast_level(self) -> u32 {
+        1
+    }
+
+    /// The level that compresses best, 9 for zlib, 12 for libdeflate and 19
+    /// for zstd.
+    pub fn best_level(self) -> u32 {
+        match self {
+            Codec::Zlib => 9,
+            #[cfg(feature = "libdeflate")]
+            Codec::Libdeflate => 12,
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => 19,
+            #[cfg(feature = "lz4")]
+            Codec::Lz4 => 1,
+        }
+    }
+
+    /// Creates the codec, using its default level if `level` is `None`.
+    /// The lz4 codec has no levels and ignores `level`.
+    pub fn build(self, level: Option<u32>) -> Box<dyn ChunkCodec + Send> {
+        match self {
+            Codec::Zlib => Box::new(level.map_or_else(Zlib::default, Zlib::new)),
+            #[cfg(feature = "libdeflate")]
+            Codec::Libdeflate => Box::new(level.map_or_else(Libdeflate::default, Libdeflate::new)),
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => Box::new(level.map_or_else(Zstd::default, Zstd::new)),
+            #[cfg(feature = "lz4")]
+            Codec::Lz4 => Box::new(Lz4),
+        }
+    }
+}
+
+/// The zlib codec used by Dwarf Fortress itself.
+///
+/// The zlib states are kept and reset between chunks.
+#[derive(Debug, Default)]
+pub struct Zlib {
+    level: ZlibLevel,
+    compress: Option<Compress>,
+    decompress: Option<Decompress>,
+}
+
+impl Zlib {
+    pub fn new(level: u32) -> Zlib {
+        Zlib {
+            level: ZlibLevel::new(level),
+            ..Zlib::default()
+        }
+    }
+}
+
+impl Clone for Zlib {
+    fn clone(&self) -> Zlib {
+        Zlib {
+            level: self.level,
+            ..Zlib::default()
+        }
+    }
+}
+
+impl ChunkCodec for Zlib {
+    fn compression(&self) -> Compression {
+        Compression::Zlib
+    }
+
+    fn compress(&mut self, input: &[u8], output: &mut Vec<u8>) -> io::Result<()> {
+        let level = self.level;
+        let compress = self
+            .compress
+            .get_or_insert_with(|| Compress::new(level, true));
+        compress.reset();
+        loop {
+            output.reserve(input.len() / 2 + 64);
+            let rest = &input[compress.total_in() as usize..];
+            match compress.compress_vec(rest, output, FlushCompress::Finish)? {
+                Status::StreamEnd => return Ok(()),
+                Status::Ok | Status::BufError => (),
+            }
+        }
+    }
+
+    fn decompress(&mut self, input: &[u8], output: &mut Vec<u8>) -> io::Result<()> {
+        self.decompress_to(input, output, usize::MAX)
+    }
+
+    fn decompress_limited(
+        &mut self,
+        input: &[u8],
+        output: &mut Vec<u8>,
+        limit: usize,
+    ) -> io::Result<()> {
+        self.decompress_to(input, output, limit)
+    }
+
+    fn decompress_to(
+        &mut self,
+        input: &[u8],
+        output: &mut dyn Write,
+        limit: usize,
+    ) -> io::Result<()> {
+        let mut output = Limited {
+            output,
+            left: limit,
+        };
+        let decompress = self.decompress.get_or_insert_with(|| Decompress::new(true));
+        decompress.reset(true);
+        let mut buf = [0; 32 * 1024];
+        loop {
+            let (read, written) = (decompress.total_in(), decompress.total_out());
+            let rest = &input[read as usize..];
+            let status = decompress
+                .decompress(rest, &mut buf, FlushDecompress::None)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let n = (decompress.total_out() - written) as usize;
+            output.write_all(&buf[..n])?;
+            if status == Status::StreamEnd {
+                return Ok(());
+            }
+            if n == 0 && decompress.total_in() == read {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "zlib stream is truncated",
+                ));
+            }
+        }
+    }
+}
+
+/// A zlib codec using libdeflate, which works on whole chunks at once.
+///
+/// `decompress` and `decompress_limited` need a buffer as large as the
+/// chunk decompresses to, grown until it fits, up to the limit and what
+/// deflate can expand to. `decompress_to` streams the chunk through zlib
+/// instead, which reads the same streams.
+#[cfg(feature = "libdeflate")]
+#[derive(Default)]
+pub struct Libdeflate {
+    level: CompressionLvl,
+    compressor: Option<Compressor>,
+    decompressor: Option<Decompressor>,
+    zlib: Option<Zlib>,
+}
+
+/// The most that deflate expands its input, 258 bytes from two bits.
+#[cfg(any(feature = "libdeflate", feature = "parallel"))]
+pub(crate) const MAX_EXPANSION: usize = 1032;
+
+#[cfg(feature = "libdeflate")]
+impl Libdeflate {
+    /// Creates a libdeflate codec with the given level, from 1 to 12.
+    pub fn new(level: u32) -> Libdeflate {
+        let level = CompressionLvl::new(level.clamp(1, 12) as i32).unwrap_or_default();
+        Libdeflate {
+            level,
+            ..Libdeflate::default()
+        }
+    }
+}
+
+#[cfg(feature = "libdeflate")]
+impl ChunkCodec for Libdeflate {
+    fn compression(&self) -> Compression {
+        Compression::Zlib
+    }
+
+    fn compress(&mut self, input: &[u8], output: &mut Vec<u8>) -> io::Result<()> {
+        let level = self.level;
+        let compressor = self
+            .compressor
+            .get_or_insert_with(|| Compressor::new(level));
+        let start = output.len();
+        output.resize(start + compressor.zlib_compress_bound(input.len()), 0);
+        let n = compressor
+            .zlib_compress(input, &mut output[start..])
+            .map_err(io::Error::other)?;
+        output.truncate(start + n);
+        Ok(())
+    }
+
+    fn decompress(&mut self, input: &[u8], output: &mut Vec<u8>) -> io::Result<()> {
+        self.decompress_limited(input, output, usize::MAX)
+    }
+
+    fn decompress_limited(
+        &mut self,
+        input: &[u8],
+        output: &mut Vec<u8>,
+        limit: usize,
+    ) -> io::Result<()> {
+        let decompressor = self.decompressor.get_or_insert_with(Decompressor::new);
+        let start = output.len();
+        // The decompressed size is unknown, so grow the buffer until it fits.
+        let limit = limit.min(input.len().saturating_mul(MAX_EXPANSION));
+        let mut size = (4 * input.len()).max(super::CHUNK_SIZE).min(limit);
+        loop {
+            output.resize(start + size, 0);
+            match decompressor.zlib_decompress(input, &mut output[start..]) {
+                Ok(n) => {
+                    output.truncate(start + n);
+                    return Ok(());
+                }
+                Err(DecompressionError::InsufficientSpace) if size < limit => {
+                    size = size.saturating_mul(2).min(limit);
+                }
+                Err(DecompressionError::InsufficientSpace) => {
+                    output.truncate(start);
+                    return Err(limit_exceeded());
+                }
+                Err(e) => {
+                    output.truncate(start);
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+                }
+            }
+        }
+    }
+
+    fn decompress_to(
+        &mut self,
+        input: &[u8],
+        output: &mut dyn Write,
+        limit: usize,
+    ) -> io::Result<()> {
+        self.zlib
+            .get_or_insert_with(Zlib::default)
+            .decompress_to(input, output, limit)
+    }
+}
+
+/// A zstd codec, producing files that Dwarf Fortress cannot read.
+#[cfg(feature = "zstd")]
+#[derive(Clone, Debug)]
+pub struct Zstd {
+    level: i32,
+    dictionary: Option<Arc<Dictionary>>,
+}
+
+#[cfg(feature = "zstd")]
+impl Zstd {
+    /// Creates a zstd codec with the given level, from 1 to 22.
+    pub fn new(level: u32) -> Zstd {
+        Zstd {
+            level: level as i32,
+            dictionary: None,
+        }
+    }
+
+    /// Creates a zstd codec that compresses and decompresses with
+    /// `dictionary`, at the given level or the default one.
+    pub fn with_dictionary(level: Option<u32>, dictionary: Arc<Dictionary>) -> Zstd {
+        Zstd {
+            dictionary: Some(dictionary),
+            ..level.map_or_else(Zstd::default, Zstd::new)
+        }
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl Default for Zstd {
+    fn default() -> Zstd {
+        Zstd {
+            level: zstd::DEFAULT_COMPRESSION_LEVEL,
+            dictionary: None,
+        }
+    }
+}
+
+/// A zstd dictionary, such as one trained on the chunks of saves, which
+/// compresses small chunks much better. Chunks compressed with it only
+/// decompress with it.
+#[cfg(feature = "zstd")]
+pub struct Dictionary {
+    data: Vec<u8>,
+    decoder: DecoderDictionary<'static>,
+    /// The dictionary prepared for each level it compressed at.
+    encoders: Mutex<Vec<(i32, Arc<EncoderDictionary<'static>>)>>,
+}
+
+#[cfg(feature = "zstd")]
+impl Dictionary {
+    /// Takes the bytes of a dictionary, as `as_bytes` gives them, or as
+    /// `zstd --train` writes them.
+    pub fn new(data: Vec<u8>) -> Dictionary {
+        Dictionary {
+            decoder: DecoderDictionary::copy(&data),
+            data,
+            encoders: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Trains a dictionary of up to `max_size` bytes on `samples`, such as
+    /// decompressed chunks. The samples should come to a hundred times that.
+    pub fn train<S: AsRef<[u8]>>(samples: &[S], max_size: usize) -> io::Result<Dictionary> {
+        zstd::dict::from_samples(samples, max_size).map(Dictionary::new)
+    }
+
+    /// The bytes of the dictionary, to save it with.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn encoder(&self, level: i32) -> Arc<EncoderDictionary<'static>> {
+        let mut encoders = self.encoders.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some((_, encoder)) = encoders.iter().find(|(l, _)| *l == level) {
+            return encoder.clone();
+        }
+        let encoder = Arc::new(EncoderDictionary::copy(&self.data, level));
+        encoders.push((level, encoder.clone()));
+        encoder
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl fmt::Debug for Dictionary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Dictionary")
+            .field("len", &self.data.len())
+            .finish()
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl ChunkCodec for Zstd {
+    fn compression(&self) -> Compression {
+        Compression::Zstd
+    }
+
+    fn compress(&mut self, input: &[u8], output: &mut Vec<u8>) -> io::Result<()> {
+        let Some(ref dictionary) = self.dictionary else {
+            return zstd::stream::copy_encode(input, output, self.level);
+        };
+        let encoder = dictionary.encoder(self.level);
+        let mut writer = zstd::stream::write::Encoder::with_prepared_dictionary(output, &encoder)?;
+        writer.write_all(input)?;
+        writer.finish().map(|_| ())
+    }
+
+    fn decompress(&mut self, input: &[u8], output: &mut Vec<u8>) -> io::Result<()> {
+        self.decompress_to(input, output, usize::MAX)
+    }
+
+    fn decompress_limited(
+        &mut self,
+        input: &[u8],
+        output: &mut Vec<u8>,
+        limit: usize,
+    ) -> io::Result<()> {
+        self.decompress_to(input, output, limit)
+    }
+
+    fn decompress_to(
+        &mut self,
+        input: &[u8],
+        output: &mut dyn Write,
+        limit: usize,
+    ) -> io::Result<()> {
+        // Tell a missing or another dictionary from a damaged chunk.
+        let wanted = zstd::zstd_safe::get_dict_id_from_frame(input);
+        let given = self
+            .dictionary
+            .as_ref()
+            .and_then(|dictionary| zstd::zstd_safe::get_dict_id_from_dict(&dictionary.data));
+        if wanted.is_some() && wanted != given {
+            let message = match given {
+                None => "Compressed with a zstd dictionary",
+                Some(_) => "Compressed with another zstd dictionary",
+            };
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+        }
+        let mut output = Limited {
+            output,
+            left: limit,
+        };
+        match self.dictionary {
+            Some(ref dictionary) => {
+                let mut reader = zstd::stream::read::Decoder::with_prepared_dictionary(
+                    input,
+                    &dictionary.decoder,
+                )?;
+                io::copy(&mut reader, &mut output).map(|_| ())
+            }
+            None => zstd::stream::copy_decode(input, output),
+        }
+    }
+}
+
+/// An lz4 codec, trading compression ratio for speed.
+#[cfg(feature = "lz4")]
+#[derive(Clone, Debug, Default)]
+pub struct Lz4;
+
+#[cfg(feature = "lz4")]
+impl ChunkCodec for Lz4 {
+    fn compression(&self) -> Compression {
+        Compression::Lz4
+    }
+
+    fn compress(&mut self, input: &[u8], output: &mut Vec<u8>) -> io::Result<()> {
+        output.extend_from_slice(&lz4_flex::compress_prepend_size(input));
+        Ok(())
+    }
+
+    fn decompress(&mut self, input: &[u8], output: &mut Vec<u8>) -> io::Result<()> {
+        let data = lz4_flex::decompress_size_prepended(input)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        output.extend_from_slice(&data);
+        Ok(())
+    }
+
+    fn decompress_limited(
+        &mut self,
+        input: &[u8],
+        output: &mut Vec<u8>,
+        limit: usize,
+    ) -> io::Result<()> {
+        // The size prefix tells us the size before we allocate.
+        match lz4_flex::block::uncompressed_size(input) {
+            Ok((size, _)) if size > limit => Err(limit_exceeded()),
+            _ => self.decompress(input, output),
+        }
+    }
+}
+
+#[test]
+fn zlib_codec_test() {
+    let data: Vec<u8> = (0..20000).map(|i| (i % 17) as u8).collect();
+    let mut codec = Zlib::new(9);
+    let mut compressed = vec![42];
+    codec.compress(&data, &mut compressed).unwrap();
+    let mut decompressed = vec![42];
+    codec
+        .decompress(&compressed[1..], &mut decompressed)
+        .unwrap();
+    assert_eq!(decompressed[0], 42);
+    assert_eq!(&decompressed[1..], &data[..]);
+    // The reused zlib states must not carry over between chunks.
+    let mut again = vec![42];
+    codec.compress(&data, &mut again).unwrap();
+    assert_eq!(again, compressed);
+    assert!(codec
+        .decompress(&compressed[1..compressed.len() - 1], &mut Vec::new())
+        .is_err());
+    let mut decompressed = Vec::new();
+    codec
+        .decompress(&compressed[1..], &mut decompressed)
+        .unwrap();
+    assert_eq!(decompressed, data);
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn zstd_codec_test() {
+    let data: Vec<u8> = (0..20000).map(|i| (i % 17) as u8).collect();
+    let mut codec = Codec::Zstd.build(Some(19));
+    assert_eq!(codec.compression(), Compression::Zstd);
+    let mut compressed = Vec::new();
+    codec.compress(&data, &mut compressed).unwrap();
+    let mut decompressed = Vec::new();
+    codec.decompress(&compressed, &mut decompressed).unwrap();
+    assert_eq!(decompressed, data);
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn zstd_dictionary_test() {
+    let samples = crate::fixture::dictionary_samples();
+    let dictionary = Arc::new(Dictionary::train(&samples, 4096).unwrap());
+    assert_eq!(
+        Dictionary::new(dictionary.as_bytes().to_vec()).as_bytes(),
+        dictionary.as_bytes()
+    );
+    let mut codec = Zstd::with_dictionary(Some(19), dictionary.clone());
+    let (mut with, mut without) = (Vec::new(), Vec::new());
+    codec.compress(&samples[42], &mut with).unwrap();
+    Zstd::new(19).compress(&samples[42], &mut without).unwrap();
+    assert!(with.len() < without.len());
+    let mut decompressed = Vec::new();
+    Zstd::with_dictionary(None, dictionary)
+        .decompress(&with, &mut decompressed)
+        .unwrap();
+    assert_eq!(decompressed, samples[42]);
+    assert!(Zstd::default().decompress(&with, &mut Vec::new()).is_err());
+    assert!(codec
+        .decompress(&with[..with.len() - 2], &mut Vec::new())
+        .is_err());
+}
+
+#[cfg(feature = "lz4")]
+#[test]
+fn lz4_codec_test() {
+    let data: Vec<u8> = (0..20000).map(|i| (i % 17) as u8).collect();
+    let mut codec = Codec::Lz4.build(None);
+    assert_eq!(codec.compression(), Compression::Lz4);
+    let mut compressed = Vec::new();
+    codec.compress(&data, &mut compressed).unwrap();
+    assert!(compressed.len() < data.len());
+    let mut decompressed = Vec::new();
+    codec.decompress(&compressed, &mut decompressed).unwrap();
+    assert_eq!(decompressed, data);
+    assert!(codec
+        .decompress(&compressed[..100], &mut Vec::new())
+        .is_err());
+}
+
+#[test]
+fn limited_test() {
+    let data = vec![0; 100000];
+    let mut codec = Codec::default().build(None);
+    let mut compressed = Vec::new();
+    codec.compress(&data, &mut compressed).unwrap();
+    let mut output = Vec::new();
+    codec
+        .decompress_limited(&compressed, &mut output, 100000)
+        .unwrap();
+    assert_eq!(output, data);
+    let e = codec
+        .decompress_limited(&compressed, &mut Vec::new(), 99999)
+        .unwrap_err();
+    match *Error::from(e).kind() {
+        ErrorKind::LimitExceeded => (),
+        ref kind => panic!("{:?}", kind),
+    }
+}
+
+#[test]
+fn decompress_to_test() {
+    struct Largest(usize, usize);
+    impl Write for Largest {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.0 += data.len();
+            self.1 = self.1.max(data.len());
+            Ok(data.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    let data = vec![7; 1 << 20];
+    let mut codec = Zlib::default();
+    let mut compressed = Vec::new();
+    codec.compress(&data, &mut compressed).unwrap();
+    let mut output = Largest(0, 0);
+    codec
+        .decompress_to(&compressed, &mut output, data.len())
+        .unwrap();
+    assert_eq!(output.0, data.len());
+    assert!(output.1 < data.len() / 8);
+}
+
+#[cfg(feature = "libdeflate")]
+#[test]
+fn libdeflate_codec_test() {
+    let data: Vec<u8> = (0..20000).map(|i| (i % 17) as u8).collect();
+    let mut codec = Codec::Libdeflate.build(Some(12));
+    let mut zlib = Zlib::default();
+    let mut compressed = Vec::new();
+    codec.compress(&data, &mut compressed).unwrap();
+    let mut decompressed = Vec::new();
+    zlib.decompress(&compressed, &mut decompressed).unwrap();
+    assert_eq!(decompressed, data);
+
+    let data = vec![0; 1 << 20];
+    let mut compressed = Vec::new();
+    zlib.compress(&data, &mut compressed).unwrap();
+    let mut decompressed = Vec::new();
+    codec.decompress(&compressed, &mut decompressed).unwrap();
+    assert_eq!(decompressed, data);
+    assert!(codec
+        .decompress_limited(&compressed, &mut Vec::new(), data.len() - 1)
+        .is_err());
+    assert!(codec
+        .decompress(&compressed[..100], &mut Vec::new())
+        .is_err());
+
+    // Streamed, within the limit and keeping what a cut stream holds.
+    let mut written = Vec::new();
+    assert!(codec
+        .decompress_to(&compressed, &mut written, 1000)
+        .is_err());
+    assert!(written.len() <= 1000);
+    let mut written = Vec::new();
+    codec
+        .decompress_to(&compressed, &mut written, usize::MAX)
+        .unwrap();
+    assert_eq!(written, data);
+    let mut written = Vec::new();
+    let cut = &compressed[..compressed.len() / 2];
+    assert!(codec.decompress_to(cut, &mut written, usize::MAX).is_err());
+    assert!(!written.is_empty());
+}
+
+#[test]
+fn backend_test() {
+    let backend = backend();
+    assert!(["miniz_oxide", "zlib-ng"].contains(&backend.compress));
+    assert_eq!(
+        backend.decompress == "libdeflate",
+        cfg!(feature = "libdeflate")
+    );
+}
@@ -0,0 +1,329 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+//! Deltas of a data file against another, such as an autosave against the
+//! one before it, which shares most of its payload.
+//!
+//! A delta is worked out from what the files decompress to. The base is cut
+//! into blocks of `BLOCK` bytes; a rolling hash of the new file, taken at
+//! every offset, finds where a block of the base comes again, and the match
+//! is extended both ways. The delta is a list of copies from the base and
+//! bytes inserted between them.
+//!
+//! It starts with `DELTA_MAGIC`, then the compression of the new file and the
+//! sizes and CRC-32s of the decompressed base and new file, in little
+//! endian, and then the operations compressed with zlib: byte 0 with the
+//! offset and length of a copy, or byte 1 with the length of the bytes
+//! inserted and the bytes, the numbers as LEB128.
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Crc;
+
+use super::{decompress_bytes, recode_with, Compression, Header, Options, Target};
+use super::{ErrorKind, Result};
+
+/// The bytes a delta starts with.
+pub const DELTA_MAGIC: &[u8; 8] = b"dfdelta\x01";
+
+/// The bytes a copy must share with the base, at least.
+const BLOCK: usize = 64;
+
+/// The multiplier of the rolling hash.
+const PRIME: u64 = 0x100000001b3;
+
+const COPY: u8 = 0;
+const INSERT: u8 = 1;
+
+/// The hash of `BLOCK` bytes.
+fn block_hash(block: &[u8]) -> u64 {
+    block.iter().fold(0u64, |hash, &b| {
+        hash.wrapping_mul(PRIME).wrapping_add(b as u64)
+    })
+}
+
+fn crc(data: &[u8]) -> u32 {
+    let mut crc = Crc::new();
+    crc.update(data);
+    crc.sum()
+}
+
+fn write_varint<W: Write>(w: &mut W, mut value: u64) -> io::Result<()> {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            return w.write_all(&[byte]);
+        }
+        w.write_all(&[byte | 0x80])?;
+    }
+}
+
+fn read_varint<R: Read>(r: &mut R) -> Result<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0];
+        r.read_exact(&mut byte)?;
+        value |= ((byte[0] & 0x7f) as u64) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(ErrorKind::DeltaCorrupt.into())
+}
+
+/// The operations that make `new` from `base`, written to `ops`.
+fn encode<W: Write>(base: &[u8], new: &[u8], ops: &mut W) -> io::Result<()> {
+    let mut blocks: HashMap<u64, usize> = HashMap::new();
+    for (i, block) in base.chunks_exact(BLOCK).enumerate() {
+        blocks.entry(block_hash(block)).or_insert(i * BLOCK);
+    }
+    // PRIME to the power of BLOCK - 1, to take the oldest byte out.
+    let top = (1..BLOCK).fold(1u64, |power, _| power.wrapping_mul(PRIME));
+    let mut insert_from = 0;
+    let mut i = 0;
+    let mut hash = match new.len() >= BLOCK {
+        true => block_hash(&new[..BLOCK]),
+        false => 0,
+    };
+    while i + BLOCK <= new.len() {
+        let found = blocks
+            .get(&hash)
+            .copied()
+            .filter(|&at| base[at..at + BLOCK] == new[i..i + BLOCK]);
+        let Some(mut at) = found else {
+            if i + BLOCK < new.len() {
+                hash = hash
+                    .wrapping_sub((new[i] as u64).wrapping_mul(top))
+                    .wrapping_mul(PRIME)
+                    .wrapping_add(new[i + BLOCK] as u64);
+            }
+            i += 1;
+            continue;
+        };
+        // Extend the match back over the bytes not yet written, and on.
+        let mut start = i;
+        while start > insert_from && at > 0 && base[at - 1] == new[start - 1] {
+            start -= 1;
+            at -= 1;
+        }
+        let mut end = i + BLOCK;
+        while end < new.len()
+            && at + (end - start) < base.len()
+            && base[at + (end - start)] == new[end]
+        {
+            end += 1;
+        }
+        if start > insert_from {
+            ops.write_all(&[INSERT])?;
+            write_varint(ops, (start - insert_from) as u64)?;
+            ops.write_all(&new[insert_from..start])?;
+        }
+        ops.write_all(&[COPY])?;
+        write_varint(ops, at as u64)?;
+        write_varint(ops, (end - start) as u64)?;
+        insert_from = end;
+        i = end;
+        if i + BLOCK <= new.len() {
+            hash = block_hash(&new[i..i + BLOCK]);
+        }
+    }
+    if new.len() > insert_from {
+        ops.write_all(&[INSERT])?;
+        write_varint(ops, (new.len() - insert_from) as u64)?;
+        ops.write_all(&new[insert_from..])?;
+    }
+    Ok(())
+}
+
+/// Encodes the data file `new` as a delta against the data file `base`.
+/// Either may be compressed; the delta is of what they decompress to, and
+/// notes how `new` was compressed for `delta_apply` to compress it again.
+pub fn delta_create(base: &[u8], new: &[u8]) -> Result<Vec<u8>> {
+    let compression = Header::parse(new)?.compression;
+    let base = decompress_bytes(base)?;
+    let new = decompress_bytes(new)?;
+    let mut delta = Vec::with_capacity(new.len() / 16);
+    delta.extend_from_slice(DELTA_MAGIC);
+    delta.extend_from_slice(&compression.to_u32().to_le_bytes());
+    for data in [&base, &new] {
+        delta.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        delta.extend_from_slice(&crc(data).to_le_bytes());
+    }
+    let mut ops = ZlibEncoder::new(delta, flate2::Compression::best());
+    encode(&base, &new, &mut ops)?;
+    Ok(ops.finish()?)
+}
+
+/// Makes the data file that `delta` was created from, against the same
+/// `base`. It is compressed as it was, with the default level, so that what
+/// it decompresses to is the same, if not every byte of it.
+///
+/// Fails with `ErrorKind::NotADelta` if `delta` is not one, with
+/// `ErrorKind::DeltaMismatch` if it was created against another base, and
+/// with `ErrorKind::DeltaCorrupt` if it is damaged.
+pub fn delta_apply(base: &[u8], delta: &[u8]) -> Result<Vec<u8>> {
+    if delta.len() < 8 || &delta[..8] != DELTA_MAGIC {
+        return Err(ErrorKind::NotADelta.into());
+    }
+    if delta.len() < 36 {
+        return Err(ErrorKind::DeltaCorrupt.into());
+    }
+    let word = |at: usize| u32::from_le_bytes(delta[at..at + 4].try_into().unwrap());
+    let long = |at: usize| u64::from_le_bytes(delta[at..at + 8].try_into().unwrap());
+    let compression = word(8);
+    let compression =
+        Compression::from_u32(compression).ok_or(ErrorKind::CompressionUnknown(compression))?;
+    let base = decompress_bytes(base)?;
+    if base.len() as u64 != long(12) || crc(&base) != word(20) {
+        return Err(ErrorKind::DeltaMismatch.into());
+    }
+    // The base is the one it was created against, so what goes wrong from
+    // here on is the fault of the delta.
+    let (size, checksum) = (long(24), word(32));
+    let new = decode(&base, &delta[36..], size).ok_or(ErrorKind::DeltaCorrupt)?;
+    if new.len() as u64 != size || crc(&new) != checksum {
+        return Err(ErrorKind::DeltaCorrupt.into());
+    }
+    if !compression.is_compressed() {
+        return Ok(new);
+    }
+    let codec = compression
+        .codec()
+        .ok_or(ErrorKind::CompressionUnknown(compression.to_u32()))?;
+    let mut out = Vec::with_capacity(new.len() / 2);
+    recode_with(
+        &new[..],
+        &mut out,
+        Target::Compressed,
+        &Options::new().codec(codec),
+    )?;
+    Ok(out)
+}
+
+/// What the compressed operations `ops` make from `base`, or `None` if they
+/// cannot be read, copy from past the end of `base` or would make more than
+/// `size` bytes.
+fn decode(base: &[u8], ops: &[u8], size: u64) -> Option<Vec<u8>> {
+    let mut ops = ZlibDecoder::new(ops);
+    let mut new = Vec::with_capacity(size.min(1 << 30) as usize);
+    loop {
+        let mut op = [0];
+        if ops.read(&mut op).ok()? == 0 {
+            break;
+        }
+        // Nothing is read or copied past `size`, whatever the ops say.
+        let left = size - new.len() as u64;
+        match op[0] {
+            COPY => {
+                let at = read_varint(&mut ops).ok()?;
+                let len = read_varint(&mut ops).ok()?;
+                let end = at.checked_add(len)?;
+                if len > left || end > base.len() as u64 {
+                    return None;
+                }
+                new.extend_from_slice(&base[at as usize..end as usize]);
+            }
+            INSERT => {
+                let len = read_varint(&mut ops).ok()?;
+                if len > left || (&mut ops).take(len).read_to_end(&mut new).ok()? as u64 != len {
+                    return None;
+                }
+            }
+            _ => return None,
+        }
+    }
+    Some(new)
+}
+
+#[test]
+fn varint_test() {
+    for value in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+        let mut bytes = Vec::new();
+        write_varint(&mut bytes, value).unwrap();
+        assert_eq!(read_varint(&mut &bytes[..]).unwrap(), value);
+    }
+}
+
+#[test]
+fn delta_test() {
+    use super::compress_bytes;
+
+    let mut base = Header::new(1625, Compression::Uncompressed)
+        .to_bytes()
+        .to_vec();
+    let mut state = 12345u32;
+    for _ in 0..200_000 {
+        state = state.wrapping_mul(1103515245).wrapping_add(12345);
+        base.push((state >> 16) as u8);
+    }
+    let mut new = base.clone();
+    new[5000..5010].copy_from_slice(b"0123456789");
+    new.splice(90_000..90_000, b"inserted".iter().copied());
+    new.drain(150_000..150_100);
+    new.extend_from_slice(b"appended");
+    let new = compress_bytes(&new).unwrap();
+    let delta = delta_create(&base, &new).unwrap();
+    assert!(delta.len() < 1000, "{} bytes", delta.len());
+    let applied = delta_apply(&compress_bytes(&base).unwrap(), &delta).unwrap();
+    assert_eq!(
+        Header::parse(&applied).unwrap().compression,
+        Compression::Zlib
+    );
+    assert_eq!(
+        decompress_bytes(&applied).unwrap(),
+        decompress_bytes(&new).unwrap()
+    );
+
+    // Against itself, and from nothing in common.
+    let same = delta_create(&base, &base).unwrap();
+    assert_eq!(delta_apply(&base, &same).unwrap(), base);
+    let short = Header::new(1625, Compression::Uncompressed).to_bytes();
+    let from_short = delta_create(&short, &base).unwrap();
+    assert_eq!(delta_apply(&short, &from_short).unwrap(), base);
+    let to_short = delta_create(&base, &short).unwrap();
+    assert_eq!(delta_apply(&base, &to_short).unwrap(), short);
+
+    match *delta_apply(&short, &delta).unwrap_err().kind() {
+        ErrorKind::DeltaMismatch => (),
+        ref kind => panic!("{:?}", kind),
+    }
+    match *delta_apply(&base, &base).unwrap_err().kind() {
+        ErrorKind::NotADelta => (),
+        ref kind => panic!("{:?}", kind),
+    }
+    for cut in [&delta[..20], &delta[..delta.len() - 10]] {
+        match *delta_apply(&base, cut).unwrap_err().kind() {
+            ErrorKind::DeltaCorrupt => (),
+            ref kind => panic!("{:?}", kind),
+        }
+    }
+
+    // Operations that copy from past the end of the base, that overflow, or
+    // that are not operations at all.
+    let with_ops = |ops: &[u8]| {
+        let mut damaged = same[..36].to_vec();
+        let mut encoder = ZlibEncoder::new(&mut damaged, flate2::Compression::default());
+        encoder.write_all(ops).unwrap();
+        encoder.finish().unwrap();
+        damaged
+    };
+    let mut past_end = vec![COPY];
+    write_varint(&mut past_end, base.len() as u64 - 10).unwrap();
+    write_varint(&mut past_end, 20).unwrap();
+    let mut overflow = vec![COPY];
+    write_varint(&mut overflow, u64::MAX).unwrap();
+    write_varint(&mut overflow, 2).unwrap();
+    // More than the size of what it makes, which is not even there.
+    let mut oversized = vec![INSERT];
+    write_varint(&mut oversized, base.len() as u64 + 1).unwrap();
+    oversized.resize(oversized.len() + 10, 0);
+    let ops = [past_end, overflow, oversized, vec![0xff], vec![COPY, 0x80]];
+    for ops in ops {
+        match *delta_apply(&base, &with_ops(&ops)).unwrap_err().kind() {
+            ErrorKind::DeltaCorrupt => (),
+            ref kind => panic!("{:?}: {:?}", ops, kind),
+        }
+    }
+}
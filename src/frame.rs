@@ -0,0 +1,99 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+use alloc::vec::Vec;
+
+use super::{ChunkContext, Error, ErrorKind, Result};
+
+/// Splits a compressed payload, the data after the header, into the
+/// compressed chunks without doing any I/O.
+///
+/// Stops after the first error, whose context has `bytes_out` set to 0.
+#[derive(Clone, Debug)]
+pub struct Frames<'a> {
+    rest: &'a [u8],
+    chunk: u64,
+    offset: u64,
+    failed: bool,
+}
+
+impl<'a> Frames<'a> {
+    pub fn new(payload: &'a [u8]) -> Frames<'a> {
+        Frames {
+            rest: payload,
+            chunk: 0,
+            offset: 8,
+            failed: false,
+        }
+    }
+
+    /// The part of the payload that has not been split off yet.
+    pub fn remainder(&self) -> &'a [u8] {
+        self.rest
+    }
+}
+
+impl<'a> Iterator for Frames<'a> {
+    type Item = Result<&'a [u8]>;
+
+    fn next(&mut self) -> Option<Result<&'a [u8]>> {
+        if self.failed || self.rest.is_empty() {
+            return None;
+        }
+        let size = match frame_len(self.rest) {
+            Some(size) if size <= self.rest.len() => size,
+            _ => {
+                self.failed = true;
+                let context = ChunkContext {
+                    chunk: self.chunk,
+                    offset: self.offset,
+                    bytes_out: 0,
+                };
+                return Some(Err(Error::from(ErrorKind::UnexpectedEof).in_chunk(context)));
+            }
+        };
+        let (frame, rest) = self.rest.split_at(size);
+        self.rest = rest;
+        self.chunk += 1;
+        self.offset += size as u64;
+        Some(Ok(&frame[4..]))
+    }
+}
+
+/// The size of the frame at the start of `data`, including its length
+/// prefix, or `None` if `data` is too short to hold the prefix.
+pub fn frame_len(data: &[u8]) -> Option<usize> {
+    if data.len() < 4 {
+        return None;
+    }
+    let len = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+    Some(4 + len as usize)
+}
+
+/// Appends `chunk` to `out`, preceded by its length.
+pub fn push_frame(out: &mut Vec<u8>, chunk: &[u8]) -> Result<()> {
+    if chunk.len() as u64 > u32::MAX as u64 {
+        return Err(ErrorKind::ChunkTooLarge(chunk.len() as u64).into());
+    }
+    out.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+    out.extend_from_slice(chunk);
+    Ok(())
+}
+
+#[test]
+fn frames_test() {
+    let mut payload = Vec::new();
+    push_frame(&mut payload, b"abc").unwrap();
+    push_frame(&mut payload, b"").unwrap();
+    push_frame(&mut payload, b"de").unwrap();
+    assert_eq!(frame_len(&payload), Some(7));
+    let mut frames = Frames::new(&payload);
+    assert_eq!(frames.next().unwrap().unwrap(), b"abc");
+    assert_eq!(frames.remainder().len(), 10);
+    assert_eq!(frames.next().unwrap().unwrap(), b"");
+    assert_eq!(frames.next().unwrap().unwrap(), b"de");
+    assert!(frames.next().is_none());
+
+    let mut frames = Frames::new(&payload[..payload.len() - 1]);
+    assert!(frames.nth(2).unwrap().is_err());
+    assert!(frames.next().is_none());
+}
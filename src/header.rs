@@ -0,0 +1,302 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+#[cfg(feature = "std")]
+use std::io;
+
+#[cfg(feature = "std")]
+use super::{read_u32, write_u32, Codec};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use super::{Error, ErrorKind, Result};
+
+/// The compression field of the header.
+///
+/// Dwarf Fortress itself only writes `Uncompressed` (0) and `Zlib` (1).
+/// The other values are extensions of the format used by optional codecs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+#[non_exhaustive]
+pub enum Compression {
+    Uncompressed,
+    Zlib,
+    /// Compression flag 2.
+    #[cfg(feature = "zstd")]
+    Zstd,
+    /// Compression flag 3.
+    #[cfg(feature = "lz4")]
+    Lz4,
+}
+
+impl Compression {
+    pub fn from_u32(value: u32) -> Option<Compression> {
+        match value {
+            0 => Some(Compression::Uncompressed),
+            1 => Some(Compression::Zlib),
+            #[cfg(feature = "zstd")]
+            2 => Some(Compression::Zstd),
+            #[cfg(feature = "lz4")]
+            3 => Some(Compression::Lz4),
+            _ => None,
+        }
+    }
+
+    pub fn to_u32(self) -> u32 {
+        match self {
+            Compression::Uncompressed => 0,
+            Compression::Zlib => 1,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => 2,
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => 3,
+        }
+    }
+
+    pub fn is_compressed(self) -> bool {
+        self != Compression::Uncompressed
+    }
+
+    /// Returns the codec that decompresses chunks of this kind.
+    #[cfg(feature = "std")]
+    pub fn codec(self) -> Option<Codec> {
+        match self {
+            Compression::Uncompressed => None,
+            #[cfg(not(feature = "libdeflate"))]
+            Compression::Zlib => Some(Codec::Zlib),
+            // libdeflate reads the same zlib streams, only faster.
+            #[cfg(feature = "libdeflate")]
+            Compression::Zlib => Some(Codec::Libdeflate),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Some(Codec::Zstd),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Some(Codec::Lz4),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<Codec> for Compression {
+    fn from(codec: Codec) -> Compression {
+        codec.compression()
+    }
+}
+
+/// Save versions of Dwarf Fortress releases, sorted by version.
+/// This follows the list kept by DFHack.
+const RELEASES: &[(u32, &str)] = &[
+    (1287, "0.31.01"),
+    (1288, "0.31.02"),
+    (1289, "0.31.03"),
+    (1292, "0.31.04"),
+    (1295, "0.31.05"),
+    (1297, "0.31.06"),
+    (1300, "0.31.08"),
+    (1304, "0.31.09"),
+    (1305, "0.31.10"),
+    (1310, "0.31.11"),
+    (1311, "0.31.12"),
+    (1323, "0.31.13"),
+    (1325, "0.31.14"),
+    (1326, "0.31.15"),
+    (1327, "0.31.16"),
+    (1340, "0.31.17"),
+    (1341, "0.31.18"),
+    (1351, "0.31.19"),
+    (1353, "0.31.20"),
+    (1354, "0.31.21"),
+    (1359, "0.31.22"),
+    (1360, "0.31.23"),
+    (1361, "0.31.24"),
+    (1362, "0.31.25"),
+    (1372, "0.34.01"),
+    (1374, "0.34.02"),
+    (1376, "0.34.03"),
+    (1377, "0.34.04"),
+    (1378, "0.34.05"),
+    (1382, "0.34.06"),
+    (1383, "0.34.07"),
+    (1400, "0.34.08"),
+    (1402, "0.34.09"),
+    (1403, "0.34.10"),
+    (1404, "0.34.11"),
+    (1441, "0.40.01"),
+    (1442, "0.40.02"),
+    (1443, "0.40.03"),
+    (1444, "0.40.04"),
+    (1445, "0.40.05"),
+    (1446, "0.40.06"),
+    (1448, "0.40.07"),
+    (1449, "0.40.08"),
+    (1451, "0.40.09"),
+    (1452, "0.40.10"),
+    (1456, "0.40.11"),
+    (1459, "0.40.12"),
+    (1462, "0.40.13"),
+    (1469, "0.40.14"),
+    (1470, "0.40.15"),
+    (1471, "0.40.16"),
+    (1472, "0.40.17"),
+    (1473, "0.40.18"),
+    (1474, "0.40.19"),
+    (1477, "0.40.20"),
+    (1478, "0.40.21"),
+    (1479, "0.40.22"),
+    (1480, "0.40.23"),
+    (1481, "0.40.24"),
+    (1531, "0.42.01"),
+    (1532, "0.42.02"),
+    (1533, "0.42.03"),
+    (1534, "0.42.04"),
+    (1537, "0.42.05"),
+    (1542, "0.42.06"),
+    (1551, "0.43.01"),
+    (1552, "0.43.02"),
+    (1553, "0.43.03"),
+    (1555, "0.43.04"),
+    (1556, "0.43.05"),
+    (1596, "0.44.01"),
+    (1597, "0.44.02"),
+    (1600, "0.44.03"),
+    (1603, "0.44.04"),
+    (1604, "0.44.05"),
+    (1611, "0.44.06"),
+    (1612, "0.44.07"),
+    (1613, "0.44.08"),
+    (1614, "0.44.09"),
+    (1620, "0.44.10"),
+    (1623, "0.44.11"),
+    (1625, "0.44.12"),
+    (1710, "0.47.01"),
+    (1711, "0.47.02"),
+    (1713, "0.47.03"),
+    (1715, "0.47.04"),
+    (1716, "0.47.05"),
+    // The Steam and itch.io releases.
+    (2078, "50.01"),
+    (2079, "50.02"),
+    (2080, "50.03"),
+    (2081, "50.04"),
+    (2082, "50.05"),
+    (2083, "50.06"),
+    (2084, "50.07"),
+    (2085, "50.08"),
+    (2086, "50.09"),
+    (2087, "50.10"),
+    (2088, "50.11"),
+    (2089, "50.12"),
+    (2090, "50.13"),
+];
+
+/// The 8-byte header at the start of every data file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Header {
+    pub version: u32,
+    pub compression: Compression,
+}
+
+impl Header {
+    pub fn new(version: u32, compression: Compression) -> Header {
+        Header {
+            version,
+            compression,
+        }
+    }
+
+    /// Reads and validates a header, leaving `r` at the start of the payload.
+    #[cfg(feature = "std")]
+    pub fn read<R: io::Read>(mut r: R) -> Result<Header> {
+        let version = read_u32(&mut r).map_err(Error::of_header)?;
+        let compression = read_u32(&mut r).map_err(Error::of_header)?;
+        let header = Header::validate(version, compression)?;
+        debug!("header: version {}, {:?}", version, header.compression);
+        if header.df_release().is_none() {
+            warn!("unknown save version {}", version);
+        }
+        Ok(header)
+    }
+
+    /// Validates the header at the start of `data`, which may continue with
+    /// the payload.
+    pub fn parse(data: &[u8]) -> Result<Header> {
+        if data.len() < 8 {
+            return Err(Error::from(ErrorKind::UnexpectedEof).of_header());
+        }
+        let word = |i: usize| u32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+        Header::validate(word(0), word(4))
+    }
+
+    fn validate(version: u32, compression: u32) -> Result<Header> {
+        if version == 0 {
+            return Err(Error::from(ErrorKind::VersionIsZero).of_header());
+        }
+        match Compression::from_u32(compression) {
+            Some(compression) => Ok(Header::new(version, compression)),
+            None => Err(Error::from(ErrorKind::CompressionUnknown(compression)).of_header()),
+        }
+    }
+
+    /// Returns the Dwarf Fortress release that writes this save version,
+    /// such as `"0.44.12"`, or `None` if the version is not known.
+    pub fn df_release(&self) -> Option<&'static str> {
+        RELEASES
+            .binary_search_by_key(&self.version, |&(version, _)| version)
+            .ok()
+            .map(|i| RELEASES[i].1)
+    }
+
+    #[cfg(feature = "std")]
+    pub fn write<W: io::Write>(&self, mut w: W) -> Result<()> {
+        write_u32(&mut w, self.version)?;
+        write_u32(&mut w, self.compression.to_u32())
+    }
+
+    pub fn to_bytes(&self) -> [u8; 8] {
+        let mut bytes = [0; 8];
+        bytes[..4].copy_from_slice(&self.version.to_le_bytes());
+        bytes[4..].copy_from_slice(&self.compression.to_u32().to_le_bytes());
+        bytes
+    }
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn header_test() {
+    let header = Header::read(&[0x59, 0x06, 0, 0, 1, 0, 0, 0][..]).unwrap();
+    assert_eq!(header, Header::new(1625, Compression::Zlib));
+    let mut buf = Vec::new();
+    Header::new(1625, Compression::Uncompressed)
+        .write(&mut buf)
+        .unwrap();
+    assert_eq!(buf, [0x59, 0x06, 0, 0, 0, 0, 0, 0]);
+    assert!(Header::read(&[0, 0, 0, 0, 1, 0, 0, 0][..]).is_err());
+    assert!(Header::read(&[1, 0, 0, 0, 99, 0, 0, 0][..]).is_err());
+    assert!(Header::read(&[1, 0, 0, 0, 99, 0, 0, 0][..])
+        .unwrap_err()
+        .is_header());
+    assert!(Header::read(&[1, 0, 0, 0, 1][..]).unwrap_err().is_header());
+}
+
+#[test]
+fn parse_test() {
+    let data = [0x59, 0x06, 0, 0, 1, 0, 0, 0, 42];
+    let header = Header::parse(&data).unwrap();
+    assert_eq!(header, Header::new(1625, Compression::Zlib));
+    assert_eq!(header.to_bytes(), data[..8]);
+    assert!(Header::parse(&[0, 0, 0, 0, 1, 0, 0, 0]).is_err());
+    assert!(Header::parse(&[1, 0, 0, 0, 99, 0, 0, 0]).is_err());
+    assert!(Header::parse(&data[..7]).unwrap_err().is_header());
+}
+
+#[test]
+fn df_release_test() {
+    assert!(RELEASES.windows(2).all(|w| w[0].0 < w[1].0));
+    let release = |version| Header::new(version, Compression::Zlib).df_release();
+    assert_eq!(release(1625), Some("0.44.12"));
+    assert_eq!(release(1716), Some("0.47.05"));
+    assert_eq!(release(2078), Some("50.01"));
+    assert_eq!(release(2090), Some("50.13"));
+    assert_eq!(release(1800), None);
+    assert_eq!(release(1626), None);
+}
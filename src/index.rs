@@ -0,0 +1,133 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::io;
+
+use super::{ChunkCodec, Chunks, Header, Options, Result};
+
+/// The location of a chunk in a compressed data file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct IndexEntry {
+    /// The offset in the file of the chunk's length prefix.
+    pub offset: u64,
+    /// The length of the compressed payload, without the length prefix.
+    pub compressed_len: u64,
+    /// The offset of the chunk's data in the uncompressed payload.
+    pub decompressed_offset: u64,
+    pub decompressed_len: u64,
+}
+
+impl IndexEntry {
+    pub fn payload_offset(&self) -> u64 {
+        self.offset + 4
+    }
+
+    pub fn decompressed_end(&self) -> u64 {
+        self.decompressed_offset + self.decompressed_len
+    }
+}
+
+/// The chunk table of a compressed data file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Index {
+    pub header: Header,
+    pub chunks: Vec<IndexEntry>,
+}
+
+impl Index {
+    /// The size of the compressed file, including the header.
+    pub fn compressed_len(&self) -> u64 {
+        self.chunks
+            .last()
+            .map_or(8, |e| e.payload_offset() + e.compressed_len)
+    }
+
+    /// The size of the uncompressed payload, excluding the header.
+    pub fn decompressed_len(&self) -> u64 {
+        self.chunks.last().map_or(0, |e| e.decompressed_end())
+    }
+
+    /// Returns the index of the chunk containing the payload offset `pos`.
+    pub fn find(&self, pos: u64) -> Option<usize> {
+        let i = self.chunks.partition_point(|e| e.decompressed_end() <= pos);
+        if i < self.chunks.len() {
+            Some(i)
+        } else {
+            None
+        }
+    }
+}
+
+/// Scans a compressed data file and returns its chunk table.
+///
+/// Every chunk is decompressed to find its decompressed length,
+/// but the decompressed data is discarded.
+pub fn build_index<R: io::Read>(r: R) -> Result<Index> {
+    build_index_with(r, &Options::new())
+}
+
+/// Like `build_index`, decompressing with the dictionary of `options`.
+pub fn build_index_with<R: io::Read>(r: R, options: &Options) -> Result<Index> {
+    let chunks = Chunks::new(r)?;
+    let header = chunks.header();
+    let codec = options.build_decoder(header.compression.codec().unwrap_or_default());
+    scan(chunks, codec)
+}
+
+/// Like `build_index`, for an iterator that has already read the header.
+pub(crate) fn scan<R: io::Read, C: ChunkCodec>(chunks: Chunks<R>, mut codec: C) -> Result<Index> {
+    let header = chunks.header();
+    let mut entries = Vec::new();
+    let mut decompressed_offset = 0;
+    let mut buf = Vec::new();
+    for chunk in chunks {
+        let chunk = chunk?;
+        buf.clear();
+        codec.decompress(&chunk.data, &mut buf)?;
+        entries.push(IndexEntry {
+            offset: chunk.offset,
+            compressed_len: chunk.data.len() as u64,
+            decompressed_offset,
+            decompressed_len: buf.len() as u64,
+        });
+        decompressed_offset += buf.len() as u64;
+    }
+    Ok(Index {
+        header,
+        chunks: entries,
+    })
+}
+
+#[test]
+fn build_index_test() {
+    let mut data = crate::fixture::empty_file();
+    data.resize(50008, b'i');
+    let compressed = crate::compress_bytes(&data).unwrap();
+    let index = build_index(&compressed[..]).unwrap();
+    assert_eq!(index.header.version, 1625);
+    assert_eq!(index.chunks.len(), 3);
+    assert_eq!(index.chunks[2].decompressed_offset, 40000);
+    assert_eq!(index.chunks[2].decompressed_len, 10000);
+    assert_eq!(index.decompressed_len(), 50000);
+    assert_eq!(index.compressed_len(), compressed.len() as u64);
+    assert_eq!(index.find(19999), Some(0));
+    assert_eq!(index.find(20000), Some(1));
+    assert_eq!(index.find(50000), None);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn serde_test() {
+    let mut data = crate::fixture::empty_file();
+    data.resize(30008, b'i');
+    let compressed = crate::compress_bytes(&data).unwrap();
+    let index = build_index(&compressed[..]).unwrap();
+    let json = serde_json::to_value(&index).unwrap();
+    assert_eq!(json["header"]["version"], 1625);
+    assert_eq!(json["header"]["compression"], "zlib");
+    assert_eq!(json["chunks"][1]["decompressed_len"], 10000);
+    assert_eq!(serde_json::from_value::<Index>(json).unwrap(), index);
+}
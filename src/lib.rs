@@ -1,36 +1,224 @@
 // Copyright 2018, Mathias Rav <m@git.strova.dk>
 // SPDX-License-Identifier: LGPL-2.1+
+#![cfg_attr(not(feature = "std"), no_std)]
+extern crate alloc;
+#[cfg(feature = "stream")]
+extern crate bytes;
+#[cfg(feature = "std")]
 extern crate flate2;
+#[cfg(feature = "stream")]
+extern crate futures_util;
+#[cfg(feature = "libdeflate")]
+extern crate libdeflater;
+#[cfg(feature = "log")]
+#[macro_use]
+extern crate log;
+#[cfg(feature = "lz4")]
+extern crate lz4_flex;
+#[cfg(feature = "parallel")]
+extern crate rayon;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "tokio")]
+extern crate tokio;
+#[cfg(feature = "zstd")]
+extern crate zstd;
 
-use flate2::read::ZlibEncoder;
-use flate2::write::ZlibDecoder;
-use flate2::Compression;
+#[cfg(not(feature = "std"))]
+use core::{fmt, result};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "std")]
 use std::io::prelude::*;
-use std::{fmt, io, result};
+#[cfg(feature = "std")]
+use std::{error, fmt, io, result};
+
+// Without the `log` feature, log statements only type-check their arguments.
+#[cfg(all(feature = "std", not(feature = "log")))]
+macro_rules! log_nothing {
+    ($($arg:tt)*) => {
+        if false {
+            let _ = format_args!($($arg)*);
+        }
+    };
+}
+#[cfg(all(feature = "std", not(feature = "log")))]
+macro_rules! trace { ($($arg:tt)*) => { log_nothing!($($arg)*) }; }
+#[cfg(all(feature = "std", not(feature = "log")))]
+macro_rules! debug { ($($arg:tt)*) => { log_nothing!($($arg)*) }; }
+#[cfg(all(feature = "std", not(feature = "log")))]
+macro_rules! info { ($($arg:tt)*) => { log_nothing!($($arg)*) }; }
+#[cfg(all(feature = "std", not(feature = "log")))]
+macro_rules! warn { ($($arg:tt)*) => { log_nothing!($($arg)*) }; }
+
+#[cfg(feature = "tokio")]
+mod async_io;
+#[cfg(feature = "std")]
+mod check;
+#[cfg(feature = "std")]
+mod chunks;
+#[cfg(feature = "std")]
+mod codec;
+#[cfg(feature = "std")]
+mod delta;
+mod frame;
+mod header;
+#[cfg(feature = "std")]
+mod index;
+#[cfg(feature = "std")]
+mod options;
+#[cfg(feature = "parallel")]
+mod parallel;
+#[cfg(feature = "std")]
+mod pipeline;
+#[cfg(feature = "std")]
+mod push;
+#[cfg(feature = "std")]
+mod read;
+#[cfg(feature = "std")]
+mod recode;
+#[cfg(feature = "std")]
+mod seek;
+#[cfg(feature = "stream")]
+mod stream;
+#[cfg(feature = "std")]
+mod summary;
+#[cfg(feature = "std")]
+mod write;
+
+#[cfg(feature = "tokio")]
+pub use async_io::{dfcompress_async, dfuncompress_async, recode_async};
+#[cfg(feature = "std")]
+pub use check::{check, CheckReport, Finding, Severity};
+#[cfg(feature = "std")]
+pub use chunks::{ChunkWriter, Chunks, RawChunk};
+#[cfg(feature = "libdeflate")]
+pub use codec::Libdeflate;
+#[cfg(feature = "lz4")]
+pub use codec::Lz4;
+#[cfg(feature = "std")]
+pub use codec::{backend, Backend, ChunkCodec, Codec, Zlib};
+#[cfg(feature = "zstd")]
+pub use codec::{Dictionary, Zstd};
+#[cfg(feature = "std")]
+pub use delta::{delta_apply, delta_create, DELTA_MAGIC};
+pub use frame::{frame_len, push_frame, Frames};
+pub use header::{Compression, Header};
+#[cfg(feature = "std")]
+pub use index::{build_index, build_index_with, Index, IndexEntry};
+#[cfg(feature = "std")]
+pub use options::Options;
+#[cfg(feature = "std")]
+pub use pipeline::recode_pipelined;
+#[cfg(feature = "std")]
+pub use push::Recoder;
+#[cfg(feature = "std")]
+pub use read::DecompressReader;
+#[cfg(feature = "std")]
+pub use recode::{compress_bytes, decompress_bytes, verify};
+#[cfg(feature = "std")]
+pub use recode::{dfcompress, dfcompress_with, dfuncompress};
+#[cfg(feature = "std")]
+pub use recode::{recode, recode_with, recode_with_codec, Target};
+#[cfg(feature = "std")]
+pub use seek::{decompress_range, decompress_range_with, SeekableDecompressor};
+#[cfg(feature = "stream")]
+pub use stream::decompress_stream;
+#[cfg(feature = "std")]
+pub use summary::Summary;
+#[cfg(feature = "std")]
+pub use write::CompressWriter;
+
+/// The number of uncompressed bytes per chunk written by Dwarf Fortress.
+pub const CHUNK_SIZE: usize = 20000;
 
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum ErrorKind {
+    Cancelled,
+    ChunkTooLarge(u64),
     CompressionUnknown(u32),
+    /// A delta is damaged: cut short, or with operations that do not make
+    /// what it was created from.
+    DeltaCorrupt,
+    /// A delta was applied to another base than it was created against.
+    DeltaMismatch,
+    #[cfg(feature = "std")]
     Io(io::Error),
+    LimitExceeded,
+    NotADelta,
+    NotCompressed,
     UnexpectedEof,
     VersionIsZero,
 }
 
+/// Where in a compressed file an error occurred.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ChunkContext {
+    /// The index of the chunk, counting from 0.
+    pub chunk: u64,
+    /// The offset in the input of the chunk's length prefix.
+    pub offset: u64,
+    /// The number of decompressed bytes produced by the preceding chunks.
+    pub bytes_out: u64,
+}
+
 #[derive(Debug)]
 pub struct Error {
     kind: ErrorKind,
+    context: Option<ChunkContext>,
+    header: bool,
 }
 
 pub type Result<T> = result::Result<T, Error>;
 
-impl Into<Error> for ErrorKind {
-    fn into(self) -> Error {
-        Error { kind: self }
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Error {
+        Error {
+            kind,
+            context: None,
+            header: false,
+        }
+    }
+}
+
+impl Error {
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+
+    /// The chunk being decompressed when the error occurred, if known.
+    pub fn context(&self) -> Option<ChunkContext> {
+        self.context
+    }
+
+    /// Whether the error is in the 8-byte header, which did not read or
+    /// validate: the input is not a data file, as opposed to a damaged one.
+    pub fn is_header(&self) -> bool {
+        self.header
+    }
+
+    /// Attaches `context` unless the error already has one.
+    fn in_chunk(mut self, context: ChunkContext) -> Error {
+        self.context = self.context.or(Some(context));
+        self
+    }
+
+    /// Marks the error as one of the header, unless it already is in a
+    /// chunk, as that of a reader of another data file may be.
+    fn of_header(mut self) -> Error {
+        self.header = self.context.is_none();
+        self
     }
 }
 
+#[cfg(feature = "std")]
 impl From<io::Error> for Error {
     fn from(e: io::Error) -> Error {
+        if e.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+            return *e.into_inner().unwrap().downcast::<Error>().unwrap();
+        }
         if e.kind() == io::ErrorKind::UnexpectedEof {
             ErrorKind::UnexpectedEof.into()
         } else {
@@ -39,17 +227,65 @@ impl From<io::Error> for Error {
     }
 }
 
+/// Converts to an `io::Error` that converts back to the original `Error`.
+#[cfg(feature = "std")]
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        let kind = match e.kind {
+            ErrorKind::Io(ref inner) => inner.kind(),
+            ErrorKind::Cancelled => io::ErrorKind::Other,
+            ErrorKind::UnexpectedEof => io::ErrorKind::UnexpectedEof,
+            _ => io::ErrorKind::InvalidData,
+        };
+        match e {
+            Error {
+                kind: ErrorKind::Io(inner),
+                context: None,
+                header: false,
+            } => inner,
+            e => io::Error::new(kind, e),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self.kind {
+            ErrorKind::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self.kind {
+            ErrorKind::Cancelled => write!(f, "Cancelled"),
+            ErrorKind::ChunkTooLarge(n) => write!(f, "Chunk of {} bytes is too large", n),
             ErrorKind::CompressionUnknown(c) => write!(f, "Unknown compression {}", c),
+            ErrorKind::DeltaCorrupt => write!(f, "Delta is damaged"),
+            ErrorKind::DeltaMismatch => write!(f, "Delta does not apply to this base"),
+            #[cfg(feature = "std")]
             ErrorKind::Io(ref e) => write!(f, "{}", e),
+            ErrorKind::LimitExceeded => write!(f, "Decompressed size exceeds the limit"),
+            ErrorKind::NotADelta => write!(f, "File is not a delta"),
+            ErrorKind::NotCompressed => write!(f, "File is not compressed"),
             ErrorKind::UnexpectedEof => write!(f, "Unexpected end-of-file"),
             ErrorKind::VersionIsZero => write!(f, "Version is 0"),
+        }?;
+        if let Some(c) = self.context {
+            write!(
+                f,
+                " in chunk {} at offset {} ({} bytes decompressed)",
+                c.chunk, c.offset, c.bytes_out
+            )?;
         }
+        Ok(())
     }
 }
 
+#[cfg(feature = "std")]
 fn read_u32<R: io::Read>(r: &mut R) -> Result<u32> {
     let buf = &mut [0, 0, 0, 0];
     r.read_exact(buf)?;
@@ -59,16 +295,28 @@ fn read_u32<R: io::Read>(r: &mut R) -> Result<u32> {
     )
 }
 
+/// Reads a u32, or returns `None` at the end of the input. One to three
+/// bytes before the end are a value cut short, not the end.
+#[cfg(feature = "std")]
 fn read_u32_or_eof<R: io::Read>(r: &mut R) -> Result<Option<u32>> {
-    match read_u32(r) {
-        Ok(v) => Ok(Some(v)),
-        Err(e) => match e.kind {
-            ErrorKind::UnexpectedEof => Ok(None),
-            _ => Err(e),
-        },
+    let mut buf = [0; 4];
+    let mut read = 0;
+    while read < buf.len() {
+        match r.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    match read {
+        0 => Ok(None),
+        4 => Ok(Some(u32::from_le_bytes(buf))),
+        _ => Err(ErrorKind::UnexpectedEof.into()),
     }
 }
 
+#[cfg(feature = "std")]
 fn write_u32<W: io::Write>(handle: &mut W, value: u32) -> Result<()> {
     let buf = &[
         value as u8,
@@ -80,60 +328,86 @@ fn write_u32<W: io::Write>(handle: &mut W, value: u32) -> Result<()> {
     Ok(())
 }
 
-fn read_header<R: io::Read>(stdin: &mut R) -> Result<(u32, u32)> {
-    let version = read_u32(stdin)?;
-    if version == 0 {
-        return Err(ErrorKind::VersionIsZero.into());
-    }
-    let compression = read_u32(stdin)?;
-    if compression > 1 {
-        return Err(ErrorKind::CompressionUnknown(compression).into());
-    }
-    Ok((version, compression))
-}
-
-pub fn dfuncompress<R: io::Read, W: io::Write>(mut stdin: R, mut stdout: W) -> Result<()> {
-    let (version, compression) = read_header(&mut stdin)?;
-    write_u32(&mut stdout, version)?;
-    write_u32(&mut stdout, 0)?;
-    if compression == 0 {
-        io::copy(&mut stdin, &mut stdout)?;
-    } else {
-        loop {
-            let n = match read_u32_or_eof(&mut stdin)? {
-                Some(v) => v as u64,
-                None => break,
-            };
-            let mut decoder = ZlibDecoder::new(stdout.by_ref());
-            io::copy(&mut stdin.by_ref().take(n), decoder.by_ref())?;
-            decoder.finish()?;
-        }
+/// Reads the next length-prefixed chunk payload into `buf`, returning false
+/// at the end of the input.
+#[cfg(feature = "std")]
+fn read_chunk<R: io::Read>(r: &mut R, buf: &mut Vec<u8>) -> Result<bool> {
+    buf.clear();
+    let n = match read_u32_or_eof(r)? {
+        Some(n) => n as u64,
+        None => return Ok(false),
+    };
+    if r.take(n).read_to_end(buf)? as u64 != n {
+        return Err(ErrorKind::UnexpectedEof.into());
     }
-    Ok(())
+    Ok(true)
 }
 
-pub fn dfcompress<R: io::Read, W: io::Write>(mut stdin: R, mut stdout: W) -> Result<()> {
-    let (version, compression) = read_header(&mut stdin)?;
-    write_u32(&mut stdout, version)?;
-    write_u32(&mut stdout, 1)?;
-    if compression == 1 {
-        io::copy(&mut stdin, &mut stdout)?;
-    } else {
-        let mut buf = Vec::new();
-        loop {
-            buf.clear();
-            let mut encoder = ZlibEncoder::new((&mut stdin).take(20000), Compression::default());
-            encoder.read_to_end(&mut buf)?;
-            if encoder.total_in() == 0 {
-                break;
-            }
-            write_u32(&mut stdout, buf.len() as u32)?;
-            stdout.write_all(&buf)?;
-        }
+/// The data files the tests of the library work on.
+#[cfg(test)]
+pub(crate) mod fixture {
+    use super::{Compression, Header};
+    use alloc::vec::Vec;
+
+    /// An uncompressed data file of save version 1625 with nothing after the
+    /// header, for a test to append its payload to.
+    pub(crate) fn empty_file() -> Vec<u8> {
+        Header::new(1625, Compression::Uncompressed)
+            .to_bytes()
+            .to_vec()
+    }
+
+    /// An uncompressed data file with `len` bytes of a payload that does not
+    /// repeat within a chunk but still compresses.
+    pub(crate) fn sample_file(len: usize) -> Vec<u8> {
+        let mut data = empty_file();
+        data.extend(sample_payload(len));
+        data
+    }
+
+    /// The payload of `sample_file`.
+    pub(crate) fn sample_payload(len: usize) -> impl Iterator<Item = u8> {
+        (0..len).map(|i| (i * i % 251) as u8)
+    }
+
+    /// Small chunks with much in common, as the files of a region folder,
+    /// to train a dictionary on.
+    #[cfg(feature = "zstd")]
+    pub(crate) fn dictionary_samples() -> Vec<Vec<u8>> {
+        (0..500u32)
+            .map(|i| {
+                let mut sample = b"[OBJECT:CREATURE][CREATURE:DWARF]".repeat(4);
+                sample.extend_from_slice(&i.to_le_bytes());
+                sample.extend((0..200).map(|j| ((i * 7 + j) % 251) as u8));
+                sample
+            })
+            .collect()
+    }
+
+    /// Options that compress with zstd and a dictionary, and `sample_file`
+    /// compressed with them, which only decompresses with the dictionary.
+    #[cfg(feature = "zstd")]
+    pub(crate) fn dictionary_file(len: usize) -> (super::Options, Vec<u8>) {
+        use super::{recode_with, Codec, Dictionary, Options, Target};
+        use std::sync::Arc;
+
+        let dictionary = Dictionary::train(&dictionary_samples(), 4096).unwrap();
+        let options = Options::new()
+            .codec(Codec::Zstd)
+            .dictionary(Arc::new(dictionary));
+        let mut compressed = Vec::new();
+        recode_with(
+            &sample_file(len)[..],
+            &mut compressed,
+            Target::Compressed,
+            &options,
+        )
+        .unwrap();
+        (options, compressed)
     }
-    Ok(())
 }
 
+#[cfg(feature = "std")]
 #[test]
 fn u32_tests() {
     fn read_help(d: Vec<u8>) -> u32 {
@@ -152,19 +426,35 @@ fn u32_tests() {
         (4 << 24) + (3 << 16) + (2 << 8) + 1
     );
     assert_eq!(read_help(write_help(11111111)), 11111111);
+
+    assert!(read_u32_or_eof(&mut &[][..]).unwrap().is_none());
+    assert_eq!(read_u32_or_eof(&mut &[7, 0, 0, 0][..]).unwrap(), Some(7));
+    for short in 1..4 {
+        match *read_u32_or_eof(&mut &[0; 3][..short]).unwrap_err().kind() {
+            ErrorKind::UnexpectedEof => (),
+            ref kind => panic!("{:?}", kind),
+        }
+    }
+    // Bytes after the last chunk too few for a length are a truncated one.
+    let mut file =
+        compress_bytes(&Header::new(1625, Compression::Uncompressed).to_bytes()).unwrap();
+    file.extend_from_slice(&[1, 0]);
+    assert!(decompress_bytes(&file).is_err());
 }
 
+#[cfg(feature = "std")]
 #[test]
-fn compress_test() {
-    let mut buf = io::Cursor::new(Vec::new());
-    buf.get_mut().resize(30000, b'a');
-    write_u32(&mut buf, 1234).unwrap(); // version
-    write_u32(&mut buf, 0).unwrap(); // compression
-    buf.seek(io::SeekFrom::Start(0)).unwrap();
-    let mut buf2 = io::Cursor::new(Vec::new());
-    dfcompress(&mut buf, &mut buf2).unwrap();
-    buf2.seek(io::SeekFrom::Start(0)).unwrap();
-    let mut buf3 = io::Cursor::new(Vec::new());
-    dfuncompress(&mut buf2, &mut buf3).unwrap();
-    assert_eq!(buf.get_ref(), buf3.get_ref());
+fn error_test() {
+    let e: io::Error = Error::from(ErrorKind::VersionIsZero).into();
+    match *Error::from(e).kind() {
+        ErrorKind::VersionIsZero => (),
+        ref kind => panic!("{:?}", kind),
+    }
+    let e = Error::from(io::Error::other("disk on fire"));
+    assert_eq!(
+        error::Error::source(&e).unwrap().to_string(),
+        "disk on fire"
+    );
+    let boxed: Box<dyn error::Error + Send + Sync> = Box::new(e);
+    assert_eq!(boxed.to_string(), "disk on fire");
 }
@@ -0,0 +1,251 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use super::{ChunkCodec, Codec, Compression, ErrorKind, Result, CHUNK_SIZE};
+#[cfg(feature = "zstd")]
+use super::{Dictionary, Zstd};
+
+/// Called after each chunk with the number of bytes read and written so far,
+/// including the header, and the index of the chunk.
+type Progress = Arc<dyn Fn(u64, u64, u64) + Send + Sync>;
+
+/// The callbacks of `Options`, run between chunks.
+#[derive(Clone, Default)]
+pub(crate) struct Hooks {
+    progress: Option<Progress>,
+    cancel: Option<Arc<AtomicBool>>,
+}
+
+impl Hooks {
+    pub fn progress(&self, bytes_in: u64, bytes_out: u64, chunk: u64) {
+        if let Some(ref progress) = self.progress {
+            progress(bytes_in, bytes_out, chunk);
+        }
+    }
+
+    /// Fails with `ErrorKind::Cancelled` if cancellation was requested.
+    pub fn check(&self) -> Result<()> {
+        match self.cancel {
+            Some(ref flag) if flag.load(Ordering::Relaxed) => Err(ErrorKind::Cancelled.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// The same hooks without the progress callback.
+    pub fn silent(&self) -> Hooks {
+        Hooks {
+            progress: None,
+            cancel: self.cancel.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Level {
+    Fast,
+    Best,
+    Exact(u32),
+}
+
+/// Parameters for compression.
+///
+/// ```
+/// let options = dfcompress::Options::new().level(9).chunk_size(65536);
+/// # let _ = options;
+/// ```
+#[derive(Clone)]
+pub struct Options {
+    pub(crate) codec: Codec,
+    level: Option<Level>,
+    pub(crate) chunk_size: usize,
+    pub(crate) hooks: Hooks,
+    pub(crate) lenient: bool,
+    pub(crate) max_chunk_size: Option<usize>,
+    pub(crate) max_output: Option<u64>,
+    pub(crate) recompress: bool,
+    #[cfg(feature = "parallel")]
+    pub(crate) threads: Option<usize>,
+    #[cfg(feature = "zstd")]
+    pub(crate) dictionary: Option<Arc<Dictionary>>,
+}
+
+impl Options {
+    pub fn new() -> Options {
+        Options {
+            codec: Codec::default(),
+            level: None,
+            chunk_size: CHUNK_SIZE,
+            hooks: Hooks::default(),
+            lenient: false,
+            max_chunk_size: None,
+            max_output: None,
+            recompress: false,
+            #[cfg(feature = "parallel")]
+            threads: None,
+            #[cfg(feature = "zstd")]
+            dictionary: None,
+        }
+    }
+
+    /// Sets the codec used for compression. Defaults to zlib, the only codec
+    /// that Dwarf Fortress itself can read.
+    pub fn codec(mut self, codec: Codec) -> Options {
+        self.codec = codec;
+        self
+    }
+
+    /// Sets the compression level. For zlib, this ranges from 0 (store) to
+    /// 9 (best), with 6 as the default. Input that already uses the codec is
+    /// compressed again at this level rather than copied.
+    pub fn level(mut self, level: u32) -> Options {
+        self.level = Some(Level::Exact(level));
+        self
+    }
+
+    /// Uses the fastest level of the codec.
+    pub fn fast(mut self) -> Options {
+        self.level = Some(Level::Fast);
+        self
+    }
+
+    /// Uses the level of the codec that compresses best.
+    pub fn best(mut self) -> Options {
+        self.level = Some(Level::Best);
+        self
+    }
+
+    /// Creates the codec with the selected level.
+    pub(crate) fn build_codec(&self) -> Box<dyn ChunkCodec + Send> {
+        let level = self.level.map(|level| match level {
+            Level::Fast => self.codec.fast_level(),
+            Level::Best => self.codec.best_level(),
+            Level::Exact(level) => level,
+        });
+        #[cfg(feature = "zstd")]
+        if let (Codec::Zstd, Some(dictionary)) = (self.codec, &self.dictionary) {
+            return Box::new(Zstd::with_dictionary(level, dictionary.clone()));
+        }
+        self.codec.build(level)
+    }
+
+    /// Creates `codec` to decompress with, with the dictionary if it is zstd.
+    pub(crate) fn build_decoder(&self, codec: Codec) -> Box<dyn ChunkCodec + Send> {
+        #[cfg(feature = "zstd")]
+        if let (Codec::Zstd, Some(dictionary)) = (codec, &self.dictionary) {
+            return Box::new(Zstd::with_dictionary(None, dictionary.clone()));
+        }
+        codec.build(None)
+    }
+
+    /// Compresses and decompresses zstd chunks with `dictionary`, which the
+    /// files compressed with it need to be decompressed.
+    #[cfg(feature = "zstd")]
+    pub fn dictionary(mut self, dictionary: Arc<Dictionary>) -> Options {
+        self.dictionary = Some(dictionary);
+        self
+    }
+
+    /// Sets the number of uncompressed bytes in each chunk. Defaults to
+    /// `CHUNK_SIZE`; larger chunks compress better but use more memory.
+    /// A size of 0 is taken as 1. Any other size compresses input that
+    /// already uses the codec again, as the level does.
+    pub fn chunk_size(mut self, chunk_size: usize) -> Options {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    /// Calls `f(bytes_in, bytes_out, chunk)` after each chunk is read or
+    /// written. The byte counts include the header.
+    pub fn on_progress<F>(mut self, f: F) -> Options
+    where
+        F: Fn(u64, u64, u64) + Send + Sync + 'static,
+    {
+        self.hooks.progress = Some(Arc::new(f));
+        self
+    }
+
+    /// Skips chunks that fail to decompress instead of failing, keeping
+    /// whatever part of them was recovered. See `Summary::skipped`.
+    pub fn lenient(mut self, lenient: bool) -> Options {
+        self.lenient = lenient;
+        self
+    }
+
+    /// Fails with `ErrorKind::LimitExceeded` if a chunk decompresses to more
+    /// than `limit` bytes. Dwarf Fortress writes chunks of 20000 bytes.
+    pub fn max_chunk_size(mut self, limit: usize) -> Options {
+        self.max_chunk_size = Some(limit);
+        self
+    }
+
+    /// Fails with `ErrorKind::LimitExceeded` if the decompressed payload
+    /// exceeds `limit` bytes.
+    pub fn max_output(mut self, limit: u64) -> Options {
+        self.max_output = Some(limit);
+        self
+    }
+
+    /// Compresses chunks again even if the input already uses the codec,
+    /// instead of copying them, so that the output only depends on the data
+    /// and the options. A level or chunk size that is not the default does
+    /// so too.
+    pub fn recompress(mut self, recompress: bool) -> Options {
+        self.recompress = recompress;
+        self
+    }
+
+    /// Whether a payload compressed as `from` is copied as it is to be
+    /// written as `to`, rather than decompressed or compressed again.
+    pub(crate) fn copies(&self, from: Compression, to: Compression) -> bool {
+        let recompress = self.recompress || self.level.is_some() || self.chunk_size != CHUNK_SIZE;
+        from == to && !(to.is_compressed() && recompress)
+    }
+
+    /// Sets the number of threads that (de)compress chunks. Defaults to the
+    /// global rayon thread pool, which has one thread per CPU.
+    ///
+    /// Four chunks per thread are read and decompressed at once, and held
+    /// in memory whole until they are written. A zlib chunk is at most 1032
+    /// times its compressed size; chunks of other codecs are only bounded by
+    /// `max_chunk_size`, which is worth setting for input that is not
+    /// trusted.
+    #[cfg(feature = "parallel")]
+    pub fn threads(mut self, threads: usize) -> Options {
+        self.threads = Some(threads);
+        self
+    }
+
+    /// Aborts with `ErrorKind::Cancelled` at the next chunk once `flag` is
+    /// set, for example from another thread.
+    pub fn cancel_flag(mut self, flag: Arc<AtomicBool>) -> Options {
+        self.hooks.cancel = Some(flag);
+        self
+    }
+}
+
+impl fmt::Debug for Options {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut s = f.debug_struct("Options");
+        s.field("codec", &self.codec)
+            .field("level", &self.level)
+            .field("chunk_size", &self.chunk_size)
+            .field("lenient", &self.lenient)
+            .field("max_chunk_size", &self.max_chunk_size)
+            .field("max_output", &self.max_output)
+            .field("recompress", &self.recompress)
+            .field("progress", &self.hooks.progress.is_some())
+            .field("cancel", &self.hooks.cancel);
+        #[cfg(feature = "parallel")]
+        s.field("threads", &self.threads);
+        s.finish()
+    }
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options::new()
+    }
+}
@@ -0,0 +1,294 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use std::convert::TryFrom;
+use std::io;
+use std::io::prelude::*;
+
+use super::codec::MAX_EXPANSION;
+use super::read::chunk_error;
+use super::{read_chunk, ChunkCodec, ChunkContext, ChunkWriter, Compression, Error, ErrorKind};
+use super::{Header, Options, Result};
+
+/// The number of chunks read ahead for each thread of the pool.
+const BATCH_PER_THREAD: usize = 4;
+
+/// The thread pool selected by `Options::threads`.
+struct Pool(Option<ThreadPool>);
+
+impl Pool {
+    fn new(options: &Options) -> Result<Pool> {
+        match options.threads {
+            None => Ok(Pool(None)),
+            Some(threads) => ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .map(|pool| Pool(Some(pool)))
+                .map_err(|e| io::Error::other(e).into()),
+        }
+    }
+
+    /// The number of chunks to read before handing them to the pool.
+    fn batch(&self) -> usize {
+        let threads = match self.0 {
+            Some(ref pool) => pool.current_num_threads(),
+            None => rayon::current_num_threads(),
+        };
+        threads * BATCH_PER_THREAD
+    }
+
+    fn install<T: Send, F: FnOnce() -> T + Send>(&self, f: F) -> T {
+        match self.0 {
+            Some(ref pool) => pool.install(f),
+            None => f(),
+        }
+    }
+}
+
+/// Compresses `stdin` like `CompressWriter`, compressing batches of chunks on
+/// the thread pool and writing them in order.
+pub(crate) fn compress<R: Read, W: Write>(
+    mut stdin: R,
+    stdout: W,
+    version: u32,
+    options: &Options,
+) -> Result<u64> {
+    let pool = Pool::new(options)?;
+    let batch = pool.batch();
+    let header = Header::new(version, options.codec.compression());
+    let mut writer = ChunkWriter::new(stdout, header)?;
+    let mut inputs = Vec::with_capacity(batch);
+    let (mut bytes_in, mut bytes_out, mut chunks) = (8, 8, 0);
+    let mut eof = false;
+    while !eof {
+        inputs.clear();
+        while inputs.len() < batch {
+            let mut input = Vec::with_capacity(options.chunk_size);
+            let size = options.chunk_size as u64;
+            if stdin.by_ref().take(size).read_to_end(&mut input)? as u64 != size {
+                eof = true;
+                if input.is_empty() {
+                    break;
+                }
+            }
+            inputs.push(input);
+            if eof {
+                break;
+            }
+        }
+        let outputs = pool.install(|| {
+            inputs
+                .par_iter()
+                .map_init(
+                    || options.build_codec(),
+                    |codec, input| {
+                        let mut output = Vec::new();
+                        codec.compress(input, &mut output).map(|()| output)
+                    },
+                )
+                .collect::<io::Result<Vec<_>>>()
+        })?;
+        for (input, output) in inputs.iter().zip(&outputs) {
+            options.hooks.check()?;
+            writer.write_chunk(output)?;
+            trace!(
+                "compressed chunk {}: {} -> {} bytes",
+                chunks,
+                input.len(),
+                output.len()
+            );
+            bytes_in += input.len() as u64;
+            bytes_out += 4 + output.len() as u64;
+            options.hooks.progress(bytes_in, bytes_out, chunks);
+            chunks += 1;
+        }
+    }
+    writer.get_mut().flush()?;
+    Ok(chunks)
+}
+
+/// Decompresses a compressed payload like `DecompressReader`, decompressing
+/// batches of chunks on the thread pool and writing them in order.
+/// Returns the number of chunks read and skipped.
+pub(crate) fn decompress<R: Read, W: Write>(
+    mut stdin: R,
+    mut stdout: W,
+    header: Header,
+    options: &Options,
+) -> Result<(u64, u64)> {
+    let pool = Pool::new(options)?;
+    let batch = pool.batch();
+    let codec = header.compression.codec().unwrap_or_default();
+    // No chunk may exceed the total limit either.
+    let total = options
+        .max_output
+        .map(|max| usize::try_from(max).unwrap_or(usize::MAX));
+    let limit = options
+        .max_chunk_size
+        .unwrap_or(usize::MAX)
+        .min(total.unwrap_or(usize::MAX));
+    // A whole batch of chunks is held at once. Those of zlib cannot be more
+    // than `MAX_EXPANSION` times their size, which bounds that even without
+    // a limit; for other codecs, `Options::threads` tells of the cost.
+    let zlib = codec.compression() == Compression::Zlib;
+    Header::new(header.version, Compression::Uncompressed).write(&mut stdout)?;
+    let mut payloads = Vec::with_capacity(batch);
+    let (mut offset, mut decompressed, mut chunks, mut skipped) = (8, 0, 0, 0);
+    let mut failure = None;
+    let mut truncated = false;
+    while failure.is_none() && !truncated {
+        payloads.clear();
+        while payloads.len() < batch {
+            let mut payload = Vec::new();
+            match read_chunk(&mut stdin, &mut payload) {
+                Ok(true) => payloads.push(payload),
+                Ok(false) => break,
+                // Recover what we can from a truncated last chunk.
+                Err(ref e) if options.lenient && is_eof(e) && !payload.is_empty() => {
+                    payloads.push(payload);
+                    truncated = true;
+                    break;
+                }
+                Err(e) => {
+                    failure = Some(e);
+                    break;
+                }
+            }
+        }
+        if payloads.is_empty() && failure.is_none() {
+            break;
+        }
+        let outputs = pool.install(|| {
+            payloads
+                .par_iter()
+                .map_init(
+                    || options.build_decoder(codec),
+                    |codec, payload| {
+                        let mut output = Vec::new();
+                        let limit = match zlib {
+                            true => limit.min(payload.len().saturating_mul(MAX_EXPANSION)),
+                            false => limit,
+                        };
+                        let result = codec.decompress_limited(payload, &mut output, limit);
+                        (output, result.err().map(Error::from))
+                    },
+                )
+                .collect::<Vec<_>>()
+        });
+        let last = payloads.len().wrapping_sub(1);
+        for (i, (payload, (output, error))) in payloads.iter().zip(outputs).enumerate() {
+            options.hooks.check()?;
+            let context = ChunkContext {
+                chunk: chunks,
+                offset,
+                bytes_out: decompressed,
+            };
+            let eof = (truncated && i == last).then(|| ErrorKind::UnexpectedEof.into());
+            let mut error = chunk_error(eof, error);
+            let total = decompressed + output.len() as u64;
+            if options.max_output.is_some_and(|max| total > max) {
+                error = Some(ErrorKind::LimitExceeded.into());
+            }
+            if let Some(e) = error {
+                let e = e.in_chunk(context);
+                if !options.lenient || matches!(e.kind, ErrorKind::LimitExceeded) {
+                    return Err(e);
+                }
+                warn!("skipping chunk: {}", e);
+                skipped += 1;
+            }
+            trace!(
+                "chunk {} at offset {}: {} -> {} bytes",
+                chunks,
+                offset,
+                payload.len(),
+                output.len()
+            );
+            stdout.write_all(&output)?;
+            offset += 4 + payload.len() as u64;
+            decompressed += output.len() as u64;
+            options.hooks.progress(offset, 8 + decompressed, chunks);
+            chunks += 1;
+        }
+    }
+    if let Some(e) = failure {
+        let context = ChunkContext {
+            chunk: chunks,
+            offset,
+            bytes_out: decompressed,
+        };
+        return Err(e.in_chunk(context));
+    }
+    stdout.flush()?;
+    Ok((chunks, skipped))
+}
+
+fn is_eof(e: &Error) -> bool {
+    matches!(e.kind, ErrorKind::UnexpectedEof)
+}
+
+#[test]
+fn parallel_compress_test() {
+    for size in &[0, 20000, 45000, 1000000] {
+        let payload: Vec<u8> = crate::fixture::sample_payload(*size).collect();
+        let mut expected = crate::CompressWriter::new(Vec::new(), 1625).unwrap();
+        expected.write_all(&payload).unwrap();
+        let mut output = Vec::new();
+        let chunks = compress(&payload[..], &mut output, 1625, &Options::new()).unwrap();
+        assert_eq!(output, expected.finish().unwrap());
+        assert_eq!(chunks, (*size as u64).div_ceil(20000));
+    }
+}
+
+#[test]
+fn parallel_decompress_test() {
+    let data = crate::fixture::sample_file(1000000);
+    let compressed = crate::compress_bytes(&data).unwrap();
+    for threads in 1..4 {
+        let options = Options::new().threads(threads);
+        let mut input = &compressed[8..];
+        let header = Header::read(&compressed[..]).unwrap();
+        let mut output = Vec::new();
+        let (chunks, skipped) = decompress(&mut input, &mut output, header, &options).unwrap();
+        assert_eq!((chunks, skipped), (50, 0));
+        assert_eq!(output, data);
+    }
+}
+
+#[test]
+fn lenient_limit_test() {
+    let data = crate::fixture::sample_file(20000);
+    let compressed = crate::compress_bytes(&data).unwrap();
+    let truncated = &compressed[8..compressed.len() - 10];
+    let header = Header::read(&compressed[..]).unwrap();
+    let lenient = Options::new().lenient(true);
+    let (chunks, skipped) = decompress(truncated, io::sink(), header, &lenient).unwrap();
+    assert_eq!((chunks, skipped), (1, 1));
+    // Past the limit, the cut chunk is not skipped nor its output written.
+    let limited = lenient.max_chunk_size(5000);
+    let mut output = Vec::new();
+    let e = decompress(truncated, &mut output, header, &limited).unwrap_err();
+    assert!(matches!(e.kind, ErrorKind::LimitExceeded));
+    assert_eq!(output.len(), 8);
+}
+
+#[test]
+fn expansion_test() {
+    // Zeros compress about as well as deflate can, close to the bound.
+    let mut data = crate::fixture::empty_file();
+    data.resize(8 + (4 << 20), 0);
+    let options = Options::new().chunk_size(4 << 20).level(9);
+    let mut compressed = Vec::new();
+    crate::recode_with(
+        &data[..],
+        &mut compressed,
+        crate::Target::Compressed,
+        &options,
+    )
+    .unwrap();
+    let header = Header::read(&compressed[..]).unwrap();
+    let mut output = Vec::new();
+    decompress(&compressed[8..], &mut output, header, &Options::new()).unwrap();
+    assert_eq!(output, data);
+}
@@ -0,0 +1,146 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+use std::io;
+use std::io::prelude::*;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::thread;
+
+use super::{recode_with, Options, Result, Summary, Target};
+
+/// The size of the blocks passed between threads.
+const BLOCK_SIZE: usize = 1 << 16;
+/// The number of blocks buffered between two threads.
+const DEPTH: usize = 4;
+
+/// Like `recode_with`, but reads the input and writes the output on separate
+/// threads, so that I/O overlaps with (de)compression.
+pub fn recode_pipelined<R: Read + Send, W: Write + Send>(
+    stdin: R,
+    stdout: W,
+    target: Target,
+    options: &Options,
+) -> Result<Summary> {
+    thread::scope(|scope| {
+        let (input, blocks) = sync_channel(DEPTH);
+        let (output, results) = sync_channel(DEPTH);
+        scope.spawn(move || read_blocks(stdin, input));
+        let writer = scope.spawn(move || write_blocks(stdout, results));
+        let mut sink = BlockWriter {
+            sender: output,
+            buf: Vec::with_capacity(BLOCK_SIZE),
+        };
+        let source = BlockReader {
+            receiver: blocks,
+            buf: Vec::new(),
+            pos: 0,
+        };
+        let summary = recode_with(source, &mut sink, target, options);
+        let sent = sink.send();
+        drop(sink);
+        // An error of the writer explains a failure to send to it.
+        writer.join().unwrap()?;
+        sent?;
+        summary
+    })
+}
+
+fn read_blocks<R: Read>(mut stdin: R, input: SyncSender<io::Result<Vec<u8>>>) {
+    loop {
+        let mut block = Vec::with_capacity(BLOCK_SIZE);
+        let result = stdin
+            .by_ref()
+            .take(BLOCK_SIZE as u64)
+            .read_to_end(&mut block);
+        let done = match result {
+            Ok(n) => n == 0,
+            Err(_) => true,
+        };
+        if input.send(result.map(|_| block)).is_err() || done {
+            return;
+        }
+    }
+}
+
+fn write_blocks<W: Write>(mut stdout: W, results: Receiver<Vec<u8>>) -> io::Result<()> {
+    for block in results {
+        stdout.write_all(&block)?;
+    }
+    stdout.flush()
+}
+
+struct BlockReader {
+    receiver: Receiver<io::Result<Vec<u8>>>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for BlockReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.buf.len() {
+            match self.receiver.recv() {
+                Ok(block) => self.buf = block?,
+                Err(_) => return Ok(0),
+            }
+            self.pos = 0;
+        }
+        let n = (&self.buf[self.pos..]).read(out)?;
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+struct BlockWriter {
+    sender: SyncSender<Vec<u8>>,
+    buf: Vec<u8>,
+}
+
+impl BlockWriter {
+    fn send(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let block = std::mem::replace(&mut self.buf, Vec::with_capacity(BLOCK_SIZE));
+        self.sender
+            .send(block)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "writer thread stopped"))
+    }
+}
+
+impl Write for BlockWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let n = data.len().min(BLOCK_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&data[..n]);
+        if self.buf.len() == BLOCK_SIZE {
+            self.send()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send()
+    }
+}
+
+#[test]
+fn pipelined_test() {
+    let data = crate::fixture::sample_file(500000);
+    let compressed = crate::compress_bytes(&data).unwrap();
+    let mut output = Vec::new();
+    let summary =
+        recode_pipelined(&data[..], &mut output, Target::Compressed, &Options::new()).unwrap();
+    assert_eq!(output, compressed);
+    assert_eq!(summary.bytes_out, compressed.len() as u64);
+    let mut output = Vec::new();
+    recode_pipelined(
+        &compressed[..],
+        &mut output,
+        Target::Uncompressed,
+        &Options::new(),
+    )
+    .unwrap();
+    assert_eq!(output, data);
+    let truncated = &compressed[..compressed.len() - 1];
+    assert!(
+        recode_pipelined(truncated, io::sink(), Target::Uncompressed, &Options::new()).is_err()
+    );
+}
@@ -0,0 +1,227 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+use std::convert::TryFrom;
+use std::io;
+use std::io::prelude::*;
+
+use super::{frame_len, ChunkCodec, CompressWriter, Compression, ErrorKind, Header, Options};
+use super::{Result, Target};
+
+/// Converts a data file to `target` like `recode_with`, but is fed the input
+/// piece by piece instead of reading it, for callers that cannot block.
+///
+/// After `push` or `finish`, take the available output from `output`.
+/// Lenient mode and progress callbacks are not supported.
+pub struct Recoder {
+    target: Target,
+    options: Options,
+    header: Option<Header>,
+    decoder: Option<Box<dyn ChunkCodec + Send>>,
+    encoder: Option<CompressWriter<Vec<u8>>>,
+    input: Vec<u8>,
+    scratch: Vec<u8>,
+    output: Vec<u8>,
+    decompressed: u64,
+    done: bool,
+}
+
+impl Recoder {
+    pub fn new(target: Target) -> Recoder {
+        Recoder::with_options(target, &Options::default())
+    }
+
+    pub fn with_options(target: Target, options: &Options) -> Recoder {
+        Recoder {
+            target,
+            options: options.clone(),
+            header: None,
+            decoder: None,
+            encoder: None,
+            input: Vec::new(),
+            scratch: Vec::new(),
+            output: Vec::new(),
+            decompressed: 0,
+            done: false,
+        }
+    }
+
+    /// The header of the input, once it has been pushed.
+    pub fn header(&self) -> Option<Header> {
+        self.header
+    }
+
+    /// The output produced so far. Remove the part you have consumed.
+    pub fn output(&mut self) -> &mut Vec<u8> {
+        &mut self.output
+    }
+
+    /// Feeds the next part of the input. After an error, or after `finish`,
+    /// every call fails.
+    pub fn push(&mut self, data: &[u8]) -> Result<()> {
+        if self.done {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput).into());
+        }
+        let result = self.try_push(data);
+        self.done = result.is_err();
+        result
+    }
+
+    /// Signals the end of the input, flushing the rest of the output.
+    pub fn finish(&mut self) -> Result<()> {
+        if self.done {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput).into());
+        }
+        self.done = true;
+        if self.header.is_none() || !self.input.is_empty() {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        if let Some(encoder) = self.encoder.take() {
+            self.output.append(&mut encoder.finish()?);
+        }
+        Ok(())
+    }
+
+    fn try_push(&mut self, mut data: &[u8]) -> Result<()> {
+        if self.header.is_none() {
+            let n = data.len().min(8 - self.input.len());
+            self.input.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            if self.input.len() < 8 {
+                return Ok(());
+            }
+            let header = Header::parse(&self.input)?;
+            self.input.clear();
+            self.start(header)?;
+        }
+        if self.decoder.is_none() {
+            return self.encode(data);
+        }
+        self.input.extend_from_slice(data);
+        let mut start = 0;
+        while let Some(len) = frame_len(&self.input[start..]) {
+            if start + len > self.input.len() {
+                break;
+            }
+            self.options.hooks.check()?;
+            let limit = self.limit();
+            self.scratch.clear();
+            let payload = &self.input[start + 4..start + len];
+            let decoder = self.decoder.as_mut().unwrap();
+            decoder.decompress_limited(payload, &mut self.scratch, limit)?;
+            self.decompressed += self.scratch.len() as u64;
+            let scratch = std::mem::take(&mut self.scratch);
+            let result = self.encode(&scratch);
+            self.scratch = scratch;
+            result?;
+            start += len;
+        }
+        self.input.drain(..start);
+        Ok(())
+    }
+
+    /// Sets up for converting the payload after `header`, and writes the
+    /// header of the output.
+    fn start(&mut self, header: Header) -> Result<()> {
+        self.header = Some(header);
+        let to = match self.target {
+            Target::Compressed => self.options.codec.compression(),
+            Target::Uncompressed => Compression::Uncompressed,
+        };
+        if self.options.copies(header.compression, to) {
+            self.output.extend_from_slice(&header.to_bytes());
+            return Ok(());
+        }
+        self.decoder = (header.compression.codec()).map(|codec| self.options.build_decoder(codec));
+        if to.is_compressed() {
+            let writer = CompressWriter::with_options(Vec::new(), header.version, &self.options)?;
+            self.encoder = Some(writer);
+        } else {
+            let header = Header::new(header.version, Compression::Uncompressed);
+            self.output.extend_from_slice(&header.to_bytes());
+        }
+        Ok(())
+    }
+
+    /// Passes uncompressed payload to the encoder, if any.
+    fn encode(&mut self, data: &[u8]) -> Result<()> {
+        match self.encoder {
+            Some(ref mut encoder) => {
+                encoder.write_all(data)?;
+                self.output.append(encoder.get_mut());
+            }
+            None => self.output.extend_from_slice(data),
+        }
+        Ok(())
+    }
+
+    /// The number of bytes the next chunk may decompress to.
+    fn limit(&self) -> usize {
+        let left = self
+            .options
+            .max_output
+            .map_or(u64::MAX, |max| max.saturating_sub(self.decompressed));
+        let chunk = self.options.max_chunk_size.unwrap_or(usize::MAX);
+        chunk.min(usize::try_from(left).unwrap_or(usize::MAX))
+    }
+}
+
+#[test]
+fn recoder_test() {
+    fn run(mut recoder: Recoder, input: &[u8]) -> Result<Vec<u8>> {
+        let mut output = Vec::new();
+        for piece in input.chunks(777) {
+            recoder.push(piece)?;
+            output.append(recoder.output());
+        }
+        recoder.finish()?;
+        output.append(recoder.output());
+        Ok(output)
+    }
+
+    let data = crate::fixture::sample_file(100000);
+    let compressed = crate::compress_bytes(&data).unwrap();
+    let compress = || Recoder::new(Target::Compressed);
+    let uncompress = || Recoder::new(Target::Uncompressed);
+    assert_eq!(run(compress(), &data).unwrap(), compressed);
+    assert_eq!(run(compress(), &compressed).unwrap(), compressed);
+    assert_eq!(run(uncompress(), &compressed).unwrap(), data);
+    assert_eq!(run(uncompress(), &data).unwrap(), data);
+    let truncated = &compressed[..compressed.len() - 1];
+    assert!(run(uncompress(), truncated).is_err());
+    assert!(run(uncompress(), &data[..7]).is_err());
+    let options = Options::new().max_output(1000);
+    let limited = Recoder::with_options(Target::Uncompressed, &options);
+    assert!(run(limited, &compressed).is_err());
+
+    // Chunks are copied or compressed again as `recode_with` would.
+    let fast = Options::new().fast();
+    let mut fast_compressed = Vec::new();
+    crate::recode_with(&data[..], &mut fast_compressed, Target::Compressed, &fast).unwrap();
+    for options in [
+        Options::new(),
+        Options::new().recompress(true),
+        Options::new().level(9),
+    ] {
+        let mut expected = Vec::new();
+        let input = &fast_compressed[..];
+        crate::recode_with(input, &mut expected, Target::Compressed, &options).unwrap();
+        let recoder = Recoder::with_options(Target::Compressed, &options);
+        assert_eq!(run(recoder, input).unwrap(), expected, "{:?}", options);
+    }
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn recoder_dictionary_test() {
+    let data = crate::fixture::sample_file(50000);
+    let (options, compressed) = crate::fixture::dictionary_file(50000);
+    let mut recoder = Recoder::with_options(Target::Uncompressed, &options);
+    let mut output = Vec::new();
+    for piece in compressed.chunks(777) {
+        recoder.push(piece).unwrap();
+        output.append(recoder.output());
+    }
+    recoder.finish().unwrap();
+    output.append(recoder.output());
+    assert_eq!(output, data);
+}
@@ -0,0 +1,361 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+use std::convert::TryFrom;
+use std::io;
+use std::io::prelude::*;
+
+use super::options::{Hooks, Options};
+use super::{read_chunk, ChunkCodec, ChunkContext, Error, ErrorKind, Header, Result};
+
+/// Reads a Dwarf Fortress data file and yields its uncompressed payload.
+///
+/// The header is consumed by `new`; the version is available via `version`.
+pub struct DecompressReader<R, C = Box<dyn ChunkCodec + Send>> {
+    inner: R,
+    codec: C,
+    header: Header,
+    chunks: u64,
+    offset: u64,
+    decompressed: u64,
+    hooks: Hooks,
+    lenient: bool,
+    max_chunk_size: Option<usize>,
+    max_output: Option<u64>,
+    errors: Vec<Error>,
+    payload: Vec<u8>,
+    buf: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl<R: Read> DecompressReader<R> {
+    /// Reads the header from `inner` and picks the codec it calls for.
+    pub fn new(mut inner: R) -> Result<DecompressReader<R>> {
+        let header = Header::read(&mut inner)?;
+        let codec = header.compression.codec().unwrap_or_default().build(None);
+        Ok(DecompressReader::from_parts(inner, header, codec))
+    }
+}
+
+impl<R: Read, C: ChunkCodec> DecompressReader<R, C> {
+    /// Reads the header from `inner` and decompresses chunks with `codec`,
+    /// whatever the compression flag of the header.
+    pub fn with_codec(mut inner: R, codec: C) -> Result<DecompressReader<R, C>> {
+        let header = Header::read(&mut inner)?;
+        Ok(DecompressReader::from_parts(inner, header, codec))
+    }
+
+    /// Creates a reader for `inner` positioned just after the header.
+    pub(crate) fn from_parts(inner: R, header: Header, codec: C) -> DecompressReader<R, C> {
+        DecompressReader {
+            inner,
+            codec,
+            header,
+            chunks: 0,
+            offset: 8,
+            decompressed: 0,
+            hooks: Hooks::default(),
+            lenient: false,
+            max_chunk_size: None,
+            max_output: None,
+            errors: Vec::new(),
+            payload: Vec::new(),
+            buf: Vec::new(),
+            pos: 0,
+            done: false,
+        }
+    }
+
+    /// Applies the hooks, lenient mode and limits of `options`.
+    pub(crate) fn configure(&mut self, options: &Options) {
+        self.hooks = options.hooks.clone();
+        self.lenient = options.lenient;
+        self.max_chunk_size = options.max_chunk_size;
+        self.max_output = options.max_output;
+    }
+
+    /// The number of bytes the next chunk may decompress to.
+    fn limit(&self) -> usize {
+        let left = self
+            .max_output
+            .map_or(u64::MAX, |max| max.saturating_sub(self.decompressed));
+        let chunk = self.max_chunk_size.unwrap_or(usize::MAX);
+        chunk.min(usize::try_from(left).unwrap_or(usize::MAX))
+    }
+
+    /// The header of the input, as it was before decompression.
+    pub fn header(&self) -> Header {
+        self.header
+    }
+
+    pub fn version(&self) -> u32 {
+        self.header.version
+    }
+
+    /// The number of chunks read so far.
+    pub fn chunks(&self) -> u64 {
+        self.chunks
+    }
+
+    /// In lenient mode, chunks that fail to decompress are skipped instead of
+    /// failing the read, keeping whatever part of them was recovered.
+    pub fn set_lenient(&mut self, lenient: bool) {
+        self.lenient = lenient;
+    }
+
+    /// The errors of the chunks skipped in lenient mode.
+    pub fn errors(&self) -> &[Error] {
+        &self.errors
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Returns the rest of the current chunk, decompressing the next chunk if
+    /// nothing is left of it, or `None` at the end of the payload.
+    /// Uncompressed payloads come in pieces of whatever size `inner` reads.
+    #[cfg(feature = "stream")]
+    pub(crate) fn take_chunk(&mut self) -> Result<Option<Vec<u8>>> {
+        if !self.header.compression.is_compressed() {
+            let mut piece = vec![0; super::CHUNK_SIZE];
+            let n = self.inner.read(&mut piece)?;
+            piece.truncate(n);
+            return Ok(Some(piece).filter(|_| n > 0));
+        }
+        while self.pos == self.buf.len() {
+            if self.done {
+                return Ok(None);
+            }
+            self.fill_chunk()?;
+        }
+        let chunk = self.buf.split_off(self.pos);
+        self.buf.clear();
+        self.pos = 0;
+        Ok(Some(chunk))
+    }
+
+    fn fill_chunk(&mut self) -> Result<()> {
+        self.hooks.check()?;
+        let context = ChunkContext {
+            chunk: self.chunks,
+            offset: self.offset,
+            bytes_out: self.decompressed,
+        };
+        self.buf.clear();
+        self.pos = 0;
+        let (more, mut error) = match read_chunk(&mut self.inner, &mut self.payload) {
+            Ok(more) => (more, None),
+            // Recover what we can from a truncated last chunk.
+            Err(ref e) if self.lenient && is_eof(e) && !self.payload.is_empty() => {
+                self.done = true;
+                (true, Some(Error::from(ErrorKind::UnexpectedEof)))
+            }
+            Err(e) => return Err(e.in_chunk(context)),
+        };
+        if more {
+            let limit = self.limit();
+            if let Err(e) = self
+                .codec
+                .decompress_limited(&self.payload, &mut self.buf, limit)
+            {
+                error = chunk_error(error, Some(Error::from(e)));
+            }
+        }
+        if let Some(e) = error {
+            let e = e.in_chunk(context);
+            if !self.lenient || matches!(e.kind, ErrorKind::LimitExceeded) {
+                return Err(e);
+            }
+            warn!("skipping chunk: {}", e);
+            self.errors.push(e);
+        }
+        if more {
+            trace!(
+                "chunk {} at offset {}: {} -> {} bytes",
+                self.chunks,
+                self.offset,
+                self.payload.len(),
+                self.buf.len()
+            );
+            self.offset += 4 + self.payload.len() as u64;
+            self.decompressed += self.buf.len() as u64;
+            self.hooks
+                .progress(self.offset, 8 + self.decompressed, self.chunks);
+            self.chunks += 1;
+        } else {
+            self.done = true;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn is_eof(e: &Error) -> bool {
+    matches!(e.kind, ErrorKind::UnexpectedEof)
+}
+
+/// The error of a chunk from `eof`, if it was cut short, and `error`, if it
+/// failed to decompress. A limit exceeded comes first, as lenient mode must
+/// not skip it.
+pub(crate) fn chunk_error(eof: Option<Error>, error: Option<Error>) -> Option<Error> {
+    match (eof, error) {
+        (_, Some(e)) if matches!(e.kind, ErrorKind::LimitExceeded) => Some(e),
+        (eof, error) => eof.or(error),
+    }
+}
+
+/// Decompresses the chunks of `stdin` straight into `stdout`, returning the
+/// number of chunks read and skipped. Unlike `DecompressReader`, this never
+/// holds a decompressed chunk in memory if the codec can stream.
+pub(crate) fn decompress_payload<R: Read, W: Write, C: ChunkCodec>(
+    mut stdin: R,
+    stdout: W,
+    mut codec: C,
+    options: &Options,
+) -> Result<(u64, u64)> {
+    let mut stdout = Output {
+        inner: stdout,
+        count: 0,
+        failed: false,
+    };
+    let mut payload = Vec::new();
+    let (mut offset, mut chunks, mut skipped) = (8, 0, 0);
+    let mut truncated = false;
+    while !truncated {
+        options.hooks.check()?;
+        let context = ChunkContext {
+            chunk: chunks,
+            offset,
+            bytes_out: stdout.count,
+        };
+        let mut error = match read_chunk(&mut stdin, &mut payload) {
+            Ok(true) => None,
+            Ok(false) => break,
+            // Recover what we can from a truncated last chunk.
+            Err(ref e) if options.lenient && is_eof(e) && !payload.is_empty() => {
+                truncated = true;
+                Some(Error::from(ErrorKind::UnexpectedEof))
+            }
+            Err(e) => return Err(e.in_chunk(context)),
+        };
+        let left = options
+            .max_output
+            .map_or(u64::MAX, |max| max.saturating_sub(stdout.count));
+        let limit = options
+            .max_chunk_size
+            .unwrap_or(usize::MAX)
+            .min(usize::try_from(left).unwrap_or(usize::MAX));
+        if let Err(e) = codec.decompress_to(&payload, &mut stdout, limit) {
+            if stdout.failed {
+                return Err(e.into());
+            }
+            error = chunk_error(error, Some(Error::from(e)));
+        }
+        if let Some(e) = error {
+            let e = e.in_chunk(context);
+            if !options.lenient || matches!(e.kind, ErrorKind::LimitExceeded) {
+                return Err(e);
+            }
+            warn!("skipping chunk: {}", e);
+            skipped += 1;
+        }
+        let len = stdout.count - context.bytes_out;
+        trace!(
+            "chunk {} at offset {}: {} -> {} bytes",
+            chunks,
+            offset,
+            payload.len(),
+            len
+        );
+        offset += 4 + payload.len() as u64;
+        options.hooks.progress(offset, 8 + stdout.count, chunks);
+        chunks += 1;
+    }
+    stdout.flush()?;
+    Ok((chunks, skipped))
+}
+
+/// Counts the bytes written to `inner` and remembers whether writing failed,
+/// telling errors of the output apart from errors in a chunk.
+struct Output<W> {
+    inner: W,
+    count: u64,
+    failed: bool,
+}
+
+impl<W: Write> Write for Output<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        match self.inner.write(data) {
+            Ok(n) => {
+                self.count += n as u64;
+                Ok(n)
+            }
+            Err(e) => {
+                self.failed = true;
+                Err(e)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R: Read, C: ChunkCodec> Read for DecompressReader<R, C> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if !self.header.compression.is_compressed() {
+            return self.inner.read(out);
+        }
+        while self.pos == self.buf.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.fill_chunk()?;
+        }
+        let n = (&self.buf[self.pos..]).read(out)?;
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[test]
+fn decompress_reader_test() {
+    let mut data = Vec::new();
+    data.extend_from_slice(&[210, 4, 0, 0, 0, 0, 0, 0]);
+    for i in 0..50000 {
+        data.push((i % 251) as u8);
+    }
+    let mut compressed = Vec::new();
+    crate::dfcompress(&data[..], &mut compressed).unwrap();
+    let mut reader = DecompressReader::new(&compressed[..]).unwrap();
+    assert_eq!(reader.version(), 1234);
+    let mut payload = Vec::new();
+    reader.read_to_end(&mut payload).unwrap();
+    assert_eq!(&payload[..], &data[8..]);
+
+    let mut reader = DecompressReader::new(&data[..]).unwrap();
+    let mut payload = Vec::new();
+    reader.read_to_end(&mut payload).unwrap();
+    assert_eq!(&payload[..], &data[8..]);
+
+    // A cut last chunk is skipped, unless it exceeds a limit too.
+    let truncated = &compressed[..compressed.len() - 10];
+    let lenient = Options::new().lenient(true);
+    let mut reader = DecompressReader::new(truncated).unwrap();
+    reader.configure(&lenient);
+    reader.read_to_end(&mut Vec::new()).unwrap();
+    assert_eq!(reader.errors().len(), 1);
+    let mut reader = DecompressReader::new(truncated).unwrap();
+    reader.configure(&lenient.max_chunk_size(5000));
+    let e = Error::from(reader.read_to_end(&mut Vec::new()).unwrap_err());
+    assert!(matches!(e.kind, ErrorKind::LimitExceeded));
+}
@@ -0,0 +1,566 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+use std::io;
+use std::time::Instant;
+
+use super::summary::Counter;
+use super::{ChunkCodec, ChunkWriter, Chunks, CompressWriter, Compression, DecompressReader};
+use super::{ChunkContext, ErrorKind, Header, Options, Result, Summary};
+
+/// The desired compression state of a recoded data file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Target {
+    Compressed,
+    Uncompressed,
+}
+
+/// Converts a data file to `target`, copying it if it is already there.
+pub fn recode<R: io::Read, W: io::Write>(stdin: R, stdout: W, target: Target) -> Result<Summary> {
+    recode_with(stdin, stdout, target, &Options::default())
+}
+
+pub fn recode_with<R: io::Read, W: io::Write>(
+    stdin: R,
+    stdout: W,
+    target: Target,
+    options: &Options,
+) -> Result<Summary> {
+    let start = now();
+    let mut stdin = Counter::new(stdin);
+    let mut stdout = Counter::new(stdout);
+    let header = Header::read(&mut stdin)?;
+    let to = match target {
+        Target::Compressed => options.codec.compression(),
+        Target::Uncompressed => Compression::Uncompressed,
+    };
+    let mut skipped = 0;
+    let chunks = if options.copies(header.compression, to) {
+        copy_payload(&mut stdin, &mut stdout, header, options)?
+    } else if !to.is_compressed() {
+        let (chunks, n) = decompress_builtin(&mut stdin, &mut stdout, header, options)?;
+        skipped = n;
+        chunks
+    } else if let Some(codec) = header.compression.codec() {
+        let mut reader =
+            DecompressReader::from_parts(&mut stdin, header, options.build_decoder(codec));
+        reader.configure(options);
+        // Progress is reported by the reader, which knows the input offset.
+        let mut options = options.clone();
+        options.hooks = options.hooks.silent();
+        let chunks = compress_builtin(&mut reader, &mut stdout, header.version, &options)?;
+        skipped = reader.errors().len() as u64;
+        chunks
+    } else {
+        compress_builtin(&mut stdin, &mut stdout, header.version, options)?
+    };
+    Ok(finished(
+        Summary {
+            version: header.version,
+            chunks,
+            bytes_in: stdin.count,
+            bytes_out: stdout.count,
+            skipped,
+        },
+        start,
+    ))
+}
+
+/// Like `recode_with`, but (de)compresses chunks with `codec` instead of the
+/// built-in codecs. Compressed input must use the compression field of `codec`.
+pub fn recode_with_codec<R: io::Read, W: io::Write, C: ChunkCodec>(
+    stdin: R,
+    stdout: W,
+    target: Target,
+    options: &Options,
+    codec: C,
+) -> Result<Summary> {
+    let start = now();
+    let mut stdin = Counter::new(stdin);
+    let mut stdout = Counter::new(stdout);
+    let header = Header::read(&mut stdin)?;
+    let compressed = header.compression.is_compressed();
+    if compressed && header.compression != codec.compression() {
+        let flag = header.compression.to_u32();
+        return Err(ErrorKind::CompressionUnknown(flag).into());
+    }
+    let mut skipped = 0;
+    let chunks = match (target, compressed) {
+        (Target::Compressed, false) => {
+            compress_payload(&mut stdin, &mut stdout, header.version, options, codec)?
+        }
+        (Target::Uncompressed, true) => {
+            Header::new(header.version, Compression::Uncompressed).write(&mut stdout)?;
+            let (chunks, n) =
+                super::read::decompress_payload(&mut stdin, &mut stdout, codec, options)?;
+            skipped = n;
+            chunks
+        }
+        _ => copy_payload(&mut stdin, &mut stdout, header, options)?,
+    };
+    Ok(finished(
+        Summary {
+            version: header.version,
+            chunks,
+            bytes_in: stdin.count,
+            bytes_out: stdout.count,
+            skipped,
+        },
+        start,
+    ))
+}
+
+/// The start time of a conversion, for the log. There is no clock on
+/// wasm32-unknown-unknown, where `Instant::now` panics.
+fn now() -> Option<Instant> {
+    let wasm = cfg!(all(target_arch = "wasm32", target_os = "unknown"));
+    match cfg!(feature = "log") && !wasm {
+        true => Some(Instant::now()),
+        false => None,
+    }
+}
+
+/// Logs the summary of a conversion started at `start`.
+fn finished(summary: Summary, start: Option<Instant>) -> Summary {
+    info!(
+        "recoded {} chunks ({} skipped): {} -> {} bytes{}",
+        summary.chunks,
+        summary.skipped,
+        summary.bytes_in,
+        summary.bytes_out,
+        match start {
+            Some(start) => format!(" in {:?}", start.elapsed()),
+            None => String::new(),
+        }
+    );
+    summary
+}
+
+/// Writes `header` followed by the rest of `stdin`, returning the number of
+/// chunks copied. Compressed payloads are copied chunk by chunk, and their
+/// errors are in the chunk they occur in. Nothing is decompressed, so
+/// `bytes_out` of the context is 0.
+fn copy_payload<R: io::Read, W: io::Write>(
+    mut stdin: R,
+    mut stdout: W,
+    header: Header,
+    options: &Options,
+) -> Result<u64> {
+    if !header.compression.is_compressed() {
+        header.write(&mut stdout)?;
+        io::copy(&mut stdin, &mut stdout)?;
+        return Ok(0);
+    }
+    let mut writer = ChunkWriter::new(stdout, header)?;
+    let mut chunks = 0;
+    let mut offset = 8;
+    for chunk in Chunks::from_parts(stdin, header) {
+        options.hooks.check()?;
+        let context = ChunkContext {
+            chunk: chunks,
+            offset,
+            bytes_out: 0,
+        };
+        let chunk = chunk.map_err(|e| e.in_chunk(context))?;
+        writer
+            .write_chunk(&chunk.data)
+            .map_err(|e| e.in_chunk(context))?;
+        offset = chunk.offset + chunk.stored_len();
+        options.hooks.progress(offset, offset, chunks);
+        chunks += 1;
+    }
+    Ok(chunks)
+}
+
+/// Decompresses the payload of a compressed file with the codec of its
+/// header, returning the number of chunks read and skipped. Chunks are
+/// decompressed in parallel if the `parallel` feature is enabled.
+fn decompress_builtin<R: io::Read, W: io::Write>(
+    stdin: R,
+    stdout: W,
+    header: Header,
+    options: &Options,
+) -> Result<(u64, u64)> {
+    #[cfg(feature = "parallel")]
+    return super::parallel::decompress(stdin, stdout, header, options);
+    #[cfg(not(feature = "parallel"))]
+    {
+        let codec = options.build_decoder(header.compression.codec().unwrap_or_default());
+        let mut stdout = stdout;
+        Header::new(header.version, Compression::Uncompressed).write(&mut stdout)?;
+        super::read::decompress_payload(stdin, stdout, codec, options)
+    }
+}
+
+/// Compresses with the codec selected by `options`, in parallel if the
+/// `parallel` feature is enabled.
+fn compress_builtin<R: io::Read, W: io::Write>(
+    stdin: R,
+    stdout: W,
+    version: u32,
+    options: &Options,
+) -> Result<u64> {
+    #[cfg(feature = "parallel")]
+    return super::parallel::compress(stdin, stdout, version, options);
+    #[cfg(not(feature = "parallel"))]
+    compress_payload(stdin, stdout, version, options, options.build_codec())
+}
+
+fn compress_payload<R: io::Read, W: io::Write, C: ChunkCodec>(
+    mut stdin: R,
+    stdout: W,
+    version: u32,
+    options: &Options,
+    codec: C,
+) -> Result<u64> {
+    let mut writer = CompressWriter::with_codec(stdout, version, options, codec)?;
+    io::copy(&mut stdin, &mut writer)?;
+    writer.try_finish()?;
+    Ok(writer.chunks())
+}
+
+pub fn dfuncompress<R: io::Read, W: io::Write>(stdin: R, stdout: W) -> Result<Summary> {
+    recode(stdin, stdout, Target::Uncompressed)
+}
+
+pub fn dfcompress<R: io::Read, W: io::Write>(stdin: R, stdout: W) -> Result<Summary> {
+    dfcompress_with(stdin, stdout, &Options::default())
+}
+
+pub fn dfcompress_with<R: io::Read, W: io::Write>(
+    stdin: R,
+    stdout: W,
+    options: &Options,
+) -> Result<Summary> {
+    recode_with(stdin, stdout, Target::Compressed, options)
+}
+
+/// Compresses an in-memory data file, as `dfcompress` does for streams.
+pub fn compress_bytes(data: &[u8]) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len() / 2);
+    dfcompress(data, &mut out)?;
+    Ok(out)
+}
+
+/// Decompresses an in-memory data file, as `dfuncompress` does for streams.
+pub fn decompress_bytes(data: &[u8]) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len() * 2);
+    dfuncompress(data, &mut out)?;
+    Ok(out)
+}
+
+/// Decompresses a data file without writing the result anywhere, checking
+/// the header, the chunk framing and every compressed chunk.
+///
+/// `bytes_out` of the summary is the size the decompressed file would have.
+pub fn verify<R: io::Read>(r: R) -> Result<Summary> {
+    dfuncompress(r, io::sink())
+}
+
+#[test]
+fn compress_test() {
+    use std::io::Seek;
+    let mut buf = io::Cursor::new(Vec::new());
+    buf.get_mut().resize(30000, b'a');
+    crate::write_u32(&mut buf, 1234).unwrap(); // version
+    crate::write_u32(&mut buf, 0).unwrap(); // compression
+    buf.seek(io::SeekFrom::Start(0)).unwrap();
+    let mut buf2 = io::Cursor::new(Vec::new());
+    dfcompress(&mut buf, &mut buf2).unwrap();
+    buf2.seek(io::SeekFrom::Start(0)).unwrap();
+    let mut buf3 = io::Cursor::new(Vec::new());
+    dfuncompress(&mut buf2, &mut buf3).unwrap();
+    assert_eq!(buf.get_ref(), buf3.get_ref());
+}
+
+#[test]
+fn bytes_test() {
+    let mut data = crate::fixture::empty_file();
+    data.resize(25000, b'x');
+    let compressed = compress_bytes(&data).unwrap();
+    assert_eq!(&compressed[..8], &[0x59, 0x06, 0, 0, 1, 0, 0, 0]);
+    assert_eq!(decompress_bytes(&compressed).unwrap(), data);
+    assert_eq!(compress_bytes(&compressed).unwrap(), compressed);
+}
+
+#[test]
+fn recode_test() {
+    let mut data = crate::fixture::empty_file();
+    data.resize(45000, b'y');
+    let compressed = compress_bytes(&data).unwrap();
+    for input in &[&data, &compressed] {
+        let mut out = Vec::new();
+        recode(&input[..], &mut out, Target::Compressed).unwrap();
+        assert_eq!(out, compressed);
+        let mut out = Vec::new();
+        recode(&input[..], &mut out, Target::Uncompressed).unwrap();
+        assert_eq!(out, data);
+    }
+}
+
+#[test]
+fn recompress_test() {
+    let mut data = crate::fixture::empty_file();
+    data.resize(45000, b'r');
+    let fast = Options::new().fast();
+    let mut compressed = Vec::new();
+    recode_with(&data[..], &mut compressed, Target::Compressed, &fast).unwrap();
+    let mut copied = Vec::new();
+    recode_with(
+        &compressed[..],
+        &mut copied,
+        Target::Compressed,
+        &Options::new(),
+    )
+    .unwrap();
+    assert_eq!(copied, compressed);
+    let options = Options::new().recompress(true);
+    let mut out = Vec::new();
+    recode_with(&compressed[..], &mut out, Target::Compressed, &options).unwrap();
+    assert_eq!(out, compress_bytes(&data).unwrap());
+
+    // A level or chunk size of its own asks for it too.
+    for options in [Options::new().level(6), Options::new().chunk_size(30000)] {
+        let mut out = Vec::new();
+        recode_with(&compressed[..], &mut out, Target::Compressed, &options).unwrap();
+        assert_ne!(out, compressed, "{:?}", options);
+        assert_eq!(decompress_bytes(&out).unwrap(), data);
+    }
+    let mut fast = Vec::new();
+    recode_with(
+        &out[..],
+        &mut fast,
+        Target::Compressed,
+        &Options::new().fast(),
+    )
+    .unwrap();
+    assert_eq!(fast, compressed);
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn zstd_recode_test() {
+    let mut data = crate::fixture::empty_file();
+    data.resize(45000, b'z');
+    let compressed = compress_bytes(&data).unwrap();
+    let options = Options::new().codec(crate::Codec::Zstd);
+    let mut zstd = Vec::new();
+    recode_with(&compressed[..], &mut zstd, Target::Compressed, &options).unwrap();
+    assert_eq!(&zstd[4..8], &[2, 0, 0, 0]);
+    assert_eq!(decompress_bytes(&zstd).unwrap(), data);
+    assert_eq!(compress_bytes(&zstd).unwrap(), compressed);
+}
+
+#[test]
+fn summary_test() {
+    let mut data = crate::fixture::empty_file();
+    data.resize(45008, b's');
+    let mut compressed = Vec::new();
+    let summary = dfcompress(&data[..], &mut compressed).unwrap();
+    assert_eq!(summary.version, 1625);
+    assert_eq!(summary.chunks, 3);
+    assert_eq!(summary.bytes_in, data.len() as u64);
+    assert_eq!(summary.bytes_out, compressed.len() as u64);
+    assert!(summary.ratio() < 0.1);
+
+    let summary = dfuncompress(&compressed[..], io::sink()).unwrap();
+    assert_eq!(summary.chunks, 3);
+    assert_eq!(summary.bytes_out, data.len() as u64);
+    let summary = dfcompress(&compressed[..], io::sink()).unwrap();
+    assert_eq!(summary.chunks, 3);
+    assert_eq!(summary.bytes_out, compressed.len() as u64);
+}
+
+#[test]
+fn context_test() {
+    let mut data = crate::fixture::empty_file();
+    data.resize(45008, b'e');
+    let mut compressed = compress_bytes(&data).unwrap();
+    let second = 8 + 4 + crate::read_u32(&mut &compressed[8..]).unwrap() as usize;
+    compressed[second + 6] ^= 0xff;
+    let e = decompress_bytes(&compressed).unwrap_err();
+    let context = e.context().unwrap();
+    assert_eq!(context.chunk, 1);
+    assert_eq!(context.offset, second as u64);
+    assert_eq!(context.bytes_out, 20000);
+    let suffix = format!(
+        " in chunk 1 at offset {} (20000 bytes decompressed)",
+        second
+    );
+    assert!(e.to_string().ends_with(&suffix));
+
+    // Copied chunks are not decompressed, but a cut one is still found.
+    let e = dfcompress(&compressed[..second + 6], io::sink()).unwrap_err();
+    assert!(matches!(e.kind(), ErrorKind::UnexpectedEof));
+    let context = e.context().unwrap();
+    assert_eq!((context.chunk, context.offset), (1, second as u64));
+}
+
+#[test]
+fn progress_test() {
+    use std::sync::{Arc, Mutex};
+    let mut data = crate::fixture::empty_file();
+    data.resize(45008, b'p');
+    let calls = Arc::new(Mutex::new(Vec::new()));
+    let log = calls.clone();
+    let options = Options::new().on_progress(move |bytes_in, bytes_out, chunk| {
+        log.lock().unwrap().push((bytes_in, bytes_out, chunk));
+    });
+    let check = |summary: Summary| {
+        let mut calls = calls.lock().unwrap();
+        assert_eq!(calls.len(), 3);
+        let last = (summary.bytes_in, summary.bytes_out, summary.chunks - 1);
+        assert_eq!(calls.pop(), Some(last));
+        calls.clear();
+    };
+    let mut compressed = Vec::new();
+    check(dfcompress_with(&data[..], &mut compressed, &options).unwrap());
+    check(recode_with(&compressed[..], io::sink(), Target::Compressed, &options).unwrap());
+    check(recode_with(&compressed[..], io::sink(), Target::Uncompressed, &options).unwrap());
+}
+
+#[test]
+fn cancel_test() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    let mut data = crate::fixture::empty_file();
+    data.resize(65008, b'c');
+    let flag = Arc::new(AtomicBool::new(false));
+    let cancel = flag.clone();
+    let options = Options::new()
+        .cancel_flag(flag.clone())
+        .on_progress(move |_, _, chunk| {
+            if chunk == 1 {
+                cancel.store(true, Ordering::Relaxed);
+            }
+        });
+    let mut compressed = Vec::new();
+    let e = dfcompress_with(&data[..], &mut compressed, &options).unwrap_err();
+    match *e.kind() {
+        ErrorKind::Cancelled => (),
+        ref kind => panic!("{:?}", kind),
+    }
+    flag.store(false, Ordering::Relaxed);
+    let compressed = compress_bytes(&data).unwrap();
+    let e = recode_with(&compressed[..], io::sink(), Target::Uncompressed, &options).unwrap_err();
+    match *e.kind() {
+        ErrorKind::Cancelled => (),
+        ref kind => panic!("{:?}", kind),
+    }
+}
+
+#[test]
+fn lenient_test() {
+    let mut data = crate::fixture::empty_file();
+    data.extend((0..60000u32).map(|i| (i / 1000) as u8));
+    let mut compressed = compress_bytes(&data).unwrap();
+    let second = 8 + 4 + crate::read_u32(&mut &compressed[8..]).unwrap() as usize;
+    compressed[second + 6] ^= 0xff;
+    assert!(decompress_bytes(&compressed).is_err());
+    let options = Options::new().lenient(true);
+    let mut output = Vec::new();
+    let summary =
+        recode_with(&compressed[..], &mut output, Target::Uncompressed, &options).unwrap();
+    assert_eq!(summary.chunks, 3);
+    assert_eq!(summary.skipped, 1);
+    assert_eq!(output[..20008], data[..20008]);
+    assert_eq!(output[output.len() - 20000..], data[40008..]);
+
+    let truncated = &compressed[..compressed.len() - 10];
+    let mut output = Vec::new();
+    let summary = recode_with(truncated, &mut output, Target::Uncompressed, &options).unwrap();
+    assert_eq!(summary.skipped, 2);
+    assert_eq!(output[..20008], data[..20008]);
+}
+
+#[test]
+fn verify_test() {
+    let mut data = crate::fixture::empty_file();
+    data.resize(45008, b'v');
+    let mut compressed = compress_bytes(&data).unwrap();
+    let summary = verify(&compressed[..]).unwrap();
+    assert_eq!(summary.chunks, 3);
+    assert_eq!(summary.bytes_in, compressed.len() as u64);
+    assert_eq!(summary.bytes_out, data.len() as u64);
+    assert!(verify(&compressed[..compressed.len() - 1]).is_err());
+    let last = compressed.len() - 6;
+    compressed[last] ^= 0xff;
+    assert!(verify(&compressed[..]).is_err());
+}
+
+#[test]
+fn limit_test() {
+    let mut data = crate::fixture::empty_file();
+    data.resize(45008, 0);
+    let compressed = compress_bytes(&data).unwrap();
+    let decompress =
+        |options: Options| recode_with(&compressed[..], io::sink(), Target::Uncompressed, &options);
+    assert!(decompress(Options::new().max_chunk_size(20000).max_output(45000)).is_ok());
+    for options in [
+        Options::new().max_chunk_size(19999),
+        Options::new().max_output(44999).lenient(true),
+    ] {
+        match *decompress(options).unwrap_err().kind() {
+            ErrorKind::LimitExceeded => (),
+            ref kind => panic!("{:?}", kind),
+        }
+    }
+}
+
+#[test]
+fn level_test() {
+    let data = crate::fixture::sample_file(100000);
+    let compress = |options: Options| {
+        let mut out = Vec::new();
+        dfcompress_with(&data[..], &mut out, &options).unwrap();
+        out
+    };
+    let fast = compress(Options::new().fast());
+    let best = compress(Options::new().best());
+    assert_eq!(fast, compress(Options::new().level(1)));
+    assert_eq!(best, compress(Options::new().level(9)));
+    assert!(best.len() < fast.len());
+}
+
+#[cfg(feature = "log")]
+#[test]
+fn log_test() {
+    use std::sync::Mutex;
+
+    struct Recorder(Mutex<Vec<(log::Level, String)>>);
+
+    impl log::Log for Recorder {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            let message = record.args().to_string();
+            self.0.lock().unwrap().push((record.level(), message));
+        }
+
+        fn flush(&self) {}
+    }
+
+    static RECORDER: Recorder = Recorder(Mutex::new(Vec::new()));
+    log::set_logger(&RECORDER).unwrap();
+    log::set_max_level(log::LevelFilter::Trace);
+
+    let mut data = crate::fixture::empty_file();
+    data.resize(45008, 7);
+    let mut compressed = compress_bytes(&data).unwrap();
+    let last = compressed.len() - 6;
+    compressed[last] ^= 0xff;
+    let options = Options::new().lenient(true);
+    let summary = recode_with(&compressed[..], io::sink(), Target::Uncompressed, &options);
+    assert_eq!(summary.unwrap().skipped, 1);
+    let records = RECORDER.0.lock().unwrap();
+    let has = |level, prefix: &str| {
+        records
+            .iter()
+            .any(|(l, message)| *l == level && message.starts_with(prefix))
+    };
+    assert!(has(log::Level::Debug, "header: version 1625, Zlib"));
+    assert!(has(log::Level::Trace, "chunk 0 at offset 8: "));
+    assert!(has(log::Level::Warn, "skipping chunk: "));
+    assert!(has(log::Level::Info, "recoded 3 chunks (1 skipped): "));
+}
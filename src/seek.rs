@@ -0,0 +1,360 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+use std::io;
+use std::io::prelude::*;
+
+use super::index::{self, Index};
+use super::{ChunkCodec, Chunks, Header, Options, Result};
+
+/// Provides random access to the uncompressed payload of a data file.
+///
+/// Opening a compressed file decompresses every chunk once to build an index
+/// of chunk offsets. Afterwards, reads only decompress the chunks they touch.
+pub struct SeekableDecompressor<R> {
+    inner: R,
+    header: Header,
+    codec: Box<dyn ChunkCodec + Send>,
+    index: Option<Index>,
+    len: u64,
+    pos: u64,
+    cached: Option<usize>,
+    payload: Vec<u8>,
+    buf: Vec<u8>,
+}
+
+impl<R: Read + Seek> SeekableDecompressor<R> {
+    pub fn new(inner: R) -> Result<SeekableDecompressor<R>> {
+        SeekableDecompressor::with_options(inner, &Options::new())
+    }
+
+    /// Like `new`, decompressing with the dictionary of `options`.
+    pub fn with_options(mut inner: R, options: &Options) -> Result<SeekableDecompressor<R>> {
+        inner.seek(io::SeekFrom::Start(0))?;
+        let header = Header::read(&mut inner)?;
+        let mut codec = options.build_decoder(header.compression.codec().unwrap_or_default());
+        let (index, len) = if header.compression.is_compressed() {
+            let chunks = Chunks::from_parts(&mut inner, header);
+            let index = index::scan(chunks, &mut *codec)?;
+            let len = index.decompressed_len();
+            (Some(index), len)
+        } else {
+            (None, inner.seek(io::SeekFrom::End(0))? - 8)
+        };
+        Ok(SeekableDecompressor {
+            inner,
+            header,
+            codec,
+            index,
+            len,
+            pos: 0,
+            cached: None,
+            payload: Vec::new(),
+            buf: Vec::new(),
+        })
+    }
+
+    /// Like `with_options`, with the chunk table of the file from
+    /// `build_index` or an earlier `index`, which saves decompressing every
+    /// chunk to build it. Only the header is checked here; a read fails with
+    /// `InvalidData` once a chunk turns out not to match the index.
+    pub fn with_index(
+        mut inner: R,
+        index: Index,
+        options: &Options,
+    ) -> Result<SeekableDecompressor<R>> {
+        inner.seek(io::SeekFrom::Start(0))?;
+        let header = Header::read(&mut inner)?;
+        if header != index.header || !header.compression.is_compressed() {
+            let message = "the index is of another file";
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, message).into());
+        }
+        let codec = options.build_decoder(header.compression.codec().unwrap_or_default());
+        Ok(SeekableDecompressor {
+            inner,
+            header,
+            codec,
+            len: index.decompressed_len(),
+            index: Some(index),
+            pos: 0,
+            cached: None,
+            payload: Vec::new(),
+            buf: Vec::new(),
+        })
+    }
+
+    pub fn header(&self) -> Header {
+        self.header
+    }
+
+    /// The length of the uncompressed payload.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The chunk table, if the file is compressed.
+    pub fn index(&self) -> Option<&Index> {
+        self.index.as_ref()
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn load_chunk(&mut self, i: usize) -> Result<()> {
+        if self.cached == Some(i) {
+            return Ok(());
+        }
+        self.cached = None;
+        let entry = self.index.as_ref().unwrap().chunks[i];
+        self.inner
+            .seek(io::SeekFrom::Start(entry.payload_offset()))?;
+        self.payload.resize(entry.compressed_len as usize, 0);
+        self.inner.read_exact(&mut self.payload)?;
+        self.buf.clear();
+        self.codec.decompress(&self.payload, &mut self.buf)?;
+        // An index of another file may have the same header.
+        if self.buf.len() as u64 != entry.decompressed_len {
+            return Err(stale().into());
+        }
+        self.cached = Some(i);
+        Ok(())
+    }
+}
+
+/// The error of an index that does not match the chunks of the file.
+fn stale() -> io::Error {
+    let message = "the index does not match the chunks of the file";
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl<R: Read + Seek> Read for SeekableDecompressor<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len || out.is_empty() {
+            return Ok(0);
+        }
+        if !self.header.compression.is_compressed() {
+            self.inner.seek(io::SeekFrom::Start(8 + self.pos))?;
+            let n = (&mut self.inner).take(self.len - self.pos).read(out)?;
+            self.pos += n as u64;
+            return Ok(n);
+        }
+        let (i, offset) = {
+            let index = self.index.as_ref().unwrap();
+            let i = index.find(self.pos).ok_or_else(stale)?;
+            (i, index.chunks[i].decompressed_offset)
+        };
+        if offset > self.pos {
+            return Err(stale());
+        }
+        self.load_chunk(i)?;
+        let start = (self.pos - offset) as usize;
+        let n = (&self.buf[start..]).read(out)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Read + Seek> Seek for SeekableDecompressor<R> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            io::SeekFrom::Start(n) => {
+                self.pos = n;
+                return Ok(n);
+            }
+            io::SeekFrom::End(n) => (self.len, n),
+            io::SeekFrom::Current(n) => (self.pos, n),
+        };
+        match base.checked_add_signed(offset) {
+            Some(n) => {
+                self.pos = n;
+                Ok(n)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+/// Writes the bytes of the uncompressed payload of the data file `r` from
+/// `offset` on, up to `length` of them if given, to `w`. Chunks before
+/// `offset` are only decompressed to learn their length, and nothing after
+/// the chunk holding the end is read. Returns the number of bytes written,
+/// which is less than `length` if the payload ends first.
+pub fn decompress_range<R: Read, W: Write>(
+    r: R,
+    w: W,
+    offset: u64,
+    length: Option<u64>,
+) -> Result<u64> {
+    decompress_range_with(r, w, offset, length, &Options::new())
+}
+
+/// Like `decompress_range`, decompressing with the dictionary of `options`.
+pub fn decompress_range_with<R: Read, W: Write>(
+    mut r: R,
+    mut w: W,
+    offset: u64,
+    length: Option<u64>,
+    options: &Options,
+) -> Result<u64> {
+    let header = Header::read(&mut r)?;
+    let end = length.map_or(u64::MAX, |length| offset.saturating_add(length));
+    if !header.compression.is_compressed() {
+        io::copy(&mut (&mut r).take(offset), &mut io::sink())?;
+        return Ok(io::copy(&mut r.take(end - offset), &mut w)?);
+    }
+    let mut codec = options.build_decoder(header.compression.codec().unwrap_or_default());
+    let mut buf = Vec::new();
+    let (mut pos, mut written) = (0, 0);
+    for chunk in Chunks::from_parts(r, header) {
+        if pos >= end {
+            break;
+        }
+        let chunk = chunk?;
+        buf.clear();
+        codec.decompress(&chunk.data, &mut buf)?;
+        let next = pos + buf.len() as u64;
+        if next > offset {
+            let start = offset.saturating_sub(pos) as usize;
+            let stop = (end.min(next) - pos) as usize;
+            w.write_all(&buf[start..stop])?;
+            written += (stop - start) as u64;
+        }
+        pos = next;
+    }
+    Ok(written)
+}
+
+#[test]
+fn seekable_test() {
+    let mut data = crate::fixture::empty_file();
+    data.extend((0..70000u32).map(|i| (i * 7 % 256) as u8));
+    let compressed = crate::compress_bytes(&data).unwrap();
+    for input in &[&data, &compressed] {
+        let mut reader = SeekableDecompressor::new(io::Cursor::new(&input[..])).unwrap();
+        assert_eq!(reader.len(), 70000);
+        let mut buf = [0; 1000];
+        reader.seek(io::SeekFrom::Start(39500)).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &data[39508..40508]);
+        reader.seek(io::SeekFrom::Current(-3000)).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &data[37508..38508]);
+        reader.seek(io::SeekFrom::End(-10)).unwrap();
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(&rest[..], &data[data.len() - 10..]);
+        assert!(reader.seek(io::SeekFrom::Current(-70001)).is_err());
+    }
+}
+
+#[test]
+fn with_index_test() {
+    let mut data = crate::fixture::empty_file();
+    data.extend((0..50000u32).map(|i| (i * 11 % 256) as u8));
+    let compressed = crate::compress_bytes(&data).unwrap();
+    let index = super::build_index(&compressed[..]).unwrap();
+    let options = Options::new();
+    let mut reader =
+        SeekableDecompressor::with_index(io::Cursor::new(&compressed[..]), index, &options)
+            .unwrap();
+    assert_eq!(reader.len(), 50000);
+    let mut buf = [0; 100];
+    reader.seek(io::SeekFrom::Start(45000)).unwrap();
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf[..], &data[45008..45108]);
+    let other = super::build_index(&crate::compress_bytes(&data[..30008]).unwrap()[..]).unwrap();
+    let mut uncompressed = io::Cursor::new(&data[..]);
+    assert!(SeekableDecompressor::with_index(&mut uncompressed, other, &options).is_err());
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn seekable_dictionary_test() {
+    let data = crate::fixture::sample_file(50000);
+    let (options, compressed) = crate::fixture::dictionary_file(50000);
+    assert!(SeekableDecompressor::new(io::Cursor::new(&compressed[..])).is_err());
+    let reader = SeekableDecompressor::with_options(io::Cursor::new(&compressed[..]), &options);
+    let mut reader = reader.unwrap();
+    let mut buf = [0; 100];
+    reader.seek(io::SeekFrom::Start(45000)).unwrap();
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf[..], &data[45008..45108]);
+    let index = super::build_index_with(&compressed[..], &options).unwrap();
+    assert_eq!(Some(&index), reader.index());
+    let cursor = io::Cursor::new(&compressed[..]);
+    let mut reader = SeekableDecompressor::with_index(cursor, index, &options).unwrap();
+    reader.seek(io::SeekFrom::Start(1000)).unwrap();
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf[..], &data[1008..1108]);
+    let mut out = Vec::new();
+    decompress_range_with(&compressed[..], &mut out, 30000, Some(100), &options).unwrap();
+    assert_eq!(&out[..], &data[30008..30108]);
+}
+
+#[test]
+fn stale_index_test() {
+    let mut data = crate::fixture::empty_file();
+    data.extend((0..50000u32).map(|i| (i * 17 % 256) as u8));
+    let compressed = crate::compress_bytes(&data).unwrap();
+    let index = super::build_index(&compressed[..]).unwrap();
+    let read_at = |index: Index, pos: u64| {
+        let cursor = io::Cursor::new(&compressed[..]);
+        let mut reader = SeekableDecompressor::with_index(cursor, index, &Options::new()).unwrap();
+        reader.seek(io::SeekFrom::Start(pos)).unwrap();
+        reader.read_exact(&mut [0; 100])
+    };
+    // A first chunk said to be longer than it is, and one said to start
+    // after the end of the one before.
+    let mut longer = index.clone();
+    longer.chunks[0].decompressed_len += 5000;
+    for entry in &mut longer.chunks[1..] {
+        entry.decompressed_offset += 5000;
+    }
+    let mut gap = index.clone();
+    gap.chunks[1].decompressed_offset += 5000;
+    for (index, pos) in [(longer.clone(), 22000), (longer, 100), (gap, 21000)] {
+        let e = read_at(index, pos).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{}", pos);
+    }
+    assert!(read_at(index, 22000).is_ok());
+}
+
+#[test]
+fn decompress_range_test() {
+    let mut data = crate::fixture::empty_file();
+    data.extend((0..70000u32).map(|i| (i * 13 % 256) as u8));
+    let compressed = crate::compress_bytes(&data).unwrap();
+    for input in &[&data, &compressed] {
+        let mut out = Vec::new();
+        assert_eq!(
+            decompress_range(&input[..], &mut out, 19990, Some(20020)).unwrap(),
+            20020
+        );
+        assert_eq!(&out[..], &data[19998..40018]);
+        let mut out = Vec::new();
+        assert_eq!(
+            decompress_range(&input[..], &mut out, 69000, Some(5000)).unwrap(),
+            1000
+        );
+        assert_eq!(&out[..], &data[69008..]);
+        let mut out = Vec::new();
+        assert_eq!(
+            decompress_range(&input[..], &mut out, 100, None).unwrap(),
+            69900
+        );
+        assert_eq!(&out[..], &data[108..]);
+    }
+    // Nothing is read past the chunk holding the end.
+    let mut truncated = compressed.clone();
+    truncated.truncate(compressed.len() - 10);
+    let mut out = Vec::new();
+    decompress_range(&truncated[..], &mut out, 0, Some(100)).unwrap();
+    assert_eq!(&out[..], &data[8..108]);
+}
@@ -0,0 +1,112 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+use bytes::Bytes;
+use futures_util::stream::{self, Stream};
+use tokio::io::AsyncRead;
+
+use super::async_io::{would_block, Prefetch};
+use super::{Compression, DecompressReader, Header, Options, Result};
+
+enum State<R> {
+    Start(Prefetch<R>, Options),
+    Chunks(DecompressReader<Prefetch<R>>),
+    Done,
+}
+
+/// Decompresses a data file into a stream of the pieces of the output of
+/// `dfuncompress`: first the header, then one item per chunk.
+///
+/// Nothing is read before the stream is polled, and each chunk is read only
+/// when the previous item has been taken. The stream ends after an error.
+pub fn decompress_stream<R: AsyncRead + Unpin>(
+    stdin: R,
+    options: &Options,
+) -> impl Stream<Item = Result<Bytes>> {
+    let state = State::Start(Prefetch::new(stdin), options.clone());
+    stream::unfold(state, |state| async move {
+        let result = match state {
+            State::Start(mut input, options) => match input.read_header().await {
+                Ok(header) => {
+                    let codec =
+                        options.build_decoder(header.compression.codec().unwrap_or_default());
+                    let mut reader = DecompressReader::from_parts(input, header, codec);
+                    reader.configure(&options);
+                    let header = Header::new(header.version, Compression::Uncompressed);
+                    Ok((header.to_bytes().to_vec(), State::Chunks(reader)))
+                }
+                Err(e) => Err(e),
+            },
+            State::Chunks(mut reader) => loop {
+                match reader.take_chunk() {
+                    Ok(Some(chunk)) => break Ok((chunk, State::Chunks(reader))),
+                    Ok(None) => return None,
+                    Err(ref e) if would_block(e) => {
+                        if let Err(e) = reader.get_mut().fill().await {
+                            break Err(e.into());
+                        }
+                    }
+                    Err(e) => break Err(e),
+                }
+            },
+            State::Done => return None,
+        };
+        Some(match result {
+            Ok((data, state)) => (Ok(Bytes::from(data)), state),
+            Err(e) => (Err(e), State::Done),
+        })
+    })
+}
+
+#[test]
+fn decompress_stream_test() {
+    use futures_util::StreamExt;
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let data = crate::fixture::sample_file(500000);
+    let compressed = crate::compress_bytes(&data).unwrap();
+    runtime.block_on(async {
+        for input in [&compressed, &data] {
+            let stream = decompress_stream(&input[..], &Options::new());
+            let items: Vec<_> = stream.collect().await;
+            let mut output = Vec::new();
+            for item in &items {
+                output.extend_from_slice(&item.as_ref().unwrap()[..]);
+            }
+            assert_eq!(items[0].as_ref().unwrap().len(), 8);
+            assert_eq!(output, data);
+        }
+        let items: Vec<_> = decompress_stream(&compressed[..], &Options::new())
+            .collect()
+            .await;
+        assert_eq!(items.len(), 1 + 25);
+
+        let truncated = &compressed[..compressed.len() - 1];
+        let items: Vec<_> = decompress_stream(truncated, &Options::new())
+            .collect()
+            .await;
+        assert!(items.last().unwrap().is_err());
+        assert_eq!(items.len(), 1 + 25);
+    });
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn decompress_stream_dictionary_test() {
+    use futures_util::StreamExt;
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let data = crate::fixture::sample_file(50000);
+    let (options, compressed) = crate::fixture::dictionary_file(50000);
+    runtime.block_on(async {
+        let mut output = Vec::new();
+        let mut stream = Box::pin(decompress_stream(&compressed[..], &options));
+        while let Some(item) = stream.next().await {
+            output.extend_from_slice(&item.unwrap()[..]);
+        }
+        assert_eq!(output, data);
+    });
+}
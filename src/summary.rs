@@ -0,0 +1,62 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::io;
+
+/// Statistics about a completed operation.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Summary {
+    pub version: u32,
+    /// The number of compressed chunks read or written.
+    pub chunks: u64,
+    /// The number of bytes read, including the header.
+    pub bytes_in: u64,
+    /// The number of bytes written, including the header.
+    pub bytes_out: u64,
+    /// The number of chunks skipped because they failed to decompress.
+    pub skipped: u64,
+}
+
+impl Summary {
+    /// The size of the output relative to the input.
+    pub fn ratio(&self) -> f64 {
+        if self.bytes_in == 0 {
+            return 1.0;
+        }
+        self.bytes_out as f64 / self.bytes_in as f64
+    }
+}
+
+/// Counts the bytes passing through a reader or writer.
+pub(crate) struct Counter<T> {
+    pub inner: T,
+    pub count: u64,
+}
+
+impl<T> Counter<T> {
+    pub fn new(inner: T) -> Counter<T> {
+        Counter { inner, count: 0 }
+    }
+}
+
+impl<R: io::Read> io::Read for Counter<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+impl<W: io::Write> io::Write for Counter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
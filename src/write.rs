@@ -0,0 +1,182 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+use std::io;
+use std::io::prelude::*;
+
+use super::options::Hooks;
+use super::{ChunkCodec, ChunkWriter, Header, Options, Result};
+
+/// Writes a compressed Dwarf Fortress data file from uncompressed payload.
+///
+/// The header is written by `new`. Data is buffered and compressed in chunks;
+/// call `finish` to write the last chunk and recover the inner writer.
+pub struct CompressWriter<W: Write, C: ChunkCodec = Box<dyn ChunkCodec + Send>> {
+    inner: Option<ChunkWriter<W>>,
+    codec: C,
+    chunk_size: usize,
+    chunks: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+    hooks: Hooks,
+    buf: Vec<u8>,
+    scratch: Vec<u8>,
+}
+
+impl<W: Write> CompressWriter<W> {
+    pub fn new(inner: W, version: u32) -> Result<CompressWriter<W>> {
+        CompressWriter::with_options(inner, version, &Options::default())
+    }
+
+    pub fn with_options(inner: W, version: u32, options: &Options) -> Result<CompressWriter<W>> {
+        let codec = options.build_codec();
+        CompressWriter::with_codec(inner, version, options, codec)
+    }
+}
+
+impl<W: Write, C: ChunkCodec> CompressWriter<W, C> {
+    /// Creates a writer compressing chunks with `codec`. The compression
+    /// level in `options` is ignored.
+    pub fn with_codec(
+        inner: W,
+        version: u32,
+        options: &Options,
+        codec: C,
+    ) -> Result<CompressWriter<W, C>> {
+        let inner = ChunkWriter::new(inner, Header::new(version, codec.compression()))?;
+        Ok(CompressWriter {
+            inner: Some(inner),
+            codec,
+            chunk_size: options.chunk_size,
+            chunks: 0,
+            bytes_in: 8,
+            bytes_out: 8,
+            hooks: options.hooks.clone(),
+            buf: Vec::with_capacity(options.chunk_size),
+            scratch: Vec::new(),
+        })
+    }
+
+    pub fn get_ref(&self) -> &W {
+        self.inner.as_ref().unwrap().get_ref()
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        self.inner.as_mut().unwrap().get_mut()
+    }
+
+    /// The number of chunks written so far.
+    pub fn chunks(&self) -> u64 {
+        self.chunks
+    }
+
+    pub fn finish(mut self) -> Result<W> {
+        self.try_finish()?;
+        Ok(self.inner.take().unwrap().into_inner())
+    }
+
+    pub(crate) fn try_finish(&mut self) -> Result<()> {
+        if !self.buf.is_empty() {
+            self.write_chunk()?;
+        }
+        self.get_mut().flush()?;
+        Ok(())
+    }
+
+    fn write_chunk(&mut self) -> Result<()> {
+        self.hooks.check()?;
+        self.scratch.clear();
+        self.codec.compress(&self.buf, &mut self.scratch)?;
+        self.inner.as_mut().unwrap().write_chunk(&self.scratch)?;
+        trace!(
+            "compressed chunk {}: {} -> {} bytes",
+            self.chunks,
+            self.buf.len(),
+            self.scratch.len()
+        );
+        self.bytes_in += self.buf.len() as u64;
+        self.bytes_out += 4 + self.scratch.len() as u64;
+        self.buf.clear();
+        self.hooks
+            .progress(self.bytes_in, self.bytes_out, self.chunks);
+        self.chunks += 1;
+        Ok(())
+    }
+}
+
+impl<W: Write, C: ChunkCodec> Write for CompressWriter<W, C> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let n = data.len().min(self.chunk_size - self.buf.len());
+        self.buf.extend_from_slice(&data[..n]);
+        if self.buf.len() == self.chunk_size {
+            self.write_chunk()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.get_mut().flush()
+    }
+}
+
+impl<W: Write, C: ChunkCodec> Drop for CompressWriter<W, C> {
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            let _ = self.try_finish();
+        }
+    }
+}
+
+#[test]
+fn compress_writer_test() {
+    let payload: Vec<u8> = (0..45000).map(|i| (i % 7) as u8).collect();
+    let mut writer = CompressWriter::new(Vec::new(), 1234).unwrap();
+    for piece in payload.chunks(999) {
+        writer.write_all(piece).unwrap();
+    }
+    let compressed = writer.finish().unwrap();
+    let mut reader = crate::DecompressReader::new(&compressed[..]).unwrap();
+    assert_eq!(reader.version(), 1234);
+    let mut result = Vec::new();
+    reader.read_to_end(&mut result).unwrap();
+    assert_eq!(result, payload);
+}
+
+#[test]
+fn compress_writer_options_test() {
+    let payload: Vec<u8> = (0..100000).map(|i| (i % 13) as u8).collect();
+    let options = Options::new().level(9).chunk_size(65536);
+    let mut writer = CompressWriter::with_options(Vec::new(), 1234, &options).unwrap();
+    writer.write_all(&payload).unwrap();
+    let compressed = writer.finish().unwrap();
+    let mut rest = &compressed[8..];
+    let mut chunks = 0;
+    while let Some(n) = crate::read_u32_or_eof(&mut rest).unwrap() {
+        rest = &rest[n as usize..];
+        chunks += 1;
+    }
+    assert_eq!(chunks, 2);
+    assert_eq!(
+        crate::decompress_bytes(&compressed).unwrap()[8..],
+        payload[..]
+    );
+}
+
+#[test]
+fn zero_chunk_size_test() {
+    // Taken as chunks of one byte.
+    let options = Options::new().chunk_size(0);
+    let mut writer = CompressWriter::with_options(Vec::new(), 1234, &options).unwrap();
+    writer.write_all(b"abc").unwrap();
+    let compressed = writer.finish().unwrap();
+    let mut rest = &compressed[8..];
+    let mut chunks = 0;
+    while let Some(n) = crate::read_u32_or_eof(&mut rest).unwrap() {
+        rest = &rest[n as usize..];
+        chunks += 1;
+    }
+    assert_eq!(chunks, 3);
+    assert_eq!(
+        crate::decompress_bytes(&compressed).unwrap()[8..],
+        b"abc"[..]
+    );
+}
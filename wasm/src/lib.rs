@@ -0,0 +1,104 @@
+// Copyright 2018, Mathias Rav <m@git.strova.dk>
+// SPDX-License-Identifier: LGPL-2.1+
+//! JavaScript bindings of dfcompress, built with wasm-pack.
+//! Byte arrays are passed as `Uint8Array`; errors are thrown as `Error`.
+use dfcompress::{Error, Header, Recoder, Target};
+use wasm_bindgen::prelude::*;
+
+fn js_err(e: Error) -> JsError {
+    JsError::new(&e.to_string())
+}
+
+/// Compresses a data file.
+#[wasm_bindgen]
+pub fn compress(data: &[u8]) -> Result<Vec<u8>, JsError> {
+    dfcompress::compress_bytes(data).map_err(js_err)
+}
+
+/// Decompresses a data file.
+#[wasm_bindgen]
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>, JsError> {
+    dfcompress::decompress_bytes(data).map_err(js_err)
+}
+
+/// The header of a data file.
+#[wasm_bindgen]
+pub struct HeaderInfo(Header);
+
+#[wasm_bindgen]
+impl HeaderInfo {
+    #[wasm_bindgen(getter)]
+    pub fn version(&self) -> u32 {
+        self.0.version
+    }
+
+    /// The compression flag: 0 if uncompressed, 1 for zlib.
+    #[wasm_bindgen(getter)]
+    pub fn compression(&self) -> u32 {
+        self.0.compression.to_u32()
+    }
+
+    /// The Dwarf Fortress release that writes this version, if known.
+    #[wasm_bindgen(getter, js_name = dfRelease)]
+    pub fn df_release(&self) -> Option<String> {
+        self.0.df_release().map(String::from)
+    }
+}
+
+/// Reads the header at the start of `data`, which need not hold the rest
+/// of the file.
+#[wasm_bindgen]
+pub fn header(data: &[u8]) -> Result<HeaderInfo, JsError> {
+    Header::parse(data).map(HeaderInfo).map_err(js_err)
+}
+
+/// Converts a data file fed piece by piece, such as the chunks of a
+/// `ReadableStream`: `write` returns the output so far, `finish` the rest.
+#[wasm_bindgen]
+pub struct Converter(Recoder);
+
+#[wasm_bindgen]
+impl Converter {
+    /// A converter that compresses, or decompresses if `compress` is false.
+    #[wasm_bindgen(constructor)]
+    pub fn new(compress: bool) -> Converter {
+        let target = if compress {
+            Target::Compressed
+        } else {
+            Target::Uncompressed
+        };
+        Converter(Recoder::new(target))
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> Result<Vec<u8>, JsError> {
+        self.0.push(data).map_err(js_err)?;
+        Ok(std::mem::take(self.0.output()))
+    }
+
+    pub fn finish(&mut self) -> Result<Vec<u8>, JsError> {
+        self.0.finish().map_err(js_err)?;
+        Ok(std::mem::take(self.0.output()))
+    }
+}
+
+#[test]
+fn wasm_test() {
+    let mut data = Header::new(1625, dfcompress::Compression::Uncompressed)
+        .to_bytes()
+        .to_vec();
+    data.extend((0..100000usize).map(|i| (i * i % 251) as u8));
+    let compressed = compress(&data).ok().unwrap();
+    assert_eq!(compressed, dfcompress::compress_bytes(&data).unwrap());
+    assert_eq!(decompress(&compressed).ok().unwrap(), data);
+    let info = header(&compressed).ok().unwrap();
+    assert_eq!((info.version(), info.compression()), (1625, 1));
+    assert_eq!(info.df_release().as_deref(), Some("0.44.12"));
+
+    let mut converter = Converter::new(false);
+    let mut output = Vec::new();
+    for piece in compressed.chunks(1000) {
+        output.extend(converter.write(piece).ok().unwrap());
+    }
+    output.extend(converter.finish().ok().unwrap());
+    assert_eq!(output, data);
+}